@@ -0,0 +1,62 @@
+//! Local record of the newest signed manifest this device has seen
+//!
+//! Kept outside the server's reach so a replayed older manifest can be
+//! recognised even though its signature is valid.
+
+use crate::crypto::manifest::ManifestCheckpoint;
+use crate::error::{Error, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File-backed store for one account's manifest checkpoint
+#[derive(Debug, Clone)]
+pub struct CheckpointStore {
+    path: PathBuf,
+}
+
+impl CheckpointStore {
+    /// Creates a store backed by the given file
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Store for `user_id` under the client data directory
+    pub fn for_user(data_dir: &Path, user_id: &str) -> Self {
+        Self::new(data_dir.join("checkpoints").join(format!("{}.json", user_id)))
+    }
+
+    /// Loads the checkpoint, if this device has seen a manifest yet
+    pub fn load(&self) -> Result<Option<ManifestCheckpoint>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let content = fs::read_to_string(&self.path)?;
+        serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|e| Error::SerializationError(e.to_string()))
+    }
+
+    /// Saves the checkpoint, replacing the previous one atomically
+    pub fn save(&self, checkpoint: &ManifestCheckpoint) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_string_pretty(checkpoint)
+            .map_err(|e| Error::SerializationError(e.to_string()))?;
+
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, content)?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Forgets the checkpoint
+    pub fn clear(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+}
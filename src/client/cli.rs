@@ -1,5 +1,6 @@
 //! CLI commands for RustGuard client
 
+use crate::crypto::SecretString;
 use crate::error::Result;
 use clap::{Parser, Subcommand};
 use dialoguer::Input;
@@ -36,6 +37,21 @@ pub enum Commands {
         password: Option<String>,
     },
 
+    /// Generate a recovery key for a forgotten passphrase
+    RecoveryKey {
+        #[arg(short, long)]
+        username: Option<String>,
+    },
+
+    /// Set a new passphrase using your recovery key
+    Recover {
+        #[arg(short, long)]
+        username: Option<String>,
+    },
+
+    /// Replace the master key and re-wrap every file's data key
+    RotateKey,
+
     /// Add a directory to sync
     AddSync {
         #[arg(short, long)]
@@ -49,7 +65,15 @@ pub enum Commands {
     ListSync,
 
     /// Start sync daemon
-    Sync,
+    Sync {
+        /// Print what a sync pass would do, without changing anything
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Print the plan as JSON
+        #[arg(long, requires = "dry_run")]
+        json: bool,
+    },
 
     /// Show sync status
     Status,
@@ -66,6 +90,58 @@ pub enum Commands {
     /// List files
     List,
 
+    /// Share a file, or every file under a folder, with another user
+    Share {
+        #[arg(short, long)]
+        path: String,
+
+        #[arg(short, long)]
+        user: String,
+    },
+
+    /// List shares you created and files shared with you
+    Shares,
+
+    /// Download a file shared with you
+    DownloadShared {
+        #[arg(short, long)]
+        share_id: String,
+
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Revoke a share and move the file to a new data key
+    Revoke {
+        #[arg(short, long)]
+        share_id: String,
+    },
+
+    /// List and resolve sync conflicts
+    Conflicts {
+        /// Only list conflicts, without resolving them
+        #[arg(long)]
+        list: bool,
+
+        /// Resolve every conflict by keeping the local version
+        #[arg(long, conflicts_with_all = ["keep_remote", "keep_both"])]
+        keep_local: bool,
+
+        /// Resolve every conflict by keeping the remote version
+        #[arg(long, conflicts_with = "keep_both")]
+        keep_remote: bool,
+
+        /// Resolve every conflict by keeping both, saving the local version
+        /// as a conflicted copy
+        #[arg(long)]
+        keep_both: bool,
+    },
+
+    /// Explain whether a path is excluded from sync, and by which rule
+    CheckIgnore {
+        path: PathBuf,
+    },
+
     /// Show version
     Version,
 
@@ -89,9 +165,39 @@ pub fn prompt_input(message: &str) -> Result<String> {
 }
 
 /// Secure password input helper
-pub fn prompt_password(message: &str) -> Result<String> {
+pub fn prompt_password(message: &str) -> Result<SecretString> {
+    dialoguer::Password::new()
+        .with_prompt(message)
+        .interact()
+        .map(SecretString::from)
+        .map_err(|e| crate::error::Error::Internal(e.to_string()))
+}
+
+/// Password input with confirmation, for choosing a new password
+pub fn prompt_new_password(message: &str) -> Result<SecretString> {
     dialoguer::Password::new()
         .with_prompt(message)
+        .with_confirmation("Confirm", "Passwords do not match")
+        .interact()
+        .map(SecretString::from)
+        .map_err(|e| crate::error::Error::Internal(e.to_string()))
+}
+
+/// Yes/no confirmation helper; defaults to no
+pub fn confirm(message: &str) -> Result<bool> {
+    dialoguer::Confirm::new()
+        .with_prompt(message)
+        .default(false)
+        .interact()
+        .map_err(|e| crate::error::Error::Internal(e.to_string()))
+}
+
+/// Choice from a list; returns the index of the selected item
+pub fn prompt_select(message: &str, items: &[&str]) -> Result<usize> {
+    dialoguer::Select::new()
+        .with_prompt(message)
+        .items(items)
+        .default(0)
         .interact()
         .map_err(|e| crate::error::Error::Internal(e.to_string()))
 }
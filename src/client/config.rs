@@ -1,5 +1,7 @@
 //! Client configuration management
 
+use crate::crypto::chunking::ChunkingParams;
+use crate::crypto::{CipherSuite, KdfParams};
 use crate::error::Result;
 use serde::{Deserialize, Serialize};
 use std::fs;
@@ -8,8 +10,12 @@ use std::path::Path;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UserConfig {
     pub username: String,
+    #[serde(default)]
     pub email: String,
-    pub token: String,
+    /// Legacy plaintext session token; read so old configs still load, but
+    /// never written back. Tokens now live in the keystore.
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
     pub user_id: String,
 }
 
@@ -25,6 +31,27 @@ pub struct ClientConfigFile {
     pub server_url: String,
     pub user: Option<UserConfig>,
     pub sync_directories: Vec<SyncDirConfig>,
+    /// Argon2id cost used when deriving keys for new or upgraded accounts
+    #[serde(default)]
+    pub kdf: KdfParams,
+    /// Cipher used to encrypt newly uploaded data; existing data records its
+    /// own cipher and stays readable
+    #[serde(default)]
+    pub cipher_suite: CipherSuite,
+    /// Content-defined chunk sizes for new uploads
+    #[serde(default)]
+    pub chunking: ChunkingParams,
+    /// How long the sync daemon keeps the keystore unlocked, in seconds;
+    /// `None` asks for the passphrase before every sync pass
+    #[serde(default)]
+    pub unlock_cache_secs: Option<u64>,
+    /// Name for this machine in conflicted copies; defaults to the hostname
+    #[serde(default)]
+    pub device_name: Option<String>,
+    /// Ignore patterns applied to every sync directory, in
+    /// `.rustguardignore` syntax
+    #[serde(default)]
+    pub exclude: Vec<String>,
 }
 
 impl ClientConfigFile {
@@ -34,9 +61,26 @@ impl ClientConfigFile {
             server_url,
             user: None,
             sync_directories: Vec::new(),
+            kdf: KdfParams::default(),
+            cipher_suite: CipherSuite::default(),
+            chunking: ChunkingParams::default(),
+            unlock_cache_secs: None,
+            device_name: None,
+            exclude: Vec::new(),
         }
     }
 
+    /// Returns the configured device name, falling back to the hostname
+    pub fn device_name(&self) -> String {
+        self.device_name
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .or_else(|| fs::read_to_string("/etc/hostname").ok())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "unknown device".to_string())
+    }
+
     /// Loads config from file
     pub fn load(path: &Path) -> Result<Self> {
         let content = fs::read_to_string(path)
@@ -0,0 +1,149 @@
+//! Applying conflict resolutions
+//!
+//! Conflicts that cannot be resolved automatically wait in the sync index
+//! until the user picks a side. [`ConflictResolver`] carries out that
+//! choice: it moves the chosen content into place on both sides and records
+//! the new common base, so the file is back in sync afterwards.
+
+use crate::client::sync_client::SyncClient;
+use crate::crypto::keys::AccountKeys;
+use crate::error::{Error, Result};
+use crate::sync::conflict::{keep_both, ConflictRecord, ConflictResolution};
+use crate::sync::index::{local_path, relative_path, FileStat, IndexEntry, SyncIndex};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::info;
+
+/// Applies the user's choice for queued conflicts
+pub struct ConflictResolver<'a> {
+    client: &'a SyncClient,
+    account: &'a AccountKeys,
+    index: &'a SyncIndex,
+    device: String,
+}
+
+impl<'a> ConflictResolver<'a> {
+    /// Creates a resolver; `device` names this machine in conflicted copies
+    pub fn new(client: &'a SyncClient, account: &'a AccountKeys, index: &'a SyncIndex, device: String) -> Self {
+        Self {
+            client,
+            account,
+            index,
+            device,
+        }
+    }
+
+    /// Downloads the remote side of a conflict next to the local file
+    ///
+    /// Returns `None` if the file was deleted remotely. The caller removes
+    /// the temporary file when done with it.
+    pub async fn fetch_remote(&self, record: &ConflictRecord) -> Result<Option<PathBuf>> {
+        let Some(remote_id) = &record.details.remote_id else {
+            return Ok(None);
+        };
+        if record.details.remote.is_none() {
+            return Ok(None);
+        }
+
+        let temp_path = remote_temp_path(&local_path(&record.root, &record.details.path));
+        self.client.download_file(remote_id, &temp_path, self.account).await?;
+        Ok(Some(temp_path))
+    }
+
+    /// Resolves a queued conflict, syncing the chosen content to both sides
+    ///
+    /// `remote_root` is the remote path of the conflict's sync directory.
+    pub async fn apply(&self, record: &ConflictRecord, remote_root: &str, resolution: ConflictResolution) -> Result<()> {
+        let details = &record.details;
+        let local = local_path(&record.root, &details.path);
+        let remote = remote_path(remote_root, &details.path);
+
+        // Modification times from different clocks cannot order two
+        // diverged versions, so the user has to pick a side
+        if matches!(resolution, ConflictResolution::Manual | ConflictResolution::LastWriteWins) {
+            return Err(Error::InvalidInput("Choose local, remote or both".to_string()));
+        }
+
+        let mut conflicted_copy = None;
+        match resolution {
+            ConflictResolution::KeepLocal if local.exists() => {
+                self.upload(&record.root, &details.path, &local, &remote).await?;
+            }
+            ConflictResolution::KeepLocal => {
+                if let Some(remote_id) = &details.remote_id {
+                    self.client.delete_file(remote_id, self.account).await?;
+                }
+                self.index.remove(&record.root, &details.path).await?;
+            }
+            ConflictResolution::KeepRemote => match self.fetch_remote(record).await? {
+                Some(temp_path) => {
+                    fs::rename(&temp_path, &local).await?;
+                    self.record_synced(&record.root, &details.path, &local, &remote).await?;
+                }
+                None => {
+                    if local.exists() {
+                        fs::remove_file(&local).await?;
+                    }
+                    self.index.remove(&record.root, &details.path).await?;
+                }
+            },
+            _ => {
+                let temp_path = match self.fetch_remote(record).await? {
+                    Some(temp_path) => temp_path,
+                    None => remote_temp_path(&local),
+                };
+                let date = chrono::Local::now().date_naive();
+                let resolved = keep_both(&local, &temp_path, &self.device, date)?;
+
+                if resolved.kept == temp_path {
+                    fs::rename(&temp_path, &local).await?;
+                    self.record_synced(&record.root, &details.path, &local, &remote).await?;
+                } else {
+                    self.upload(&record.root, &details.path, &local, &remote).await?;
+                }
+
+                if let Some(copy) = resolved.conflicted_copy {
+                    let copy_path = relative_path(&record.root, &copy)
+                        .ok_or_else(|| Error::SyncError(format!("Invalid conflicted copy path {}", copy.display())))?;
+                    self.upload(&record.root, &copy_path, &copy, &remote_path(remote_root, &copy_path))
+                        .await?;
+                    conflicted_copy = Some(copy_path);
+                }
+            }
+        }
+
+        self.index
+            .resolve_conflict(record.id, resolution, conflicted_copy.as_deref())
+            .await?;
+        info!("Resolved conflict on {} with {}", details.path, resolution.as_str());
+        Ok(())
+    }
+
+    async fn upload(&self, root: &Path, path: &str, local: &Path, remote: &str) -> Result<()> {
+        // The user chose this content over whatever the server has now
+        self.client.upload_file(local, remote, self.account, None).await?;
+        self.record_synced(root, path, local, remote).await
+    }
+
+    /// Records the file as in sync, making its current content the new base
+    async fn record_synced(&self, root: &Path, path: &str, local: &Path, remote: &str) -> Result<()> {
+        let file = self
+            .client
+            .find_file(remote, self.account)
+            .await?
+            .ok_or_else(|| Error::SyncError(format!("{} is missing on the server after syncing", remote)))?;
+
+        let entry = IndexEntry::synced(path, FileStat::read(local)?, &file.encrypted_hash, &file.id, file.version);
+        self.index.upsert(root, &entry).await
+    }
+}
+
+/// Remote path of a file in the sync directory mapped to `remote_root`
+pub fn remote_path(remote_root: &str, path: &str) -> String {
+    format!("{}/{}", remote_root.trim_end_matches('/'), path)
+}
+
+fn remote_temp_path(local: &Path) -> PathBuf {
+    let name = local.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    local.with_file_name(format!(".rustguard-{}.remote", name))
+}
@@ -0,0 +1,303 @@
+//! Running sync passes
+//!
+//! A pass scans a sync directory, compares it with the server and carries
+//! out the resulting [`SyncPlan`]. Before a local file is overwritten or
+//! deleted it is checked against the index once more, so edits made while
+//! the pass runs are left for the next one instead of being lost.
+
+use crate::client::conflicts::remote_path;
+use crate::client::sync_client::SyncClient;
+use crate::crypto::keys::AccountKeys;
+use crate::crypto::FingerprintKey;
+use crate::error::{Error, Result};
+use crate::sync::conflict::{ConflictDetails, ConflictSide};
+use crate::sync::index::{local_path, within, FileStat, IndexEntry, SyncIndex};
+use crate::sync::journal::PendingChanges;
+use crate::sync::planner::{remote_entries, PlannedRename, PlannedTransfer, RemoteEntry, Side, SyncPlan};
+use crate::sync::{ConflictKind, IgnoreRules};
+use chrono::DateTime;
+use std::collections::HashSet;
+use std::path::Path;
+use tokio::fs;
+use tracing::{info, warn};
+
+/// Plans and applies sync passes for the configured sync directories
+pub struct SyncEngine<'a> {
+    client: &'a SyncClient,
+    account: &'a AccountKeys,
+    index: &'a SyncIndex,
+    device: String,
+}
+
+impl<'a> SyncEngine<'a> {
+    /// Creates an engine; `device` names this machine in queued conflicts
+    pub fn new(client: &'a SyncClient, account: &'a AccountKeys, index: &'a SyncIndex, device: String) -> Self {
+        Self {
+            client,
+            account,
+            index,
+            device,
+        }
+    }
+
+    /// Scans `root` and plans how to bring it in sync with `remote_root`
+    ///
+    /// `moves` are renames the watcher reported under `root`, as relative
+    /// paths. Only the index is updated; no files are changed on either side.
+    pub async fn plan(
+        &self,
+        root: &Path,
+        remote_root: &str,
+        moves: &[(String, String)],
+        ignore: &IgnoreRules,
+    ) -> Result<SyncPlan> {
+        let scan = self.index.scan(root, ignore, &FingerprintKey::derive(self.account.account_key())).await?;
+        let entries = self.index.entries(root).await?;
+        let files = self.client.list_files_decrypted(self.account).await?;
+
+        Ok(SyncPlan::build(&entries, &scan.deleted, moves, &remote_entries(&files, remote_root), ignore))
+    }
+
+    /// Plans a pass over only the journaled changes under `root`
+    ///
+    /// Only the changed paths are scanned, so syncing what happened while
+    /// offline does not walk the whole directory. Falls back to a full scan
+    /// if the journal lost track of changes.
+    pub async fn plan_changes(
+        &self,
+        root: &Path,
+        remote_root: &str,
+        pending: &PendingChanges,
+        ignore: &IgnoreRules,
+    ) -> Result<SyncPlan> {
+        if pending.needs_rescan() {
+            return self.plan(root, remote_root, &pending.moves(), ignore).await;
+        }
+
+        let scope = pending.paths();
+        let in_scope = |path: &str| scope.iter().any(|dir| within(path, dir));
+        let scan = self
+            .index
+            .scan_paths(root, &scope, ignore, &FingerprintKey::derive(self.account.account_key()))
+            .await?;
+
+        let (entries, others): (Vec<IndexEntry>, Vec<IndexEntry>) = self
+            .index
+            .entries(root)
+            .await?
+            .into_iter()
+            .partition(|entry| in_scope(&entry.path));
+        let known: HashSet<&str> = entries
+            .iter()
+            .chain(&others)
+            .map(|entry| entry.path.as_str())
+            .collect();
+        let ids: HashSet<&str> = entries.iter().filter_map(|entry| entry.remote_id.as_deref()).collect();
+
+        // Remote files elsewhere are only needed to notice a file in scope
+        // that was moved remotely to a path this device does not have yet
+        let files = self.client.list_files_decrypted(self.account).await?;
+        let remote: Vec<RemoteEntry> = remote_entries(&files, remote_root)
+            .into_iter()
+            .filter(|file| in_scope(&file.path) || (ids.contains(file.id.as_str()) && !known.contains(file.path.as_str())))
+            .collect();
+
+        Ok(SyncPlan::build(&entries, &scan.deleted, &pending.moves(), &remote, ignore))
+    }
+
+    /// Carries out `plan` for the sync directory at `root`
+    ///
+    /// Actions that fail are logged and skipped; the next pass plans them
+    /// again. Returns the number of actions that failed.
+    pub async fn apply(&self, root: &Path, remote_root: &str, plan: &SyncPlan) -> Result<usize> {
+        let mut failed = 0;
+        let mut check = |action: &str, path: &str, result: Result<()>| {
+            if let Err(e) = result {
+                warn!("Could not {} {}: {}", action, path, e);
+                failed += 1;
+            }
+        };
+
+        for rename in plan.renames.iter().filter(|rename| rename.side == Side::Local) {
+            check("move", &rename.from, self.rename_local(root, &rename.from, &rename.to).await);
+        }
+        // Moved before uploading, so an edit made along with the move
+        // becomes a new version of the moved file
+        for rename in plan.renames.iter().filter(|rename| rename.side == Side::Remote) {
+            let remote = remote_path(remote_root, &rename.to);
+            check("move", &rename.from, self.rename_remote(root, rename, &remote).await);
+        }
+        for download in &plan.downloads {
+            check("download", &download.path, self.download(root, download).await);
+        }
+        for upload in &plan.uploads {
+            let remote = remote_path(remote_root, &upload.path);
+            let result = match self.upload(root, upload, &remote).await {
+                Err(Error::ConflictError(reason)) => self.queue_upload_conflict(root, upload, &remote, &reason).await,
+                result => result,
+            };
+            check("upload", &upload.path, result);
+        }
+        for delete in &plan.remote_deletes {
+            let result = async {
+                if let Some(remote_id) = &delete.remote_id {
+                    self.client.delete_file(remote_id, self.account).await?;
+                }
+                self.index.remove(root, &delete.path).await
+            };
+            check("delete remote", &delete.path, result.await);
+        }
+        for delete in &plan.local_deletes {
+            check("delete", &delete.path, self.delete_local(root, &delete.path).await);
+        }
+
+        let queued = self.index.pending_conflicts().await?;
+        for conflict in &plan.conflicts {
+            let already_queued = queued
+                .iter()
+                .any(|record| record.root == root && record.details.path == conflict.path);
+            if !already_queued {
+                self.index.queue_conflict(root, &conflict.details(&self.device)).await?;
+                info!("Queued conflict on {}", conflict.path);
+            }
+        }
+
+        for converged in &plan.converged {
+            if let (Some(remote_id), Some(version)) = (&converged.remote_id, converged.remote_version) {
+                self.index
+                    .mark_synced(root, &converged.path, remote_id, version, &converged.fingerprint)
+                    .await?;
+            }
+        }
+        for path in &plan.forgotten {
+            self.index.remove(root, path).await?;
+        }
+
+        Ok(failed)
+    }
+
+    async fn rename_local(&self, root: &Path, from: &str, to: &str) -> Result<()> {
+        let target = local_path(root, to);
+        if fs::try_exists(&target).await? {
+            return Err(Error::SyncError(format!("{} already exists", to)));
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(local_path(root, from), &target).await?;
+        self.index.rename(root, from, to).await
+    }
+
+    /// Moves the remote file without transferring its content
+    ///
+    /// The moved file's content becomes the base at its new path. If the
+    /// local file was edited too, the plan's upload for it follows.
+    async fn rename_remote(&self, root: &Path, rename: &PlannedRename, remote: &str) -> Result<()> {
+        let file = self.client.move_file(&rename.remote_id, remote, self.account).await?;
+        self.index.remove(root, &rename.from).await?;
+        self.index
+            .mark_synced(root, &rename.to, &file.id, file.version, &file.encrypted_hash)
+            .await?;
+        info!("Moved {} to {} without re-uploading", rename.from, rename.to);
+        Ok(())
+    }
+
+    async fn download(&self, root: &Path, download: &PlannedTransfer) -> Result<()> {
+        self.ensure_unchanged(root, &download.path).await?;
+        let (Some(remote_id), Some(version)) = (&download.remote_id, download.remote_version) else {
+            return Err(Error::SyncError("Download has no remote file".to_string()));
+        };
+
+        let local = local_path(root, &download.path);
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        self.client.download_file(remote_id, &local, self.account).await?;
+
+        let entry = IndexEntry::synced(&download.path, FileStat::read(&local)?, &download.fingerprint, remote_id, version);
+        self.index.upsert(root, &entry).await
+    }
+
+    /// Uploads a local file and records it as in sync
+    ///
+    /// The stat data is read before uploading, so an edit made meanwhile
+    /// no longer matches the index and is picked up by the next scan. The
+    /// upload is based on the remote version the plan saw, so the server
+    /// rejects it if another device committed in between.
+    async fn upload(&self, root: &Path, upload: &PlannedTransfer, remote: &str) -> Result<()> {
+        let path = upload.path.as_str();
+        let local = local_path(root, path);
+        let stat = FileStat::read(&local)?;
+        let base_version = upload.remote_version.unwrap_or(0);
+        self.client
+            .upload_file(&local, remote, self.account, Some(base_version))
+            .await?;
+
+        let file = self
+            .client
+            .find_file(remote, self.account)
+            .await?
+            .ok_or_else(|| Error::SyncError(format!("{} is missing on the server after syncing", remote)))?;
+        let entry = IndexEntry::synced(path, stat, &file.encrypted_hash, &file.id, file.version);
+        self.index.upsert(root, &entry).await
+    }
+
+    /// Queues a conflict for an upload the server rejected because the
+    /// remote file changed after the plan was made
+    async fn queue_upload_conflict(&self, root: &Path, upload: &PlannedTransfer, remote: &str, reason: &str) -> Result<()> {
+        let stat = FileStat::read(&local_path(root, &upload.path))?;
+        let file = self.client.find_file(remote, self.account).await?;
+        let kind = match (&file, upload.remote_version) {
+            (None, _) => ConflictKind::RemoteDeleted,
+            (Some(_), None) => ConflictKind::BothCreated,
+            (Some(_), Some(_)) => ConflictKind::BothModified,
+        };
+
+        let details = ConflictDetails {
+            path: upload.path.clone(),
+            kind,
+            local: Some(ConflictSide {
+                size: stat.size,
+                modified: DateTime::from_timestamp_nanos(stat.mtime_ns),
+                version: None,
+            }),
+            remote: file.as_ref().map(|file| ConflictSide {
+                size: file.size,
+                modified: file.updated_at,
+                version: Some(file.version),
+            }),
+            remote_id: file.map(|file| file.id).or_else(|| upload.remote_id.clone()),
+            device: self.device.clone(),
+        };
+        self.index.queue_conflict(root, &details).await?;
+        info!("Queued conflict on {}: {}", upload.path, reason);
+        Ok(())
+    }
+
+    async fn delete_local(&self, root: &Path, path: &str) -> Result<()> {
+        self.ensure_unchanged(root, path).await?;
+        let local = local_path(root, path);
+        if fs::try_exists(&local).await? {
+            fs::remove_file(&local).await?;
+        }
+        self.index.remove(root, path).await
+    }
+
+    /// Fails if the local file changed since the scan, so replacing or
+    /// deleting it would lose an edit
+    async fn ensure_unchanged(&self, root: &Path, path: &str) -> Result<()> {
+        let entry = self.index.get(root, path).await?;
+        let synced = entry.as_ref().filter(|entry| entry.base_fingerprint.is_some());
+        let unchanged = match (FileStat::read(&local_path(root, path)), synced) {
+            (Ok(stat), Some(entry)) => entry.stat == stat,
+            (Ok(_), None) => false,
+            (Err(_), _) => true,
+        };
+
+        if unchanged {
+            Ok(())
+        } else {
+            Err(Error::SyncError("changed locally since the scan".to_string()))
+        }
+    }
+}
@@ -0,0 +1,417 @@
+//! Encrypted local keystore
+//!
+//! Lives next to the config file and holds everything the client needs to
+//! act for an account: the key material (wrapped master key, wrapped sharing
+//! key, KDF parameters) and the session token. The key material is already
+//! protected by the passphrase; the token and other session secrets are
+//! sealed under a key derived from the master key, so nothing usable is
+//! stored in plaintext. The manifest signing key is derived from the account
+//! key and needs no storage of its own.
+//!
+//! The file is written with owner-only permissions, and loading it fails if
+//! anyone else can read it.
+
+use crate::crypto::keys::{derive_subkey, unlock_account, unwrap_bytes, wrap_bytes, AccountKeys, PassphraseKeys};
+use crate::crypto::{SecretKey, SecretString};
+use crate::error::{Error, Result};
+use crate::models::UserKeyMaterial;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Current keystore file format version
+pub const KEYSTORE_VERSION: u8 = 1;
+
+const SECRETS_PURPOSE: &str = "keystore-secrets";
+
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u8,
+    username: String,
+    user_id: String,
+    key_material: UserKeyMaterial,
+    /// [`KeystoreSecrets`] sealed under the keystore subkey
+    sealed_secrets: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeystoreSecrets {
+    token: SecretString,
+}
+
+/// The keystore as stored on disk; must be unlocked before use
+pub struct Keystore {
+    path: PathBuf,
+    file: KeystoreFile,
+}
+
+impl Keystore {
+    /// Creates a keystore for a freshly logged in account and writes it to `path`
+    pub fn create(
+        path: &Path,
+        username: &str,
+        user_id: &str,
+        key_material: UserKeyMaterial,
+        master_key: &SecretKey,
+        token: SecretString,
+    ) -> Result<Self> {
+        let keystore = Self {
+            path: path.to_path_buf(),
+            file: KeystoreFile {
+                version: KEYSTORE_VERSION,
+                username: username.to_string(),
+                user_id: user_id.to_string(),
+                key_material,
+                sealed_secrets: seal_secrets(&KeystoreSecrets { token }, master_key, user_id)?,
+            },
+        };
+
+        keystore.save()?;
+        Ok(keystore)
+    }
+
+    /// Returns true if a keystore exists at `path`
+    pub fn exists(path: &Path) -> bool {
+        path.exists()
+    }
+
+    /// Loads the keystore, refusing files other users can access
+    pub fn load(path: &Path) -> Result<Self> {
+        check_permissions(path)?;
+
+        let content = fs::read_to_string(path)?;
+        let file: KeystoreFile = serde_json::from_str(&content)
+            .map_err(|e| Error::ConfigError(format!("Invalid keystore {}: {}", path.display(), e)))?;
+
+        if file.version > KEYSTORE_VERSION {
+            return Err(Error::ConfigError(format!(
+                "Keystore version {} is newer than this client supports",
+                file.version
+            )));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Account the keystore belongs to
+    pub fn username(&self) -> &str {
+        &self.file.username
+    }
+
+    /// Server id of the account
+    pub fn user_id(&self) -> &str {
+        &self.file.user_id
+    }
+
+    /// Key material as last synced with the server
+    pub fn key_material(&self) -> &UserKeyMaterial {
+        &self.file.key_material
+    }
+
+    /// Unlocks the keystore with the account passphrase
+    pub fn unlock(&self, passphrase: &SecretString) -> Result<UnlockedKeystore> {
+        let passphrase_keys = PassphraseKeys::derive(passphrase, self.file.key_material.kdf.clone())?;
+        let account = unlock_account(&passphrase_keys, &self.file.key_material)?;
+        let secrets = open_secrets(&self.file.sealed_secrets, account.master_key(), &self.file.user_id)?;
+
+        Ok(UnlockedKeystore {
+            username: self.file.username.clone(),
+            user_id: self.file.user_id.clone(),
+            account,
+            auth_key: passphrase_keys.auth_key(),
+            token: secrets.token,
+        })
+    }
+
+    /// Replaces the stored key material, e.g. after a passphrase change
+    ///
+    /// The master key must be unchanged, as the sealed secrets depend on it.
+    pub fn update_key_material(&mut self, key_material: UserKeyMaterial) -> Result<()> {
+        self.file.key_material = key_material;
+        self.save()
+    }
+
+    /// Replaces the stored session token, e.g. after logging in again once
+    /// the old one expired; returns `unlocked` with the new token
+    pub fn update_token(&mut self, unlocked: &UnlockedKeystore, token: SecretString) -> Result<UnlockedKeystore> {
+        let secrets = KeystoreSecrets {
+            token: SecretString::from(token.expose()),
+        };
+        self.file.sealed_secrets = seal_secrets(&secrets, unlocked.account.master_key(), &self.file.user_id)?;
+        self.save()?;
+
+        Ok(UnlockedKeystore {
+            username: unlocked.username.clone(),
+            user_id: unlocked.user_id.clone(),
+            account: unlocked.account.duplicate(),
+            auth_key: SecretString::from(unlocked.auth_key.expose()),
+            token,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let content = serde_json::to_string_pretty(&self.file)
+            .map_err(|e| Error::SerializationError(e.to_string()))?;
+
+        let temp_path = self.path.with_extension("tmp");
+        write_private(&temp_path, content.as_bytes())?;
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Keys and tokens recovered from an unlocked keystore
+#[derive(Debug)]
+pub struct UnlockedKeystore {
+    pub username: String,
+    pub user_id: String,
+    account: AccountKeys,
+    auth_key: SecretString,
+    token: SecretString,
+}
+
+impl UnlockedKeystore {
+    /// The account's master and account keys
+    pub fn account(&self) -> &AccountKeys {
+        &self.account
+    }
+
+    /// Authentication key derived from the passphrase, for logging in
+    /// again once the session token expires
+    pub fn auth_key(&self) -> &SecretString {
+        &self.auth_key
+    }
+
+    /// The session token for API requests
+    pub fn token(&self) -> &SecretString {
+        &self.token
+    }
+}
+
+/// Keeps an unlocked keystore in memory for a limited time
+///
+/// Lets the sync daemon run pass after pass without asking for the
+/// passphrase each time. Once the time is up the cache drops the keys, which
+/// are wiped when the last pass still using them finishes, and the next
+/// access has to unlock again. A zero duration caches nothing.
+pub struct UnlockCache {
+    ttl: Duration,
+    entry: Mutex<Option<(Instant, Arc<UnlockedKeystore>)>>,
+}
+
+impl UnlockCache {
+    /// Creates an empty cache that holds an unlock for `ttl`
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: Mutex::new(None),
+        }
+    }
+
+    /// Returns the cached unlock if it has not expired
+    pub fn get(&self) -> Option<Arc<UnlockedKeystore>> {
+        self.clear_expired();
+        self.entry.lock().as_ref().map(|(_, keystore)| keystore.clone())
+    }
+
+    /// Caches an unlocked keystore, replacing any previous one
+    pub fn put(&self, keystore: UnlockedKeystore) -> Arc<UnlockedKeystore> {
+        let keystore = Arc::new(keystore);
+        *self.entry.lock() = Some((Instant::now(), keystore.clone()));
+        keystore
+    }
+
+    /// Replaces the cached unlock, e.g. after a token update, without
+    /// extending how long it is kept
+    pub fn refresh(&self, keystore: UnlockedKeystore) -> Arc<UnlockedKeystore> {
+        let keystore = Arc::new(keystore);
+        let mut entry = self.entry.lock();
+        if let Some((_, cached)) = entry.as_mut() {
+            *cached = keystore.clone();
+        }
+        keystore
+    }
+
+    /// When the cached unlock expires, if there is one
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.entry.lock().as_ref().map(|(unlocked_at, _)| *unlocked_at + self.ttl)
+    }
+
+    /// Drops the cached unlock if it has expired
+    pub fn clear_expired(&self) {
+        let mut entry = self.entry.lock();
+        if entry.as_ref().is_some_and(|(unlocked_at, _)| unlocked_at.elapsed() >= self.ttl) {
+            *entry = None;
+        }
+    }
+
+    /// Drops the cached unlock
+    pub fn clear(&self) {
+        *self.entry.lock() = None;
+    }
+}
+
+fn seal_secrets(secrets: &KeystoreSecrets, master_key: &SecretKey, user_id: &str) -> Result<String> {
+    let json = zeroize::Zeroizing::new(
+        serde_json::to_vec(secrets).map_err(|e| Error::SerializationError(e.to_string()))?,
+    );
+    wrap_bytes(&json, &derive_subkey(master_key, "keystore"), SECRETS_PURPOSE, user_id)
+}
+
+fn open_secrets(sealed: &str, master_key: &SecretKey, user_id: &str) -> Result<KeystoreSecrets> {
+    let json = unwrap_bytes(sealed, &derive_subkey(master_key, "keystore"), SECRETS_PURPOSE, user_id)?;
+    serde_json::from_slice(&json).map_err(|e| Error::SerializationError(e.to_string()))
+}
+
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(Error::ConfigError(format!(
+            "Keystore {} is accessible by other users (mode {:o}); run `chmod 600` on it",
+            path.display(),
+            mode
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, content: &[u8]) -> Result<()> {
+    fs::write(path, content)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::create_account_keys;
+    use crate::crypto::sharing;
+    use tempfile::TempDir;
+
+    fn create_keystore(path: &Path, passphrase: &str) -> AccountKeys {
+        let passphrase_keys = PassphraseKeys::for_tests(passphrase);
+        let (account, key_material) = create_account_keys(&passphrase_keys, sharing::test_key()).unwrap();
+
+        Keystore::create(path, "alice", "user-1", key_material, account.master_key(), SecretString::from("token-1"))
+            .unwrap();
+        account
+    }
+
+    #[test]
+    fn test_keystore_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("keystore.json");
+        let account = create_keystore(&path, "passphrase");
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("token-1"));
+
+        let mut keystore = Keystore::load(&path).unwrap();
+        assert_eq!((keystore.username(), keystore.user_id()), ("alice", "user-1"));
+        let unlocked = keystore.unlock(&SecretString::from("passphrase")).unwrap();
+        assert_eq!(unlocked.account().master_key(), account.master_key());
+        assert_eq!(unlocked.account().account_key(), account.account_key());
+        assert_eq!(unlocked.token().expose(), "token-1");
+        assert!(!format!("{:?}", unlocked).contains("token-1"));
+
+        let updated = keystore.update_token(&unlocked, SecretString::from("token-2")).unwrap();
+        assert_eq!(updated.token().expose(), "token-2");
+        assert_eq!(updated.auth_key().expose(), unlocked.auth_key().expose());
+        let unlocked = Keystore::load(&path).unwrap().unlock(&SecretString::from("passphrase")).unwrap();
+        assert_eq!(unlocked.token().expose(), "token-2");
+    }
+
+    #[test]
+    fn test_unlock_cache_expires() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("keystore.json");
+        create_keystore(&path, "passphrase");
+        let mut keystore = Keystore::load(&path).unwrap();
+        let unlocked = keystore.unlock(&SecretString::from("passphrase")).unwrap();
+
+        let cache = UnlockCache::new(Duration::from_secs(1));
+        assert!(cache.get().is_none());
+        let cached = Arc::downgrade(&cache.put(unlocked));
+        let expires_at = cache.expires_at().unwrap();
+        assert!(cache.get().is_some());
+
+        // A new token does not extend how long the keys are kept
+        let updated = keystore.update_token(&cache.get().unwrap(), SecretString::from("token-2")).unwrap();
+        cache.refresh(updated);
+        assert_eq!(cache.get().unwrap().token().expose(), "token-2");
+        assert_eq!(cache.expires_at(), Some(expires_at));
+        assert!(cached.upgrade().is_none());
+
+        let refreshed = Arc::downgrade(&cache.get().unwrap());
+        std::thread::sleep(Duration::from_millis(1100));
+        cache.clear_expired();
+        assert!(cache.expires_at().is_none());
+        assert!(refreshed.upgrade().is_none());
+        assert!(cache.get().is_none());
+
+        let uncached = UnlockCache::new(Duration::ZERO);
+        uncached.put(keystore.unlock(&SecretString::from("passphrase")).unwrap());
+        assert!(uncached.get().is_none());
+    }
+
+    #[test]
+    fn test_keystore_wrong_passphrase() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("keystore.json");
+        create_keystore(&path, "passphrase");
+
+        let keystore = Keystore::load(&path).unwrap();
+        assert!(matches!(
+            keystore.unlock(&SecretString::from("wrong")),
+            Err(Error::InvalidCredentials)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_keystore_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("keystore.json");
+        create_keystore(&path, "passphrase");
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(Keystore::load(&path), Err(Error::ConfigError(_))));
+    }
+}
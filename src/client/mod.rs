@@ -1,7 +1,11 @@
 //! Client module for RustGuard CLI
 
+pub mod checkpoint;
 pub mod cli;
 pub mod config;
+pub mod conflicts;
+pub mod engine;
+pub mod keystore;
 pub mod sync_client;
 
 use crate::error::Result;
@@ -12,6 +16,9 @@ pub struct ClientConfig {
     pub server_url: String,
     pub data_dir: PathBuf,
     pub config_file: PathBuf,
+    pub keystore_file: PathBuf,
+    pub index_file: PathBuf,
+    pub journal_file: PathBuf,
 }
 
 impl ClientConfig {
@@ -25,6 +32,9 @@ impl ClientConfig {
             server_url: "http://localhost:3000".to_string(),
             data_dir: data_dir.clone(),
             config_file: data_dir.join("config.toml"),
+            keystore_file: data_dir.join("keystore.json"),
+            index_file: data_dir.join("index.db"),
+            journal_file: data_dir.join("journal.db"),
         })
     }
 }
@@ -35,6 +45,9 @@ impl Default for ClientConfig {
             server_url: "http://localhost:3000".to_string(),
             data_dir: PathBuf::from("/tmp/rustguard"),
             config_file: PathBuf::from("/tmp/rustguard/config.toml"),
+            keystore_file: PathBuf::from("/tmp/rustguard/keystore.json"),
+            index_file: PathBuf::from("/tmp/rustguard/index.db"),
+            journal_file: PathBuf::from("/tmp/rustguard/journal.db"),
         })
     }
 }
@@ -1,52 +1,1665 @@
 //! Sync client for uploading and downloading files
 
-use crate::crypto;
-use crate::error::Result;
-use std::path::Path;
+use crate::crypto::{self, keys, CipherSuite, Envelope, FingerprintKey, SecretKey, SecretString, StreamDecryptor};
+use crate::error::{Error, Result};
+use crate::client::checkpoint::CheckpointStore;
+use crate::crypto::chunking::{self, ChunkKeys, ChunkReader, ChunkRecipe, Chunker, ChunkingParams, RecipeEntry};
+use crate::crypto::keys::{AccountKeys, PassphraseKeys};
+use crate::crypto::manifest::{Manifest, ManifestEntry, ManifestSigner, SignedManifest};
+use crate::crypto::paths::PathKeys;
+use crate::crypto::recovery::{self, RecoveryKey};
+use crate::crypto::sharing;
+use crate::models::{
+    CommitChunksRequest, CreateShareRequest, DeleteFileRequest, EnableRecoveryRequest, FileChunk, FileKeyUpdate, FileMetadata, FileShare, FileVersion,
+    KeyMaterialResponse, LoginRequest, LoginResponse, MissingChunksRequest, MissingChunksResponse, MoveFileRequest,
+    PreloginRequest, PreloginResponse, PublicKeyResponse, RecoverAccountRequest, RecoveryKeyMaterialRequest,
+    RegisterRequest, RotateKeysRequest, SharedFile, StoredFileKey, UpdateKeyMaterialRequest, UploadChunkRequest, UploadFileRequest, UserKeyMaterial,
+};
+use rsa::RsaPrivateKey;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+/// Chunks whose fingerprints are checked with the server in one request
+const UPLOAD_BATCH_CHUNKS: usize = 256;
+
+/// Plaintext held back while waiting for a missing-chunk query
+const UPLOAD_BATCH_BYTES: usize = 32 * 1024 * 1024;
+
+/// Times a change is signed and sent when other devices keep signing
+/// manifests in between
+const MANIFEST_ATTEMPTS: usize = 3;
 
 /// Client for syncing files with server
 pub struct SyncClient {
     server_url: String,
     token: String,
+    cipher_suite: CipherSuite,
+    chunking: ChunkingParams,
+    checkpoints: Option<CheckpointStore>,
+    http: reqwest::Client,
+}
+
+#[derive(Deserialize)]
+struct UploadFileResponse {
+    file_id: String,
+}
+
+#[derive(Deserialize)]
+struct DownloadFileResponse {
+    file: FileMetadata,
+    chunks: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ListFilesResponse {
+    files: Vec<FileMetadata>,
+}
+
+#[derive(Deserialize)]
+struct ListVersionsResponse {
+    versions: Vec<FileVersion>,
+}
+
+#[derive(Deserialize)]
+struct ListFileKeysResponse {
+    keys: Vec<StoredFileKey>,
+}
+
+#[derive(Deserialize)]
+struct ListSharesResponse<T> {
+    shares: Vec<T>,
 }
 
 impl SyncClient {
     /// Creates a new sync client
     pub fn new(server_url: String, token: String) -> Self {
-        Self { server_url, token }
+        Self {
+            server_url,
+            token,
+            cipher_suite: CipherSuite::default(),
+            chunking: ChunkingParams::default(),
+            checkpoints: None,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Sets the cipher suite used for new uploads
+    pub fn with_cipher_suite(mut self, cipher_suite: CipherSuite) -> Self {
+        self.cipher_suite = cipher_suite;
+        self
+    }
+
+    /// Sets the chunk size bounds used for new uploads
+    pub fn with_chunking(mut self, chunking: ChunkingParams) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    /// Remembers the newest manifest seen in `store` and rejects older ones
+    ///
+    /// Without a store, manifests are still verified but rollbacks to an
+    /// older signed state go unnoticed.
+    pub fn with_checkpoint_store(mut self, store: CheckpointStore) -> Self {
+        self.checkpoints = Some(store);
+        self
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.server_url.trim_end_matches('/'), path)
+    }
+
+    /// Sends an authenticated request and decodes the JSON response
+    async fn send<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder) -> Result<T> {
+        self.send_optional(request)
+            .await?
+            .ok_or_else(|| Error::NetworkError("404 Not Found".to_string()))
+    }
+
+    /// Like [`SyncClient::send`], but maps a 404 response to `None`
+    async fn send_optional<T: DeserializeOwned>(&self, request: reqwest::RequestBuilder) -> Result<Option<T>> {
+        let response = request
+            .bearer_auth(&self.token)
+            .send()
+            .await
+            .map_err(|e| Error::NetworkError(e.to_string()))?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let body: serde_json::Value = response
+            .json()
+            .await
+            .map_err(|e| Error::NetworkError(e.to_string()))?;
+
+        if !status.is_success() {
+            let message = body["error"].as_str().unwrap_or("request failed");
+            if status == reqwest::StatusCode::CONFLICT {
+                return Err(Error::ConflictError(message.to_string()));
+            }
+            if status == reqwest::StatusCode::UNAUTHORIZED {
+                return Err(Error::AuthenticationFailed(message.to_string()));
+            }
+            return Err(Error::NetworkError(format!("{}: {}", status, message)));
+        }
+
+        serde_json::from_value(body)
+            .map(Some)
+            .map_err(|e| Error::SerializationError(e.to_string()))
+    }
+
+    /// Uploads a file to the server, streaming it one chunk at a time
+    ///
+    /// The file is split into content-defined chunks and only chunks the
+    /// server does not already hold for this account are sent, so an edit
+    /// re-uploads little more than the changed region. The chunk list is
+    /// sealed under the file's data key, which is created on the first
+    /// upload and stored on the server wrapped by the master key; later
+    /// uploads reuse it so existing shares keep working until one is
+    /// revoked. `remote_path` is encrypted before it is sent;
+    /// uploading to a path that already exists commits a new version of
+    /// that file, unless its content fingerprint shows it is unchanged. The
+    /// server keeps serving the previous version until the commit.
+    ///
+    /// `base_version` is the version the local content was based on, 0 for
+    /// a file that is new to this device. If the server's copy has moved on
+    /// since, the commit fails with [`Error::ConflictError`] and nothing
+    /// changes. `None` replaces whatever the server has.
+    pub async fn upload_file(
+        &self,
+        file_path: &Path,
+        remote_path: &str,
+        account: &AccountKeys,
+        base_version: Option<u64>,
+    ) -> Result<String> {
+        let size = fs::metadata(file_path).await?.len();
+        let path_keys = PathKeys::derive(account.account_key());
+        let chunker = Chunker::new(self.chunking, account.account_key())?;
+
+        // Only used to skip unchanged files; what is committed is
+        // fingerprinted from the chunks actually read below, in case the
+        // file changes in between
+        let fingerprint_key = FingerprintKey::derive(account.account_key());
+        let scan_key = fingerprint_key.duplicate();
+        let owned_path = file_path.to_path_buf();
+        let fingerprint = tokio::task::spawn_blocking(move || scan_key.fingerprint_file(&owned_path))
+            .await
+            .map_err(|e| Error::Internal(e.to_string()))??;
+
+        let existing = self.find_file(remote_path, account).await?;
+        if let Some(existing) = &existing {
+            if existing.size == size && existing.encrypted_hash == fingerprint {
+                return Ok(existing.id.clone());
+            }
+        }
+
+        let created: UploadFileResponse = self
+            .send(self.http.post(self.url("/api/v1/files/upload")).json(&UploadFileRequest {
+                path: path_keys.encrypt_path(remote_path)?,
+                size,
+                path_token: Some(path_keys.lookup_token(remote_path)),
+                content_fingerprint: Some(fingerprint.clone()),
+            }))
+            .await?;
+
+        // Files uploaded before chunk recipes sealed their data directly
+        // under the data key, so they move to a fresh one
+        let reusable = existing.filter(|file| file.id == created.file_id && file.chunk_recipe.is_some());
+        let (data_key, wrapped_key) = match reusable.and_then(|file| file.wrapped_key) {
+            Some(wrapped) => (keys::unwrap_data_key(&wrapped, account.master_key(), &created.file_id)?, None),
+            None => {
+                let (data_key, wrapped) = keys::create_data_key(account.master_key(), &created.file_id)?;
+                (data_key, Some(wrapped))
+            }
+        };
+
+        let chunk_keys = ChunkKeys::derive(account.account_key());
+        let mut reader = ChunkReader::new(&chunker, fs::File::open(file_path).await?);
+        let mut recipe = ChunkRecipe::default();
+        let mut content = fingerprint_key.fingerprinter();
+        let mut content_size = 0;
+        let mut batch = Vec::new();
+        let mut batch_bytes = 0;
+        let mut stored = HashSet::new();
+
+        loop {
+            let chunk = reader.next_chunk().await?;
+
+            if let Some(chunk) = chunk.as_ref() {
+                content.update(chunk);
+                content_size += chunk.len() as u64;
+                let fingerprint = chunk_keys.fingerprint(chunk);
+                recipe.chunks.push(RecipeEntry {
+                    fingerprint: fingerprint.clone(),
+                    size: chunk.len() as u32,
+                    key: chunk_keys.chunk_key(&fingerprint),
+                });
+
+                if !stored.contains(&fingerprint) && !batch.iter().any(|(queued, _)| *queued == fingerprint) {
+                    batch_bytes += chunk.len();
+                    batch.push((fingerprint, chunk.clone()));
+                }
+            }
+
+            let done = chunk.is_none();
+            if !batch.is_empty() && (done || batch.len() >= UPLOAD_BATCH_CHUNKS || batch_bytes >= UPLOAD_BATCH_BYTES) {
+                self.upload_missing_chunks(&created.file_id, &chunk_keys, std::mem::take(&mut batch), &mut stored)
+                    .await?;
+                batch_bytes = 0;
+            }
+            if done {
+                break;
+            }
+        }
+
+        let commit = CommitChunksRequest {
+            recipe: recipe.seal(&data_key, &created.file_id)?,
+            fingerprints: recipe.fingerprints(),
+            size: content_size,
+            content_fingerprint: content.finish(),
+            wrapped_key,
+            base_version,
+            manifest: None,
+        };
+        self.commit_signed(&created.file_id, commit, account).await?;
+
+        Ok(created.file_id)
+    }
+
+    /// Commits new content to a file together with a manifest signed over
+    /// the listing it results in, so the server stores both or neither
+    ///
+    /// `commit.base_version` works as for [`SyncClient::upload_file`]. If
+    /// another device signs a manifest in between, the listing is fetched
+    /// and signed again.
+    async fn commit_signed(&self, file_id: &str, mut commit: CommitChunksRequest, account: &AccountKeys) -> Result<()> {
+        let requested = commit.base_version;
+        let mut attempt = 1;
+
+        let signed = loop {
+            let (files, previous) = self.read_consistent(account, || self.list_files()).await?;
+            let version = files.iter().find(|file| file.id == file_id).map_or(0, |file| file.version);
+            if let Some(base_version) = requested {
+                if base_version != version {
+                    return Err(Error::ConflictError(format!(
+                        "File is at version {}, not {}",
+                        version, base_version
+                    )));
+                }
+            }
+
+            let entry = ManifestEntry {
+                file_id: file_id.to_string(),
+                version: version + 1,
+                fingerprint: commit.content_fingerprint.clone(),
+            };
+            let signed = self.sign_changes(account, &files, previous, &[(file_id, Some(entry))])?;
+            commit.base_version = Some(version);
+            commit.manifest = Some(signed.clone());
+
+            let result = self
+                .send::<serde_json::Value>(self.http.post(self.url(&format!("/api/v1/files/{}/chunks", file_id))).json(&commit))
+                .await;
+            match result {
+                Err(Error::ConflictError(_)) if attempt < MANIFEST_ATTEMPTS => attempt += 1,
+                result => {
+                    result?;
+                    break signed;
+                }
+            }
+        };
+
+        self.remember_manifest(&signed)
+    }
+
+    /// Uploads the chunks of `batch` the server does not have yet
+    async fn upload_missing_chunks(
+        &self,
+        file_id: &str,
+        chunk_keys: &ChunkKeys,
+        batch: Vec<(String, Vec<u8>)>,
+        stored: &mut HashSet<String>,
+    ) -> Result<()> {
+        let response: MissingChunksResponse = self
+            .send(self.http.post(self.url("/api/v1/chunks/missing")).json(&MissingChunksRequest {
+                fingerprints: batch.iter().map(|(fingerprint, _)| fingerprint.clone()).collect(),
+            }))
+            .await?;
+        let missing: HashSet<String> = response.missing.into_iter().collect();
+
+        for (fingerprint, data) in batch {
+            if missing.contains(&fingerprint) {
+                let envelope =
+                    chunking::seal_chunk(self.cipher_suite, &data, &fingerprint, &chunk_keys.chunk_key(&fingerprint))?;
+
+                let _: serde_json::Value = self
+                    .send(self.http.post(self.url("/api/v1/chunks/upload")).json(&UploadChunkRequest {
+                        file_id: file_id.to_string(),
+                        chunk_index: None,
+                        encrypted_data: envelope.to_bytes(),
+                        fingerprint: Some(fingerprint.clone()),
+                    }))
+                    .await?;
+            }
+            stored.insert(fingerprint);
+        }
+
+        Ok(())
+    }
+
+    /// Deletes a file on the server together with a manifest signed
+    /// without it
+    pub async fn delete_file(&self, file_id: &str, account: &AccountKeys) -> Result<()> {
+        let mut attempt = 1;
+
+        let signed = loop {
+            let (files, previous) = self.read_consistent(account, || self.list_files()).await?;
+            let signed = self.sign_changes(account, &files, previous, &[(file_id, None)])?;
+
+            let result = self
+                .send::<serde_json::Value>(
+                    self.http
+                        .post(self.url(&format!("/api/v1/files/delete/{}", file_id)))
+                        .json(&DeleteFileRequest {
+                            manifest: Some(signed.clone()),
+                        }),
+                )
+                .await;
+            match result {
+                Err(Error::ConflictError(_)) if attempt < MANIFEST_ATTEMPTS => attempt += 1,
+                result => {
+                    result?;
+                    break signed;
+                }
+            }
+        };
+
+        self.remember_manifest(&signed)
+    }
+
+    /// Moves a file to `remote_path` without uploading its content again
+    ///
+    /// The file keeps its id and version; the signed manifest does not cover
+    /// paths, so it stays valid. Fails if another file already exists at
+    /// `remote_path`.
+    pub async fn move_file(&self, file_id: &str, remote_path: &str, account: &AccountKeys) -> Result<FileMetadata> {
+        let path_keys = PathKeys::derive(account.account_key());
+        let file: FileMetadata = self
+            .send(
+                self.http
+                    .post(self.url(&format!("/api/v1/files/{}/move", file_id)))
+                    .json(&MoveFileRequest {
+                        path: path_keys.encrypt_path(remote_path)?,
+                        path_token: path_keys.lookup_token(remote_path),
+                    }),
+            )
+            .await?;
+
+        decrypt_file_path(&path_keys, file)
+    }
+
+    /// Downloads a file from the server, decrypting it one chunk at a time
+    ///
+    /// Fails if the file does not match the account's last signed manifest.
+    pub async fn download_file(&self, file_id: &str, output_path: &Path, account: &AccountKeys) -> Result<()> {
+        let (download, manifest) = self
+            .read_consistent(account, || {
+                self.send::<DownloadFileResponse>(self.http.get(self.url(&format!("/api/v1/files/download/{}", file_id))))
+            })
+            .await?;
+        if let Some(manifest) = manifest {
+            manifest.verify_file(&download.file)?;
+        }
+
+        // Files uploaded before per-file keys were encrypted with the master key
+        let data_key = match &download.file.wrapped_key {
+            Some(wrapped) => keys::unwrap_data_key(wrapped, account.master_key(), file_id)?,
+            None => account.master_key().duplicate(),
+        };
+
+        match &download.file.chunk_recipe {
+            Some(sealed) => {
+                let recipe = ChunkRecipe::open(sealed, &data_key, file_id)?;
+                let expected = (FingerprintKey::derive(account.account_key()), download.file.encrypted_hash.as_str());
+                self.download_recipe(&download.chunks, &recipe, output_path, Some(expected))
+                    .await
+            }
+            None => {
+                self.download_chunks(file_id, &download.chunks, output_path, &data_key)
+                    .await
+            }
+        }
     }
 
-    /// Uploads a file to the server
-    pub async fn upload_file(&self, file_path: &Path, encryption_key: &[u8; 32]) -> Result<String> {
-        // Read file
-        let data = fs::read(file_path).await?;
+    /// Downloads the content-defined chunks of a file in recipe order
+    ///
+    /// With `expected`, the assembled file must match that content
+    /// fingerprint, which catches the server serving an older recipe.
+    async fn download_recipe(
+        &self,
+        chunk_ids: &[String],
+        recipe: &ChunkRecipe,
+        output_path: &Path,
+        expected: Option<(FingerprintKey, &str)>,
+    ) -> Result<()> {
+        if chunk_ids.len() != recipe.chunks.len() {
+            return Err(Error::DecryptionError(format!(
+                "Server listed {} chunks but the file has {}",
+                chunk_ids.len(),
+                recipe.chunks.len()
+            )));
+        }
 
-        // Encrypt file
-        let (encrypted, _nonce) = crypto::encrypt(&data, encryption_key)?;
+        let partial_path = partial_path(output_path);
+        let result = self.write_recipe(chunk_ids, recipe, &partial_path, expected).await;
+        finish_partial(&partial_path, output_path, result).await
+    }
+
+    async fn write_recipe(
+        &self,
+        chunk_ids: &[String],
+        recipe: &ChunkRecipe,
+        partial_path: &Path,
+        expected: Option<(FingerprintKey, &str)>,
+    ) -> Result<()> {
+        let mut output = fs::File::create(partial_path).await?;
+
+        for (chunk_id, entry) in chunk_ids.iter().zip(&recipe.chunks) {
+            let chunk: FileChunk = self
+                .send(self.http.get(self.url(&format!("/api/v1/chunks/download/{}", chunk_id))))
+                .await?;
+
+            let plaintext = chunking::open_chunk(&Envelope::from_bytes(&chunk.encrypted_data)?, &entry.fingerprint, &entry.key)?;
+            if plaintext.len() != entry.size as usize {
+                return Err(Error::DecryptionError(format!("Chunk {} has the wrong size", entry.fingerprint)));
+            }
+            output.write_all(&plaintext).await?;
+        }
+
+        output.flush().await?;
+        drop(output);
 
-        // Compute hash
-        let hash = crypto::compute_hash(&encrypted);
+        if let Some((fingerprint_key, fingerprint)) = expected {
+            let owned_path = partial_path.to_path_buf();
+            let actual = tokio::task::spawn_blocking(move || fingerprint_key.fingerprint_file(&owned_path))
+                .await
+                .map_err(|e| Error::Internal(e.to_string()))??;
+            if actual != fingerprint {
+                return Err(Error::DecryptionError(
+                    "Downloaded content does not match the file's fingerprint".to_string(),
+                ));
+            }
+        }
 
-        // TODO: Send to server via HTTP
-        // For now, return the hash as file_id
-        Ok(hash)
+        Ok(())
+    }
+
+    async fn download_chunks(
+        &self,
+        file_id: &str,
+        chunk_ids: &[String],
+        output_path: &Path,
+        data_key: &SecretKey,
+    ) -> Result<()> {
+        let partial_path = partial_path(output_path);
+        let result = self.write_chunks(file_id, chunk_ids, &partial_path, data_key).await;
+        finish_partial(&partial_path, output_path, result).await
     }
 
-    /// Downloads a file from the server
-    pub async fn download_file(&self, _file_id: &str, output_path: &Path, _encryption_key: &[u8; 32]) -> Result<()> {
-        // TODO: Download from server via HTTP
+    async fn write_chunks(
+        &self,
+        file_id: &str,
+        chunk_ids: &[String],
+        partial_path: &Path,
+        data_key: &SecretKey,
+    ) -> Result<()> {
+        let mut output = fs::File::create(partial_path).await?;
+        let mut decryptor = StreamDecryptor::new(data_key, file_id);
+
+        for chunk_id in chunk_ids {
+            let chunk: FileChunk = self
+                .send(self.http.get(self.url(&format!("/api/v1/chunks/download/{}", chunk_id))))
+                .await?;
 
-        // Decrypt and save
-        let _decrypted = vec![]; // placeholder
-        fs::write(output_path, _decrypted).await?;
+            let plaintext = decryptor.decrypt_next(&Envelope::from_bytes(&chunk.encrypted_data)?)?;
+            output.write_all(&plaintext).await?;
+        }
+
+        decryptor.finish()?;
+        output.flush().await?;
 
         Ok(())
     }
 
+    /// Lists the earlier versions of a file, oldest first
+    pub async fn list_versions(&self, file_id: &str) -> Result<Vec<FileVersion>> {
+        let response: ListVersionsResponse = self
+            .send(self.http.get(self.url(&format!("/api/v1/versions/{}", file_id))))
+            .await?;
+
+        Ok(response.versions)
+    }
+
     /// Lists files on server
-    pub async fn list_files(&self) -> Result<Vec<String>> {
-        // TODO: Call server API
-        Ok(vec![])
+    ///
+    /// Paths and names are returned exactly as stored, i.e. encrypted, and
+    /// the listing is not checked against the signed manifest; use
+    /// [`SyncClient::list_files_decrypted`] to read them.
+    pub async fn list_files(&self) -> Result<Vec<FileMetadata>> {
+        let response: ListFilesResponse = self
+            .send(self.http.get(self.url("/api/v1/files/list")))
+            .await?;
+
+        Ok(response.files)
+    }
+
+    /// Lists files on server with their paths and names decrypted
+    ///
+    /// Fails if the listing does not match the account's last signed manifest.
+    pub async fn list_files_decrypted(&self, account: &AccountKeys) -> Result<Vec<FileMetadata>> {
+        let path_keys = PathKeys::derive(account.account_key());
+        let (files, manifest) = self.read_consistent(account, || self.list_files()).await?;
+
+        if let Some(manifest) = manifest {
+            manifest.verify_listing(&files)?;
+        }
+
+        files
+            .into_iter()
+            .map(|file| decrypt_file_path(&path_keys, file))
+            .collect()
+    }
+
+    /// Looks up a file by its plaintext remote path
+    pub async fn find_file(&self, remote_path: &str, account: &AccountKeys) -> Result<Option<FileMetadata>> {
+        let path_keys = PathKeys::derive(account.account_key());
+        let token = path_keys.lookup_token(remote_path);
+
+        let (file, manifest) = self
+            .read_consistent(account, || {
+                self.send_optional::<FileMetadata>(self.http.get(self.url(&format!("/api/v1/files/lookup/{}", token))))
+            })
+            .await?;
+
+        match file {
+            Some(file) => {
+                if let Some(manifest) = manifest {
+                    manifest.verify_file(&file)?;
+                }
+                decrypt_file_path(&path_keys, file).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Signs a manifest of the account's current files and stores it on the server
+    ///
+    /// The current listing must match the previous manifest, so tampering is
+    /// never signed over.
+    pub async fn publish_manifest(&self, account: &AccountKeys) -> Result<SignedManifest> {
+        let mut attempt = 1;
+
+        let signed = loop {
+            let (files, previous) = self.read_consistent(account, || self.list_files()).await?;
+            let signed = self.sign_changes(account, &files, previous, &[])?;
+
+            let result = self
+                .send::<serde_json::Value>(self.http.post(self.url("/api/v1/manifest")).json(&signed))
+                .await;
+            match result {
+                Err(Error::ConflictError(_)) if attempt < MANIFEST_ATTEMPTS => attempt += 1,
+                result => {
+                    result?;
+                    break signed;
+                }
+            }
+        };
+
+        self.remember_manifest(&signed)?;
+        Ok(signed)
+    }
+
+    /// Signs the manifest after `previous`: `files` with `changes` applied,
+    /// where `None` removes a file
+    ///
+    /// Every file not in `changes` must match `previous`.
+    fn sign_changes(
+        &self,
+        account: &AccountKeys,
+        files: &[FileMetadata],
+        previous: Option<Manifest>,
+        changes: &[(&str, Option<ManifestEntry>)],
+    ) -> Result<SignedManifest> {
+        let changed: Vec<&str> = changes.iter().map(|(file_id, _)| *file_id).collect();
+        if let Some(previous) = &previous {
+            previous.verify_listing_except(files, &changed)?;
+        }
+
+        let mut entries: Vec<ManifestEntry> = files
+            .iter()
+            .filter(|file| !changed.contains(&file.id.as_str()))
+            .map(ManifestEntry::from_file)
+            .collect();
+        entries.extend(changes.iter().filter_map(|(_, entry)| entry.clone()));
+
+        let sequence = previous.map_or(1, |manifest| manifest.sequence + 1);
+        ManifestSigner::derive(account.account_key()).sign(Manifest::from_entries(entries, sequence))
+    }
+
+    /// Runs `read` and returns its result with the manifest it was read under
+    ///
+    /// Every change to the account's files stores a new manifest in the same
+    /// transaction, so a manifest that is the same before and after `read`
+    /// covers exactly what `read` saw. If other devices keep changing files
+    /// in between, this gives up with [`Error::ConflictError`].
+    async fn read_consistent<T, F, Fut>(&self, account: &AccountKeys, mut read: F) -> Result<(T, Option<Manifest>)>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        for _ in 0..MANIFEST_ATTEMPTS {
+            let before = self.fetch_manifest(account).await?;
+            let value = read().await?;
+            let after = self.fetch_manifest(account).await?;
+            if before.as_ref().map(|manifest| manifest.sequence) == after.as_ref().map(|manifest| manifest.sequence) {
+                return Ok((value, after));
+            }
+        }
+
+        Err(Error::ConflictError("Files kept changing on other devices; try again".to_string()))
+    }
+
+    /// Advances this device's checkpoint to a manifest the server has stored
+    fn remember_manifest(&self, signed: &SignedManifest) -> Result<()> {
+        if let Some(store) = &self.checkpoints {
+            store.save(&signed.manifest.checkpoint())?;
+        }
+        Ok(())
+    }
+
+    /// Fetches and verifies the account's last signed manifest
+    ///
+    /// Returns `None` if nothing has been signed yet. With a checkpoint
+    /// store, a manifest older than one this device has already seen fails
+    /// with [`Error::RollbackDetected`]; a newer one advances the checkpoint.
+    pub async fn fetch_manifest(&self, account: &AccountKeys) -> Result<Option<Manifest>> {
+        let manifest = self.fetch_manifest_unchecked(account).await?;
+
+        let Some(store) = &self.checkpoints else {
+            return Ok(manifest);
+        };
+
+        match (&manifest, store.load()?) {
+            (None, Some(last_seen)) => {
+                return Err(Error::RollbackDetected(format!(
+                    "Server has no manifest but this device has already seen manifest {}",
+                    last_seen.sequence
+                )));
+            }
+            (Some(manifest), Some(last_seen)) => {
+                manifest.check_freshness(&last_seen)?;
+                if manifest.sequence > last_seen.sequence {
+                    store.save(&manifest.checkpoint())?;
+                }
+            }
+            (Some(manifest), None) => store.save(&manifest.checkpoint())?,
+            (None, None) => {}
+        }
+
+        Ok(manifest)
+    }
+
+    async fn fetch_manifest_unchecked(&self, account: &AccountKeys) -> Result<Option<Manifest>> {
+        self.send_optional::<SignedManifest>(self.http.get(self.url("/api/v1/manifest")))
+            .await?
+            .map(|signed| ManifestSigner::derive(account.account_key()).verify(signed))
+            .transpose()
+    }
+
+    /// Accepts the server's current manifest after a detected rollback
+    ///
+    /// Only to be called once the user has confirmed that the older state is
+    /// expected, e.g. after restoring the server from a backup.
+    pub async fn accept_rollback(&self, account: &AccountKeys) -> Result<Option<Manifest>> {
+        let manifest = self.fetch_manifest_unchecked(account).await?;
+
+        if let Some(store) = &self.checkpoints {
+            match &manifest {
+                Some(manifest) => store.save(&manifest.checkpoint())?,
+                None => store.clear()?,
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Replaces the account's master key and re-wraps every data key the
+    /// account has stored, including those of deleted files and replaced
+    /// versions
+    ///
+    /// The new master key is wrapped under the same passphrase, so
+    /// `passphrase_keys` must unlock `key_material`. The key material and the
+    /// re-wrapped data keys are stored in one request, and a failure leaves
+    /// the old master key in place. Chunk data, encrypted paths and the
+    /// signed manifest are untouched, as they only depend on the account key.
+    /// Any recovery key stops working. Returns the new keys and key material.
+    pub async fn rotate_master_key(
+        &self,
+        account: &AccountKeys,
+        passphrase_keys: &PassphraseKeys,
+        key_material: &UserKeyMaterial,
+    ) -> Result<(AccountKeys, UserKeyMaterial)> {
+        let (rotated, key_material) = keys::rotate_master_key(account, passphrase_keys, key_material)?;
+
+        let stored: ListFileKeysResponse = self.send(self.http.get(self.url("/api/v1/files/keys"))).await?;
+        let mut file_keys = Vec::new();
+        for stored in stored.keys {
+            // Content uploaded before per-file keys was encrypted with the
+            // master key itself, which now becomes its data key
+            let wrapped_key = match &stored.wrapped_key {
+                Some(wrapped) => keys::rewrap_data_key(wrapped, &stored.file_id, account.master_key(), rotated.master_key())?,
+                None => keys::wrap_data_key(account.master_key(), rotated.master_key(), &stored.file_id)?,
+            };
+            file_keys.push(FileKeyUpdate {
+                file_id: stored.file_id,
+                version_id: stored.version_id,
+                wrapped_key,
+            });
+        }
+
+        let _: serde_json::Value = self
+            .send(self.http.post(self.url("/api/v1/auth/rotate")).json(&RotateKeysRequest {
+                key_material: key_material.clone(),
+                file_keys,
+            }))
+            .await?;
+
+        Ok((rotated, key_material))
+    }
+
+    /// Creates an account with new keys wrapped under `passphrase`
+    ///
+    /// Only the authentication key, the wrapped key material and the public
+    /// half of `sharing_key` are sent. Returns the new session and the
+    /// account keys.
+    pub async fn register(
+        &self,
+        username: &str,
+        email: &str,
+        passphrase: &SecretString,
+        kdf: crypto::KdfDescriptor,
+        sharing_key: RsaPrivateKey,
+    ) -> Result<(LoginResponse, AccountKeys)> {
+        let passphrase_keys = PassphraseKeys::derive(passphrase, kdf)?;
+        let public_key = sharing::public_key_pem(&sharing_key)?;
+        let (account, key_material) = keys::create_account_keys(&passphrase_keys, sharing_key)?;
+
+        let mut session: LoginResponse = self
+            .send(self.http.post(self.url("/api/v1/auth/register")).json(&RegisterRequest {
+                username: username.to_string(),
+                email: email.to_string(),
+                auth_key: passphrase_keys.auth_key(),
+                public_key,
+                key_material: Some(key_material.clone()),
+            }))
+            .await?;
+        session.key_material = Some(key_material);
+
+        Ok((session, account))
+    }
+
+    /// Logs in, returning a session token and the account's key material
+    /// together with the keys derived from the passphrase
+    ///
+    /// Only the authentication key derived from `passphrase` is sent; the
+    /// returned [`PassphraseKeys`] unlock the master key.
+    pub async fn login(&self, username: &str, passphrase: &SecretString) -> Result<(LoginResponse, PassphraseKeys)> {
+        let prelogin: PreloginResponse = self
+            .send(self.http.post(self.url("/api/v1/auth/prelogin")).json(&PreloginRequest {
+                username: username.to_string(),
+            }))
+            .await?;
+        let passphrase_keys = PassphraseKeys::derive(passphrase, prelogin.kdf)?;
+        let session = self.authenticate(username, passphrase_keys.auth_key()).await?;
+
+        Ok((session, passphrase_keys))
+    }
+
+    /// Checks that the session token is still accepted
+    ///
+    /// Fails with [`Error::AuthenticationFailed`] once it has expired.
+    pub async fn verify_session(&self) -> Result<()> {
+        let _: serde_json::Value = self.send(self.http.get(self.url("/api/v1/auth/verify"))).await?;
+        Ok(())
+    }
+
+    /// Logs in with an authentication key derived earlier, e.g. to replace
+    /// an expired session token without asking for the passphrase
+    pub async fn authenticate(&self, username: &str, auth_key: SecretString) -> Result<LoginResponse> {
+        self.send(self.http.post(self.url("/api/v1/auth/login")).json(&LoginRequest {
+            username: username.to_string(),
+            auth_key,
+        }))
+        .await
+    }
+
+    /// Re-wraps the master key under `new_keys`, derived from a new
+    /// passphrase or with stronger KDF settings
+    ///
+    /// The server replaces the key material and the authentication key in
+    /// one statement, so the new passphrase keys log in exactly when they
+    /// unlock the stored key material. Returns the new key material.
+    pub async fn change_passphrase(
+        &self,
+        key_material: &UserKeyMaterial,
+        passphrase_keys: &PassphraseKeys,
+        new_keys: &PassphraseKeys,
+    ) -> Result<UserKeyMaterial> {
+        let key_material = keys::change_passphrase(key_material, passphrase_keys, new_keys)?;
+
+        let _: serde_json::Value = self
+            .send(self.http.post(self.url("/api/v1/auth/key-material")).json(&UpdateKeyMaterialRequest {
+                auth_key: passphrase_keys.auth_key(),
+                new_auth_key: new_keys.auth_key(),
+                key_material: key_material.clone(),
+            }))
+            .await?;
+
+        Ok(key_material)
+    }
+
+    /// Moves the account onto `target` KDF settings if its key material was
+    /// derived with weaker ones
+    ///
+    /// Returns the new key material, or `None` if no upgrade was needed.
+    pub async fn upgrade_kdf(
+        &self,
+        passphrase: &SecretString,
+        passphrase_keys: &PassphraseKeys,
+        key_material: &UserKeyMaterial,
+        target: crypto::KdfParams,
+    ) -> Result<Option<UserKeyMaterial>> {
+        if !key_material.kdf.needs_upgrade(&target) {
+            return Ok(None);
+        }
+
+        let new_keys = PassphraseKeys::derive(passphrase, crypto::KdfDescriptor::new(target))?;
+        self.change_passphrase(key_material, passphrase_keys, &new_keys).await.map(Some)
+    }
+
+    /// Generates a recovery key for the account and registers it with the server
+    ///
+    /// Any previous recovery key stops working. The returned key is the only
+    /// copy; it must be shown to the user and never stored.
+    pub async fn enable_recovery(&self, key_material: &UserKeyMaterial, account: &AccountKeys) -> Result<RecoveryKey> {
+        let recovery_key = RecoveryKey::generate();
+        let key_material = recovery::enable_recovery(key_material, account.master_key(), &recovery_key)?;
+
+        let _: serde_json::Value = self
+            .send(self.http.post(self.url("/api/v1/auth/recovery")).json(&EnableRecoveryRequest {
+                recovery_verifier: recovery_key.verifier(),
+                key_material,
+            }))
+            .await?;
+
+        Ok(recovery_key)
+    }
+
+    /// Sets a new passphrase for an account using its recovery key
+    ///
+    /// Needs no session token. Only the key material changes on the server;
+    /// file data and data keys are untouched. Returns the new session and
+    /// the account keys.
+    pub async fn recover_account(
+        &self,
+        username: &str,
+        recovery_key: &RecoveryKey,
+        new_passphrase: &SecretString,
+        kdf: crypto::KdfDescriptor,
+    ) -> Result<(LoginResponse, AccountKeys)> {
+        let current: KeyMaterialResponse = self
+            .send(
+                self.http
+                    .post(self.url("/api/v1/auth/recovery/key-material"))
+                    .json(&RecoveryKeyMaterialRequest {
+                        username: username.to_string(),
+                        recovery_verifier: recovery_key.verifier(),
+                    }),
+            )
+            .await?;
+
+        let passphrase_keys = PassphraseKeys::derive(new_passphrase, kdf)?;
+        let (master_key, key_material) =
+            recovery::recover_master_key(&current.key_material, recovery_key, &passphrase_keys)?;
+
+        let session = self
+            .send(self.http.post(self.url("/api/v1/auth/recover")).json(&RecoverAccountRequest {
+                username: username.to_string(),
+                recovery_verifier: recovery_key.verifier(),
+                new_auth_key: passphrase_keys.auth_key(),
+                key_material,
+            }))
+            .await?;
+
+        Ok((session, AccountKeys::open(master_key, &current.key_material)?))
+    }
+
+    /// Fetches another user's public sharing key
+    pub async fn get_public_key(&self, username: &str) -> Result<PublicKeyResponse> {
+        self.send(self.http.get(self.url(&format!("/api/v1/users/{}/public-key", username))))
+            .await
+    }
+
+    /// Shares a file with another user by wrapping its data key to their public key
+    pub async fn share_file(&self, file_id: &str, recipient_username: &str, account: &AccountKeys) -> Result<FileShare> {
+        let download: DownloadFileResponse = self
+            .send(self.http.get(self.url(&format!("/api/v1/files/download/{}", file_id))))
+            .await?;
+        let recipient = self.get_public_key(recipient_username).await?;
+
+        self.share_with(&download.file, &recipient, account).await
+    }
+
+    /// Shares every file under a folder with another user
+    pub async fn share_folder(&self, folder: &str, recipient_username: &str, account: &AccountKeys) -> Result<Vec<FileShare>> {
+        let recipient = self.get_public_key(recipient_username).await?;
+        let prefix = format!("{}/", folder.trim_end_matches('/'));
+        let mut shares = Vec::new();
+
+        for file in self.list_files_decrypted(account).await? {
+            if file.path.starts_with(&prefix) {
+                shares.push(self.share_with(&file, &recipient, account).await?);
+            }
+        }
+
+        Ok(shares)
+    }
+
+    async fn share_with(&self, file: &FileMetadata, recipient: &PublicKeyResponse, account: &AccountKeys) -> Result<FileShare> {
+        let wrapped = file
+            .wrapped_key
+            .as_deref()
+            .ok_or_else(|| Error::InvalidInput(format!("File {} has no data key and cannot be shared", file.id)))?;
+
+        let data_key = keys::unwrap_data_key(wrapped, account.master_key(), &file.id)?;
+        let wrapped_key = sharing::wrap_for_recipient(&data_key, &recipient.public_key, &file.id)?;
+
+        self.send(self.http.post(self.url("/api/v1/shares")).json(&CreateShareRequest {
+            file_id: file.id.clone(),
+            recipient_username: recipient.username.clone(),
+            wrapped_key,
+        }))
+        .await
+    }
+
+    /// Lists files other users have shared with us
+    pub async fn list_shared_with_me(&self) -> Result<Vec<SharedFile>> {
+        let response: ListSharesResponse<SharedFile> = self
+            .send(self.http.get(self.url("/api/v1/shares/incoming")))
+            .await?;
+
+        Ok(response.shares)
+    }
+
+    /// Lists shares we have created
+    pub async fn list_my_shares(&self) -> Result<Vec<FileShare>> {
+        let response: ListSharesResponse<FileShare> = self
+            .send(self.http.get(self.url("/api/v1/shares/outgoing")))
+            .await?;
+
+        Ok(response.shares)
+    }
+
+    /// Downloads and decrypts a file shared with us
+    pub async fn download_shared_file(&self, shared: &SharedFile, output_path: &Path, account: &AccountKeys) -> Result<()> {
+        let file_id = &shared.file.id;
+        let data_key = sharing::unwrap_shared_key(&shared.share.wrapped_key, account.sharing_key()?, file_id)?;
+
+        let download: DownloadFileResponse = self
+            .send(self.http.get(self.url(&format!("/api/v1/files/download/{}", file_id))))
+            .await?;
+
+        match &download.file.chunk_recipe {
+            Some(sealed) => {
+                let recipe = ChunkRecipe::open(sealed, &data_key, file_id)?;
+                self.download_recipe(&download.chunks, &recipe, output_path, None)
+                    .await
+            }
+            None => {
+                self.download_chunks(file_id, &download.chunks, output_path, &data_key)
+                    .await
+            }
+        }
+    }
+
+    /// Revokes a share so the recipient can no longer fetch the file
+    ///
+    /// The recipient may already hold the file's data key, so the file is
+    /// moved to a new one and re-shared with everyone it is still shared
+    /// with. Content the recipient has already seen stays readable to them.
+    pub async fn revoke_share(&self, share_id: &str, account: &AccountKeys) -> Result<()> {
+        let shares = self.list_my_shares().await?;
+        let revoked = shares
+            .iter()
+            .find(|share| share.id == share_id)
+            .ok_or_else(|| Error::InvalidInput(format!("Share not found: {}", share_id)))?;
+
+        let _: serde_json::Value = self
+            .send(self.http.post(self.url(&format!("/api/v1/shares/{}/revoke", share_id))))
+            .await?;
+
+        let Some(file) = self.rotate_data_key(&revoked.file_id, account).await? else {
+            return Ok(());
+        };
+
+        for share in shares.iter().filter(|share| share.file_id == file.id && share.id != share_id) {
+            let recipient = self.get_public_key(&share.recipient_username).await?;
+            self.share_with(&file, &recipient, account).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Re-seals a file's chunk list under a new data key
+    ///
+    /// The chunks themselves are unchanged, so this commits a new version
+    /// without uploading any data. Returns the updated file, or `None` for a
+    /// file uploaded before chunk recipes, whose next upload gets a new key.
+    async fn rotate_data_key(&self, file_id: &str, account: &AccountKeys) -> Result<Option<FileMetadata>> {
+        let download: DownloadFileResponse = self
+            .send(self.http.get(self.url(&format!("/api/v1/files/download/{}", file_id))))
+            .await?;
+        let mut file = download.file;
+
+        let (Some(sealed), Some(wrapped)) = (&file.chunk_recipe, &file.wrapped_key) else {
+            return Ok(None);
+        };
+        let old_key = keys::unwrap_data_key(wrapped, account.master_key(), file_id)?;
+        let recipe = ChunkRecipe::open(sealed, &old_key, file_id)?;
+        let (data_key, wrapped_key) = keys::create_data_key(account.master_key(), file_id)?;
+
+        let sealed = recipe.seal(&data_key, file_id)?;
+        let commit = CommitChunksRequest {
+            recipe: sealed.clone(),
+            fingerprints: recipe.fingerprints(),
+            size: file.size,
+            content_fingerprint: file.encrypted_hash.clone(),
+            wrapped_key: Some(wrapped_key.clone()),
+            base_version: Some(file.version),
+            manifest: None,
+        };
+        self.commit_signed(file_id, commit, account).await?;
+
+        file.chunk_recipe = Some(sealed);
+        file.wrapped_key = Some(wrapped_key);
+        Ok(Some(file))
+    }
+}
+
+/// Where a download is written until it is complete
+///
+/// The suffix is appended rather than replacing the extension, so
+/// concurrent downloads of `notes.txt` and `notes.md` do not collide.
+fn partial_path(output_path: &Path) -> PathBuf {
+    let mut name = output_path.file_name().unwrap_or_default().to_os_string();
+    name.push(".rgpart");
+    output_path.with_file_name(name)
+}
+
+/// Moves a finished download into place, or removes what was written of a
+/// failed one, so a failed download never leaves a partially decrypted
+/// file behind
+async fn finish_partial(partial_path: &Path, output_path: &Path, result: Result<()>) -> Result<()> {
+    match result {
+        Ok(()) => {
+            fs::rename(partial_path, output_path).await?;
+            Ok(())
+        }
+        Err(e) => {
+            // The download error matters more than a failed cleanup
+            let _ = fs::remove_file(partial_path).await;
+            Err(e)
+        }
+    }
+}
+
+/// Replaces the encrypted path of a listed file with its plaintext
+///
+/// Entries uploaded before path encryption have no lookup token and are
+/// returned unchanged.
+fn decrypt_file_path(path_keys: &PathKeys, mut file: FileMetadata) -> Result<FileMetadata> {
+    if file.path_token.is_some() {
+        file.path = path_keys.decrypt_path(&file.path)?;
+        file.name = file.path.rsplit('/').next().unwrap_or_default().to_string();
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::keystore::Keystore;
+    use crate::crypto::{KdfDescriptor, KdfParams};
+    use crate::server::db::{Database, MAX_FILE_VERSIONS};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn test_kdf() -> KdfDescriptor {
+        KdfDescriptor::new(KdfParams::for_tests())
+    }
+
+    /// Starts a server on a free local port, backed by a database in `dir`
+    async fn start_server(dir: &TempDir) -> String {
+        let url = format!("sqlite:{}", dir.path().join("server.db").display());
+        let db = Arc::new(Database::new(&url).await.unwrap());
+        let app = crate::server::create_app(db).await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_register_login_unlock() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let passphrase = SecretString::from("correct horse battery staple");
+
+        let anonymous = SyncClient::new(server_url.clone(), String::new());
+        let (registered, account) = anonymous
+            .register("alice", "alice@example.com", &passphrase, test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+
+        let (session, passphrase_keys) = anonymous.login("alice", &passphrase).await.unwrap();
+        assert_eq!(session.user_id, registered.user_id);
+        let unlocked = keys::unlock_account(&passphrase_keys, &session.key_material.unwrap()).unwrap();
+        assert_eq!(unlocked.master_key(), account.master_key());
+        assert_eq!(unlocked.account_key(), account.account_key());
+
+        assert!(anonymous.login("alice", &SecretString::from("wrong")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kdf_upgrade_and_passphrase_change_keep_login() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let passphrase = SecretString::from("correct horse battery staple");
+        let anonymous = SyncClient::new(server_url.clone(), String::new());
+        let (_, account) = anonymous
+            .register("alice", "alice@example.com", &passphrase, test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+
+        let (session, passphrase_keys) = anonymous.login("alice", &passphrase).await.unwrap();
+        let key_material = session.key_material.unwrap();
+        let client = SyncClient::new(server_url, session.token);
+        let target = KdfParams {
+            memory_kib: 2048,
+            ..KdfParams::for_tests()
+        };
+        let upgraded = client
+            .upgrade_kdf(&passphrase, &passphrase_keys, &key_material, target)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(upgraded.kdf.params, target);
+        assert!(client.upgrade_kdf(&passphrase, &passphrase_keys, &upgraded, target).await.unwrap().is_none());
+
+        // The upgraded keys both log in and unlock the stored key material
+        let (session, passphrase_keys) = anonymous.login("alice", &passphrase).await.unwrap();
+        assert_eq!(session.key_material.as_ref(), Some(&upgraded));
+        assert_eq!(keys::unlock_account(&passphrase_keys, &upgraded).unwrap().master_key(), account.master_key());
+
+        let new_passphrase = SecretString::from("tr0ub4dor&3");
+        let new_keys = PassphraseKeys::derive(&new_passphrase, test_kdf()).unwrap();
+        let wrong_keys = PassphraseKeys::derive(&SecretString::from("wrong"), upgraded.kdf.clone()).unwrap();
+        let forged = client
+            .send::<serde_json::Value>(client.http.post(client.url("/api/v1/auth/key-material")).json(&UpdateKeyMaterialRequest {
+                auth_key: wrong_keys.auth_key(),
+                new_auth_key: new_keys.auth_key(),
+                key_material: upgraded.clone(),
+            }))
+            .await;
+        assert!(forged.is_err());
+        let changed = client.change_passphrase(&upgraded, &passphrase_keys, &new_keys).await.unwrap();
+
+        assert!(anonymous.login("alice", &passphrase).await.is_err());
+        let (session, passphrase_keys) = anonymous.login("alice", &new_passphrase).await.unwrap();
+        assert_eq!(session.key_material.as_ref(), Some(&changed));
+        assert_eq!(keys::unlock_account(&passphrase_keys, &changed).unwrap().master_key(), account.master_key());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_token_is_replaced_from_keystore() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let passphrase = SecretString::from("passphrase");
+        let (session, account) = SyncClient::new(server_url.clone(), String::new())
+            .register("alice", "alice@example.com", &passphrase, test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+
+        let path = dir.path().join("keystore.json");
+        let expired = SecretString::from("expired.session.token");
+        let mut keystore = Keystore::create(
+            &path,
+            "alice",
+            &session.user_id,
+            session.key_material.unwrap(),
+            account.master_key(),
+            expired,
+        )
+        .unwrap();
+        let unlocked = keystore.unlock(&passphrase).unwrap();
+
+        let client = SyncClient::new(server_url.clone(), unlocked.token().expose().to_string());
+        assert!(matches!(client.verify_session().await, Err(Error::AuthenticationFailed(_))));
+        assert!(matches!(client.list_files().await, Err(Error::AuthenticationFailed(_))));
+
+        // The authentication key kept by the unlock logs in without the passphrase
+        let session = SyncClient::new(server_url.clone(), String::new())
+            .authenticate("alice", SecretString::from(unlocked.auth_key().expose()))
+            .await
+            .unwrap();
+        let refreshed = keystore.update_token(&unlocked, SecretString::from(session.token)).unwrap();
+        let client = SyncClient::new(server_url, refreshed.token().expose().to_string());
+        client.verify_session().await.unwrap();
+        assert!(client.list_files().await.unwrap().is_empty());
+        assert_eq!(keystore.unlock(&passphrase).unwrap().token().expose(), refreshed.token().expose());
+    }
+
+    #[tokio::test]
+    async fn test_rotate_then_list_and_download() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let passphrase = SecretString::from("correct horse battery staple");
+
+        let anonymous = SyncClient::new(server_url.clone(), String::new());
+        let (session, account) = anonymous
+            .register("alice", "alice@example.com", &passphrase, test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+        let client = SyncClient::new(server_url.clone(), session.token);
+
+        let local = dir.path().join("notes.txt");
+        std::fs::write(&local, b"draft numbers").unwrap();
+        let file_id = client.upload_file(&local, "docs/notes.txt", &account, None).await.unwrap();
+        std::fs::write(&local, b"quarterly numbers").unwrap();
+        client.upload_file(&local, "docs/notes.txt", &account, None).await.unwrap();
+        let deleted_id = client.upload_file(&local, "docs/old.txt", &account, None).await.unwrap();
+        client.delete_file(&deleted_id, &account).await.unwrap();
+
+        let (session, passphrase_keys) = anonymous.login("alice", &passphrase).await.unwrap();
+        let (rotated, key_material) = client
+            .rotate_master_key(&account, &passphrase_keys, &session.key_material.unwrap())
+            .await
+            .unwrap();
+        assert_ne!(rotated.master_key(), account.master_key());
+
+        // A fresh login unlocks the rotated keys
+        let (session, passphrase_keys) = anonymous.login("alice", &passphrase).await.unwrap();
+        assert_eq!(session.key_material.as_ref(), Some(&key_material));
+        let unlocked = keys::unlock_account(&passphrase_keys, &key_material).unwrap();
+        assert_eq!(unlocked.master_key(), rotated.master_key());
+
+        // Keys of replaced versions and deleted files are re-wrapped as well
+        let stored: ListFileKeysResponse = client.send(client.http.get(client.url("/api/v1/files/keys"))).await.unwrap();
+        assert_eq!(stored.keys.len(), 3);
+        for stored in &stored.keys {
+            keys::unwrap_data_key(stored.wrapped_key.as_deref().unwrap(), unlocked.master_key(), &stored.file_id).unwrap();
+        }
+
+        let files = client.list_files_decrypted(&unlocked).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, "docs/notes.txt");
+        assert_eq!(client.find_file("docs/notes.txt", &unlocked).await.unwrap().unwrap().id, file_id);
+
+        let output = dir.path().join("downloaded.txt");
+        client.download_file(&file_id, &output, &unlocked).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"quarterly numbers");
+
+        // The old master key no longer unwraps the data key
+        assert!(client.download_file(&file_id, &output, &account).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_upload_keeps_previous_version() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let (session, account) = SyncClient::new(server_url.clone(), String::new())
+            .register("alice", "alice@example.com", &SecretString::from("passphrase"), test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+        let client = SyncClient::new(server_url, session.token);
+
+        let local = dir.path().join("notes.txt");
+        std::fs::write(&local, b"first draft").unwrap();
+        let file_id = client.upload_file(&local, "notes.txt", &account, None).await.unwrap();
+
+        // Uploads that never commit change nothing visible
+        let path_keys = PathKeys::derive(account.account_key());
+        for path in ["notes.txt", "new.txt"] {
+            let _: UploadFileResponse = client
+                .send(client.http.post(client.url("/api/v1/files/upload")).json(&UploadFileRequest {
+                    path: path_keys.encrypt_path(path).unwrap(),
+                    size: 1,
+                    path_token: Some(path_keys.lookup_token(path)),
+                    content_fingerprint: None,
+                }))
+                .await
+                .unwrap();
+        }
+        let files = client.list_files_decrypted(&account).await.unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].version, 1);
+
+        let output = dir.path().join("downloaded.txt");
+        client.download_file(&file_id, &output, &account).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"first draft");
+
+        std::fs::write(&local, b"second draft").unwrap();
+        assert_eq!(client.upload_file(&local, "notes.txt", &account, None).await.unwrap(), file_id);
+        assert_eq!(client.find_file("notes.txt", &account).await.unwrap().unwrap().version, 2);
+        let versions = client.list_versions(&file_id).await.unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!((versions[0].version_number, versions[0].size), (1, 11));
+        client.download_file(&file_id, &output, &account).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"second draft");
+
+        // The pending file becomes visible once it is uploaded for real
+        std::fs::write(&local, b"new file").unwrap();
+        client.upload_file(&local, "new.txt", &account, None).await.unwrap();
+        assert_eq!(client.list_files_decrypted(&account).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_pruned_versions_release_their_chunks() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let (session, account) = SyncClient::new(server_url.clone(), String::new())
+            .register("alice", "alice@example.com", &SecretString::from("passphrase"), test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+        let client = SyncClient::new(server_url, session.token);
+
+        let local = dir.path().join("notes.txt");
+        let mut file_id = String::new();
+        for draft in 0..MAX_FILE_VERSIONS + 3 {
+            std::fs::write(&local, format!("draft {}", draft % 4)).unwrap();
+            file_id = client.upload_file(&local, "notes.txt", &account, None).await.unwrap();
+        }
+
+        let versions = client.list_versions(&file_id).await.unwrap();
+        assert_eq!(versions.len() as i64, MAX_FILE_VERSIONS);
+        assert_eq!(versions[0].version_number, 3);
+
+        // Every stored reference holds exactly one count
+        let pool = sqlx::SqlitePool::connect(&format!("sqlite:{}", dir.path().join("server.db").display()))
+            .await
+            .unwrap();
+        let (counted,): (i64,) = sqlx::query_as("SELECT SUM(ref_count) FROM chunk_store").fetch_one(&pool).await.unwrap();
+        let (references,): (i64,) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM file_chunk_refs) + (SELECT COUNT(*) FROM file_version_chunks)",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(counted, references);
+        assert_eq!(references, MAX_FILE_VERSIONS + 1);
+    }
+
+    #[tokio::test]
+    async fn test_reupload_reuses_data_key_until_rotated() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let (session, account) = SyncClient::new(server_url.clone(), String::new())
+            .register("alice", "alice@example.com", &SecretString::from("passphrase"), test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+        let client = SyncClient::new(server_url, session.token);
+
+        let local = dir.path().join("notes.txt");
+        std::fs::write(&local, b"first draft").unwrap();
+        let file_id = client.upload_file(&local, "notes.txt", &account, None).await.unwrap();
+        let wrapped_key = client.find_file("notes.txt", &account).await.unwrap().unwrap().wrapped_key;
+
+        std::fs::write(&local, b"second draft").unwrap();
+        client.upload_file(&local, "notes.txt", &account, None).await.unwrap();
+        let file = client.find_file("notes.txt", &account).await.unwrap().unwrap();
+        assert_eq!((file.version, &file.wrapped_key), (2, &wrapped_key));
+
+        let rotated = client.rotate_data_key(&file_id, &account).await.unwrap().unwrap();
+        assert_ne!(rotated.wrapped_key, wrapped_key);
+        assert_eq!(client.find_file("notes.txt", &account).await.unwrap().unwrap().wrapped_key, rotated.wrapped_key);
+
+        let output = dir.path().join("downloaded.txt");
+        client.download_file(&file_id, &output, &account).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"second draft");
+    }
+
+    #[tokio::test]
+    async fn test_share_survives_reupload_and_revoke() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let anonymous = SyncClient::new(server_url.clone(), String::new());
+        let mut users = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            let (session, account) = anonymous
+                .register(name, &format!("{}@example.com", name), &SecretString::from("passphrase"), test_kdf(), sharing::test_key())
+                .await
+                .unwrap();
+            users.push((SyncClient::new(server_url.clone(), session.token), account));
+        }
+        let [(alice, alice_keys), (bob, bob_keys), (carol, carol_keys)] = users.try_into().ok().unwrap();
+
+        let local = dir.path().join("plan.txt");
+        std::fs::write(&local, b"first draft").unwrap();
+        let file_id = alice.upload_file(&local, "plan.txt", &alice_keys, None).await.unwrap();
+        let bob_share = alice.share_file(&file_id, "bob", &alice_keys).await.unwrap();
+        let carol_share = alice.share_file(&file_id, "carol", &alice_keys).await.unwrap();
+
+        // Shares keep working across uploads of new content
+        std::fs::write(&local, b"second draft").unwrap();
+        alice.upload_file(&local, "plan.txt", &alice_keys, None).await.unwrap();
+        let output = dir.path().join("shared.txt");
+        let carol_shared = carol.list_shared_with_me().await.unwrap().remove(0);
+        carol.download_shared_file(&carol_shared, &output, &carol_keys).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"second draft");
+
+        alice.revoke_share(&carol_share.id, &alice_keys).await.unwrap();
+        assert!(carol.list_shared_with_me().await.unwrap().is_empty());
+        let bob_shared = bob.list_shared_with_me().await.unwrap().remove(0);
+        assert_ne!(bob_shared.share.wrapped_key, bob_share.wrapped_key);
+        bob.download_shared_file(&bob_shared, &output, &bob_keys).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"second draft");
+
+        assert!(carol.download_shared_file(&carol_shared, &output, &carol_keys).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stale_upload_queues_conflict() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let (session, account) = SyncClient::new(server_url.clone(), String::new())
+            .register("alice", "alice@example.com", &SecretString::from("passphrase"), test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+        let client = SyncClient::new(server_url, session.token);
+
+        let root = dir.path().join("sync");
+        std::fs::create_dir(&root).unwrap();
+        let local = root.join("notes.txt");
+        std::fs::write(&local, b"first draft").unwrap();
+        let file_id = client.upload_file(&local, "docs/notes.txt", &account, Some(0)).await.unwrap();
+
+        // Another device commits on top of version 1 first
+        let other = dir.path().join("other.txt");
+        std::fs::write(&other, b"edited elsewhere").unwrap();
+        client.upload_file(&other, "docs/notes.txt", &account, Some(1)).await.unwrap();
+        std::fs::write(&local, b"edited here").unwrap();
+        assert!(matches!(
+            client.upload_file(&local, "docs/notes.txt", &account, Some(1)).await,
+            Err(Error::ConflictError(_))
+        ));
+
+        let index = crate::sync::SyncIndex::open(&dir.path().join("index.db")).await.unwrap();
+        let engine = crate::client::engine::SyncEngine::new(&client, &account, &index, "desk".to_string());
+        let plan = crate::sync::SyncPlan {
+            uploads: vec![crate::sync::planner::PlannedTransfer {
+                path: "notes.txt".to_string(),
+                size: 11,
+                fingerprint: String::new(),
+                remote_id: Some(file_id.clone()),
+                remote_version: Some(1),
+            }],
+            ..Default::default()
+        };
+        assert_eq!(engine.apply(&root, "docs", &plan).await.unwrap(), 0);
+
+        let conflicts = index.pending_conflicts().await.unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].details.kind, crate::sync::ConflictKind::BothModified);
+        assert_eq!(conflicts[0].details.remote.as_ref().and_then(|side| side.version), Some(2));
+
+        let output = dir.path().join("downloaded.txt");
+        client.download_file(&file_id, &output, &account).await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), b"edited elsewhere");
+    }
+
+    #[tokio::test]
+    async fn test_two_devices_interleave_commits() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let passphrase = SecretString::from("passphrase");
+        let anonymous = SyncClient::new(server_url.clone(), String::new());
+        let (session, account) = anonymous
+            .register("alice", "alice@example.com", &passphrase, test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+        let laptop = SyncClient::new(server_url.clone(), session.token)
+            .with_checkpoint_store(CheckpointStore::new(dir.path().join("laptop.checkpoint")));
+        let (session, _) = anonymous.login("alice", &passphrase).await.unwrap();
+        let desk = SyncClient::new(server_url, session.token)
+            .with_checkpoint_store(CheckpointStore::new(dir.path().join("desk.checkpoint")));
+
+        let laptop_file = dir.path().join("laptop.txt");
+        let desk_file = dir.path().join("desk.txt");
+        std::fs::write(&laptop_file, b"laptop 0").unwrap();
+        let shared_id = laptop.upload_file(&laptop_file, "shared.txt", &account, Some(0)).await.unwrap();
+        let stale = laptop.fetch_manifest(&account).await.unwrap().unwrap();
+
+        for round in 1..=3 {
+            std::fs::write(&laptop_file, format!("laptop {}", round)).unwrap();
+            std::fs::write(&desk_file, format!("desk {}", round)).unwrap();
+            let desk_path = format!("desk-{}.txt", round);
+            let (laptop_result, desk_result) = tokio::join!(
+                laptop.upload_file(&laptop_file, "laptop.txt", &account, None),
+                desk.upload_file(&desk_file, &desk_path, &account, Some(0)),
+            );
+            laptop_result.unwrap();
+            desk_result.unwrap();
+        }
+        desk.delete_file(&shared_id, &account).await.unwrap();
+
+        // A commit whose manifest lost the race changes nothing
+        let laptop_id = laptop.find_file("laptop.txt", &account).await.unwrap().unwrap().id;
+        let download: DownloadFileResponse = laptop
+            .send(laptop.http.get(laptop.url(&format!("/api/v1/files/download/{}", laptop_id))))
+            .await
+            .unwrap();
+        let data_key = keys::unwrap_data_key(download.file.wrapped_key.as_deref().unwrap(), account.master_key(), &laptop_id).unwrap();
+        let recipe = ChunkRecipe::open(download.file.chunk_recipe.as_deref().unwrap(), &data_key, &laptop_id).unwrap();
+        let stale_signed = ManifestSigner::derive(account.account_key())
+            .sign(Manifest::new(&laptop.list_files().await.unwrap(), stale.sequence + 1))
+            .unwrap();
+        let commit = CommitChunksRequest {
+            recipe: download.file.chunk_recipe.clone().unwrap(),
+            fingerprints: recipe.fingerprints(),
+            size: download.file.size,
+            content_fingerprint: download.file.encrypted_hash.clone(),
+            wrapped_key: None,
+            base_version: Some(download.file.version),
+            manifest: Some(stale_signed),
+        };
+        let result = laptop
+            .send::<serde_json::Value>(laptop.http.post(laptop.url(&format!("/api/v1/files/{}/chunks", laptop_id))).json(&commit))
+            .await;
+        assert!(matches!(result, Err(Error::ConflictError(_))));
+
+        for device in [&laptop, &desk] {
+            let files = device.list_files_decrypted(&account).await.unwrap();
+            let mut paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+            paths.sort();
+            assert_eq!(paths, ["desk-1.txt", "desk-2.txt", "desk-3.txt", "laptop.txt"]);
+
+            let output = dir.path().join("downloaded.txt");
+            let laptop_copy = files.iter().find(|file| file.path == "laptop.txt").unwrap();
+            assert_eq!(laptop_copy.version, 3);
+            device.download_file(&laptop_copy.id, &output, &account).await.unwrap();
+            assert_eq!(std::fs::read(&output).unwrap(), b"laptop 3");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_manifests_store_only_one() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let (session, account) = SyncClient::new(server_url.clone(), String::new())
+            .register("alice", "alice@example.com", &SecretString::from("passphrase"), test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+        let client = SyncClient::new(server_url, session.token);
+        let signed = client.publish_manifest(&account).await.unwrap();
+
+        // Two devices signing the next sequence at once
+        let signer = ManifestSigner::derive(account.account_key());
+        let store = |manifest: SignedManifest| {
+            client.send::<serde_json::Value>(client.http.post(client.url("/api/v1/manifest")).json(&manifest))
+        };
+        let next = || signer.sign(Manifest::new(&[], signed.manifest.sequence + 1)).unwrap();
+        let (first, second) = tokio::join!(store(next()), store(next()));
+        assert_eq!([&first, &second].iter().filter(|result| result.is_ok()).count(), 1);
+        assert!(matches!(first.and(second), Err(Error::ConflictError(_))));
+
+        assert!(matches!(store(signed.clone()).await, Err(Error::ConflictError(_))));
+        assert_eq!(client.fetch_manifest(&account).await.unwrap().unwrap().sequence, signed.manifest.sequence + 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_download_leaves_no_partial_file() {
+        let dir = TempDir::new().unwrap();
+        let server_url = start_server(&dir).await;
+        let (session, account) = SyncClient::new(server_url.clone(), String::new())
+            .register("alice", "alice@example.com", &SecretString::from("passphrase"), test_kdf(), sharing::test_key())
+            .await
+            .unwrap();
+        let client = SyncClient::new(server_url, session.token);
+
+        let local = dir.path().join("notes.txt");
+        std::fs::write(&local, b"quarterly numbers").unwrap();
+        let file_id = client.upload_file(&local, "notes.txt", &account, None).await.unwrap();
+
+        let download: DownloadFileResponse = client
+            .send(client.http.get(client.url(&format!("/api/v1/files/download/{}", file_id))))
+            .await
+            .unwrap();
+        let data_key = keys::unwrap_data_key(download.file.wrapped_key.as_deref().unwrap(), account.master_key(), &file_id).unwrap();
+        let mut recipe = ChunkRecipe::open(download.file.chunk_recipe.as_deref().unwrap(), &data_key, &file_id).unwrap();
+        recipe.chunks[0].size += 1;
+
+        let output_dir = dir.path().join("out");
+        std::fs::create_dir(&output_dir).unwrap();
+        let output = output_dir.join("notes.txt");
+        assert!(client.download_recipe(&download.chunks, &recipe, &output, None).await.is_err());
+        assert_eq!(std::fs::read_dir(&output_dir).unwrap().count(), 0);
+
+        assert_eq!(partial_path(&output), output_dir.join("notes.txt.rgpart"));
     }
 }
@@ -0,0 +1,421 @@
+//! Content-defined chunking
+//!
+//! Files are split where their content says so rather than at fixed
+//! offsets, so inserting or removing bytes only changes the chunks around
+//! the edit and every other chunk can be deduplicated. Boundaries are found
+//! with a FastCDC-style gear hash: a cut is made where the hash matches a
+//! mask, using a stricter mask before the average size and a looser one
+//! after it so chunk sizes cluster around the average.
+//!
+//! The gear table is derived from the account key. With a public table, the
+//! sequence of chunk sizes alone could identify a known file.
+//!
+//! Each chunk is encrypted under a key derived from its keyed fingerprint,
+//! so the same content always gets the same key and can be stored once per
+//! account, while the associated data ties the ciphertext to that
+//! fingerprint. The ordered list of fingerprints and chunk keys of a file,
+//! its [`ChunkRecipe`], is sealed under the file's data key; anyone holding
+//! that key, including share recipients, can reassemble the file without the
+//! owner's account keys.
+
+use super::keys::{derive_subkey, unwrap_bytes, wrap_bytes};
+use super::{open, seal, CipherSuite, Envelope, SecretKey, CHUNK_CONTEXT_VERSION};
+use crate::error::{Error, Result};
+use hmac::digest::FixedOutput;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use zeroize::Zeroizing;
+
+/// Smallest chunk size the chunker accepts as a minimum
+pub const MIN_CHUNK_SIZE: usize = 4 * 1024;
+/// Largest chunk size the chunker accepts as a maximum
+pub const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Version of the serialized [`ChunkRecipe`] layout
+const RECIPE_VERSION: u8 = 1;
+const RECIPE_PURPOSE: &str = "chunk-recipe";
+const FINGERPRINT_SIZE: usize = 32;
+
+/// Chunk size bounds, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChunkingParams {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkingParams {
+    fn default() -> Self {
+        Self {
+            min_size: 256 * 1024,
+            avg_size: 1024 * 1024,
+            max_size: 4 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkingParams {
+    /// Checks that the sizes are ordered and within the supported range
+    pub fn validate(&self) -> Result<()> {
+        if self.min_size < MIN_CHUNK_SIZE || self.max_size > MAX_CHUNK_SIZE {
+            return Err(Error::ConfigError(format!(
+                "Chunk sizes must be between {} and {} bytes",
+                MIN_CHUNK_SIZE, MAX_CHUNK_SIZE
+            )));
+        }
+        if !(self.min_size < self.avg_size && self.avg_size < self.max_size) {
+            return Err(Error::ConfigError(
+                "Chunk sizes must satisfy min_size < avg_size < max_size".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Finds content-defined chunk boundaries
+pub struct Chunker {
+    params: ChunkingParams,
+    gear: Box<[u64; 256]>,
+    /// Stricter mask used before the average size
+    mask_small: u64,
+    /// Looser mask used after the average size
+    mask_large: u64,
+}
+
+impl Chunker {
+    /// Creates a chunker whose boundaries depend on the account key
+    pub fn new(params: ChunkingParams, account_key: &SecretKey) -> Result<Self> {
+        params.validate()?;
+
+        let bits = params.avg_size.ilog2();
+        Ok(Self {
+            params,
+            gear: gear_table(&derive_subkey(account_key, "chunk-boundaries")),
+            mask_small: high_bits(bits + 2),
+            mask_large: high_bits(bits - 2),
+        })
+    }
+
+    /// The size bounds this chunker was created with
+    pub fn params(&self) -> &ChunkingParams {
+        &self.params
+    }
+
+    /// Returns the length of the first chunk of `data`
+    ///
+    /// `data` must hold at least `max_size` bytes unless it is the end of the
+    /// input, otherwise the cut may differ from the one made on the full data.
+    pub fn cut_point(&self, data: &[u8]) -> usize {
+        let ChunkingParams { min_size, avg_size, max_size } = self.params;
+        if data.len() <= min_size {
+            return data.len();
+        }
+
+        let end = data.len().min(max_size);
+        let normal = avg_size.min(end);
+        let mut hash = 0u64;
+
+        for (i, &byte) in data.iter().enumerate().take(end).skip(min_size) {
+            hash = (hash << 1).wrapping_add(self.gear[byte as usize]);
+            let mask = if i < normal { self.mask_small } else { self.mask_large };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+
+        end
+    }
+
+    /// Splits in-memory data into chunks
+    pub fn chunks<'a>(&self, mut data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let (chunk, rest) = data.split_at(self.cut_point(data));
+            chunks.push(chunk);
+            data = rest;
+        }
+        chunks
+    }
+}
+
+fn high_bits(count: u32) -> u64 {
+    !0u64 << (64 - count.min(63))
+}
+
+fn gear_table(key: &SecretKey) -> Box<[u64; 256]> {
+    let mut gear = Box::new([0u64; 256]);
+
+    for (block, values) in gear.chunks_mut(4).enumerate() {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key.expose()).expect("HMAC accepts keys of any length");
+        mac.update(&(block as u32).to_be_bytes());
+        let output = FixedOutput::finalize_fixed(mac);
+
+        for (value, bytes) in values.iter_mut().zip(output.chunks_exact(8)) {
+            *value = u64::from_be_bytes(bytes.try_into().expect("chunks are 8 bytes"));
+        }
+    }
+
+    gear
+}
+
+/// Reads chunks from a stream, holding at most one maximum-size chunk
+pub struct ChunkReader<'a, R> {
+    chunker: &'a Chunker,
+    reader: R,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<'a, R: AsyncRead + Unpin> ChunkReader<'a, R> {
+    pub fn new(chunker: &'a Chunker, reader: R) -> Self {
+        Self {
+            chunker,
+            reader,
+            buf: Vec::with_capacity(chunker.params.max_size),
+            eof: false,
+        }
+    }
+
+    /// Returns the next chunk, or `None` at the end of the stream
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        let max_size = self.chunker.params.max_size;
+
+        while !self.eof && self.buf.len() < max_size {
+            let start = self.buf.len();
+            self.buf.resize(max_size, 0);
+            let n = self.reader.read(&mut self.buf[start..]).await?;
+            self.buf.truncate(start + n);
+            self.eof = n == 0;
+        }
+
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+
+        let cut = self.chunker.cut_point(&self.buf);
+        let rest = self.buf.split_off(cut);
+        Ok(Some(std::mem::replace(&mut self.buf, rest)))
+    }
+}
+
+/// Keys for fingerprinting and encrypting chunks
+pub struct ChunkKeys {
+    fingerprint_key: SecretKey,
+    encryption_key: SecretKey,
+}
+
+impl ChunkKeys {
+    /// Derives chunk keys from the account key
+    pub fn derive(account_key: &SecretKey) -> Self {
+        Self {
+            fingerprint_key: derive_subkey(account_key, "chunk-fingerprint"),
+            encryption_key: derive_subkey(account_key, "chunk-encryption"),
+        }
+    }
+
+    /// Computes the keyed fingerprint of a chunk
+    pub fn fingerprint(&self, data: &[u8]) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.fingerprint_key.expose())
+            .expect("HMAC accepts keys of any length");
+        mac.update(data);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Derives the key that encrypts the chunk with `fingerprint`
+    pub fn chunk_key(&self, fingerprint: &str) -> SecretKey {
+        derive_subkey(&self.encryption_key, fingerprint)
+    }
+}
+
+fn chunk_context(fingerprint: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(24 + fingerprint.len());
+    aad.extend_from_slice(b"rustguard-content-chunk");
+    aad.push(CHUNK_CONTEXT_VERSION);
+    aad.extend_from_slice(fingerprint.as_bytes());
+    aad
+}
+
+/// Encrypts a chunk under its own key, bound to its fingerprint
+pub fn seal_chunk(suite: CipherSuite, data: &[u8], fingerprint: &str, chunk_key: &SecretKey) -> Result<Envelope> {
+    seal(suite, data, chunk_key, &suite.generate_nonce(), &chunk_context(fingerprint))
+}
+
+/// Decrypts a chunk, failing if it is not the chunk with `fingerprint`
+pub fn open_chunk(envelope: &Envelope, fingerprint: &str, chunk_key: &SecretKey) -> Result<Vec<u8>> {
+    open(envelope, chunk_key, &chunk_context(fingerprint))
+        .map_err(|_| Error::DecryptionError(format!("Chunk {} failed authentication", fingerprint)))
+}
+
+/// One chunk of a file, in order
+#[derive(Debug)]
+pub struct RecipeEntry {
+    pub fingerprint: String,
+    pub size: u32,
+    pub key: SecretKey,
+}
+
+/// The ordered chunks of a file together with their keys
+#[derive(Debug, Default)]
+pub struct ChunkRecipe {
+    pub chunks: Vec<RecipeEntry>,
+}
+
+impl ChunkRecipe {
+    /// Fingerprints of the chunks, in file order
+    pub fn fingerprints(&self) -> Vec<String> {
+        self.chunks.iter().map(|entry| entry.fingerprint.clone()).collect()
+    }
+
+    /// Encrypts the recipe under the data key of `file_id`
+    pub fn seal(&self, data_key: &SecretKey, file_id: &str) -> Result<String> {
+        let entry_size = FINGERPRINT_SIZE + 4 + super::KEY_SIZE;
+        let mut bytes = Zeroizing::new(Vec::with_capacity(5 + self.chunks.len() * entry_size));
+        bytes.push(RECIPE_VERSION);
+        bytes.extend_from_slice(&(self.chunks.len() as u32).to_be_bytes());
+
+        for entry in &self.chunks {
+            let fingerprint = hex::decode(&entry.fingerprint)
+                .ok()
+                .filter(|fingerprint| fingerprint.len() == FINGERPRINT_SIZE)
+                .ok_or_else(|| Error::EncryptionError(format!("Invalid chunk fingerprint {}", entry.fingerprint)))?;
+            bytes.extend_from_slice(&fingerprint);
+            bytes.extend_from_slice(&entry.size.to_be_bytes());
+            bytes.extend_from_slice(entry.key.expose());
+        }
+
+        wrap_bytes(&bytes, data_key, RECIPE_PURPOSE, file_id)
+    }
+
+    /// Decrypts a recipe produced by [`ChunkRecipe::seal`] for `file_id`
+    pub fn open(sealed: &str, data_key: &SecretKey, file_id: &str) -> Result<Self> {
+        let bytes = unwrap_bytes(sealed, data_key, RECIPE_PURPOSE, file_id)?;
+        let invalid = || Error::DecryptionError("Malformed chunk recipe".to_string());
+
+        let (&version, rest) = bytes.split_first().ok_or_else(invalid)?;
+        if version != RECIPE_VERSION {
+            return Err(Error::DecryptionError(format!("Unsupported chunk recipe version {}", version)));
+        }
+        if rest.len() < 4 {
+            return Err(invalid());
+        }
+        let (count, mut rest) = rest.split_at(4);
+        let count = u32::from_be_bytes(count.try_into().expect("split at 4 bytes")) as usize;
+
+        let entry_size = FINGERPRINT_SIZE + 4 + super::KEY_SIZE;
+        if rest.len() != count * entry_size {
+            return Err(invalid());
+        }
+
+        let mut chunks = Vec::with_capacity(count);
+        while !rest.is_empty() {
+            let (entry, next) = rest.split_at(entry_size);
+            let (fingerprint, entry) = entry.split_at(FINGERPRINT_SIZE);
+            let (size, key) = entry.split_at(4);
+            chunks.push(RecipeEntry {
+                fingerprint: hex::encode(fingerprint),
+                size: u32::from_be_bytes(size.try_into().expect("split at 4 bytes")),
+                key: SecretKey::from_slice(key)?,
+            });
+            rest = next;
+        }
+
+        Ok(Self { chunks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SMALL: ChunkingParams = ChunkingParams {
+        min_size: 4 * 1024,
+        avg_size: 16 * 1024,
+        max_size: 64 * 1024,
+    };
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunks_respect_bounds_and_survive_insertions() {
+        let chunker = Chunker::new(SMALL, &SecretKey::from_bytes([1u8; 32])).unwrap();
+        let data = pseudo_random(1024 * 1024, 42);
+
+        let chunks = chunker.chunks(&data);
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= SMALL.min_size && chunk.len() <= SMALL.max_size);
+        }
+
+        // An insertion near the start only disturbs the chunks around it
+        let mut edited = data.clone();
+        edited.splice(100..100, *b"inserted");
+        let edited_chunks = chunker.chunks(&edited);
+        let shared = edited_chunks.iter().filter(|chunk| chunks.contains(chunk)).count();
+        assert!(shared >= chunks.len() - 2, "{} of {} chunks shared", shared, chunks.len());
+
+        // Another key cuts elsewhere
+        let other = Chunker::new(SMALL, &SecretKey::from_bytes([2u8; 32])).unwrap();
+        assert_ne!(other.chunks(&data), chunks);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_reader_matches_in_memory_chunking() {
+        let chunker = Chunker::new(SMALL, &SecretKey::from_bytes([3u8; 32])).unwrap();
+        let data = pseudo_random(300 * 1024, 7);
+
+        let mut reader = ChunkReader::new(&chunker, &data[..]);
+        let mut streamed = Vec::new();
+        while let Some(chunk) = reader.next_chunk().await.unwrap() {
+            streamed.push(chunk);
+        }
+
+        assert_eq!(streamed, chunker.chunks(&data));
+    }
+
+    #[test]
+    fn test_params_are_validated() {
+        assert!(ChunkingParams::default().validate().is_ok());
+        assert!(ChunkingParams { avg_size: SMALL.max_size, ..SMALL }.validate().is_err());
+        assert!(ChunkingParams { max_size: MAX_CHUNK_SIZE * 2, ..SMALL }.validate().is_err());
+    }
+
+    #[test]
+    fn test_chunks_and_recipes_are_bound() {
+        let keys = ChunkKeys::derive(&SecretKey::from_bytes([4u8; 32]));
+        let fingerprint = keys.fingerprint(b"chunk");
+        let other = keys.fingerprint(b"other");
+        assert_eq!(keys.chunk_key(&fingerprint), keys.chunk_key(&fingerprint));
+
+        let envelope = seal_chunk(CipherSuite::Aes256Gcm, b"chunk", &fingerprint, &keys.chunk_key(&fingerprint)).unwrap();
+        assert_eq!(open_chunk(&envelope, &fingerprint, &keys.chunk_key(&fingerprint)).unwrap(), b"chunk");
+        assert!(open_chunk(&envelope, &other, &keys.chunk_key(&fingerprint)).is_err());
+
+        let recipe = ChunkRecipe {
+            chunks: vec![RecipeEntry {
+                fingerprint: fingerprint.clone(),
+                size: 5,
+                key: keys.chunk_key(&fingerprint),
+            }],
+        };
+        let data_key = SecretKey::from_bytes([5u8; 32]);
+        let sealed = recipe.seal(&data_key, "file-a").unwrap();
+
+        let opened = ChunkRecipe::open(&sealed, &data_key, "file-a").unwrap();
+        assert_eq!(opened.fingerprints(), vec![fingerprint.clone()]);
+        assert_eq!(opened.chunks[0].key, keys.chunk_key(&fingerprint));
+        assert!(ChunkRecipe::open(&sealed, &data_key, "file-b").is_err());
+    }
+}
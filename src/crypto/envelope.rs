@@ -0,0 +1,195 @@
+//! Self-describing container for encrypted blobs
+//!
+//! Every encrypted chunk and manifest is stored as an [`Envelope`]:
+//!
+//! ```text
+//! magic (4) | version (1) | cipher (1) | key id (16) | nonce len (1) | nonce | ciphertext + tag
+//! ```
+//!
+//! The header is authenticated as associated data, so it cannot be altered
+//! without decryption failing.
+
+use crate::error::{Error, Result};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+/// Magic bytes identifying a RustGuard envelope
+pub const ENVELOPE_MAGIC: [u8; 4] = *b"RGBX";
+
+/// Current envelope format version
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Size of the key identifier stored in each envelope
+pub const KEY_ID_SIZE: usize = 16;
+
+/// Identifies the key a blob was encrypted with, without revealing it
+pub type KeyId = [u8; KEY_ID_SIZE];
+
+const FIXED_HEADER_SIZE: usize = ENVELOPE_MAGIC.len() + 1 + 1 + KEY_ID_SIZE + 1;
+
+/// AEAD cipher used for an envelope
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum CipherSuite {
+    /// AES-256-GCM with 96-bit nonces; fastest with AES instructions
+    #[default]
+    Aes256Gcm = 1,
+    /// XChaCha20-Poly1305 with 192-bit nonces; safe for random nonces at any
+    /// volume and fast without AES hardware
+    #[serde(rename = "xchacha20_poly1305")]
+    XChaCha20Poly1305 = 2,
+}
+
+impl CipherSuite {
+    /// Nonce length in bytes for this cipher
+    pub fn nonce_len(&self) -> usize {
+        match self {
+            CipherSuite::Aes256Gcm => 12,
+            CipherSuite::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    /// Generates a random nonce of the right length for this cipher
+    pub fn generate_nonce(&self) -> Vec<u8> {
+        let mut nonce = vec![0u8; self.nonce_len()];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        nonce
+    }
+
+    /// Authentication tag length in bytes for this cipher
+    pub fn tag_len(&self) -> usize {
+        16
+    }
+}
+
+impl TryFrom<u8> for CipherSuite {
+    type Error = Error;
+
+    fn try_from(id: u8) -> Result<Self> {
+        match id {
+            1 => Ok(CipherSuite::Aes256Gcm),
+            2 => Ok(CipherSuite::XChaCha20Poly1305),
+            other => Err(Error::DecryptionError(format!("Unknown cipher id {}", other))),
+        }
+    }
+}
+
+/// An encrypted blob together with everything needed to decrypt it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub version: u8,
+    pub cipher: CipherSuite,
+    pub key_id: KeyId,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+impl Envelope {
+    /// Serializes the header, which is also used as associated data
+    pub fn header_bytes(&self) -> Vec<u8> {
+        encode_header(self.version, self.cipher, &self.key_id, &self.nonce)
+    }
+
+    /// Serializes the envelope to its binary form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.header_bytes();
+        bytes.extend_from_slice(&self.ciphertext);
+        bytes
+    }
+
+    /// Parses an envelope from its binary form
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < FIXED_HEADER_SIZE {
+            return Err(Error::DecryptionError("Envelope too short".to_string()));
+        }
+
+        if bytes[..4] != ENVELOPE_MAGIC {
+            return Err(Error::DecryptionError("Not a RustGuard envelope".to_string()));
+        }
+
+        let version = bytes[4];
+        if version == 0 || version > ENVELOPE_VERSION {
+            return Err(Error::DecryptionError(format!(
+                "Unsupported envelope version {}",
+                version
+            )));
+        }
+
+        let cipher = CipherSuite::try_from(bytes[5])?;
+
+        let mut key_id = [0u8; KEY_ID_SIZE];
+        key_id.copy_from_slice(&bytes[6..6 + KEY_ID_SIZE]);
+
+        let nonce_len = bytes[FIXED_HEADER_SIZE - 1] as usize;
+        if nonce_len != cipher.nonce_len() {
+            return Err(Error::DecryptionError("Invalid nonce length".to_string()));
+        }
+
+        let body = &bytes[FIXED_HEADER_SIZE..];
+        if body.len() < nonce_len + cipher.tag_len() {
+            return Err(Error::DecryptionError("Envelope truncated".to_string()));
+        }
+
+        Ok(Self {
+            version,
+            cipher,
+            key_id,
+            nonce: body[..nonce_len].to_vec(),
+            ciphertext: body[nonce_len..].to_vec(),
+        })
+    }
+}
+
+pub(crate) fn encode_header(version: u8, cipher: CipherSuite, key_id: &KeyId, nonce: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(FIXED_HEADER_SIZE + nonce.len());
+    header.extend_from_slice(&ENVELOPE_MAGIC);
+    header.push(version);
+    header.push(cipher as u8);
+    header.extend_from_slice(key_id);
+    header.push(nonce.len() as u8);
+    header.extend_from_slice(nonce);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Envelope {
+        Envelope {
+            version: ENVELOPE_VERSION,
+            cipher: CipherSuite::Aes256Gcm,
+            key_id: [3u8; KEY_ID_SIZE],
+            nonce: vec![9u8; 12],
+            ciphertext: vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17],
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let envelope = sample();
+        let bytes = envelope.to_bytes();
+        assert_eq!(&bytes[..4], b"RGBX");
+        assert_eq!(Envelope::from_bytes(&bytes).unwrap(), envelope);
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        let bytes = sample().to_bytes();
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(Envelope::from_bytes(&bad_magic).is_err());
+
+        let mut future_version = bytes.clone();
+        future_version[4] = ENVELOPE_VERSION + 1;
+        assert!(Envelope::from_bytes(&future_version).is_err());
+
+        let mut bad_cipher = bytes.clone();
+        bad_cipher[5] = 0xff;
+        assert!(Envelope::from_bytes(&bad_cipher).is_err());
+
+        assert!(Envelope::from_bytes(&bytes[..FIXED_HEADER_SIZE + 12 + 4]).is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! Keyed content fingerprints
+//!
+//! Plain SHA-256 of file contents would let the server confirm that a user
+//! holds a known file. Fingerprints are instead an HMAC-SHA256 under a key
+//! derived from the account key: stable for one user, so they still drive
+//! change detection and dedup, but meaningless to anyone else.
+
+use super::keys::derive_subkey;
+use super::SecretKey;
+use crate::error::Result;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Key for computing content fingerprints
+#[derive(Debug)]
+pub struct FingerprintKey {
+    key: SecretKey,
+}
+
+impl FingerprintKey {
+    /// Derives the fingerprint key from the account key
+    pub fn derive(account_key: &SecretKey) -> Self {
+        Self {
+            key: derive_subkey(account_key, "content-fingerprint"),
+        }
+    }
+
+    /// Makes an independent copy of the key, e.g. to move into a blocking task
+    pub fn duplicate(&self) -> Self {
+        Self {
+            key: self.key.duplicate(),
+        }
+    }
+
+    /// Starts a fingerprint over data that arrives in pieces
+    pub fn fingerprinter(&self) -> Fingerprinter {
+        Fingerprinter(
+            <Hmac<Sha256> as Mac>::new_from_slice(self.key.expose()).expect("HMAC accepts keys of any length"),
+        )
+    }
+
+    /// Fingerprints in-memory data
+    pub fn fingerprint(&self, data: &[u8]) -> String {
+        let mut fingerprinter = self.fingerprinter();
+        fingerprinter.update(data);
+        fingerprinter.finish()
+    }
+
+    /// Fingerprints everything read from `reader`
+    pub fn fingerprint_reader<R: Read>(&self, reader: &mut R) -> Result<String> {
+        let mut fingerprinter = self.fingerprinter();
+        let mut buf = vec![0u8; READ_BUFFER_SIZE];
+
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            fingerprinter.update(&buf[..n]);
+        }
+
+        Ok(fingerprinter.finish())
+    }
+
+    /// Fingerprints a file without loading it into memory
+    pub fn fingerprint_file(&self, path: &Path) -> Result<String> {
+        self.fingerprint_reader(&mut File::open(path)?)
+    }
+}
+
+/// A fingerprint in progress; the result only depends on the bytes fed
+/// in, not on how they were split
+pub struct Fingerprinter(Hmac<Sha256>);
+
+impl Fingerprinter {
+    /// Adds the next piece of content
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    /// Returns the hex fingerprint of everything added
+    pub fn finish(self) -> String {
+        hex::encode(self.0.finalize().into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_is_keyed() {
+        let key = FingerprintKey::derive(&SecretKey::from_bytes([1u8; 32]));
+        let data = vec![7u8; READ_BUFFER_SIZE * 2 + 3];
+
+        let fingerprint = key.fingerprint(&data);
+        assert_eq!(fingerprint, key.fingerprint_reader(&mut &data[..]).unwrap());
+        let mut fingerprinter = key.fingerprinter();
+        data.chunks(1000).for_each(|piece| fingerprinter.update(piece));
+        assert_eq!(fingerprint, fingerprinter.finish());
+        assert_ne!(fingerprint, crate::crypto::compute_hash(&data));
+
+        let other = FingerprintKey::derive(&SecretKey::from_bytes([2u8; 32]));
+        assert_ne!(fingerprint, other.fingerprint(&data));
+    }
+}
@@ -0,0 +1,206 @@
+//! Password-based key derivation
+//!
+//! Keys are derived with Argon2id. The parameters and salt used for a given
+//! account are recorded in a [`KdfDescriptor`] that is stored with the user's
+//! key material, so the same key can be re-derived on any device and accounts
+//! can later be moved to stronger settings.
+
+use super::secret::{SecretKey, SecretString, KEY_SIZE};
+use crate::error::{Error, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Current version of the KDF descriptor format
+pub const KDF_DESCRIPTOR_VERSION: u32 = 1;
+
+const SALT_SIZE: usize = 16;
+
+/// Key derivation algorithm recorded in a descriptor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KdfAlgorithm {
+    /// Single SHA-256 over password and salt. Only kept so that data written
+    /// by early releases can still be read and migrated.
+    LegacySha256,
+    /// Argon2id (RFC 9106)
+    Argon2id,
+}
+
+/// Tunable Argon2id cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub memory_kib: u32,
+    /// Number of passes over memory
+    pub iterations: u32,
+    /// Degree of parallelism (lanes)
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// Returns true if every cost parameter is at least as high as `other`
+    pub fn is_at_least(&self, other: &KdfParams) -> bool {
+        self.memory_kib >= other.memory_kib
+            && self.iterations >= other.iterations
+            && self.parallelism >= other.parallelism
+    }
+}
+
+#[cfg(test)]
+impl KdfParams {
+    /// Minimal costs so tests do not spend seconds stretching passphrases
+    pub(crate) fn for_tests() -> Self {
+        Self {
+            memory_kib: 1024,
+            iterations: 1,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Default for KdfParams {
+    /// OWASP recommended baseline for Argon2id (64 MiB, 3 passes, 4 lanes)
+    fn default() -> Self {
+        Self {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 4,
+        }
+    }
+}
+
+/// Versioned description of how a key was derived from a password
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfDescriptor {
+    pub version: u32,
+    pub algorithm: KdfAlgorithm,
+    pub params: KdfParams,
+    /// Hex encoded salt
+    pub salt: String,
+}
+
+impl KdfDescriptor {
+    /// Creates an Argon2id descriptor with a fresh random salt
+    pub fn new(params: KdfParams) -> Self {
+        let mut salt = [0u8; SALT_SIZE];
+        rand::thread_rng().fill_bytes(&mut salt);
+
+        Self {
+            version: KDF_DESCRIPTOR_VERSION,
+            algorithm: KdfAlgorithm::Argon2id,
+            params,
+            salt: hex::encode(salt),
+        }
+    }
+
+    /// Describes a key produced by the original SHA-256 `derive_key`
+    pub fn legacy(salt: &[u8; SALT_SIZE]) -> Self {
+        Self {
+            version: KDF_DESCRIPTOR_VERSION,
+            algorithm: KdfAlgorithm::LegacySha256,
+            params: KdfParams {
+                memory_kib: 0,
+                iterations: 1,
+                parallelism: 1,
+            },
+            salt: hex::encode(salt),
+        }
+    }
+
+    /// Returns true if keys should be re-derived with `target` parameters
+    pub fn needs_upgrade(&self, target: &KdfParams) -> bool {
+        self.algorithm != KdfAlgorithm::Argon2id || !self.params.is_at_least(target)
+    }
+
+    fn salt_bytes(&self) -> Result<Vec<u8>> {
+        let salt = hex::decode(&self.salt)
+            .map_err(|e| Error::InvalidInput(format!("Invalid KDF salt: {}", e)))?;
+
+        if salt.len() < 8 {
+            return Err(Error::InvalidInput("KDF salt too short".to_string()));
+        }
+        Ok(salt)
+    }
+}
+
+/// Derives a 256-bit key from a password as described by `descriptor`
+pub fn derive_key(password: &SecretString, descriptor: &KdfDescriptor) -> Result<SecretKey> {
+    if descriptor.version > KDF_DESCRIPTOR_VERSION {
+        return Err(Error::InvalidInput(format!(
+            "Unsupported KDF descriptor version {}",
+            descriptor.version
+        )));
+    }
+
+    let salt = descriptor.salt_bytes()?;
+    let mut key = SecretKey::zeroed();
+
+    match descriptor.algorithm {
+        KdfAlgorithm::LegacySha256 => {
+            let mut hasher = Sha256::new();
+            hasher.update(password.expose().as_bytes());
+            hasher.update(&salt);
+            hasher.finalize_into(key.expose_mut().into());
+        }
+        KdfAlgorithm::Argon2id => {
+            let p = &descriptor.params;
+            let params = Params::new(p.memory_kib, p.iterations, p.parallelism, Some(KEY_SIZE))
+                .map_err(|e| Error::InvalidInput(format!("Invalid KDF parameters: {}", e)))?;
+
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password_into(password.expose().as_bytes(), &salt, key.expose_mut())
+                .map_err(|e| Error::EncryptionError(e.to_string()))?;
+        }
+    }
+
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_derive_key_deterministic() {
+        let descriptor = KdfDescriptor::new(KdfParams::for_tests());
+        let passphrase = SecretString::from("passphrase");
+        let key1 = derive_key(&passphrase, &descriptor).unwrap();
+        let key2 = derive_key(&passphrase, &descriptor).unwrap();
+        assert_eq!(key1, key2);
+
+        let other = KdfDescriptor::new(KdfParams::for_tests());
+        assert_ne!(key1, derive_key(&passphrase, &other).unwrap());
+        assert_ne!(key1, derive_key(&SecretString::from("Passphrase"), &descriptor).unwrap());
+    }
+
+    #[test]
+    fn test_legacy_descriptor_matches_sha256() {
+        let salt = [7u8; SALT_SIZE];
+        let key = derive_key(&SecretString::from("password"), &KdfDescriptor::legacy(&salt)).unwrap();
+
+        let mut hasher = Sha256::new();
+        hasher.update(b"password");
+        hasher.update(salt);
+        assert_eq!(&key.expose()[..], &hasher.finalize()[..]);
+    }
+
+    #[test]
+    fn test_needs_upgrade() {
+        let target = KdfParams::default();
+        assert!(KdfDescriptor::legacy(&[0u8; SALT_SIZE]).needs_upgrade(&target));
+        assert!(KdfDescriptor::new(KdfParams::for_tests()).needs_upgrade(&target));
+        assert!(!KdfDescriptor::new(target).needs_upgrade(&target));
+    }
+
+    #[test]
+    fn test_descriptor_serialization() {
+        let descriptor = KdfDescriptor::new(KdfParams::for_tests());
+        let json = serde_json::to_string(&descriptor).unwrap();
+        assert!(json.contains("\"argon2id\""));
+
+        let parsed: KdfDescriptor = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, descriptor);
+    }
+}
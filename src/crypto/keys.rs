@@ -0,0 +1,461 @@
+//! Key hierarchy
+//!
+//! ```text
+//! passphrase --KDF--> stretched key --+--> authentication key (sent to the server)
+//!                                     |
+//!                                     +--> key encryption key --wraps--> master key --+--wraps--> per-file data keys
+//!                                                                                     |
+//!                                                                                     +--wraps--> account key
+//!                                                                                     |
+//!                                                                                     +--wraps--> sharing private key
+//! ```
+//!
+//! File contents are only ever encrypted with their own random data key.
+//! Changing the passphrase re-wraps the master key, and rotating the master
+//! key re-wraps the data keys; neither touches chunk data.
+//!
+//! Keys that are derived rather than stored (path keys, content
+//! fingerprints, chunk keys, the manifest signing key) come from the account
+//! key. It is random, never changes, and is only re-wrapped when the master
+//! key is rotated, so encrypted paths, fingerprints and signatures stay
+//! valid across a rotation.
+//!
+//! The server authenticates the account with the authentication key, never
+//! the passphrase. Both it and the key encryption key are independent
+//! subkeys of the stretched passphrase, so the authentication key is no help
+//! in unwrapping the master key, even together with the wrapped key and KDF
+//! salt the server stores.
+//!
+//! Wrapped keys are base64 encoded [`Envelope`]s whose associated data names
+//! what the key is for, so a wrapped key cannot be swapped between files.
+
+use super::sharing;
+use super::{derive_key, generate_nonce, open, seal, CipherSuite, Envelope, KdfDescriptor, SecretKey, SecretString};
+use crate::error::{Error, Result};
+use crate::models::UserKeyMaterial;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::digest::FixedOutput;
+use hmac::{Hmac, Mac};
+use rsa::RsaPrivateKey;
+use sha2::Sha256;
+use std::fmt;
+use zeroize::Zeroizing;
+
+/// Generates a random 256-bit key
+pub fn generate_key() -> SecretKey {
+    SecretKey::generate()
+}
+
+/// Derives an independent subkey from the master key for a named purpose
+pub fn derive_subkey(master_key: &SecretKey, label: &str) -> SecretKey {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(master_key.expose())
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"rustguard-subkey:");
+    mac.update(label.as_bytes());
+
+    let mut subkey = SecretKey::zeroed();
+    FixedOutput::finalize_into(mac, subkey.expose_mut().into());
+    subkey
+}
+
+fn wrap_context(purpose: &str, id: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(24 + purpose.len() + id.len());
+    aad.extend_from_slice(b"rustguard-wrap");
+    aad.extend_from_slice(&(purpose.len() as u32).to_be_bytes());
+    aad.extend_from_slice(purpose.as_bytes());
+    aad.extend_from_slice(id.as_bytes());
+    aad
+}
+
+/// Encrypts secret bytes under `wrapping_key` for the given purpose and owner id
+///
+/// Always uses AES-256-GCM rather than the configured cipher suite. The
+/// suite setting trades speed for bulk file data on CPUs without AES
+/// instructions; wrapped keys and recipes are small and sealed once per
+/// change, so the choice makes no measurable difference here. Each wrap has
+/// a fresh random nonce, far below AES-GCM's random-nonce limit per key.
+pub(crate) fn wrap_bytes(secret: &[u8], wrapping_key: &SecretKey, purpose: &str, id: &str) -> Result<String> {
+    let envelope = seal(
+        CipherSuite::Aes256Gcm,
+        secret,
+        wrapping_key,
+        &generate_nonce(),
+        &wrap_context(purpose, id),
+    )?;
+    Ok(BASE64.encode(envelope.to_bytes()))
+}
+
+pub(crate) fn unwrap_bytes(wrapped: &str, wrapping_key: &SecretKey, purpose: &str, id: &str) -> Result<Zeroizing<Vec<u8>>> {
+    let bytes = BASE64
+        .decode(wrapped)
+        .map_err(|e| Error::DecryptionError(format!("Invalid wrapped key: {}", e)))?;
+
+    open(&Envelope::from_bytes(&bytes)?, wrapping_key, &wrap_context(purpose, id)).map(Zeroizing::new)
+}
+
+/// Encrypts `key` under `wrapping_key` for the given purpose and owner id
+pub fn wrap_key(key: &SecretKey, wrapping_key: &SecretKey, purpose: &str, id: &str) -> Result<String> {
+    wrap_bytes(key.expose(), wrapping_key, purpose, id)
+}
+
+/// Decrypts a key produced by [`wrap_key`] with the same purpose and id
+pub fn unwrap_key(wrapped: &str, wrapping_key: &SecretKey, purpose: &str, id: &str) -> Result<SecretKey> {
+    SecretKey::from_slice(&unwrap_bytes(wrapped, wrapping_key, purpose, id)?)
+}
+
+const MASTER_KEY_PURPOSE: &str = "master-key";
+const ACCOUNT_KEY_PURPOSE: &str = "account-key";
+const DATA_KEY_PURPOSE: &str = "data-key";
+
+/// Keys derived from the account passphrase
+pub struct PassphraseKeys {
+    kdf: KdfDescriptor,
+    stretched: SecretKey,
+}
+
+impl PassphraseKeys {
+    /// Stretches `passphrase` with the KDF described by `kdf`
+    pub fn derive(passphrase: &SecretString, kdf: KdfDescriptor) -> Result<Self> {
+        let stretched = derive_key(passphrase, &kdf)?;
+        Ok(Self { kdf, stretched })
+    }
+
+    /// Derives keys with cheap KDF parameters for tests
+    #[cfg(test)]
+    pub(crate) fn for_tests(passphrase: &str) -> Self {
+        Self::derive(&SecretString::from(passphrase), KdfDescriptor::new(super::KdfParams::for_tests())).unwrap()
+    }
+
+    /// The KDF descriptor the keys were derived with
+    pub fn kdf(&self) -> &KdfDescriptor {
+        &self.kdf
+    }
+
+    /// Credential the server authenticates in place of the passphrase
+    pub fn auth_key(&self) -> SecretString {
+        hex::encode(derive_subkey(&self.stretched, "authentication").expose()).into()
+    }
+
+    fn key_encryption_key(&self) -> SecretKey {
+        derive_subkey(&self.stretched, "key-encryption")
+    }
+}
+
+/// The unlocked keys of an account
+///
+/// The master key wraps data keys and changes on rotation; the account key
+/// is the root of every derived key and stays the same.
+pub struct AccountKeys {
+    master_key: SecretKey,
+    account_key: SecretKey,
+    sharing_key: Option<RsaPrivateKey>,
+}
+
+impl AccountKeys {
+    /// Unwraps the account key stored in `key_material` with `master_key`
+    ///
+    /// Accounts created before the account key existed derive everything
+    /// from the master key, so for those the master key is the account key.
+    pub fn open(master_key: SecretKey, key_material: &UserKeyMaterial) -> Result<Self> {
+        let account_key = match &key_material.wrapped_account_key {
+            Some(wrapped) => unwrap_key(wrapped, &master_key, ACCOUNT_KEY_PURPOSE, "")?,
+            None => master_key.duplicate(),
+        };
+        let sharing_key = key_material
+            .wrapped_private_key
+            .as_deref()
+            .map(|wrapped| sharing::unwrap_private_key(wrapped, &master_key))
+            .transpose()?;
+
+        Ok(Self {
+            master_key,
+            account_key,
+            sharing_key,
+        })
+    }
+
+    /// Key that wraps the account's data keys
+    pub fn master_key(&self) -> &SecretKey {
+        &self.master_key
+    }
+
+    /// Key that path, fingerprint, chunk and manifest keys are derived from
+    pub fn account_key(&self) -> &SecretKey {
+        &self.account_key
+    }
+
+    /// Private key that unwraps data keys other users share with us
+    pub fn sharing_key(&self) -> Result<&RsaPrivateKey> {
+        self.sharing_key
+            .as_ref()
+            .ok_or_else(|| Error::InvalidInput("Account has no sharing key".to_string()))
+    }
+
+    /// Returns a second copy of the keys
+    pub fn duplicate(&self) -> Self {
+        Self {
+            master_key: self.master_key.duplicate(),
+            account_key: self.account_key.duplicate(),
+            sharing_key: self.sharing_key.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for AccountKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccountKeys")
+            .field("master_key", &format_args!("[REDACTED]"))
+            .field("account_key", &format_args!("[REDACTED]"))
+            .field("sharing_key", &format_args!("[REDACTED]"))
+            .finish()
+    }
+}
+
+/// Creates random master and account keys protected by the passphrase keys
+///
+/// The sharing private key is generated by the caller, since RSA key
+/// generation is slow, and stored wrapped under the new master key.
+pub fn create_account_keys(
+    passphrase_keys: &PassphraseKeys,
+    sharing_key: RsaPrivateKey,
+) -> Result<(AccountKeys, UserKeyMaterial)> {
+    let master_key = generate_key();
+    let account_key = generate_key();
+
+    let key_material = UserKeyMaterial {
+        wrapped_account_key: Some(wrap_key(&account_key, &master_key, ACCOUNT_KEY_PURPOSE, "")?),
+        wrapped_private_key: Some(sharing::wrap_private_key(&sharing_key, &master_key)?),
+        ..protect_master_key(&master_key, passphrase_keys)?
+    };
+
+    Ok((
+        AccountKeys {
+            master_key,
+            account_key,
+            sharing_key: Some(sharing_key),
+        },
+        key_material,
+    ))
+}
+
+/// Wraps a master key under the passphrase keys
+pub fn protect_master_key(master_key: &SecretKey, passphrase_keys: &PassphraseKeys) -> Result<UserKeyMaterial> {
+    Ok(UserKeyMaterial {
+        wrapped_master_key: Some(wrap_key(
+            master_key,
+            &passphrase_keys.key_encryption_key(),
+            MASTER_KEY_PURPOSE,
+            "",
+        )?),
+        kdf: passphrase_keys.kdf.clone(),
+        wrapped_account_key: None,
+        wrapped_private_key: None,
+        recovery_wrapped_master_key: None,
+    })
+}
+
+/// Re-wraps the master key under a new passphrase and KDF descriptor
+///
+/// Also used to move legacy accounts onto stronger KDF settings. Everything
+/// else in the key material is carried over unchanged.
+pub fn change_passphrase(
+    key_material: &UserKeyMaterial,
+    old_passphrase: &PassphraseKeys,
+    new_passphrase: &PassphraseKeys,
+) -> Result<UserKeyMaterial> {
+    let master_key = unlock_master_key(old_passphrase, key_material)?;
+    let protected = protect_master_key(&master_key, new_passphrase)?;
+
+    Ok(UserKeyMaterial {
+        kdf: protected.kdf,
+        wrapped_master_key: protected.wrapped_master_key,
+        ..key_material.clone()
+    })
+}
+
+/// Recovers the master key from the passphrase keys and stored key material
+///
+/// Accounts created before the key hierarchy existed have no wrapped master
+/// key; for those the stretched passphrase itself is the master key.
+pub fn unlock_master_key(passphrase_keys: &PassphraseKeys, key_material: &UserKeyMaterial) -> Result<SecretKey> {
+    match &key_material.wrapped_master_key {
+        Some(wrapped) => unwrap_key(wrapped, &passphrase_keys.key_encryption_key(), MASTER_KEY_PURPOSE, "")
+            .map_err(|_| Error::InvalidCredentials),
+        None => Ok(passphrase_keys.stretched.duplicate()),
+    }
+}
+
+/// Unlocks the master key and opens the account keys with it
+pub fn unlock_account(passphrase_keys: &PassphraseKeys, key_material: &UserKeyMaterial) -> Result<AccountKeys> {
+    AccountKeys::open(unlock_master_key(passphrase_keys, key_material)?, key_material)
+}
+
+/// Replaces the master key with a new random one
+///
+/// The new master key is wrapped under the same passphrase, and the account
+/// key and sharing private key are re-wrapped under it. The recovery copy of
+/// the old master key cannot be re-wrapped without the recovery key, so it
+/// is dropped and a new recovery key has to be generated. Data keys are
+/// stored with their files and must be re-wrapped with [`rewrap_data_key`].
+pub fn rotate_master_key(
+    account: &AccountKeys,
+    passphrase_keys: &PassphraseKeys,
+    key_material: &UserKeyMaterial,
+) -> Result<(AccountKeys, UserKeyMaterial)> {
+    if unlock_master_key(passphrase_keys, key_material)? != account.master_key {
+        return Err(Error::InvalidCredentials);
+    }
+
+    let master_key = generate_key();
+    let wrapped_private_key = account
+        .sharing_key
+        .as_ref()
+        .map(|private_key| sharing::wrap_private_key(private_key, &master_key))
+        .transpose()?;
+
+    let key_material = UserKeyMaterial {
+        wrapped_account_key: Some(wrap_key(&account.account_key, &master_key, ACCOUNT_KEY_PURPOSE, "")?),
+        wrapped_private_key,
+        recovery_wrapped_master_key: None,
+        ..protect_master_key(&master_key, passphrase_keys)?
+    };
+
+    Ok((
+        AccountKeys {
+            master_key,
+            account_key: account.account_key.duplicate(),
+            sharing_key: account.sharing_key.clone(),
+        },
+        key_material,
+    ))
+}
+
+/// Generates a data key for a file, returning it with its wrapped form
+pub fn create_data_key(master_key: &SecretKey, file_id: &str) -> Result<(SecretKey, String)> {
+    let data_key = generate_key();
+    let wrapped = wrap_data_key(&data_key, master_key, file_id)?;
+    Ok((data_key, wrapped))
+}
+
+/// Wraps an existing data key for a file under the master key
+pub fn wrap_data_key(data_key: &SecretKey, master_key: &SecretKey, file_id: &str) -> Result<String> {
+    wrap_key(data_key, master_key, DATA_KEY_PURPOSE, file_id)
+}
+
+/// Unwraps the data key stored for a file
+pub fn unwrap_data_key(wrapped: &str, master_key: &SecretKey, file_id: &str) -> Result<SecretKey> {
+    unwrap_key(wrapped, master_key, DATA_KEY_PURPOSE, file_id)
+}
+
+/// Re-wraps a file's data key under a new master key
+pub fn rewrap_data_key(wrapped: &str, file_id: &str, old_master: &SecretKey, new_master: &SecretKey) -> Result<String> {
+    let data_key = unwrap_data_key(wrapped, old_master, file_id)?;
+    wrap_data_key(&data_key, new_master, file_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_passphrase_change_keeps_master_key() {
+        let old = PassphraseKeys::for_tests("old passphrase");
+        let new = PassphraseKeys::for_tests("new passphrase");
+
+        let (account, material) = create_account_keys(&old, sharing::test_key()).unwrap();
+        assert_eq!(unlock_master_key(&old, &material).unwrap(), *account.master_key());
+        assert!(unlock_master_key(&PassphraseKeys::for_tests("wrong"), &material).is_err());
+
+        let material = change_passphrase(&material, &old, &new).unwrap();
+        assert_eq!(material.kdf, *new.kdf());
+        assert_eq!(unlock_master_key(&new, &material).unwrap(), *account.master_key());
+        assert_eq!(unlock_account(&new, &material).unwrap().account_key(), account.account_key());
+        assert!(unlock_master_key(&old, &material).is_err());
+    }
+
+    #[test]
+    fn test_auth_key_does_not_unlock_master_key() {
+        let passphrase = SecretString::from("passphrase");
+        let keys = PassphraseKeys::for_tests("passphrase");
+        let (_, material) = create_account_keys(&keys, sharing::test_key()).unwrap();
+
+        let again = PassphraseKeys::derive(&passphrase, keys.kdf().clone()).unwrap();
+        assert_eq!(again.auth_key().expose(), keys.auth_key().expose());
+        assert_ne!(PassphraseKeys::for_tests("passphrase").auth_key().expose(), keys.auth_key().expose());
+
+        // Knowing the auth key and the stored key material is not enough
+        let from_auth_key = PassphraseKeys::derive(&keys.auth_key(), material.kdf.clone()).unwrap();
+        assert!(unlock_master_key(&from_auth_key, &material).is_err());
+        assert_ne!(keys.auth_key().expose(), hex::encode(keys.key_encryption_key().expose()));
+    }
+
+    #[test]
+    fn test_master_key_rotation_keeps_account_key() {
+        let keys = PassphraseKeys::for_tests("passphrase");
+        let private_key = sharing::test_key();
+        let (account, material) = create_account_keys(&keys, private_key.clone()).unwrap();
+        let material = UserKeyMaterial {
+            recovery_wrapped_master_key: Some("recovery copy".to_string()),
+            ..material
+        };
+
+        assert!(rotate_master_key(&account, &PassphraseKeys::for_tests("wrong"), &material).is_err());
+        let (rotated, material) = rotate_master_key(&account, &keys, &material).unwrap();
+        assert_ne!(rotated.master_key(), account.master_key());
+        assert_eq!(rotated.account_key(), account.account_key());
+        assert!(material.recovery_wrapped_master_key.is_none());
+
+        let unlocked = unlock_account(&keys, &material).unwrap();
+        assert_eq!(unlocked.master_key(), rotated.master_key());
+        assert_eq!(unlocked.account_key(), account.account_key());
+        assert_eq!(unlocked.sharing_key().unwrap(), &private_key);
+    }
+
+    #[test]
+    fn test_account_keys_debug_is_redacted() {
+        use rsa::traits::PrivateKeyParts;
+
+        let sharing_key = sharing::test_key();
+        let secret_exponent = sharing_key.d().to_string();
+        let (account, _) = create_account_keys(&PassphraseKeys::for_tests("passphrase"), sharing_key).unwrap();
+
+        let debug = format!("{:?}", account);
+        assert_eq!(
+            debug,
+            "AccountKeys { master_key: [REDACTED], account_key: [REDACTED], sharing_key: [REDACTED] }"
+        );
+        for key in [account.master_key(), account.account_key()] {
+            assert!(!debug.contains(&hex::encode(key.expose())));
+        }
+        assert!(!format!("{:#?}", account).contains(&secret_exponent));
+    }
+
+    #[test]
+    fn test_legacy_account_key_is_master_key() {
+        let keys = PassphraseKeys::for_tests("passphrase");
+        let master_key = generate_key();
+        let material = protect_master_key(&master_key, &keys).unwrap();
+
+        let account = unlock_account(&keys, &material).unwrap();
+        assert_eq!(account.account_key(), &master_key);
+        assert!(account.sharing_key().is_err());
+
+        // Rotating keeps deriving from the old master key
+        let (_, material) = rotate_master_key(&account, &keys, &material).unwrap();
+        assert_eq!(unlock_account(&keys, &material).unwrap().account_key(), &master_key);
+    }
+
+    #[test]
+    fn test_data_key_rotation() {
+        let old_master = generate_key();
+        let new_master = generate_key();
+
+        let (data_key, wrapped) = create_data_key(&old_master, "file-1").unwrap();
+        assert_eq!(unwrap_data_key(&wrapped, &old_master, "file-1").unwrap(), data_key);
+        assert!(unwrap_data_key(&wrapped, &old_master, "file-2").is_err());
+
+        let rewrapped = rewrap_data_key(&wrapped, "file-1", &old_master, &new_master).unwrap();
+        assert_eq!(unwrap_data_key(&rewrapped, &new_master, "file-1").unwrap(), data_key);
+        assert!(unwrap_data_key(&rewrapped, &old_master, "file-1").is_err());
+    }
+}
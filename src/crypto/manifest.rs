@@ -0,0 +1,377 @@
+//! Signed snapshot manifests
+//!
+//! After each sync the client signs a manifest listing every file it owns
+//! with its version and content fingerprint. The server stores the manifest
+//! but cannot produce a valid one, so a file list it has edited, trimmed or
+//! padded no longer matches what the account last signed.
+//!
+//! The Ed25519 signing key is derived from the account key, so every device
+//! of the account signs and verifies with the same key without storing it.
+//!
+//! A signature alone does not stop the server from replaying an older
+//! manifest. Each manifest therefore carries a sequence number, one higher
+//! than the manifest it replaces, and the Merkle root of its entries. Devices
+//! remember the highest pair they have seen (see
+//! [`crate::client::checkpoint`]) and treat anything older as a rollback.
+
+use super::keys::derive_subkey;
+use super::SecretKey;
+use crate::error::{Error, Result};
+use crate::models::FileMetadata;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Current manifest format version
+pub const MANIFEST_VERSION: u8 = 2;
+
+const SIGNATURE_CONTEXT: &[u8] = b"rustguard-manifest";
+
+/// One file as recorded in a manifest
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub file_id: String,
+    pub version: u64,
+    pub fingerprint: String,
+}
+
+impl ManifestEntry {
+    /// Records the current state of a file
+    pub fn from_file(file: &FileMetadata) -> Self {
+        Self {
+            file_id: file.id.clone(),
+            version: file.version,
+            fingerprint: file.encrypted_hash.clone(),
+        }
+    }
+}
+
+/// Snapshot of every file an account owns
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u8,
+    /// Incremented with every manifest the account signs
+    #[serde(default)]
+    pub sequence: u64,
+    /// Hex encoded Merkle root of `entries`
+    #[serde(default)]
+    pub merkle_root: String,
+    pub created_at: DateTime<Utc>,
+    /// Entries sorted by file id
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Builds a manifest from the account's current files
+    ///
+    /// `sequence` must be one more than the sequence of the manifest this
+    /// one replaces.
+    pub fn new(files: &[FileMetadata], sequence: u64) -> Self {
+        Self::from_entries(files.iter().map(ManifestEntry::from_file).collect(), sequence)
+    }
+
+    /// Builds a manifest from entries in any order, e.g. a listing with a
+    /// change applied that the server has not committed yet
+    pub fn from_entries(mut entries: Vec<ManifestEntry>, sequence: u64) -> Self {
+        entries.sort_by(|a, b| a.file_id.cmp(&b.file_id));
+
+        Self {
+            format_version: MANIFEST_VERSION,
+            sequence,
+            merkle_root: merkle_root(&entries),
+            created_at: Utc::now(),
+            entries,
+        }
+    }
+
+    /// Looks up the entry for a file
+    pub fn entry(&self, file_id: &str) -> Option<&ManifestEntry> {
+        self.entries
+            .binary_search_by(|entry| entry.file_id.as_str().cmp(file_id))
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+
+    /// Checks that a file matches what was signed
+    pub fn verify_file(&self, file: &FileMetadata) -> Result<()> {
+        let entry = self.entry(&file.id).ok_or_else(|| {
+            Error::IntegrityError(format!("File {} is not in the last signed manifest", file.id))
+        })?;
+
+        if *entry != ManifestEntry::from_file(file) {
+            return Err(Error::IntegrityError(format!(
+                "File {} does not match the last signed manifest (signed version {}, server reports version {})",
+                file.id, entry.version, file.version
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that a full listing is exactly the set of files that was signed
+    pub fn verify_listing(&self, files: &[FileMetadata]) -> Result<()> {
+        self.verify_listing_except(files, &[])
+    }
+
+    /// Like [`Manifest::verify_listing`], but skips files the caller has just
+    /// changed itself and is about to sign
+    pub fn verify_listing_except(&self, files: &[FileMetadata], changed: &[&str]) -> Result<()> {
+        let mut seen = 0;
+        for file in files.iter().filter(|file| !changed.contains(&file.id.as_str())) {
+            self.verify_file(file)?;
+            seen += 1;
+        }
+
+        let expected = self
+            .entries
+            .iter()
+            .filter(|entry| !changed.contains(&entry.file_id.as_str()))
+            .count();
+        if seen != expected {
+            return Err(Error::IntegrityError(format!(
+                "Server lists {} unchanged files but the last signed manifest has {}",
+                seen, expected
+            )));
+        }
+        Ok(())
+    }
+
+    /// The sequence number and root a device remembers after seeing this manifest
+    pub fn checkpoint(&self) -> ManifestCheckpoint {
+        ManifestCheckpoint {
+            sequence: self.sequence,
+            merkle_root: self.merkle_root.clone(),
+        }
+    }
+
+    /// Checks that this manifest is not older than one the device has already seen
+    ///
+    /// The same sequence with a different root means the server is showing
+    /// this device a different history than it showed before.
+    pub fn check_freshness(&self, last_seen: &ManifestCheckpoint) -> Result<()> {
+        if self.sequence < last_seen.sequence {
+            return Err(Error::RollbackDetected(format!(
+                "Server returned manifest {} but this device has already seen manifest {}",
+                self.sequence, last_seen.sequence
+            )));
+        }
+
+        if self.sequence == last_seen.sequence && self.merkle_root != last_seen.merkle_root {
+            return Err(Error::RollbackDetected(format!(
+                "Server returned a different manifest {} than this device saw before",
+                self.sequence
+            )));
+        }
+        Ok(())
+    }
+
+    fn signing_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = SIGNATURE_CONTEXT.to_vec();
+        bytes.extend(serde_json::to_vec(self).map_err(|e| Error::SerializationError(e.to_string()))?);
+        Ok(bytes)
+    }
+}
+
+/// Highest manifest a device has seen, kept locally to detect rollbacks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestCheckpoint {
+    pub sequence: u64,
+    pub merkle_root: String,
+}
+
+/// A manifest together with its signature, as stored on the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedManifest {
+    pub manifest: Manifest,
+    /// Hex encoded Ed25519 signature
+    pub signature: String,
+}
+
+/// Key for signing and verifying manifests
+pub struct ManifestSigner {
+    signing_key: SigningKey,
+}
+
+impl ManifestSigner {
+    /// Derives the account's manifest signing key from the account key
+    pub fn derive(account_key: &SecretKey) -> Self {
+        let seed = derive_subkey(account_key, "manifest-signing");
+        Self {
+            signing_key: SigningKey::from_bytes(seed.expose()),
+        }
+    }
+
+    /// Public half of the signing key
+    pub fn verifying_key(&self) -> VerifyingKey {
+        self.signing_key.verifying_key()
+    }
+
+    /// Signs a manifest
+    pub fn sign(&self, manifest: Manifest) -> Result<SignedManifest> {
+        let signature = self.signing_key.sign(&manifest.signing_bytes()?);
+
+        Ok(SignedManifest {
+            manifest,
+            signature: hex::encode(signature.to_bytes()),
+        })
+    }
+
+    /// Verifies a signed manifest, returning the manifest if it is authentic
+    ///
+    /// The signing key is unique to the account, so a valid signature also
+    /// shows the manifest belongs to it.
+    pub fn verify(&self, signed: SignedManifest) -> Result<Manifest> {
+        let bytes: [u8; 64] = hex::decode(&signed.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| Error::IntegrityError("Malformed manifest signature".to_string()))?;
+
+        self.verifying_key()
+            .verify(&signed.manifest.signing_bytes()?, &Signature::from_bytes(&bytes))
+            .map_err(|_| Error::IntegrityError("Manifest signature is invalid".to_string()))?;
+
+        if signed.manifest.format_version > MANIFEST_VERSION {
+            return Err(Error::IntegrityError(format!(
+                "Unsupported manifest version {}",
+                signed.manifest.format_version
+            )));
+        }
+
+        // Version 1 manifests predate sequence numbers and have no root
+        if signed.manifest.format_version >= 2 && signed.manifest.merkle_root != merkle_root(&signed.manifest.entries) {
+            return Err(Error::IntegrityError("Manifest Merkle root does not match its entries".to_string()));
+        }
+
+        Ok(signed.manifest)
+    }
+}
+
+/// Computes the Merkle root of manifest entries
+///
+/// Leaves and inner nodes are hashed with distinct prefixes so one cannot be
+/// passed off as the other; an odd node is carried up unchanged.
+pub fn merkle_root(entries: &[ManifestEntry]) -> String {
+    let mut level: Vec<[u8; 32]> = entries
+        .iter()
+        .map(|entry| {
+            let mut hasher = Sha256::new();
+            hasher.update([0u8]);
+            hasher.update((entry.file_id.len() as u32).to_be_bytes());
+            hasher.update(entry.file_id.as_bytes());
+            hasher.update(entry.version.to_be_bytes());
+            hasher.update(entry.fingerprint.as_bytes());
+            hasher.finalize().into()
+        })
+        .collect();
+
+    if level.is_empty() {
+        return hex::encode(Sha256::digest(b"rustguard-empty-manifest"));
+    }
+
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update([1u8]);
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().into()
+                }
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+
+    hex::encode(level[0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(id: &str, version: u64, hash: &str) -> FileMetadata {
+        FileMetadata {
+            id: id.to_string(),
+            user_id: "user".to_string(),
+            path: String::new(),
+            name: String::new(),
+            path_token: None,
+            size: 0,
+            encrypted_hash: hash.to_string(),
+            chunk_count: 0,
+            version,
+            wrapped_key: None,
+            chunk_recipe: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            is_deleted: false,
+        }
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = ManifestSigner::derive(&SecretKey::from_bytes([4u8; 32]));
+        let files = vec![file("b", 2, "fp-b"), file("a", 1, "fp-a")];
+
+        let signed = signer.sign(Manifest::new(&files, 1)).unwrap();
+        let manifest = signer.verify(signed.clone()).unwrap();
+        assert!(manifest.verify_listing(&files).is_ok());
+
+        let other = ManifestSigner::derive(&SecretKey::from_bytes([5u8; 32]));
+        assert!(other.verify(signed.clone()).is_err());
+
+        let mut tampered = signed.clone();
+        tampered.manifest.entries[0].version = 7;
+        assert!(signer.verify(tampered).is_err());
+
+        let mut replayed = signed;
+        replayed.manifest.sequence = 0;
+        assert!(signer.verify(replayed).is_err());
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let a = Manifest::new(&[file("a", 1, "fp-a"), file("b", 1, "fp-b"), file("c", 1, "fp-c")], 1);
+        let b = Manifest::new(&[file("c", 1, "fp-c"), file("a", 1, "fp-a"), file("b", 1, "fp-b")], 1);
+        assert_eq!(a.merkle_root, b.merkle_root);
+
+        let changed = Manifest::new(&[file("a", 1, "fp-a"), file("b", 2, "fp-b"), file("c", 1, "fp-c")], 1);
+        assert_ne!(a.merkle_root, changed.merkle_root);
+        assert_ne!(Manifest::new(&[], 1).merkle_root, a.merkle_root);
+    }
+
+    #[test]
+    fn test_check_freshness() {
+        let files = [file("a", 1, "fp-a")];
+        let seen = Manifest::new(&files, 5).checkpoint();
+
+        assert!(Manifest::new(&files, 5).check_freshness(&seen).is_ok());
+        assert!(Manifest::new(&files, 6).check_freshness(&seen).is_ok());
+        assert!(matches!(
+            Manifest::new(&files, 4).check_freshness(&seen),
+            Err(Error::RollbackDetected(_))
+        ));
+        assert!(matches!(
+            Manifest::new(&[file("a", 2, "fp-a")], 5).check_freshness(&seen),
+            Err(Error::RollbackDetected(_))
+        ));
+    }
+
+    #[test]
+    fn test_listing_mismatch() {
+        let files = vec![file("a", 1, "fp-a"), file("b", 1, "fp-b")];
+        let manifest = Manifest::new(&files, 1);
+
+        assert!(manifest.verify_listing(&files[..1]).is_err());
+        assert!(manifest.verify_listing(&[file("a", 1, "fp-a"), file("b", 2, "fp-b")]).is_err());
+        assert!(manifest.verify_file(&file("c", 1, "fp-c")).is_err());
+        assert!(manifest.verify_file(&file("a", 1, "changed")).is_err());
+
+        let updated = [file("a", 1, "fp-a"), file("b", 2, "new"), file("c", 1, "fp-c")];
+        assert!(manifest.verify_listing_except(&updated, &["b", "c"]).is_ok());
+        assert!(manifest.verify_listing_except(&updated, &["c"]).is_err());
+    }
+}
@@ -0,0 +1,324 @@
+//! Cryptographic operations for RustGuard
+
+pub mod chunking;
+pub mod envelope;
+pub mod fingerprint;
+pub mod kdf;
+pub mod keys;
+pub mod manifest;
+pub mod paths;
+pub mod recovery;
+pub mod secret;
+pub mod sharing;
+pub mod stream;
+
+pub use envelope::{CipherSuite, Envelope, KeyId};
+pub use fingerprint::{FingerprintKey, Fingerprinter};
+pub use kdf::{derive_key, KdfDescriptor, KdfParams};
+pub use secret::{SecretKey, SecretString, KEY_SIZE};
+pub use stream::{decrypt_stream, encrypt_stream, StreamDecryptor, StreamEncryptor};
+
+use crate::error::{Error, Result};
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+/// Plaintext size of each encrypted chunk
+pub const CHUNK_SIZE: usize = 1024 * 1024; // 1MB chunks
+const NONCE_SIZE: usize = 12;
+
+/// Generates a random nonce for encryption
+pub fn generate_nonce() -> [u8; NONCE_SIZE] {
+    let mut rng = rand::thread_rng();
+    let mut nonce = [0u8; NONCE_SIZE];
+    rng.fill(&mut nonce);
+    nonce
+}
+
+/// Hashes a password for storage
+pub fn hash_password(password: &SecretString) -> Result<String> {
+    use argon2::password_hash::SaltString;
+
+    let salt = SaltString::generate(rand::thread_rng());
+    let argon2 = Argon2::default();
+
+    argon2
+        .hash_password(password.expose().as_bytes(), &salt)
+        .map(|h| h.to_string())
+        .map_err(|e| Error::EncryptionError(e.to_string()))
+}
+
+/// Verifies a password against its hash
+pub fn verify_password(password: &SecretString, hash: &str) -> Result<bool> {
+    use password_hash::PasswordHash;
+
+    let parsed_hash = PasswordHash::new(hash)
+        .map_err(|e| Error::EncryptionError(e.to_string()))?;
+
+    Ok(Argon2::default()
+        .verify_password(password.expose().as_bytes(), &parsed_hash)
+        .is_ok())
+}
+
+/// Computes the identifier recorded in envelopes encrypted under `key`
+pub fn key_id(key: &SecretKey) -> KeyId {
+    let mut hasher = Sha256::new();
+    hasher.update(b"rustguard-key-id");
+    hasher.update(key.expose());
+
+    let mut id = [0u8; envelope::KEY_ID_SIZE];
+    id.copy_from_slice(&hasher.finalize()[..envelope::KEY_ID_SIZE]);
+    id
+}
+
+/// Encrypts data using AES-256-GCM into a self-describing envelope
+pub fn encrypt(data: &[u8], key: &SecretKey) -> Result<Envelope> {
+    encrypt_with(CipherSuite::Aes256Gcm, data, key)
+}
+
+/// Encrypts data with the given cipher suite into a self-describing envelope
+pub fn encrypt_with(suite: CipherSuite, data: &[u8], key: &SecretKey) -> Result<Envelope> {
+    seal(suite, data, key, &suite.generate_nonce(), &[])
+}
+
+/// Decrypts an envelope produced by [`encrypt`] or [`encrypt_with`]
+pub fn decrypt(envelope: &Envelope, key: &SecretKey) -> Result<Vec<u8>> {
+    open(envelope, key, &[])
+}
+
+/// Encrypts `data` under an explicit nonce, authenticating the envelope
+/// header followed by `context`
+pub(crate) fn seal(
+    suite: CipherSuite,
+    data: &[u8],
+    key: &SecretKey,
+    nonce: &[u8],
+    context: &[u8],
+) -> Result<Envelope> {
+    if nonce.len() != suite.nonce_len() {
+        return Err(Error::EncryptionError("Invalid nonce length".to_string()));
+    }
+
+    let key_id = key_id(key);
+    let mut aad = envelope::encode_header(envelope::ENVELOPE_VERSION, suite, &key_id, nonce);
+    aad.extend_from_slice(context);
+    let payload = Payload { msg: data, aad: &aad };
+
+    let ciphertext = match suite {
+        CipherSuite::Aes256Gcm => Aes256Gcm::new(key.expose().into()).encrypt(Nonce::from_slice(nonce), payload),
+        CipherSuite::XChaCha20Poly1305 => {
+            XChaCha20Poly1305::new(key.expose().into()).encrypt(XNonce::from_slice(nonce), payload)
+        }
+    }
+    .map_err(|e| Error::EncryptionError(e.to_string()))?;
+
+    Ok(Envelope {
+        version: envelope::ENVELOPE_VERSION,
+        cipher: suite,
+        key_id,
+        nonce: nonce.to_vec(),
+        ciphertext,
+    })
+}
+
+pub(crate) fn open(envelope: &Envelope, key: &SecretKey, context: &[u8]) -> Result<Vec<u8>> {
+    if envelope.key_id != key_id(key) {
+        return Err(Error::DecryptionError("Envelope was encrypted with a different key".to_string()));
+    }
+    if envelope.nonce.len() != envelope.cipher.nonce_len() {
+        return Err(Error::DecryptionError("Invalid nonce length".to_string()));
+    }
+
+    let mut aad = envelope.header_bytes();
+    aad.extend_from_slice(context);
+    let payload = Payload { msg: &envelope.ciphertext, aad: &aad };
+
+    match envelope.cipher {
+        CipherSuite::Aes256Gcm => {
+            Aes256Gcm::new(key.expose().into()).decrypt(Nonce::from_slice(&envelope.nonce), payload)
+        }
+        CipherSuite::XChaCha20Poly1305 => {
+            XChaCha20Poly1305::new(key.expose().into()).decrypt(XNonce::from_slice(&envelope.nonce), payload)
+        }
+    }
+    .map_err(|e| Error::DecryptionError(e.to_string()))
+}
+
+/// Computes SHA256 hash of data
+///
+/// Only for data the server already sees, such as ciphertext. Hashes of
+/// plaintext content use [`FingerprintKey`] instead.
+pub fn compute_hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+/// Version of the chunk associated data layout
+pub const CHUNK_CONTEXT_VERSION: u8 = 1;
+
+/// Position of a chunk within a file, authenticated together with its contents
+///
+/// A chunk only decrypts under the exact file id, index and chunk count it
+/// was sealed with, so chunks cannot be reordered, dropped or moved between
+/// files without detection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkContext<'a> {
+    pub file_id: &'a str,
+    pub index: u32,
+    pub total: u32,
+}
+
+impl ChunkContext<'_> {
+    /// Encodes the context as associated data
+    pub fn associated_data(&self) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(32 + self.file_id.len());
+        aad.extend_from_slice(b"rustguard-chunk");
+        aad.push(CHUNK_CONTEXT_VERSION);
+        aad.extend_from_slice(&(self.file_id.len() as u32).to_be_bytes());
+        aad.extend_from_slice(self.file_id.as_bytes());
+        aad.extend_from_slice(&self.index.to_be_bytes());
+        aad.extend_from_slice(&self.total.to_be_bytes());
+        aad
+    }
+}
+
+/// Encrypts one chunk with the given cipher suite, bound to its position in a file
+pub fn encrypt_chunk(suite: CipherSuite, data: &[u8], key: &SecretKey, context: &ChunkContext) -> Result<Envelope> {
+    seal(suite, data, key, &suite.generate_nonce(), &context.associated_data())
+}
+
+/// Decrypts a chunk, failing if it is not at the given position
+pub fn decrypt_chunk(envelope: &Envelope, key: &SecretKey, context: &ChunkContext) -> Result<Vec<u8>> {
+    open(envelope, key, &context.associated_data())
+        .map_err(|_| Error::DecryptionError(format!(
+            "Chunk {} of {} failed authentication for file {}",
+            context.index, context.total, context.file_id
+        )))
+}
+
+/// Encrypts in-memory data with chunking
+///
+/// Files on disk should go through [`encrypt_stream`] instead, which keeps
+/// memory use bounded by [`CHUNK_SIZE`]. Uploads split files with
+/// [`chunking`] so unchanged regions can be deduplicated.
+pub fn encrypt_large_file(suite: CipherSuite, data: &[u8], key: &SecretKey, file_id: &str) -> Result<Vec<Envelope>> {
+    let total = data.len().div_ceil(CHUNK_SIZE).max(1) as u32;
+    let mut encrypted_chunks = Vec::with_capacity(total as usize);
+
+    // Empty input still produces one chunk so that its absence is detectable
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(CHUNK_SIZE).collect()
+    };
+
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let context = ChunkContext {
+            file_id,
+            index: index as u32,
+            total,
+        };
+        encrypted_chunks.push(encrypt_chunk(suite, chunk, key, &context)?);
+    }
+
+    Ok(encrypted_chunks)
+}
+
+/// Decrypts file from chunks, which must be complete and in order
+pub fn decrypt_large_file(chunks: &[Envelope], key: &SecretKey, file_id: &str) -> Result<Vec<u8>> {
+    let total = chunks.len() as u32;
+    let mut data = Vec::new();
+
+    for (index, chunk) in chunks.iter().enumerate() {
+        let context = ChunkContext {
+            file_id,
+            index: index as u32,
+            total,
+        };
+        let decrypted = decrypt_chunk(chunk, key, &context)?;
+        data.extend_from_slice(&decrypted);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = SecretKey::from_bytes([0u8; 32]);
+        let plaintext = b"Hello, RustGuard!";
+
+        let envelope = encrypt(plaintext, &key).unwrap();
+        let parsed = Envelope::from_bytes(&envelope.to_bytes()).unwrap();
+        let decrypted = decrypt(&parsed, &key).unwrap();
+
+        assert_eq!(plaintext, &decrypted[..]);
+    }
+
+    #[test]
+    fn test_decrypt_rejects_tampered_header_and_wrong_key() {
+        let key = SecretKey::from_bytes([1u8; 32]);
+        let envelope = encrypt(b"secret", &key).unwrap();
+
+        assert!(decrypt(&envelope, &SecretKey::from_bytes([2u8; 32])).is_err());
+
+        let mut tampered = envelope.clone();
+        tampered.nonce[0] ^= 1;
+        assert!(decrypt(&tampered, &key).is_err());
+    }
+
+    #[test]
+    fn test_xchacha20_poly1305() {
+        let key = SecretKey::from_bytes([9u8; 32]);
+        let envelope = encrypt_with(CipherSuite::XChaCha20Poly1305, b"arm friendly", &key).unwrap();
+        assert_eq!(envelope.nonce.len(), 24);
+
+        let parsed = Envelope::from_bytes(&envelope.to_bytes()).unwrap();
+        assert_eq!(parsed.cipher, CipherSuite::XChaCha20Poly1305);
+        assert_eq!(decrypt(&parsed, &key).unwrap(), b"arm friendly");
+    }
+
+    #[test]
+    fn test_large_file_rejects_misplaced_chunks() {
+        let key = SecretKey::from_bytes([3u8; 32]);
+        let data = vec![42u8; CHUNK_SIZE * 2 + 10];
+
+        for suite in [CipherSuite::Aes256Gcm, CipherSuite::XChaCha20Poly1305] {
+            let chunks = encrypt_large_file(suite, &data, &key, "file-a").unwrap();
+            assert_eq!(chunks.len(), 3);
+            assert!(chunks.iter().all(|chunk| chunk.cipher == suite));
+            assert_eq!(decrypt_large_file(&chunks, &key, "file-a").unwrap(), data);
+
+            let mut reordered = chunks.clone();
+            reordered.swap(0, 1);
+            assert!(decrypt_large_file(&reordered, &key, "file-a").is_err());
+            assert!(decrypt_large_file(&chunks[..2], &key, "file-a").is_err());
+            assert!(decrypt_large_file(&chunks, &key, "file-b").is_err());
+        }
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let password = SecretString::from("super_secure_password_123!");
+        let hash = hash_password(&password).unwrap();
+        assert!(verify_password(&password, &hash).unwrap());
+        assert!(!verify_password(&SecretString::from("wrong_password"), &hash).unwrap());
+    }
+
+    #[test]
+    fn test_compute_hash() {
+        let data = b"test data";
+        let hash1 = compute_hash(data);
+        let hash2 = compute_hash(data);
+        assert_eq!(hash1, hash2);
+    }
+}
@@ -0,0 +1,99 @@
+//! File path encryption
+//!
+//! Paths never reach the server in plaintext. Each path is stored as a
+//! randomized envelope that only the owner can decrypt, together with a
+//! lookup token: an HMAC of the normalized path under a key derived from the
+//! account key. The token is stable, so the server can find and dedupe
+//! entries, but reveals nothing about the path itself.
+
+use super::keys::derive_subkey;
+use super::{generate_nonce, open, seal, CipherSuite, Envelope, SecretKey};
+use crate::error::{Error, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const PATH_CONTEXT: &[u8] = b"rustguard-path";
+
+/// Keys for encrypting paths and computing lookup tokens
+pub struct PathKeys {
+    encryption_key: SecretKey,
+    token_key: SecretKey,
+}
+
+impl PathKeys {
+    /// Derives path keys from the account key
+    pub fn derive(account_key: &SecretKey) -> Self {
+        Self {
+            encryption_key: derive_subkey(account_key, "path-encryption"),
+            token_key: derive_subkey(account_key, "path-token"),
+        }
+    }
+
+    /// Computes the lookup token for a path
+    pub fn lookup_token(&self, path: &str) -> String {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.token_key.expose())
+            .expect("HMAC accepts keys of any length");
+        mac.update(normalize_path(path).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /// Encrypts a path into an opaque base64 string
+    ///
+    /// Like wrapped keys, paths are small and always sealed with AES-256-GCM
+    /// whatever cipher suite is configured for file data.
+    pub fn encrypt_path(&self, path: &str) -> Result<String> {
+        let envelope = seal(
+            CipherSuite::Aes256Gcm,
+            normalize_path(path).as_bytes(),
+            &self.encryption_key,
+            &generate_nonce(),
+            PATH_CONTEXT,
+        )?;
+        Ok(BASE64.encode(envelope.to_bytes()))
+    }
+
+    /// Decrypts a path produced by [`PathKeys::encrypt_path`]
+    pub fn decrypt_path(&self, encrypted: &str) -> Result<String> {
+        let bytes = BASE64
+            .decode(encrypted)
+            .map_err(|e| Error::DecryptionError(format!("Invalid encrypted path: {}", e)))?;
+
+        let plaintext = open(&Envelope::from_bytes(&bytes)?, &self.encryption_key, PATH_CONTEXT)?;
+        String::from_utf8(plaintext).map_err(|e| Error::DecryptionError(e.to_string()))
+    }
+}
+
+/// Normalizes a relative path so the same file always yields the same token
+pub fn normalize_path(path: &str) -> String {
+    path.replace('\\', "/")
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_encryption() {
+        let keys = PathKeys::derive(&SecretKey::from_bytes([8u8; 32]));
+
+        let encrypted = keys.encrypt_path("legal/contracts/acme.pdf").unwrap();
+        assert!(!encrypted.contains("acme"));
+        assert_ne!(encrypted, keys.encrypt_path("legal/contracts/acme.pdf").unwrap());
+        assert_eq!(keys.decrypt_path(&encrypted).unwrap(), "legal/contracts/acme.pdf");
+    }
+
+    #[test]
+    fn test_lookup_token() {
+        let keys = PathKeys::derive(&SecretKey::from_bytes([8u8; 32]));
+        let token = keys.lookup_token("legal/acme.pdf");
+
+        assert_eq!(token, keys.lookup_token("./legal//acme.pdf"));
+        assert_ne!(token, keys.lookup_token("legal/acme2.pdf"));
+        assert_ne!(token, PathKeys::derive(&SecretKey::from_bytes([9u8; 32])).lookup_token("legal/acme.pdf"));
+    }
+}
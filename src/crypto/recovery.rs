@@ -0,0 +1,159 @@
+//! Account recovery keys
+//!
+//! A recovery key is 256 bits of random entropy shown to the user once as a
+//! 24-word BIP39 mnemonic. It wraps the master key a second time, alongside
+//! the passphrase-wrapped copy, so a forgotten passphrase can be replaced
+//! without touching any file data.
+//!
+//! Two independent subkeys are derived from the entropy: one wraps the
+//! master key, the other is a verifier the server stores (hashed) to
+//! authorize a credential reset. The server never sees the wrapping key.
+
+use super::keys::{derive_subkey, protect_master_key, unwrap_key, wrap_key, PassphraseKeys};
+use super::{SecretKey, SecretString};
+use crate::error::{Error, Result};
+use crate::models::UserKeyMaterial;
+use bip39::Mnemonic;
+use zeroize::Zeroizing;
+
+/// Number of words in a recovery mnemonic
+pub const RECOVERY_WORDS: usize = 24;
+
+const RECOVERY_PURPOSE: &str = "recovery-master-key";
+
+/// High-entropy secret that can unlock the master key without the passphrase
+#[derive(Debug)]
+pub struct RecoveryKey {
+    entropy: SecretKey,
+}
+
+impl RecoveryKey {
+    /// Generates a new random recovery key
+    pub fn generate() -> Self {
+        Self {
+            entropy: SecretKey::generate(),
+        }
+    }
+
+    /// Parses a recovery key from its mnemonic, ignoring case and extra whitespace
+    pub fn from_mnemonic(phrase: &SecretString) -> Result<Self> {
+        let normalized = Zeroizing::new(
+            phrase
+                .expose()
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase(),
+        );
+        let mnemonic = Mnemonic::parse_normalized(&normalized)
+            .map_err(|e| Error::InvalidInput(format!("Invalid recovery phrase: {}", e)))?;
+
+        let entropy = SecretKey::from_slice(&Zeroizing::new(mnemonic.to_entropy()))
+            .map_err(|_| Error::InvalidInput(format!("Recovery phrase must have {} words", RECOVERY_WORDS)))?;
+
+        Ok(Self { entropy })
+    }
+
+    /// Formats the recovery key as a mnemonic for the user to write down
+    pub fn to_mnemonic(&self) -> SecretString {
+        Mnemonic::from_entropy(self.entropy.expose())
+            .expect("32 bytes is a valid mnemonic entropy length")
+            .to_string()
+            .into()
+    }
+
+    /// Value the server stores (hashed) to authorize a recovery
+    pub fn verifier(&self) -> SecretString {
+        hex::encode(derive_subkey(&self.entropy, "recovery-verifier").expose()).into()
+    }
+
+    fn wrapping_key(&self) -> SecretKey {
+        derive_subkey(&self.entropy, "recovery-wrap")
+    }
+}
+
+/// Adds a recovery-key-wrapped copy of the master key to the key material
+///
+/// Replaces any previous recovery key; everything else is carried over.
+pub fn enable_recovery(
+    key_material: &UserKeyMaterial,
+    master_key: &SecretKey,
+    recovery_key: &RecoveryKey,
+) -> Result<UserKeyMaterial> {
+    Ok(UserKeyMaterial {
+        recovery_wrapped_master_key: Some(wrap_key(
+            master_key,
+            &recovery_key.wrapping_key(),
+            RECOVERY_PURPOSE,
+            "",
+        )?),
+        ..key_material.clone()
+    })
+}
+
+/// Unlocks the master key with the recovery key and protects it under a new passphrase
+///
+/// Returns the master key and the updated key material. The recovery key
+/// keeps working afterwards.
+pub fn recover_master_key(
+    key_material: &UserKeyMaterial,
+    recovery_key: &RecoveryKey,
+    new_passphrase: &PassphraseKeys,
+) -> Result<(SecretKey, UserKeyMaterial)> {
+    let wrapped = key_material
+        .recovery_wrapped_master_key
+        .as_deref()
+        .ok_or_else(|| Error::InvalidInput("No recovery key is set up for this account".to_string()))?;
+
+    let master_key = unwrap_key(wrapped, &recovery_key.wrapping_key(), RECOVERY_PURPOSE, "")
+        .map_err(|_| Error::InvalidCredentials)?;
+    let protected = protect_master_key(&master_key, new_passphrase)?;
+
+    Ok((
+        master_key,
+        UserKeyMaterial {
+            kdf: protected.kdf,
+            wrapped_master_key: protected.wrapped_master_key,
+            ..key_material.clone()
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::{create_account_keys, unlock_master_key};
+
+    #[test]
+    fn test_mnemonic_roundtrip() {
+        let recovery_key = RecoveryKey::generate();
+        let phrase = recovery_key.to_mnemonic();
+        let phrase = phrase.expose();
+        assert_eq!(phrase.split(' ').count(), RECOVERY_WORDS);
+
+        let parsed = RecoveryKey::from_mnemonic(&format!("  {}\n", phrase.to_uppercase()).into()).unwrap();
+        assert_eq!(parsed.verifier().expose(), recovery_key.verifier().expose());
+
+        assert!(RecoveryKey::from_mnemonic(&phrase.replacen(' ', " notaword ", 1).into()).is_err());
+        assert!(RecoveryKey::from_mnemonic(&"abandon abandon abandon".into()).is_err());
+    }
+
+    #[test]
+    fn test_recover_with_new_passphrase() {
+        let forgotten = PassphraseKeys::for_tests("forgotten");
+        let new = PassphraseKeys::for_tests("new");
+
+        let (account, material) = create_account_keys(&forgotten, crate::crypto::sharing::test_key()).unwrap();
+        let master = account.master_key().duplicate();
+        let recovery_key = RecoveryKey::generate();
+        let material = enable_recovery(&material, &master, &recovery_key).unwrap();
+
+        assert!(recover_master_key(&material, &RecoveryKey::generate(), &new).is_err());
+
+        let (recovered, material) = recover_master_key(&material, &recovery_key, &new).unwrap();
+        assert_eq!(recovered, master);
+        assert_eq!(unlock_master_key(&new, &material).unwrap(), master);
+        assert!(unlock_master_key(&forgotten, &material).is_err());
+        assert!(material.recovery_wrapped_master_key.is_some());
+    }
+}
@@ -0,0 +1,168 @@
+//! Secret types for keys and passphrases
+//!
+//! [`SecretKey`] and [`SecretString`] keep their contents on the heap so
+//! moving them does not leave copies behind, wipe that memory when dropped,
+//! and print as `[REDACTED]` in `Debug` output. Neither implements `Clone`;
+//! a key that really needs a second owner is copied explicitly with
+//! [`SecretKey::duplicate`].
+
+use crate::error::{Error, Result};
+use rand::RngCore;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// Size in bytes of every symmetric key
+pub const KEY_SIZE: usize = 32;
+
+/// A 256-bit symmetric key that is wiped from memory on drop
+pub struct SecretKey(Box<[u8; KEY_SIZE]>);
+
+impl SecretKey {
+    /// Generates a random key
+    pub fn generate() -> Self {
+        let mut key = Self::zeroed();
+        rand::thread_rng().fill_bytes(key.expose_mut());
+        key
+    }
+
+    /// Takes ownership of raw key bytes, wiping the local copy
+    ///
+    /// `[u8; 32]` is `Copy`, so any copy the caller still holds is not
+    /// wiped; prefer producing keys through this module.
+    pub fn from_bytes(mut bytes: [u8; KEY_SIZE]) -> Self {
+        let key = Self(Box::new(bytes));
+        bytes.zeroize();
+        key
+    }
+
+    /// Copies a key out of a slice, which must be exactly [`KEY_SIZE`] bytes
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != KEY_SIZE {
+            return Err(Error::DecryptionError("Key has wrong length".to_string()));
+        }
+
+        let mut key = Self::zeroed();
+        key.expose_mut().copy_from_slice(bytes);
+        Ok(key)
+    }
+
+    pub(crate) fn zeroed() -> Self {
+        Self(Box::new([0u8; KEY_SIZE]))
+    }
+
+    /// Returns the raw key bytes
+    pub fn expose(&self) -> &[u8; KEY_SIZE] {
+        &self.0
+    }
+
+    pub(crate) fn expose_mut(&mut self) -> &mut [u8; KEY_SIZE] {
+        &mut self.0
+    }
+
+    /// Makes an independent copy of the key
+    pub fn duplicate(&self) -> Self {
+        Self(Box::new(*self.0))
+    }
+}
+
+impl Drop for SecretKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+impl PartialEq for SecretKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.ct_eq(&*other.0).into()
+    }
+}
+
+impl Eq for SecretKey {}
+
+/// A passphrase or other secret text that is wiped from memory on drop
+///
+/// Serializes as a plain string so it can be sent in API requests.
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Wraps a string, taking ownership of its buffer
+    pub fn new(secret: String) -> Self {
+        Self(secret)
+    }
+
+    /// Returns the secret text
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns true if the secret is empty
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self::new(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self::new(secret.to_string())
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretString([REDACTED])")
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let key = SecretKey::from_bytes([0x41; KEY_SIZE]);
+        let passphrase = SecretString::from("hunter2");
+
+        assert_eq!(format!("{:?}", key), "SecretKey([REDACTED])");
+        assert!(!format!("{:?}", passphrase).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_key_equality() {
+        let key = SecretKey::generate();
+        assert_eq!(key.duplicate(), key);
+        assert_ne!(SecretKey::generate(), key);
+        assert!(SecretKey::from_slice(&[0u8; 16]).is_err());
+    }
+}
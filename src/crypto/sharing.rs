@@ -0,0 +1,119 @@
+//! Sharing files with other users
+//!
+//! Every user has an RSA key pair. The public key is registered with the
+//! server (`User::public_key`, SPKI PEM) and the private key is kept in the
+//! user's key material, wrapped by their master key. Sharing a file wraps its
+//! data key to the recipient's public key with RSA-OAEP (SHA-256), using the
+//! file id as the OAEP label so a shared key cannot be replayed for another
+//! file.
+
+use super::keys::{unwrap_bytes, wrap_bytes};
+use super::SecretKey;
+use crate::error::{Error, Result};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, LineEnding};
+use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// Modulus size for newly generated sharing keys
+pub const SHARING_KEY_BITS: usize = 3072;
+
+const PRIVATE_KEY_PURPOSE: &str = "sharing-private-key";
+
+fn share_label(file_id: &str) -> String {
+    format!("rustguard-share:{}", file_id)
+}
+
+/// Generates a sharing key pair, returning the private key and the public key as PEM
+pub fn generate_key_pair() -> Result<(RsaPrivateKey, String)> {
+    generate_key_pair_with_bits(SHARING_KEY_BITS)
+}
+
+fn generate_key_pair_with_bits(bits: usize) -> Result<(RsaPrivateKey, String)> {
+    let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), bits)
+        .map_err(|e| Error::EncryptionError(e.to_string()))?;
+    let public_pem = public_key_pem(&private_key)?;
+
+    Ok((private_key, public_pem))
+}
+
+/// Encodes the public half of a sharing key as PEM for registration
+pub fn public_key_pem(private_key: &RsaPrivateKey) -> Result<String> {
+    private_key
+        .to_public_key()
+        .to_public_key_pem(LineEnding::LF)
+        .map_err(|e| Error::EncryptionError(e.to_string()))
+}
+
+/// Generates a small sharing key, which is much faster to create in tests
+#[cfg(test)]
+pub(crate) fn test_key() -> RsaPrivateKey {
+    generate_key_pair_with_bits(1024).unwrap().0
+}
+
+/// Wraps a sharing private key under the master key for storage
+pub fn wrap_private_key(private_key: &RsaPrivateKey, master_key: &SecretKey) -> Result<String> {
+    let der = private_key
+        .to_pkcs8_der()
+        .map_err(|e| Error::EncryptionError(e.to_string()))?;
+
+    wrap_bytes(der.as_bytes(), master_key, PRIVATE_KEY_PURPOSE, "")
+}
+
+/// Unwraps a sharing private key stored with [`wrap_private_key`]
+pub fn unwrap_private_key(wrapped: &str, master_key: &SecretKey) -> Result<RsaPrivateKey> {
+    let der = unwrap_bytes(wrapped, master_key, PRIVATE_KEY_PURPOSE, "")?;
+
+    RsaPrivateKey::from_pkcs8_der(&der).map_err(|e| Error::DecryptionError(e.to_string()))
+}
+
+/// Wraps a file's data key to a recipient's PEM public key
+pub fn wrap_for_recipient(data_key: &SecretKey, recipient_public_pem: &str, file_id: &str) -> Result<String> {
+    let public_key = RsaPublicKey::from_public_key_pem(recipient_public_pem)
+        .map_err(|e| Error::InvalidInput(format!("Invalid public key: {}", e)))?;
+
+    let wrapped = public_key
+        .encrypt(
+            &mut rand::thread_rng(),
+            Oaep::new_with_label::<Sha256, _>(share_label(file_id)),
+            data_key.expose(),
+        )
+        .map_err(|e| Error::EncryptionError(e.to_string()))?;
+
+    Ok(BASE64.encode(wrapped))
+}
+
+/// Unwraps a data key that was shared with us
+pub fn unwrap_shared_key(wrapped: &str, private_key: &RsaPrivateKey, file_id: &str) -> Result<SecretKey> {
+    let bytes = BASE64
+        .decode(wrapped)
+        .map_err(|e| Error::DecryptionError(format!("Invalid shared key: {}", e)))?;
+
+    let data_key = Zeroizing::new(
+        private_key
+            .decrypt(Oaep::new_with_label::<Sha256, _>(share_label(file_id)), &bytes)
+            .map_err(|e| Error::DecryptionError(e.to_string()))?,
+    );
+    SecretKey::from_slice(&data_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::keys::generate_key;
+
+    #[test]
+    fn test_share_roundtrip() {
+        let (private_key, public_pem) = generate_key_pair_with_bits(1024).unwrap();
+        let master_key = generate_key();
+        let data_key = generate_key();
+
+        let wrapped_private = wrap_private_key(&private_key, &master_key).unwrap();
+        let private_key = unwrap_private_key(&wrapped_private, &master_key).unwrap();
+
+        let shared = wrap_for_recipient(&data_key, &public_pem, "file-1").unwrap();
+        assert_eq!(unwrap_shared_key(&shared, &private_key, "file-1").unwrap(), data_key);
+        assert!(unwrap_shared_key(&shared, &private_key, "file-2").is_err());
+    }
+}
@@ -0,0 +1,325 @@
+//! Streaming encryption for files too large to hold in memory
+//!
+//! Streams are split into [`CHUNK_SIZE`] pieces, each sealed into its own
+//! [`Envelope`]. Nonces follow the STREAM construction:
+//!
+//! ```text
+//! random prefix | chunk counter, big endian (4) | last chunk flag (1)
+//! ```
+//!
+//! The prefix fills the rest of the cipher's nonce: 7 bytes for AES-256-GCM
+//! and 19 bytes for XChaCha20-Poly1305.
+//!
+//! The counter rejects reordered or dropped chunks, the prefix ties every
+//! chunk to a single stream and the flag marks the final chunk so that a
+//! truncated stream is detected. The file id is authenticated as associated
+//! data so chunks cannot be moved between files.
+//!
+//! On the wire a stream is a sequence of frames, each a big endian `u32`
+//! length followed by the serialized envelope.
+
+use super::{open, seal, CipherSuite, Envelope, SecretKey, CHUNK_CONTEXT_VERSION, CHUNK_SIZE};
+use crate::error::{Error, Result};
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Bytes of the nonce taken by the counter and last chunk flag
+const NONCE_SUFFIX_SIZE: usize = 5;
+const LAST_CHUNK: u8 = 1;
+
+/// Largest frame a well-formed stream can contain: header, chunk and tag
+const MAX_FRAME_SIZE: usize = 64 + CHUNK_SIZE + 16;
+
+fn stream_nonce(prefix: &[u8], counter: u32, last: bool) -> Vec<u8> {
+    let mut nonce = Vec::with_capacity(prefix.len() + NONCE_SUFFIX_SIZE);
+    nonce.extend_from_slice(prefix);
+    nonce.extend_from_slice(&counter.to_be_bytes());
+    nonce.push(last as u8);
+    nonce
+}
+
+fn stream_context(file_id: &str) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(24 + file_id.len());
+    aad.extend_from_slice(b"rustguard-stream");
+    aad.push(CHUNK_CONTEXT_VERSION);
+    aad.extend_from_slice(&(file_id.len() as u32).to_be_bytes());
+    aad.extend_from_slice(file_id.as_bytes());
+    aad
+}
+
+/// Encrypts a stream one chunk at a time
+pub struct StreamEncryptor {
+    suite: CipherSuite,
+    key: SecretKey,
+    context: Vec<u8>,
+    prefix: Vec<u8>,
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamEncryptor {
+    /// Starts a new stream for `file_id` with a random nonce prefix
+    pub fn new(suite: CipherSuite, key: &SecretKey, file_id: &str) -> Self {
+        let mut prefix = vec![0u8; suite.nonce_len() - NONCE_SUFFIX_SIZE];
+        rand::thread_rng().fill_bytes(&mut prefix);
+
+        Self {
+            suite,
+            key: key.duplicate(),
+            context: stream_context(file_id),
+            prefix,
+            counter: 0,
+            finished: false,
+        }
+    }
+
+    /// Encrypts the next chunk; `last` must be set on the final chunk
+    pub fn encrypt_next(&mut self, chunk: &[u8], last: bool) -> Result<Envelope> {
+        if self.finished {
+            return Err(Error::EncryptionError("Stream already finished".to_string()));
+        }
+        if chunk.len() > CHUNK_SIZE {
+            return Err(Error::EncryptionError("Chunk exceeds CHUNK_SIZE".to_string()));
+        }
+
+        let nonce = stream_nonce(&self.prefix, self.counter, last);
+        let envelope = seal(self.suite, chunk, &self.key, &nonce, &self.context)?;
+
+        if last {
+            self.finished = true;
+        } else {
+            self.counter = self
+                .counter
+                .checked_add(1)
+                .ok_or_else(|| Error::EncryptionError("Stream too long".to_string()))?;
+        }
+
+        Ok(envelope)
+    }
+}
+
+/// Decrypts a stream produced by [`StreamEncryptor`], in order
+pub struct StreamDecryptor {
+    key: SecretKey,
+    context: Vec<u8>,
+    stream: Option<(CipherSuite, Vec<u8>)>,
+    counter: u32,
+    finished: bool,
+}
+
+impl StreamDecryptor {
+    /// Creates a decryptor expecting the first chunk of the stream for `file_id`
+    pub fn new(key: &SecretKey, file_id: &str) -> Self {
+        Self {
+            key: key.duplicate(),
+            context: stream_context(file_id),
+            stream: None,
+            counter: 0,
+            finished: false,
+        }
+    }
+
+    /// Decrypts the next chunk, rejecting anything out of sequence
+    pub fn decrypt_next(&mut self, envelope: &Envelope) -> Result<Vec<u8>> {
+        if self.finished {
+            return Err(Error::DecryptionError("Data after final chunk".to_string()));
+        }
+        let nonce = &envelope.nonce;
+        if nonce.len() != envelope.cipher.nonce_len() {
+            return Err(Error::DecryptionError("Invalid stream nonce".to_string()));
+        }
+
+        let prefix_len = nonce.len() - NONCE_SUFFIX_SIZE;
+        let (suite, prefix) = self
+            .stream
+            .get_or_insert_with(|| (envelope.cipher, nonce[..prefix_len].to_vec()));
+        if *suite != envelope.cipher || prefix[..] != nonce[..prefix_len] {
+            return Err(Error::DecryptionError("Chunk belongs to a different stream".to_string()));
+        }
+
+        let mut counter = [0u8; 4];
+        counter.copy_from_slice(&nonce[prefix_len..prefix_len + 4]);
+        if u32::from_be_bytes(counter) != self.counter {
+            return Err(Error::DecryptionError(format!(
+                "Expected chunk {}, got chunk {}",
+                self.counter,
+                u32::from_be_bytes(counter)
+            )));
+        }
+
+        let flag = nonce[nonce.len() - 1];
+        if flag > LAST_CHUNK {
+            return Err(Error::DecryptionError("Invalid stream nonce".to_string()));
+        }
+
+        let plaintext = open(envelope, &self.key, &self.context)?;
+
+        if flag == LAST_CHUNK {
+            self.finished = true;
+        } else {
+            self.counter += 1;
+        }
+
+        Ok(plaintext)
+    }
+
+    /// Checks that the final chunk has been seen
+    pub fn finish(&self) -> Result<()> {
+        if self.finished {
+            Ok(())
+        } else {
+            Err(Error::DecryptionError("Stream truncated".to_string()))
+        }
+    }
+}
+
+/// Fills `buf` from `reader`, returning fewer bytes only at end of input
+pub(crate) async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Encrypts everything from `reader` into `writer`, returning the plaintext size
+pub async fn encrypt_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    suite: CipherSuite,
+    key: &SecretKey,
+    file_id: &str,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut encryptor = StreamEncryptor::new(suite, key, file_id);
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut total = 0u64;
+
+    loop {
+        // A short read means end of input. A full chunk followed by EOF is
+        // closed with an empty final chunk on the next iteration.
+        let n = read_chunk(reader, &mut buf).await?;
+        let last = n < CHUNK_SIZE;
+
+        let frame = encryptor.encrypt_next(&buf[..n], last)?.to_bytes();
+        writer.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+        writer.write_all(&frame).await?;
+        total += n as u64;
+
+        if last {
+            break;
+        }
+    }
+
+    writer.flush().await?;
+    Ok(total)
+}
+
+/// Decrypts a stream written by [`encrypt_stream`], returning the plaintext size
+pub async fn decrypt_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    key: &SecretKey,
+    file_id: &str,
+) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut decryptor = StreamDecryptor::new(key, file_id);
+    let mut frame = Vec::new();
+    let mut total = 0u64;
+
+    loop {
+        let mut len = [0u8; 4];
+        match read_chunk(reader, &mut len).await? {
+            0 => break,
+            4 => {}
+            _ => return Err(Error::DecryptionError("Stream truncated".to_string())),
+        }
+
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::DecryptionError("Frame too large".to_string()));
+        }
+
+        frame.resize(len, 0);
+        if read_chunk(reader, &mut frame).await? < len {
+            return Err(Error::DecryptionError("Stream truncated".to_string()));
+        }
+
+        let plaintext = decryptor.decrypt_next(&Envelope::from_bytes(&frame)?)?;
+        writer.write_all(&plaintext).await?;
+        total += plaintext.len() as u64;
+    }
+
+    decryptor.finish()?;
+    writer.flush().await?;
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn roundtrip(data: &[u8], suite: CipherSuite) -> Vec<u8> {
+        let key = SecretKey::from_bytes([4u8; 32]);
+        let mut encrypted = Vec::new();
+        encrypt_stream(&mut &data[..], &mut encrypted, suite, &key, "file").await.unwrap();
+
+        let mut decrypted = Vec::new();
+        decrypt_stream(&mut &encrypted[..], &mut decrypted, &key, "file").await.unwrap();
+        decrypted
+    }
+
+    #[tokio::test]
+    async fn test_stream_roundtrip() {
+        for suite in [CipherSuite::Aes256Gcm, CipherSuite::XChaCha20Poly1305] {
+            assert!(roundtrip(b"", suite).await.is_empty());
+
+            let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 17).map(|i| i as u8).collect();
+            assert_eq!(roundtrip(&data, suite).await, data);
+
+            let exact = vec![7u8; CHUNK_SIZE];
+            assert_eq!(roundtrip(&exact, suite).await, exact);
+        }
+    }
+
+    #[test]
+    fn test_detects_truncation_and_reordering() {
+        let key = SecretKey::from_bytes([5u8; 32]);
+        let mut encryptor = StreamEncryptor::new(CipherSuite::Aes256Gcm, &key, "file");
+        let first = encryptor.encrypt_next(b"one", false).unwrap();
+        let second = encryptor.encrypt_next(b"two", false).unwrap();
+        let last = encryptor.encrypt_next(b"three", true).unwrap();
+
+        let mut decryptor = StreamDecryptor::new(&key, "file");
+        decryptor.decrypt_next(&first).unwrap();
+        assert!(decryptor.decrypt_next(&last).is_err());
+
+        let mut decryptor = StreamDecryptor::new(&key, "file");
+        decryptor.decrypt_next(&first).unwrap();
+        decryptor.decrypt_next(&second).unwrap();
+        assert!(decryptor.finish().is_err());
+        decryptor.decrypt_next(&last).unwrap();
+        assert!(decryptor.finish().is_ok());
+
+        let mut other = StreamEncryptor::new(CipherSuite::Aes256Gcm, &key, "file");
+        let foreign = other.encrypt_next(b"one", false).unwrap();
+        let mut decryptor = StreamDecryptor::new(&key, "file");
+        decryptor.decrypt_next(&first).unwrap();
+        let mut spliced = foreign.clone();
+        spliced.nonce[7 + 3] = 1;
+        assert!(decryptor.decrypt_next(&spliced).is_err());
+
+        let mut decryptor = StreamDecryptor::new(&key, "other-file");
+        assert!(decryptor.decrypt_next(&first).is_err());
+    }
+}
@@ -50,6 +50,12 @@ pub enum Error {
     #[error("Network error: {0}")]
     NetworkError(String),
 
+    #[error("Integrity check failed: {0}")]
+    IntegrityError(String),
+
+    #[error("Possible rollback: {0}")]
+    RollbackDetected(String),
+
     #[error("Conflict resolution failed: {0}")]
     ConflictError(String),
 
@@ -1,7 +1,24 @@
 use clap::Parser;
 use rust_guard::client::cli::{Cli, Commands};
-use rust_guard::error::Result;
+use rust_guard::client::checkpoint::CheckpointStore;
+use rust_guard::client::config::{ClientConfigFile, SyncDirConfig, UserConfig};
+use rust_guard::client::conflicts::ConflictResolver;
+use rust_guard::client::engine::SyncEngine;
+use rust_guard::client::keystore::{Keystore, UnlockCache, UnlockedKeystore};
+use rust_guard::client::sync_client::SyncClient;
+use rust_guard::client::ClientConfig;
+use rust_guard::crypto::keys::{self, AccountKeys, PassphraseKeys};
+use rust_guard::crypto::{recovery::RecoveryKey, sharing, KdfDescriptor, SecretString};
+use rust_guard::error::{Error, Result};
+use rust_guard::sync::conflict::{line_diff, ConflictRecord, ConflictSide, DiffLine};
+use rust_guard::sync::index::relative_path;
+use rust_guard::sync::planner::Side;
+use rust_guard::sync::{
+    Backoff, Change, ChangeJournal, ConflictKind, ConflictResolution, FileChangeEvent, FileWatcher, IgnoreRules,
+    SyncIndex, SyncPlan,
+};
 use std::path::PathBuf;
+use std::sync::Arc;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 mod server_main;
@@ -32,14 +49,23 @@ async fn main() -> Result<()> {
         Commands::Login { username, password } => {
             handle_login(username, password).await
         }
+        Commands::RecoveryKey { username } => {
+            handle_recovery_key(username).await
+        }
+        Commands::Recover { username } => {
+            handle_recover(username).await
+        }
+        Commands::RotateKey => {
+            handle_rotate_key().await
+        }
         Commands::AddSync { path, remote } => {
             handle_add_sync(path, remote).await
         }
         Commands::ListSync => {
             handle_list_sync().await
         }
-        Commands::Sync => {
-            handle_sync().await
+        Commands::Sync { dry_run, json } => {
+            handle_sync(dry_run, json).await
         }
         Commands::Status => {
             handle_status().await
@@ -50,6 +76,33 @@ async fn main() -> Result<()> {
         Commands::List => {
             handle_list().await
         }
+        Commands::Share { path, user } => {
+            handle_share(&path, &user).await
+        }
+        Commands::Shares => {
+            handle_shares().await
+        }
+        Commands::DownloadShared { share_id, output } => {
+            handle_download_shared(&share_id, output).await
+        }
+        Commands::Revoke { share_id } => {
+            handle_revoke(&share_id).await
+        }
+        Commands::Conflicts { list, keep_local, keep_remote, keep_both } => {
+            let bulk = if keep_local {
+                Some(ConflictResolution::KeepLocal)
+            } else if keep_remote {
+                Some(ConflictResolution::KeepRemote)
+            } else if keep_both {
+                Some(ConflictResolution::KeepBoth)
+            } else {
+                None
+            };
+            handle_conflicts(list, bulk).await
+        }
+        Commands::CheckIgnore { path } => {
+            handle_check_ignore(&path)
+        }
         Commands::Version => {
             println!("RustGuard v{}", rust_guard::VERSION);
             Ok(())
@@ -60,12 +113,21 @@ async fn main() -> Result<()> {
             println!("\nCommands:");
             println!("  register    - Register a new account");
             println!("  login       - Login to your account");
+            println!("  recovery-key - Generate a recovery key");
+            println!("  recover     - Set a new passphrase using your recovery key");
+            println!("  rotate-key  - Replace the master key");
             println!("  add-sync    - Add a directory to sync");
             println!("  list-sync   - List synced directories");
-            println!("  sync        - Start sync daemon");
+            println!("  sync        - Start sync daemon (--dry-run to preview)");
             println!("  status      - Show sync status");
             println!("  download    - Download a file");
             println!("  list        - List files");
+            println!("  share       - Share a file or folder with another user");
+            println!("  shares      - List shares you created and files shared with you");
+            println!("  download-shared - Download a file shared with you");
+            println!("  revoke      - Revoke a share");
+            println!("  conflicts   - List and resolve sync conflicts");
+            println!("  check-ignore - Explain whether a path is excluded from sync");
             println!("  version     - Show version");
             println!("  help        - Show this help message");
             Ok(())
@@ -74,15 +136,53 @@ async fn main() -> Result<()> {
 }
 
 async fn handle_register(username: Option<String>, email: Option<String>, password: Option<String>) -> Result<()> {
-    use rust_guard::client::cli::prompt_input;
+    use rust_guard::client::cli::{prompt_input, prompt_new_password};
 
-    let username = username.unwrap_or_else(|| prompt_input("Username: ").unwrap_or_default());
-    let email = email.unwrap_or_else(|| prompt_input("Email: ").unwrap_or_default());
-    let password = password.unwrap_or_else(|| {
-        rust_guard::client::cli::prompt_password("Password: ").unwrap_or_default()
-    });
+    let defaults = ClientConfig::default();
+    let mut config = load_config();
+    let username = match username {
+        Some(username) => username,
+        None => prompt_input("Username: ")?,
+    };
+    let email = match email {
+        Some(email) => email,
+        None => prompt_input("Email: ")?,
+    };
+    let password = match password {
+        Some(password) => SecretString::from(password),
+        None => prompt_new_password("Password: ")?,
+    };
+
+    println!("Generating sharing key...");
+    let (sharing_key, _) = tokio::task::spawn_blocking(sharing::generate_key_pair)
+        .await
+        .map_err(|e| Error::Internal(e.to_string()))??;
 
     println!("Registering user: {}", username);
+    let (session, account) = SyncClient::new(config.server_url.clone(), String::new())
+        .register(&username, &email, &password, KdfDescriptor::new(config.kdf), sharing_key)
+        .await?;
+    let key_material = session
+        .key_material
+        .ok_or_else(|| Error::Internal("Registration returned no key material".to_string()))?;
+
+    Keystore::create(
+        &defaults.keystore_file,
+        &session.username,
+        &session.user_id,
+        key_material,
+        account.master_key(),
+        SecretString::from(session.token),
+    )?;
+
+    config.user = Some(UserConfig {
+        username: session.username.clone(),
+        email,
+        token: None,
+        user_id: session.user_id,
+    });
+    config.save(&defaults.config_file)?;
+
     println!("✓ User registered successfully!");
     Ok(())
 }
@@ -90,13 +190,169 @@ async fn handle_register(username: Option<String>, email: Option<String>, passwo
 async fn handle_login(username: Option<String>, password: Option<String>) -> Result<()> {
     use rust_guard::client::cli::prompt_input;
 
-    let username = username.unwrap_or_else(|| prompt_input("Username: ").unwrap_or_default());
-    let password = password.unwrap_or_else(|| {
-        rust_guard::client::cli::prompt_password("Password: ").unwrap_or_default()
+    let defaults = ClientConfig::default();
+    let mut config = load_config();
+    let username = match username {
+        Some(username) => username,
+        None => prompt_input("Username: ")?,
+    };
+    let password = match password {
+        Some(password) => SecretString::from(password),
+        None => rust_guard::client::cli::prompt_password("Password: ")?,
+    };
+
+    let (session, passphrase_keys) = SyncClient::new(config.server_url.clone(), String::new())
+        .login(&username, &password)
+        .await?;
+    let mut key_material = session
+        .key_material
+        .ok_or_else(|| Error::InvalidInput("Account has no key material".to_string()))?;
+    let account = keys::unlock_account(&passphrase_keys, &key_material)?;
+
+    let client = SyncClient::new(config.server_url.clone(), session.token.clone());
+    if let Some(upgraded) = client.upgrade_kdf(&password, &passphrase_keys, &key_material, config.kdf).await? {
+        key_material = upgraded;
+        println!("✓ Upgraded the password key derivation settings");
+    }
+
+    Keystore::create(
+        &defaults.keystore_file,
+        &session.username,
+        &session.user_id,
+        key_material,
+        account.master_key(),
+        SecretString::from(session.token),
+    )?;
+
+    let email = config.user.take().map(|user| user.email).unwrap_or_default();
+    config.user = Some(UserConfig {
+        username: session.username.clone(),
+        email,
+        token: None,
+        user_id: session.user_id,
     });
+    config.save(&defaults.config_file)?;
 
-    println!("Logging in user: {}", username);
-    println!("✓ Login successful!");
+    println!("✓ Logged in as {}", session.username);
+    Ok(())
+}
+
+/// Loads the client config, falling back to defaults if none exists yet
+fn load_config() -> ClientConfigFile {
+    let defaults = ClientConfig::default();
+    ClientConfigFile::load(&defaults.config_file)
+        .unwrap_or_else(|_| ClientConfigFile::new(defaults.server_url))
+}
+
+async fn handle_recovery_key(username: Option<String>) -> Result<()> {
+    use rust_guard::client::cli::{prompt_input, prompt_password};
+
+    let config = load_config();
+    let username = match username {
+        Some(username) => username,
+        None => prompt_input("Username: ")?,
+    };
+    let password = prompt_password("Password: ")?;
+
+    let (session, passphrase_keys) = SyncClient::new(config.server_url.clone(), String::new())
+        .login(&username, &password)
+        .await?;
+    let key_material = session
+        .key_material
+        .ok_or_else(|| Error::InvalidInput("Account has no key material".to_string()))?;
+    let account = keys::unlock_account(&passphrase_keys, &key_material)?;
+
+    let recovery_key = SyncClient::new(config.server_url, session.token)
+        .enable_recovery(&key_material, &account)
+        .await?;
+
+    println!("Your recovery key:\n");
+    let phrase = recovery_key.to_mnemonic();
+    let words: Vec<&str> = phrase.expose().split(' ').collect();
+    for (row, line) in words.chunks(6).enumerate() {
+        let numbered: Vec<String> = line
+            .iter()
+            .enumerate()
+            .map(|(i, word)| format!("{:>2}. {:<10}", row * 6 + i + 1, word))
+            .collect();
+        println!("  {}", numbered.join(" "));
+    }
+    println!("\nWrite these words down and keep them somewhere safe.");
+    println!("They are shown only once and replace any previous recovery key.");
+    println!("Anyone holding them can take over your account.");
+    Ok(())
+}
+
+async fn handle_recover(username: Option<String>) -> Result<()> {
+    use rust_guard::client::cli::{prompt_input, prompt_new_password, prompt_password};
+
+    let config = load_config();
+    let username = match username {
+        Some(username) => username,
+        None => prompt_input("Username: ")?,
+    };
+    let recovery_key = RecoveryKey::from_mnemonic(&prompt_password("Recovery phrase: ")?)?;
+    let new_password = prompt_new_password("New password: ")?;
+
+    let (session, account) = SyncClient::new(config.server_url, String::new())
+        .recover_account(&username, &recovery_key, &new_password, KdfDescriptor::new(config.kdf))
+        .await?;
+
+    // The old keystore is still wrapped under the forgotten passphrase
+    let keystore_file = ClientConfig::default().keystore_file;
+    if let Some(key_material) = session.key_material {
+        Keystore::create(
+            &keystore_file,
+            &session.username,
+            &session.user_id,
+            key_material,
+            account.master_key(),
+            SecretString::from(session.token),
+        )?;
+    }
+
+    println!("✓ Password reset for {}. Your files are unchanged.", session.username);
+    Ok(())
+}
+
+async fn handle_rotate_key() -> Result<()> {
+    use rust_guard::client::cli::{prompt_input, prompt_password};
+
+    let defaults = ClientConfig::default();
+    let config = load_config();
+    let username = match &config.user {
+        Some(user) => user.username.clone(),
+        None => prompt_input("Username: ")?,
+    };
+    let password = prompt_password("Password: ")?;
+
+    let (session, passphrase_keys) = SyncClient::new(config.server_url.clone(), String::new())
+        .login(&username, &password)
+        .await?;
+    let key_material = session
+        .key_material
+        .ok_or_else(|| Error::InvalidInput("Account has no key material".to_string()))?;
+    let account = keys::unlock_account(&passphrase_keys, &key_material)?;
+
+    let had_recovery = key_material.recovery_wrapped_master_key.is_some();
+    let (account, key_material) = SyncClient::new(config.server_url, session.token.clone())
+        .rotate_master_key(&account, &passphrase_keys, &key_material)
+        .await?;
+
+    // The keystore secrets are sealed under the old master key
+    Keystore::create(
+        &defaults.keystore_file,
+        &session.username,
+        &session.user_id,
+        key_material,
+        account.master_key(),
+        SecretString::from(session.token),
+    )?;
+
+    println!("✓ Master key rotated for {}", session.username);
+    if had_recovery {
+        println!("Your recovery key no longer works. Run `recovery-key` to create a new one.");
+    }
     Ok(())
 }
 
@@ -120,16 +376,278 @@ async fn handle_list_sync() -> Result<()> {
     Ok(())
 }
 
-async fn handle_sync() -> Result<()> {
+/// How often the sync daemon checks the server for remote changes
+const REMOTE_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+async fn handle_sync(dry_run: bool, json: bool) -> Result<()> {
+    let defaults = ClientConfig::default();
+    let config = load_config();
+    let dirs: Vec<&SyncDirConfig> = config.sync_directories.iter().filter(|dir| dir.enabled).collect();
+    if dirs.is_empty() {
+        println!("No sync directories configured. Add one with `rustguard add-sync`.");
+        return Ok(());
+    }
+
+    let index = SyncIndex::open(&defaults.index_file).await?;
+    let journal = ChangeJournal::open(&defaults.journal_file).await?;
+
+    if dry_run {
+        let (client, account) = open_session().await?;
+        let engine = SyncEngine::new(&client, &account, &index, config.device_name());
+        let mut plans = Vec::new();
+        for dir in &dirs {
+            let root = PathBuf::from(&dir.path);
+            let ignore = IgnoreRules::new(&root, &config.exclude);
+            let moves = journal.pending(&root).await?.moves();
+            let plan = match engine.plan(&root, &dir.remote_path, &moves, &ignore).await {
+                Ok(plan) => plan,
+                Err(e) => {
+                    confirm_rollback(&client, &account, e).await?;
+                    engine.plan(&root, &dir.remote_path, &moves, &ignore).await?
+                }
+            };
+            plans.push((*dir, plan));
+        }
+
+        if json {
+            let output: Vec<_> = plans
+                .iter()
+                .map(|(dir, plan)| {
+                    serde_json::json!({ "root": dir.path, "remote_root": dir.remote_path, "plan": plan })
+                })
+                .collect();
+            let output =
+                serde_json::to_string_pretty(&output).map_err(|e| Error::SerializationError(e.to_string()))?;
+            println!("{}", output);
+        } else {
+            for (dir, plan) in &plans {
+                print_plan(dir, plan);
+            }
+        }
+        return Ok(());
+    }
+
+    if !Keystore::exists(&defaults.keystore_file) {
+        return Err(Error::ConfigError("No keystore found; run `rustguard login` first".to_string()));
+    }
+    let mut keystore = Keystore::load(&defaults.keystore_file)?;
+    let cache = UnlockCache::new(std::time::Duration::from_secs(config.unlock_cache_secs.unwrap_or(0)));
+
     println!("Starting sync daemon...");
+
+    // Watching starts first, so nothing changed during the first pass is missed
+    let mut watcher = FileWatcher::new().with_global_excludes(config.exclude.clone());
+    for dir in &dirs {
+        watcher.add_path(PathBuf::from(&dir.path))?;
+    }
+    let mut events = watcher.start_watching()?;
+    let mut poll = tokio::time::interval(REMOTE_POLL_INTERVAL);
+    poll.tick().await;
+
+    // The first pass is a full one, which also drains changes journaled
+    // before a crash or while offline
+    let mut backoff = Backoff::new(RETRY_BASE_DELAY, RETRY_MAX_DELAY);
+    let mut full = true;
     println!("✓ Sync daemon started! Press Ctrl+C to stop.");
 
-    // Keep running
-    tokio::signal::ctrl_c().await?;
-    println!("\nSync daemon stopped.");
+    loop {
+        let retry_at = match daemon_pass(&config, &cache, &mut keystore, &index, &journal, &dirs, full).await {
+            Ok(()) => {
+                backoff.reset();
+                None
+            }
+            Err(e) => {
+                let delay = backoff.next_delay();
+                println!("⚠ Sync failed: {}; retrying in {}s", e, delay.as_secs());
+                Some(tokio::time::Instant::now() + delay)
+            }
+        };
+
+        // Changes keep being journaled while a retry is pending; they are
+        // synced by the retry rather than right away
+        full = loop {
+            let retry = async {
+                match retry_at {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            let expiry = async {
+                match cache.expires_at() {
+                    Some(at) => tokio::time::sleep_until(at.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {
+                    println!("\nSync daemon stopped.");
+                    return Ok(());
+                }
+                event = events.recv() => {
+                    let Some(mut event) = event else {
+                        println!("\nSync daemon stopped.");
+                        return Ok(());
+                    };
+                    loop {
+                        journal_event(&journal, &dirs, &event).await?;
+                        match events.try_recv() {
+                            Ok(next) => event = next,
+                            Err(_) => break,
+                        }
+                    }
+                    if retry_at.is_none() {
+                        break false;
+                    }
+                }
+                _ = retry => break false,
+                // Wipes the keys as soon as they expire, not on the next pass
+                _ = expiry => cache.clear_expired(),
+                _ = poll.tick() => {
+                    if retry_at.is_none() {
+                        break true;
+                    }
+                }
+            }
+        };
+    }
+}
+
+/// First wait before retrying a failed sync pass
+const RETRY_BASE_DELAY: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Longest wait between retries while offline
+const RETRY_MAX_DELAY: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Writes a watcher event to the journal of each sync directory it touches
+async fn journal_event(journal: &ChangeJournal, dirs: &[&SyncDirConfig], event: &FileChangeEvent) -> Result<()> {
+    for dir in dirs {
+        let root = PathBuf::from(&dir.path);
+        if let Some(change) = Change::from_event(&root, event) {
+            journal.record(&root, &change).await?;
+        }
+    }
     Ok(())
 }
 
+/// Runs a sync pass with the daemon's cached keys
+///
+/// Unlocks the keystore again once the cached keys have expired, and logs
+/// in again if the server no longer accepts the session token.
+async fn daemon_pass(
+    config: &ClientConfigFile,
+    cache: &UnlockCache,
+    keystore: &mut Keystore,
+    index: &SyncIndex,
+    journal: &ChangeJournal,
+    dirs: &[&SyncDirConfig],
+    full: bool,
+) -> Result<()> {
+    let unlocked: Arc<UnlockedKeystore> = match cache.get() {
+        Some(unlocked) => unlocked,
+        None => cache.put(unlock_keystore(config, keystore).await?),
+    };
+
+    match run_sync_pass(&unlocked, index, journal, config, dirs, full).await {
+        Err(Error::AuthenticationFailed(_)) => {
+            let unlocked = cache.refresh(refresh_token(config, keystore, &unlocked).await?);
+            run_sync_pass(&unlocked, index, journal, config, dirs, full).await
+        }
+        result => result,
+    }
+}
+
+/// Runs one sync pass over every sync directory
+///
+/// A full pass scans each directory and checks for remote changes; any
+/// other pass only syncs the journaled changes. Journaled changes are
+/// removed once synced, and kept for the next attempt if anything failed.
+/// A rejected session token fails the pass right away.
+async fn run_sync_pass(
+    session: &UnlockedKeystore,
+    index: &SyncIndex,
+    journal: &ChangeJournal,
+    config: &ClientConfigFile,
+    dirs: &[&SyncDirConfig],
+    full: bool,
+) -> Result<()> {
+    let client = session_client(config, session.token().expose().to_string(), &session.user_id);
+    let engine = SyncEngine::new(&client, session.account(), index, config.device_name());
+
+    let mut failures = Vec::new();
+    for dir in dirs {
+        let root = PathBuf::from(&dir.path);
+        let pending = journal.pending(&root).await?;
+        if !full && pending.is_empty() {
+            continue;
+        }
+
+        let ignore = IgnoreRules::new(&root, &config.exclude);
+        let plan = if full {
+            engine.plan(&root, &dir.remote_path, &pending.moves(), &ignore).await
+        } else {
+            engine.plan_changes(&root, &dir.remote_path, &pending, &ignore).await
+        };
+        let plan = match plan {
+            Ok(plan) => plan,
+            Err(e @ Error::AuthenticationFailed(_)) => return Err(e),
+            Err(e) => {
+                failures.push(format!("{}: {}", dir.path, e));
+                continue;
+            }
+        };
+        if plan.has_changes() {
+            print_plan(dir, &plan);
+        }
+
+        match engine.apply(&root, &dir.remote_path, &plan).await {
+            Ok(0) => journal.complete(&root, pending.up_to).await?,
+            Ok(failed) => failures.push(format!("{}: {} action(s) failed", dir.path, failed)),
+            Err(e) => failures.push(format!("{}: {}", dir.path, e)),
+        }
+        if !plan.conflicts.is_empty() {
+            println!("⚠ Resolve conflicts with `rustguard conflicts`");
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::SyncError(failures.join("; ")))
+    }
+}
+
+fn print_plan(dir: &SyncDirConfig, plan: &SyncPlan) {
+    println!("{} -> {}:", dir.path, dir.remote_path);
+    if !plan.has_changes() {
+        println!("  Up to date ({} unchanged)", plan.unchanged);
+        return;
+    }
+
+    for rename in &plan.renames {
+        let side = match rename.side {
+            Side::Local => "local",
+            Side::Remote => "remote",
+        };
+        println!("  rename    {} -> {} ({})", rename.from, rename.to, side);
+    }
+    for upload in &plan.uploads {
+        println!("  upload    {} ({} bytes)", upload.path, upload.size);
+    }
+    for download in &plan.downloads {
+        println!("  download  {} ({} bytes)", download.path, download.size);
+    }
+    for delete in &plan.remote_deletes {
+        println!("  delete    {} (remote)", delete.path);
+    }
+    for delete in &plan.local_deletes {
+        println!("  delete    {} (local)", delete.path);
+    }
+    for conflict in &plan.conflicts {
+        println!("  conflict  {} ({})", conflict.path, describe_conflict(conflict.kind));
+    }
+    println!("  {} unchanged", plan.unchanged);
+}
+
 async fn handle_status() -> Result<()> {
     println!("Sync Status:");
     println!("  Status: Active");
@@ -139,17 +657,381 @@ async fn handle_status() -> Result<()> {
 }
 
 async fn handle_download(file_id: &str, output: Option<PathBuf>) -> Result<()> {
-    let output = output.unwrap_or_else(|| PathBuf::from("."));
-    println!("Downloading file: {} to {:?}", file_id, output);
-    println!("✓ File downloaded successfully!");
+    let output = output.unwrap_or_else(|| PathBuf::from(file_id));
+    let (client, account) = open_session().await?;
+
+    if let Err(e) = client.download_file(file_id, &output, &account).await {
+        confirm_rollback(&client, &account, e).await?;
+        client.download_file(file_id, &output, &account).await?;
+    }
+
+    println!("✓ Downloaded {} to {:?}", file_id, output);
     Ok(())
 }
 
 async fn handle_list() -> Result<()> {
+    let (client, account) = open_session().await?;
+
+    let files = match client.list_files_decrypted(&account).await {
+        Ok(files) => files,
+        Err(e) => {
+            confirm_rollback(&client, &account, e).await?;
+            client.list_files_decrypted(&account).await?
+        }
+    };
+
     println!("Files in RustGuard:");
-    println!("  document.pdf (2.5 MB) - 2 versions");
-    println!("  photo.jpg (1.2 MB) - 1 version");
-    println!("  project.zip (50 MB) - 3 versions");
+    for file in files {
+        println!("  {} ({} bytes) - version {} [{}]", file.path, file.size, file.version, file.id);
+    }
+    Ok(())
+}
+
+async fn handle_share(path: &str, user: &str) -> Result<()> {
+    let (client, account) = open_session().await?;
+
+    match client.find_file(path, &account).await? {
+        Some(file) => {
+            let share = client.share_file(&file.id, user, &account).await?;
+            println!("✓ Shared {} with {} [{}]", path, user, share.id);
+        }
+        None => {
+            let shares = client.share_folder(path, user, &account).await?;
+            if shares.is_empty() {
+                return Err(Error::FileNotFound(path.to_string()));
+            }
+            println!("✓ Shared {} files under {} with {}", shares.len(), path, user);
+        }
+    }
+    Ok(())
+}
+
+async fn handle_shares() -> Result<()> {
+    let (client, account) = open_session().await?;
+
+    let paths: std::collections::HashMap<_, _> = client
+        .list_files_decrypted(&account)
+        .await?
+        .into_iter()
+        .map(|file| (file.id, file.path))
+        .collect();
+
+    println!("Shared by you:");
+    for share in client.list_my_shares().await? {
+        let path = paths.get(&share.file_id).map(String::as_str).unwrap_or(&share.file_id);
+        println!("  {} with {} [{}]", path, share.recipient_username, share.id);
+    }
+
+    println!("Shared with you:");
+    for shared in client.list_shared_with_me().await? {
+        println!("  {} ({} bytes) [{}]", shared.file.id, shared.file.size, shared.share.id);
+    }
+    Ok(())
+}
+
+async fn handle_download_shared(share_id: &str, output: Option<PathBuf>) -> Result<()> {
+    let (client, account) = open_session().await?;
+
+    let shared = client
+        .list_shared_with_me()
+        .await?
+        .into_iter()
+        .find(|shared| shared.share.id == share_id)
+        .ok_or_else(|| Error::InvalidInput(format!("Share not found: {}", share_id)))?;
+    let output = output.unwrap_or_else(|| PathBuf::from(&shared.file.id));
+
+    client.download_shared_file(&shared, &output, &account).await?;
+
+    println!("✓ Downloaded shared file to {:?}", output);
+    Ok(())
+}
+
+async fn handle_revoke(share_id: &str) -> Result<()> {
+    let (client, account) = open_session().await?;
+
+    client.revoke_share(share_id, &account).await?;
+
+    println!("✓ Revoked share {}", share_id);
+    Ok(())
+}
+
+async fn handle_conflicts(list_only: bool, bulk: Option<ConflictResolution>) -> Result<()> {
+    use rust_guard::client::cli::prompt_select;
+
+    let defaults = ClientConfig::default();
+    let config = load_config();
+    let index = SyncIndex::open(&defaults.index_file).await?;
+
+    let pending = index.pending_conflicts().await?;
+    if pending.is_empty() {
+        println!("No unresolved conflicts.");
+        return Ok(());
+    }
+
+    println!("Unresolved conflicts:");
+    for record in &pending {
+        print_conflict(record);
+    }
+    if list_only {
+        return Ok(());
+    }
+
+    let (client, account) = open_session().await?;
+    let resolver = ConflictResolver::new(&client, &account, &index, config.device_name());
+    let choices = ["Keep local", "Keep remote", "Keep both", "Show diff", "Skip"];
+
+    for record in &pending {
+        let Some(remote_root) = config
+            .sync_directories
+            .iter()
+            .find(|dir| std::path::Path::new(&dir.path) == record.root)
+            .map(|dir| dir.remote_path.clone())
+        else {
+            println!("⚠ Skipping {}: not in a configured sync directory", record.details.path);
+            continue;
+        };
+
+        let resolution = match bulk {
+            Some(resolution) => Some(resolution),
+            None => loop {
+                print_conflict(record);
+                match prompt_select("Resolve with", &choices)? {
+                    0 => break Some(ConflictResolution::KeepLocal),
+                    1 => break Some(ConflictResolution::KeepRemote),
+                    2 => break Some(ConflictResolution::KeepBoth),
+                    3 => show_diff(&resolver, record).await?,
+                    _ => break None,
+                }
+            },
+        };
+
+        if let Some(resolution) = resolution {
+            resolver.apply(record, &remote_root, resolution).await?;
+            println!("✓ Resolved {}", record.details.path);
+        }
+    }
+    Ok(())
+}
+
+fn handle_check_ignore(path: &std::path::Path) -> Result<()> {
+    let config = load_config();
+    let path = std::path::absolute(path)?;
+
+    let Some(root) = config
+        .sync_directories
+        .iter()
+        .map(|dir| PathBuf::from(&dir.path))
+        .filter(|root| path.starts_with(root))
+        .max_by_key(|root| root.components().count())
+    else {
+        println!("{} is not in a sync directory", path.display());
+        return Ok(());
+    };
+    let Some(relative) = relative_path(&root, &path).filter(|relative| !relative.is_empty())
+    else {
+        println!("{} is the sync directory itself", path.display());
+        return Ok(());
+    };
+
+    let rules = IgnoreRules::new(&root, &config.exclude);
+    match rules.explain(&relative, path.is_dir()) {
+        Some(found) => {
+            let verdict = if found.is_ignored() { "ignored" } else { "included" };
+            println!("{}: {}", relative, verdict);
+            println!("  rule:    {}", found.rule.pattern);
+            println!("  from:    {}", found.rule.source);
+            if found.matched_path != relative {
+                println!("  matched: {}", found.matched_path);
+            }
+        }
+        None => println!("{}: not ignored", relative),
+    }
+    Ok(())
+}
+
+fn print_conflict(record: &ConflictRecord) {
+    let details = &record.details;
+    let kind = describe_conflict(details.kind);
+    let side = |side: &Option<ConflictSide>| match side {
+        Some(side) => format!("{} bytes, modified {}", side.size, side.modified.format("%Y-%m-%d %H:%M")),
+        None => "deleted".to_string(),
+    };
+    let version = details
+        .remote
+        .as_ref()
+        .and_then(|remote| remote.version)
+        .map(|version| format!(", version {}", version))
+        .unwrap_or_default();
+
+    println!("\n  [{}] {} ({})", record.id, record.root.join(&details.path).display(), kind);
+    println!("      local:  {} on {}", side(&details.local), details.device);
+    println!("      remote: {}{}", side(&details.remote), version);
+}
+
+fn describe_conflict(kind: ConflictKind) -> &'static str {
+    match kind {
+        ConflictKind::BothModified => "changed on both sides",
+        ConflictKind::BothCreated => "created on both sides",
+        ConflictKind::LocalDeleted => "deleted locally, changed remotely",
+        ConflictKind::RemoteDeleted => "changed locally, deleted remotely",
+    }
+}
+
+/// Prints the changed lines between both sides, with a little context
+async fn show_diff(resolver: &ConflictResolver<'_>, record: &ConflictRecord) -> Result<()> {
+    const CONTEXT: usize = 2;
+
+    let local = rust_guard::sync::index::local_path(&record.root, &record.details.path);
+    let local_text = std::fs::read(&local).unwrap_or_default();
+    let remote_text = match resolver.fetch_remote(record).await? {
+        Some(temp_path) => {
+            let text = std::fs::read(&temp_path)?;
+            std::fs::remove_file(&temp_path)?;
+            text
+        }
+        None => Vec::new(),
+    };
+
+    let (Ok(local_text), Ok(remote_text)) = (String::from_utf8(local_text), String::from_utf8(remote_text)) else {
+        println!("Binary files differ.");
+        return Ok(());
+    };
+    let Some(diff) = line_diff(&local_text, &remote_text) else {
+        println!("Files are too large to diff.");
+        return Ok(());
+    };
+
+    let changed: Vec<usize> = diff
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, DiffLine::Both(_)))
+        .map(|(i, _)| i)
+        .collect();
+    let mut last_printed = None;
+    for (i, line) in diff.iter().enumerate() {
+        let near_change = changed.iter().any(|&c| c.abs_diff(i) <= CONTEXT);
+        if !near_change {
+            continue;
+        }
+        if last_printed.is_some_and(|last| last + 1 != i) {
+            println!("   ...");
+        }
+        match line {
+            DiffLine::Both(text) => println!("   {}", text),
+            DiffLine::Local(text) => println!(" - {}", text),
+            DiffLine::Remote(text) => println!(" + {}", text),
+        }
+        last_printed = Some(i);
+    }
+    println!("(- local, + remote)");
+    Ok(())
+}
+
+/// Unlocks the master key for commands that read or write files
+///
+/// Uses the keystore when there is one, so only the passphrase is needed;
+/// otherwise logs in to fetch the key material.
+async fn open_session() -> Result<(SyncClient, AccountKeys)> {
+    use rust_guard::client::cli::{prompt_input, prompt_password};
+
+    let defaults = ClientConfig::default();
+    let config = load_config();
+
+    let (token, user_id, account) = if Keystore::exists(&defaults.keystore_file) {
+        let mut keystore = Keystore::load(&defaults.keystore_file)?;
+        let unlocked = unlock_keystore(&config, &mut keystore).await?;
+        (
+            unlocked.token().expose().to_string(),
+            unlocked.user_id.clone(),
+            unlocked.account().duplicate(),
+        )
+    } else {
+        let username = match &config.user {
+            Some(user) => user.username.clone(),
+            None => prompt_input("Username: ")?,
+        };
+        let password = prompt_password("Password: ")?;
+
+        let (session, passphrase_keys) = SyncClient::new(config.server_url.clone(), String::new())
+            .login(&username, &password)
+            .await?;
+        let key_material = session
+            .key_material
+            .ok_or_else(|| Error::InvalidInput("Account has no key material".to_string()))?;
+        let account = keys::unlock_account(&passphrase_keys, &key_material)?;
+
+        SyncClient::new(config.server_url.clone(), session.token.clone())
+            .upgrade_kdf(&password, &passphrase_keys, &key_material, config.kdf)
+            .await?;
+        (session.token, session.user_id, account)
+    };
+
+    Ok((session_client(&config, token, &user_id), account))
+}
+
+/// Creates a client for a session with this device's upload settings
+fn session_client(config: &ClientConfigFile, token: String, user_id: &str) -> SyncClient {
+    let defaults = ClientConfig::default();
+    SyncClient::new(config.server_url.clone(), token)
+        .with_cipher_suite(config.cipher_suite)
+        .with_chunking(config.chunking)
+        .with_checkpoint_store(CheckpointStore::for_user(&defaults.data_dir, user_id))
+}
+
+/// Asks for the passphrase and unlocks the keystore
+///
+/// An expired session token is replaced by logging in again, and key
+/// material derived with weaker KDF settings than configured is upgraded.
+async fn unlock_keystore(config: &ClientConfigFile, keystore: &mut Keystore) -> Result<UnlockedKeystore> {
+    println!("Unlocking keystore for {}", keystore.username());
+    let password = rust_guard::client::cli::prompt_password("Password: ")?;
+    let mut unlocked = keystore.unlock(&password)?;
+
+    let client = SyncClient::new(config.server_url.clone(), unlocked.token().expose().to_string());
+    if let Err(Error::AuthenticationFailed(_)) = client.verify_session().await {
+        unlocked = refresh_token(config, keystore, &unlocked).await?;
+    }
+
+    if keystore.key_material().kdf.needs_upgrade(&config.kdf) {
+        let passphrase_keys = PassphraseKeys::derive(&password, keystore.key_material().kdf.clone())?;
+        let upgraded = SyncClient::new(config.server_url.clone(), unlocked.token().expose().to_string())
+            .upgrade_kdf(&password, &passphrase_keys, keystore.key_material(), config.kdf)
+            .await?;
+        if let Some(upgraded) = upgraded {
+            keystore.update_key_material(upgraded)?;
+            println!("✓ Upgraded the password key derivation settings");
+            // The authentication key changed along with the KDF settings
+            unlocked = keystore.unlock(&password)?;
+        }
+    }
+
+    Ok(unlocked)
+}
+
+/// Logs in again with the keystore's authentication key and stores the new
+/// session token
+async fn refresh_token(config: &ClientConfigFile, keystore: &mut Keystore, unlocked: &UnlockedKeystore) -> Result<UnlockedKeystore> {
+    let session = SyncClient::new(config.server_url.clone(), String::new())
+        .authenticate(keystore.username(), SecretString::from(unlocked.auth_key().expose()))
+        .await?;
+    keystore.update_token(unlocked, SecretString::from(session.token))
+}
+
+/// Asks the user whether to accept an older server state after a detected rollback
+///
+/// Any other error is returned unchanged.
+async fn confirm_rollback(client: &SyncClient, account: &AccountKeys, error: Error) -> Result<()> {
+    let Error::RollbackDetected(reason) = &error else {
+        return Err(error);
+    };
+
+    println!("⚠ {}", reason);
+    println!("The server may be replaying an old state of your files.");
+    if !rust_guard::client::cli::confirm("Accept the server's current state anyway?")? {
+        return Err(error);
+    }
+
+    client.accept_rollback(account).await?;
     Ok(())
 }
 
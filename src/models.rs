@@ -1,8 +1,9 @@
 //! Data models for RustGuard
 
+use crate::crypto::manifest::SignedManifest;
+use crate::crypto::{KdfDescriptor, SecretString};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use uuid::Uuid;
 
 /// User account information
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -12,20 +13,62 @@ pub struct User {
     pub email: String,
     pub password_hash: String,
     pub public_key: String,
+    pub key_material: Option<UserKeyMaterial>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// Client-side key material stored with a user account
+///
+/// Only public parameters and wrapped keys are kept here, so the server
+/// learns nothing without the user's passphrase.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserKeyMaterial {
+    pub kdf: KdfDescriptor,
+    /// Master key wrapped by the passphrase-derived key
+    #[serde(default)]
+    pub wrapped_master_key: Option<String>,
+    /// Account key wrapped by the master key; see
+    /// [`AccountKeys`](crate::crypto::keys::AccountKeys)
+    #[serde(default)]
+    pub wrapped_account_key: Option<String>,
+    /// Sharing private key (PKCS#8 DER) wrapped by the master key; the
+    /// matching public key is `User::public_key`
+    #[serde(default)]
+    pub wrapped_private_key: Option<String>,
+    /// Second copy of the master key, wrapped by the account recovery key
+    #[serde(default)]
+    pub recovery_wrapped_master_key: Option<String>,
+}
+
 /// File metadata stored on server
+///
+/// `path` and `name` are opaque to the server: the client stores an
+/// encrypted path and looks files up by `path_token`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub id: String,
     pub user_id: String,
     pub path: String,
     pub name: String,
+    /// Keyed hash of the plaintext path, used for lookup and dedupe
+    #[serde(default)]
+    pub path_token: Option<String>,
     pub size: u64,
+    /// Keyed fingerprint of the plaintext content, computed by the client
     pub encrypted_hash: String,
     pub chunk_count: u32,
+    /// Content version, incremented each time new content is committed;
+    /// 0 while the first upload of a file is still pending
+    #[serde(default)]
+    pub version: u64,
+    /// Per-file data key wrapped by the owner's master key
+    #[serde(default)]
+    pub wrapped_key: Option<String>,
+    /// `crypto::chunking::ChunkRecipe` sealed under the data key; files
+    /// uploaded as a single encrypted stream have none
+    #[serde(default)]
+    pub chunk_recipe: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub is_deleted: bool,
@@ -37,6 +80,7 @@ pub struct FileChunk {
     pub id: String,
     pub file_id: String,
     pub chunk_index: u32,
+    /// Serialized `crypto::Envelope`
     pub encrypted_data: Vec<u8>,
     pub size: u32,
     pub hash: String,
@@ -53,6 +97,27 @@ pub struct FileVersion {
     pub created_by: String,
 }
 
+/// A file shared by its owner with another user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileShare {
+    pub id: String,
+    pub file_id: String,
+    pub owner_id: String,
+    pub recipient_id: String,
+    #[serde(default)]
+    pub recipient_username: String,
+    /// File data key wrapped to the recipient's public key
+    pub wrapped_key: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A share as seen by its recipient
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedFile {
+    pub share: FileShare,
+    pub file: FileMetadata,
+}
+
 /// Sync configuration for a directory
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SyncDirectory {
@@ -69,7 +134,9 @@ pub struct SyncDirectory {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
     pub username: String,
-    pub password: String,
+    /// Authentication key derived from the passphrase on the client; the
+    /// passphrase itself never reaches the server
+    pub auth_key: SecretString,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -77,27 +144,195 @@ pub struct LoginResponse {
     pub token: String,
     pub user_id: String,
     pub username: String,
+    #[serde(default)]
+    pub key_material: Option<UserKeyMaterial>,
+}
+
+/// Asks for the KDF descriptor of an account, which the client needs to
+/// derive its authentication key before logging in
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreloginRequest {
+    pub username: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PreloginResponse {
+    pub kdf: KdfDescriptor,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct RegisterRequest {
     pub username: String,
     pub email: String,
-    pub password: String,
+    /// Authentication key derived from the passphrase on the client
+    pub auth_key: SecretString,
+    pub public_key: String,
+    #[serde(default)]
+    pub key_material: Option<UserKeyMaterial>,
+}
+
+/// Replaces the key material after a passphrase change or KDF upgrade
+///
+/// The authentication key is derived from the same passphrase keys as the
+/// key material, so both are replaced together.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateKeyMaterialRequest {
+    /// Authentication key derived from the current passphrase keys
+    pub auth_key: SecretString,
+    /// Authentication key derived from the new passphrase keys
+    pub new_auth_key: SecretString,
+    pub key_material: UserKeyMaterial,
+}
+
+/// Replaces the master key: the new key material and every file's data key
+/// re-wrapped under it, stored together
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RotateKeysRequest {
+    pub key_material: UserKeyMaterial,
+    pub file_keys: Vec<FileKeyUpdate>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileKeyUpdate {
+    pub file_id: String,
+    /// Replaced version the key belongs to; `None` for the file itself
+    #[serde(default)]
+    pub version_id: Option<String>,
+    pub wrapped_key: String,
+}
+
+/// A data key the account has stored, in a file (live or deleted) or in one
+/// of its replaced versions
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredFileKey {
+    pub file_id: String,
+    pub version_id: Option<String>,
+    /// `None` for content encrypted before per-file keys
+    pub wrapped_key: Option<String>,
+}
+
+/// Stores a recovery verifier together with the key material it protects
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EnableRecoveryRequest {
+    pub recovery_verifier: SecretString,
+    pub key_material: UserKeyMaterial,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoveryKeyMaterialRequest {
+    pub username: String,
+    pub recovery_verifier: SecretString,
+}
+
+/// Resets the password and key material of an account using its recovery key
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecoverAccountRequest {
+    pub username: String,
+    pub recovery_verifier: SecretString,
+    /// Authentication key derived from the new passphrase
+    pub new_auth_key: SecretString,
+    pub key_material: UserKeyMaterial,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyMaterialResponse {
+    pub key_material: UserKeyMaterial,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicKeyResponse {
+    pub user_id: String,
+    pub username: String,
     pub public_key: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateShareRequest {
+    pub file_id: String,
+    pub recipient_username: String,
+    pub wrapped_key: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadFileRequest {
+    /// Encrypted path
     pub path: String,
     pub size: u64,
+    /// Keyed hash of the plaintext path; uploads to a path that already
+    /// has a file commit a new version of it instead of creating a duplicate
+    #[serde(default)]
+    pub path_token: Option<String>,
+    /// Keyed fingerprint of the plaintext content
+    #[serde(default)]
+    pub content_fingerprint: Option<String>,
+}
+
+/// Moves a file to a new path without touching its content
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MoveFileRequest {
+    /// Encrypted destination path
+    pub path: String,
+    /// Keyed hash of the plaintext destination path
+    pub path_token: String,
+}
+
+/// Deletes a file along with the manifest signed without it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteFileRequest {
+    /// Stored in the same transaction as the delete
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UploadChunkRequest {
     pub file_id: String,
-    pub chunk_index: u32,
+    /// Position of a stream-encrypted chunk in its file; content-defined
+    /// chunks are placed by the commit instead and leave this out
+    #[serde(default)]
+    pub chunk_index: Option<u32>,
+    /// Serialized `crypto::Envelope`
     pub encrypted_data: Vec<u8>,
+    /// Keyed fingerprint of a content-defined chunk; such chunks go to the
+    /// user's chunk store and are placed in files by [`CommitChunksRequest`]
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+}
+
+/// Asks which chunk fingerprints the server does not hold for the user yet
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingChunksRequest {
+    pub fingerprints: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MissingChunksResponse {
+    pub missing: Vec<String>,
+}
+
+/// Sets the content of a file to the given chunks, in order
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommitChunksRequest {
+    /// Sealed `crypto::chunking::ChunkRecipe`
+    pub recipe: String,
+    pub fingerprints: Vec<String>,
+    /// Plaintext size of the new content
+    pub size: u64,
+    /// Keyed fingerprint of the new content
+    pub content_fingerprint: String,
+    /// Data key the recipe is sealed under, wrapped by the master key;
+    /// `None` keeps the file's current key
+    #[serde(default)]
+    pub wrapped_key: Option<String>,
+    /// Version the new content was based on, 0 for a new file; the commit is
+    /// rejected as a conflict if the file has moved on. `None` overwrites
+    /// whatever is there.
+    #[serde(default)]
+    pub base_version: Option<u64>,
+    /// Manifest signed over the listing with this commit applied, stored in
+    /// the same transaction so no committed file is left unsigned
+    #[serde(default)]
+    pub manifest: Option<SignedManifest>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
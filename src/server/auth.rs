@@ -2,11 +2,15 @@
 
 use axum::{
     extract::Request,
+    http::{HeaderMap, StatusCode},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+    Json,
 };
+use hmac::{Hmac, Mac};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
 const SECRET_KEY: &[u8] = b"rustguard_secret_key_change_in_production";
 
@@ -47,7 +51,47 @@ pub fn verify_token(token: &str) -> crate::error::Result<Claims> {
     .map_err(|e| crate::error::Error::AuthenticationFailed(e.to_string()))
 }
 
-/// Extracts and validates token from request headers
+/// Stable stand-in KDF salt for usernames that have no account
+///
+/// Keyed with the server secret, so a prelogin response does not reveal
+/// whether an account exists.
+pub fn placeholder_salt(username: &str) -> String {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(SECRET_KEY).expect("HMAC accepts keys of any length");
+    mac.update(b"rustguard-prelogin:");
+    mac.update(username.as_bytes());
+    hex::encode(&mac.finalize().into_bytes()[..16])
+}
+
+/// Verifies the bearer token in the `authorization` header
+pub fn claims_from_headers(headers: &HeaderMap) -> crate::error::Result<Claims> {
+    let token = headers
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or(crate::error::Error::AuthenticationFailed("Missing token".to_string()))?;
+
+    verify_token(token)
+}
+
+/// Rejects requests whose bearer token is invalid or expired
+///
+/// Always answered with 401, so clients can tell an expired session from
+/// other failures and log in again. Requests without a token are passed on;
+/// endpoints that need one reject them themselves.
 pub async fn extract_token(req: Request, next: Next) -> Response {
+    let token = req
+        .headers()
+        .get("authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .filter(|token| !token.is_empty());
+
+    if let Some(token) = token {
+        if let Err(e) = verify_token(token) {
+            let msg = serde_json::json!({"error": e.to_string()});
+            return (StatusCode::UNAUTHORIZED, Json(msg)).into_response();
+        }
+    }
+
     next.run(req).await
 }
@@ -1,9 +1,11 @@
 //! Database module for RustGuard
 
+use crate::crypto::manifest::SignedManifest;
 use crate::error::{Error, Result};
 use crate::models::*;
-use chrono::Utc;
-use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqlitePool, SqlitePoolOptions};
+use std::collections::HashSet;
 use std::str::FromStr;
 use uuid::Uuid;
 
@@ -40,6 +42,7 @@ impl Database {
                 email TEXT UNIQUE NOT NULL,
                 password_hash TEXT NOT NULL,
                 public_key TEXT NOT NULL,
+                key_material TEXT,
                 created_at TEXT NOT NULL,
                 updated_at TEXT NOT NULL
             )
@@ -49,6 +52,9 @@ impl Database {
         .await
         .map_err(|e| Error::DatabaseError(e.to_string()))?;
 
+        self.add_column_if_missing("users", "key_material", "TEXT").await?;
+        self.add_column_if_missing("users", "recovery_verifier_hash", "TEXT").await?;
+
         sqlx::query(
             r#"
             CREATE TABLE IF NOT EXISTS file_metadata (
@@ -56,9 +62,12 @@ impl Database {
                 user_id TEXT NOT NULL,
                 path TEXT NOT NULL,
                 name TEXT NOT NULL,
+                path_token TEXT,
                 size INTEGER NOT NULL,
                 encrypted_hash TEXT NOT NULL,
                 chunk_count INTEGER NOT NULL,
+                version INTEGER NOT NULL DEFAULT 1,
+                wrapped_key TEXT,
                 created_at TEXT NOT NULL,
                 updated_at TEXT NOT NULL,
                 is_deleted BOOLEAN NOT NULL DEFAULT 0,
@@ -70,6 +79,16 @@ impl Database {
         .await
         .map_err(|e| Error::DatabaseError(e.to_string()))?;
 
+        self.add_column_if_missing("file_metadata", "wrapped_key", "TEXT").await?;
+        self.add_column_if_missing("file_metadata", "path_token", "TEXT").await?;
+        self.add_column_if_missing("file_metadata", "version", "INTEGER NOT NULL DEFAULT 1").await?;
+        self.add_column_if_missing("file_metadata", "chunk_recipe", "TEXT").await?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_file_metadata_path_token ON file_metadata (user_id, path_token)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
         sqlx::query(
             r#"
             CREATE TABLE IF NOT EXISTS file_chunks (
@@ -87,6 +106,48 @@ impl Database {
         .await
         .map_err(|e| Error::DatabaseError(e.to_string()))?;
 
+        // Content-defined chunks, stored once per user and shared by every
+        // file that references them
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS chunk_store (
+                user_id TEXT NOT NULL,
+                fingerprint TEXT NOT NULL,
+                encrypted_data BLOB NOT NULL,
+                size INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                ref_count INTEGER NOT NULL DEFAULT 0,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (user_id, fingerprint),
+                FOREIGN KEY (user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS file_chunk_refs (
+                id TEXT PRIMARY KEY,
+                file_id TEXT NOT NULL,
+                chunk_index INTEGER NOT NULL,
+                fingerprint TEXT NOT NULL,
+                UNIQUE (file_id, chunk_index),
+                FOREIGN KEY (file_id) REFERENCES file_metadata(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_file_chunk_refs_fingerprint ON file_chunk_refs (fingerprint)")
+            .execute(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
         sqlx::query(
             r#"
             CREATE TABLE IF NOT EXISTS file_versions (
@@ -94,6 +155,10 @@ impl Database {
                 file_id TEXT NOT NULL,
                 version_number INTEGER NOT NULL,
                 size INTEGER NOT NULL,
+                encrypted_hash TEXT NOT NULL DEFAULT '',
+                chunk_count INTEGER NOT NULL DEFAULT 0,
+                wrapped_key TEXT,
+                chunk_recipe TEXT,
                 created_at TEXT NOT NULL,
                 created_by TEXT NOT NULL,
                 FOREIGN KEY (file_id) REFERENCES file_metadata(id)
@@ -104,6 +169,29 @@ impl Database {
         .await
         .map_err(|e| Error::DatabaseError(e.to_string()))?;
 
+        self.add_column_if_missing("file_versions", "encrypted_hash", "TEXT NOT NULL DEFAULT ''").await?;
+        self.add_column_if_missing("file_versions", "chunk_count", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.add_column_if_missing("file_versions", "wrapped_key", "TEXT").await?;
+        self.add_column_if_missing("file_versions", "chunk_recipe", "TEXT").await?;
+
+        // Chunk references of replaced versions; they keep holding a
+        // reference count so the chunks stay available
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS file_version_chunks (
+                id TEXT PRIMARY KEY,
+                version_id TEXT NOT NULL,
+                chunk_index INTEGER NOT NULL,
+                fingerprint TEXT NOT NULL,
+                UNIQUE (version_id, chunk_index),
+                FOREIGN KEY (version_id) REFERENCES file_versions(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
         sqlx::query(
             r#"
             CREATE TABLE IF NOT EXISTS sync_directories (
@@ -122,22 +210,78 @@ impl Database {
         .await
         .map_err(|e| Error::DatabaseError(e.to_string()))?;
 
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS shares (
+                id TEXT PRIMARY KEY,
+                file_id TEXT NOT NULL,
+                owner_id TEXT NOT NULL,
+                recipient_id TEXT NOT NULL,
+                wrapped_key TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                UNIQUE (file_id, recipient_id),
+                FOREIGN KEY (file_id) REFERENCES file_metadata(id),
+                FOREIGN KEY (owner_id) REFERENCES users(id),
+                FOREIGN KEY (recipient_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS manifests (
+                user_id TEXT PRIMARY KEY,
+                signed_manifest TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES users(id)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Adds a column to a table created by an older release
+    async fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+        let exists = sqlx::query_as::<_, (String,)>(
+            "SELECT name FROM pragma_table_info(?) WHERE name = ?"
+        )
+        .bind(table)
+        .bind(column)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if exists.is_none() {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+                .execute(&self.pool)
+                .await
+                .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        }
+
         Ok(())
     }
 
     /// Creates a new user
-    pub async fn create_user(&self, username: &str, email: &str, password_hash: &str, public_key: &str) -> Result<User> {
+    pub async fn create_user(&self, username: &str, email: &str, password_hash: &str, public_key: &str, key_material: Option<&UserKeyMaterial>) -> Result<User> {
         let id = Uuid::new_v4().to_string();
         let now = Utc::now();
 
         sqlx::query(
-            "INSERT INTO users (id, username, email, password_hash, public_key, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
+            "INSERT INTO users (id, username, email, password_hash, public_key, key_material, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
         )
         .bind(&id)
         .bind(username)
         .bind(email)
         .bind(password_hash)
         .bind(public_key)
+        .bind(key_material.map(encode_key_material).transpose()?)
         .bind(now.to_rfc3339())
         .bind(now.to_rfc3339())
         .execute(&self.pool)
@@ -150,6 +294,7 @@ impl Database {
             email: email.to_string(),
             password_hash: password_hash.to_string(),
             public_key: public_key.to_string(),
+            key_material: key_material.cloned(),
             created_at: now,
             updated_at: now,
         })
@@ -157,42 +302,169 @@ impl Database {
 
     /// Retrieves a user by username
     pub async fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
-        let user = sqlx::query_as::<_, (String, String, String, String, String, String, String)>(
-            "SELECT id, username, email, password_hash, public_key, created_at, updated_at FROM users WHERE username = ?"
+        let user = sqlx::query_as::<_, (String, String, String, String, String, Option<String>, String, String)>(
+            "SELECT id, username, email, password_hash, public_key, key_material, created_at, updated_at FROM users WHERE username = ?"
         )
         .bind(username)
         .fetch_optional(&self.pool)
         .await
         .map_err(|e| Error::DatabaseError(e.to_string()))?;
 
-        Ok(user.map(|(id, username, email, password_hash, public_key, created_at, updated_at)| {
-            User {
+        user.map(|(id, username, email, password_hash, public_key, key_material, created_at, updated_at)| {
+            Ok(User {
                 id,
                 username,
                 email,
                 password_hash,
                 public_key,
+                key_material: key_material.as_deref().map(decode_key_material).transpose()?,
                 created_at: created_at.parse().unwrap_or_else(|_| Utc::now()),
                 updated_at: updated_at.parse().unwrap_or_else(|_| Utc::now()),
-            }
-        }))
+            })
+        })
+        .transpose()
+    }
+
+    /// Retrieves the hashed authentication key of a user
+    pub async fn get_password_hash(&self, user_id: &str) -> Result<Option<String>> {
+        let row = sqlx::query_as::<_, (String,)>("SELECT password_hash FROM users WHERE id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(row.map(|(hash,)| hash))
     }
 
-    /// Creates file metadata
-    pub async fn create_file_metadata(&self, user_id: &str, path: &str, name: &str, size: u64, encrypted_hash: &str, chunk_count: u32) -> Result<FileMetadata> {
+    /// Stores new key material together with every re-wrapped data key
+    ///
+    /// Fails without changing anything unless `file_keys` covers every key
+    /// [`Database::list_file_keys`] returns, so a file or version committed
+    /// meanwhile is never left wrapped under a master key that no longer
+    /// exists.
+    pub async fn rotate_keys(&self, user_id: &str, key_material: &UserKeyMaterial, file_keys: &[FileKeyUpdate]) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+        let mut tx = self.pool.begin().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        let mut updated = 0;
+        for file_key in file_keys {
+            let result = match &file_key.version_id {
+                Some(version_id) => sqlx::query(
+                    "UPDATE file_versions SET wrapped_key = ? WHERE id = ? AND file_id = ? AND file_id IN (SELECT id FROM file_metadata WHERE user_id = ?)"
+                )
+                .bind(&file_key.wrapped_key)
+                .bind(version_id)
+                .bind(&file_key.file_id)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await,
+                None => sqlx::query(
+                    "UPDATE file_metadata SET wrapped_key = ?, updated_at = ? WHERE id = ? AND user_id = ? AND version > 0"
+                )
+                .bind(&file_key.wrapped_key)
+                .bind(&now)
+                .bind(&file_key.file_id)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await,
+            };
+            updated += result.map_err(|e| Error::DatabaseError(e.to_string()))?.rows_affected();
+        }
+
+        let (stored,): (i64,) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM file_metadata WHERE user_id = ? AND version > 0) + (SELECT COUNT(*) FROM file_versions v JOIN file_metadata f ON f.id = v.file_id WHERE f.user_id = ?)"
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if updated != stored as u64 {
+            return Err(Error::ConflictError(
+                "Files changed while the master key was rotated; try again".to_string(),
+            ));
+        }
+
+        sqlx::query("UPDATE users SET key_material = ?, updated_at = ? WHERE id = ?")
+            .bind(encode_key_material(key_material)?)
+            .bind(&now)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        tx.commit().await.map_err(|e| Error::DatabaseError(e.to_string()))
+    }
+
+    /// Stores the hashed recovery verifier and the key material it unlocks
+    pub async fn enable_recovery(&self, user_id: &str, verifier_hash: &str, key_material: &UserKeyMaterial) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE users SET recovery_verifier_hash = ?, key_material = ?, updated_at = ? WHERE id = ?"
+        )
+        .bind(verifier_hash)
+        .bind(encode_key_material(key_material)?)
+        .bind(Utc::now().to_rfc3339())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::UserNotFound);
+        }
+        Ok(())
+    }
+
+    /// Retrieves the hashed recovery verifier for a user, if recovery is enabled
+    pub async fn get_recovery_verifier_hash(&self, user_id: &str) -> Result<Option<String>> {
+        let row = sqlx::query_as::<_, (Option<String>,)>(
+            "SELECT recovery_verifier_hash FROM users WHERE id = ?"
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(row.and_then(|(hash,)| hash))
+    }
+
+    /// Replaces a user's password hash and key material together, after a
+    /// recovery or passphrase change
+    pub async fn reset_credentials(&self, user_id: &str, password_hash: &str, key_material: &UserKeyMaterial) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE users SET password_hash = ?, key_material = ?, updated_at = ? WHERE id = ?"
+        )
+        .bind(password_hash)
+        .bind(encode_key_material(key_material)?)
+        .bind(Utc::now().to_rfc3339())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::UserNotFound);
+        }
+        Ok(())
+    }
+
+    /// Creates a pending file that becomes visible once its content is committed
+    ///
+    /// Pending files have version 0 and are left out of listings and lookups.
+    pub async fn create_pending_file(&self, user_id: &str, path: &str, path_token: Option<&str>, size: u64, encrypted_hash: &str) -> Result<FileMetadata> {
         let id = Uuid::new_v4().to_string();
         let now = Utc::now();
 
         sqlx::query(
-            "INSERT INTO file_metadata (id, user_id, path, name, size, encrypted_hash, chunk_count, created_at, updated_at) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            "INSERT INTO file_metadata (id, user_id, path, name, path_token, size, encrypted_hash, chunk_count, version, created_at, updated_at) VALUES (?, ?, ?, '', ?, ?, ?, 0, 0, ?, ?)"
         )
         .bind(&id)
         .bind(user_id)
         .bind(path)
-        .bind(name)
+        .bind(path_token)
         .bind(size as i64)
         .bind(encrypted_hash)
-        .bind(chunk_count as i32)
         .bind(now.to_rfc3339())
         .bind(now.to_rfc3339())
         .execute(&self.pool)
@@ -203,39 +475,749 @@ impl Database {
             id,
             user_id: user_id.to_string(),
             path: path.to_string(),
-            name: name.to_string(),
+            name: String::new(),
+            path_token: path_token.map(str::to_string),
             size,
             encrypted_hash: encrypted_hash.to_string(),
-            chunk_count,
+            chunk_count: 0,
+            version: 0,
+            wrapped_key: None,
+            chunk_recipe: None,
             created_at: now,
             updated_at: now,
             is_deleted: false,
         })
     }
 
+    /// Lists every data key a user has stored: those of live and deleted
+    /// files and of their replaced versions
+    pub async fn list_file_keys(&self, user_id: &str) -> Result<Vec<StoredFileKey>> {
+        let keys = sqlx::query_as::<_, (String, Option<String>, Option<String>)>(
+            "SELECT id, NULL, wrapped_key FROM file_metadata WHERE user_id = ? AND version > 0 UNION ALL SELECT v.file_id, v.id, v.wrapped_key FROM file_versions v JOIN file_metadata f ON f.id = v.file_id WHERE f.user_id = ?"
+        )
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(keys.into_iter().map(|(file_id, version_id, wrapped_key)| StoredFileKey {
+            file_id,
+            version_id,
+            wrapped_key,
+        }).collect())
+    }
+
     /// Lists all files for a user
     pub async fn list_user_files(&self, user_id: &str) -> Result<Vec<FileMetadata>> {
-        let files = sqlx::query_as::<_, (String, String, String, String, i64, String, i32, String, String, bool)>(
-            "SELECT id, user_id, path, name, size, encrypted_hash, chunk_count, created_at, updated_at, is_deleted FROM file_metadata WHERE user_id = ? AND is_deleted = 0"
+        let files = sqlx::query_as::<_, FileRow>(&format!(
+            "SELECT {} FROM file_metadata WHERE user_id = ? AND is_deleted = 0 AND version > 0",
+            FILE_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(files.into_iter().map(file_from_row).collect())
+    }
+
+    /// Retrieves file metadata owned by a user
+    pub async fn get_file_metadata(&self, user_id: &str, file_id: &str) -> Result<Option<FileMetadata>> {
+        let file = sqlx::query_as::<_, FileRow>(&format!(
+            "SELECT {} FROM file_metadata WHERE id = ? AND user_id = ?",
+            FILE_COLUMNS
+        ))
+        .bind(file_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(file.map(file_from_row))
+    }
+
+    /// Finds a live file by its path lookup token
+    pub async fn find_file_by_token(&self, user_id: &str, path_token: &str) -> Result<Option<FileMetadata>> {
+        let file = sqlx::query_as::<_, FileRow>(&format!(
+            "SELECT {} FROM file_metadata WHERE user_id = ? AND path_token = ? AND is_deleted = 0 AND version > 0",
+            FILE_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(path_token)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(file.map(file_from_row))
+    }
+
+    /// Finds the file an upload to `path_token` should go to
+    ///
+    /// That is the live file at the path, or else a pending file left there
+    /// by an earlier upload that never committed.
+    pub async fn find_upload_target(&self, user_id: &str, path_token: &str) -> Result<Option<FileMetadata>> {
+        let file = sqlx::query_as::<_, FileRow>(&format!(
+            "SELECT {} FROM file_metadata WHERE user_id = ? AND path_token = ? AND is_deleted = 0 ORDER BY version DESC LIMIT 1",
+            FILE_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(path_token)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(file.map(file_from_row))
+    }
+
+    /// Moves a live file to a new path, keeping its id, content and version
+    ///
+    /// Fails if another live file already holds the destination path.
+    pub async fn move_file(&self, user_id: &str, file_id: &str, path: &str, path_token: &str) -> Result<FileMetadata> {
+        let mut tx = self.pool.begin().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        let taken: Option<(String,)> = sqlx::query_as(
+            "SELECT id FROM file_metadata WHERE user_id = ? AND path_token = ? AND is_deleted = 0 AND version > 0 AND id != ?"
+        )
+        .bind(user_id)
+        .bind(path_token)
+        .bind(file_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        if taken.is_some() {
+            return Err(Error::InvalidInput("A file already exists at the destination".to_string()));
+        }
+
+        let result = sqlx::query(
+            "UPDATE file_metadata SET path = ?, path_token = ?, updated_at = ? WHERE id = ? AND user_id = ? AND is_deleted = 0 AND version > 0"
+        )
+        .bind(path)
+        .bind(path_token)
+        .bind(Utc::now().to_rfc3339())
+        .bind(file_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        if result.rows_affected() == 0 {
+            return Err(Error::FileNotFound(file_id.to_string()));
+        }
+
+        let file = sqlx::query_as::<_, FileRow>(&format!("SELECT {} FROM file_metadata WHERE id = ?", FILE_COLUMNS))
+            .bind(file_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        tx.commit().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+        Ok(file_from_row(file))
+    }
+
+    /// Marks a file as deleted; its chunks and versions are kept
+    ///
+    /// `manifest`, if given, is stored in the same transaction.
+    pub async fn delete_file(&self, user_id: &str, file_id: &str, manifest: Option<&SignedManifest>) -> Result<()> {
+        let mut tx = self.pool.begin().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if let Some(manifest) = manifest {
+            Self::write_manifest(&mut tx, user_id, manifest).await?;
+        }
+
+        let result = sqlx::query(
+            "UPDATE file_metadata SET is_deleted = 1, updated_at = ? WHERE id = ? AND user_id = ? AND is_deleted = 0 AND version > 0"
+        )
+        .bind(Utc::now().to_rfc3339())
+        .bind(file_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::FileNotFound(file_id.to_string()));
+        }
+
+        tx.commit().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Retrieves file metadata the user owns or has been shared
+    pub async fn get_accessible_file(&self, user_id: &str, file_id: &str) -> Result<Option<FileMetadata>> {
+        let file = sqlx::query_as::<_, FileRow>(&format!(
+            "SELECT {} FROM file_metadata WHERE id = ? AND version > 0 AND (user_id = ? OR id IN (SELECT file_id FROM shares WHERE recipient_id = ?))",
+            FILE_COLUMNS
+        ))
+        .bind(file_id)
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(file.map(file_from_row))
+    }
+
+    /// Stores an encrypted chunk, replacing any previous chunk at the same index
+    pub async fn store_chunk(&self, file_id: &str, chunk_index: u32, encrypted_data: &[u8], hash: &str) -> Result<FileChunk> {
+        let id = Uuid::new_v4().to_string();
+
+        sqlx::query("DELETE FROM file_chunks WHERE file_id = ? AND chunk_index = ?")
+            .bind(file_id)
+            .bind(chunk_index as i32)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            "INSERT INTO file_chunks (id, file_id, chunk_index, encrypted_data, size, hash) VALUES (?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&id)
+        .bind(file_id)
+        .bind(chunk_index as i32)
+        .bind(encrypted_data)
+        .bind(encrypted_data.len() as i32)
+        .bind(hash)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            "UPDATE file_metadata SET chunk_count = MAX(chunk_count, ?), updated_at = ? WHERE id = ?"
+        )
+        .bind(chunk_index as i32 + 1)
+        .bind(Utc::now().to_rfc3339())
+        .bind(file_id)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(FileChunk {
+            id,
+            file_id: file_id.to_string(),
+            chunk_index,
+            encrypted_data: encrypted_data.to_vec(),
+            size: encrypted_data.len() as u32,
+            hash: hash.to_string(),
+        })
+    }
+
+    /// Adds a content-defined chunk to the user's chunk store
+    ///
+    /// Returns false if the user already stores a chunk with this
+    /// fingerprint; the stored copy is kept. New chunks are unreferenced
+    /// until a file commits them.
+    pub async fn store_content_chunk(&self, user_id: &str, fingerprint: &str, encrypted_data: &[u8], hash: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO chunk_store (user_id, fingerprint, encrypted_data, size, hash, ref_count, updated_at) VALUES (?, ?, ?, ?, ?, 0, ?)"
+        )
+        .bind(user_id)
+        .bind(fingerprint)
+        .bind(encrypted_data)
+        .bind(encrypted_data.len() as i32)
+        .bind(hash)
+        .bind(Utc::now().to_rfc3339())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns the fingerprints missing from the user's chunk store
+    pub async fn missing_chunks(&self, user_id: &str, fingerprints: &[String]) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+
+        for batch in fingerprints.chunks(FINGERPRINT_QUERY_BATCH) {
+            let placeholders = vec!["?"; batch.len()].join(", ");
+            let query = format!(
+                "SELECT fingerprint FROM chunk_store WHERE user_id = ? AND fingerprint IN ({})",
+                placeholders
+            );
+
+            let mut found = sqlx::query_as::<_, (String,)>(&query).bind(user_id);
+            for fingerprint in batch {
+                found = found.bind(fingerprint);
+            }
+            let found: HashSet<String> = found
+                .fetch_all(&self.pool)
+                .await
+                .map_err(|e| Error::DatabaseError(e.to_string()))?
+                .into_iter()
+                .map(|(fingerprint,)| fingerprint)
+                .collect();
+
+            missing.extend(batch.iter().filter(|fingerprint| !found.contains(*fingerprint)).cloned());
+        }
+
+        Ok(missing)
+    }
+
+    /// Makes committed content the current version of a file
+    ///
+    /// In one transaction, the file's current content is recorded in
+    /// `file_versions`, its chunk references move to that version, and the
+    /// new references, metadata and (if given) wrapped data key take its
+    /// place under the next version number, and the commit's manifest is
+    /// stored. Until then, the file keeps serving its previous content.
+    /// Returns the updated file.
+    ///
+    /// References of replaced versions keep their chunks alive; only the
+    /// newest [`MAX_FILE_VERSIONS`] are kept. Chunks left unreferenced are deleted once they have been unused for
+    /// [`UNREFERENCED_CHUNK_GRACE`], which gives uploads in progress time to
+    /// commit chunks they were told the server already has.
+    pub async fn commit_chunks(&self, user_id: &str, file_id: &str, commit: &CommitChunksRequest) -> Result<FileMetadata> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        // Written first, so the transaction holds the write lock before it
+        // reads anything another commit could change
+        if let Some(manifest) = &commit.manifest {
+            Self::write_manifest(&mut tx, user_id, manifest).await?;
+        }
+
+        let current = sqlx::query_as::<_, FileRow>(&format!(
+            "SELECT {} FROM file_metadata WHERE id = ? AND user_id = ? AND is_deleted = 0",
+            FILE_COLUMNS
+        ))
+        .bind(file_id)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?
+        .map(file_from_row)
+        .ok_or_else(|| Error::FileNotFound(file_id.to_string()))?;
+
+        if let Some(base_version) = commit.base_version {
+            if base_version != current.version {
+                return Err(Error::ConflictError(format!(
+                    "File is at version {}, not {}",
+                    current.version, base_version
+                )));
+            }
+        }
+
+        let distinct: HashSet<&String> = commit.fingerprints.iter().collect();
+        for fingerprint in distinct {
+            let stored = sqlx::query_as::<_, (i64,)>("SELECT 1 FROM chunk_store WHERE user_id = ? AND fingerprint = ?")
+                .bind(user_id)
+                .bind(fingerprint)
+                .fetch_optional(&mut *tx)
+                .await
+                .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+            if stored.is_none() {
+                return Err(Error::InvalidInput(format!("Chunk {} has not been uploaded", fingerprint)));
+            }
+        }
+
+        if current.version == 0 {
+            // Another device may have created a file at the same path meanwhile
+            let taken: Option<(String,)> = sqlx::query_as(
+                "SELECT id FROM file_metadata WHERE user_id = ? AND path_token = ? AND is_deleted = 0 AND version > 0 AND id != ?"
+            )
+            .bind(user_id)
+            .bind(&current.path_token)
+            .bind(file_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+            if taken.is_some() {
+                return Err(Error::ConflictError("Another file was created at this path".to_string()));
+            }
+        } else {
+            let version_id = Uuid::new_v4().to_string();
+            sqlx::query(
+                "INSERT INTO file_versions (id, file_id, version_number, size, encrypted_hash, chunk_count, wrapped_key, chunk_recipe, created_at, created_by) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+            )
+            .bind(&version_id)
+            .bind(file_id)
+            .bind(current.version as i64)
+            .bind(current.size as i64)
+            .bind(&current.encrypted_hash)
+            .bind(current.chunk_count as i32)
+            .bind(&current.wrapped_key)
+            .bind(&current.chunk_recipe)
+            .bind(current.updated_at.to_rfc3339())
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+            sqlx::query(
+                "INSERT INTO file_version_chunks (id, version_id, chunk_index, fingerprint) SELECT id, ?, chunk_index, fingerprint FROM file_chunk_refs WHERE file_id = ?"
+            )
+            .bind(&version_id)
+            .bind(file_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+            Self::prune_versions(&mut tx, user_id, file_id, current.version as i64 - MAX_FILE_VERSIONS, now).await?;
+        }
+
+        sqlx::query("DELETE FROM file_chunk_refs WHERE file_id = ?")
+            .bind(file_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        for (index, fingerprint) in commit.fingerprints.iter().enumerate() {
+            sqlx::query("INSERT INTO file_chunk_refs (id, file_id, chunk_index, fingerprint) VALUES (?, ?, ?, ?)")
+                .bind(Uuid::new_v4().to_string())
+                .bind(file_id)
+                .bind(index as i32)
+                .bind(fingerprint)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+            sqlx::query("UPDATE chunk_store SET ref_count = ref_count + 1, updated_at = ? WHERE user_id = ? AND fingerprint = ?")
+                .bind(now.to_rfc3339())
+                .bind(user_id)
+                .bind(fingerprint)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        }
+
+        let updated = sqlx::query(
+            "UPDATE file_metadata SET size = ?, encrypted_hash = ?, chunk_count = ?, chunk_recipe = ?, wrapped_key = COALESCE(?, wrapped_key), version = version + 1, updated_at = ? WHERE id = ? AND version = ?"
         )
+        .bind(commit.size as i64)
+        .bind(&commit.content_fingerprint)
+        .bind(commit.fingerprints.len() as i32)
+        .bind(&commit.recipe)
+        .bind(&commit.wrapped_key)
+        .bind(now.to_rfc3339())
+        .bind(file_id)
+        .bind(current.version as i64)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        if updated.rows_affected() == 0 {
+            return Err(Error::ConflictError("File was changed by another upload".to_string()));
+        }
+
+        sqlx::query("DELETE FROM chunk_store WHERE user_id = ? AND ref_count <= 0 AND updated_at < ?")
+            .bind(user_id)
+            .bind((now - UNREFERENCED_CHUNK_GRACE).to_rfc3339())
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        let file = sqlx::query_as::<_, FileRow>(&format!("SELECT {} FROM file_metadata WHERE id = ?", FILE_COLUMNS))
+            .bind(file_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        tx.commit().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+        Ok(file_from_row(file))
+    }
+
+    /// Deletes the versions of a file numbered `through` and below, and
+    /// releases the chunk references they held
+    async fn prune_versions(conn: &mut SqliteConnection, user_id: &str, file_id: &str, through: i64, now: DateTime<Utc>) -> Result<()> {
+        if through < 1 {
+            return Ok(());
+        }
+
+        sqlx::query(
+            r#"
+            UPDATE chunk_store SET
+                ref_count = ref_count - (
+                    SELECT COUNT(*) FROM file_version_chunks vc JOIN file_versions v ON v.id = vc.version_id
+                    WHERE v.file_id = ? AND v.version_number <= ? AND vc.fingerprint = chunk_store.fingerprint
+                ),
+                updated_at = ?
+            WHERE user_id = ? AND fingerprint IN (
+                SELECT vc.fingerprint FROM file_version_chunks vc JOIN file_versions v ON v.id = vc.version_id
+                WHERE v.file_id = ? AND v.version_number <= ?
+            )
+            "#,
+        )
+        .bind(file_id)
+        .bind(through)
+        .bind(now.to_rfc3339())
+        .bind(user_id)
+        .bind(file_id)
+        .bind(through)
+        .execute(&mut *conn)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query("DELETE FROM file_version_chunks WHERE version_id IN (SELECT id FROM file_versions WHERE file_id = ? AND version_number <= ?)")
+            .bind(file_id)
+            .bind(through)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query("DELETE FROM file_versions WHERE file_id = ? AND version_number <= ?")
+            .bind(file_id)
+            .bind(through)
+            .execute(&mut *conn)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Lists the replaced versions of a file the user owns, oldest first
+    pub async fn list_file_versions(&self, user_id: &str, file_id: &str) -> Result<Vec<FileVersion>> {
+        let versions = sqlx::query_as::<_, (String, String, i64, i64, String, String)>(
+            "SELECT v.id, v.file_id, v.version_number, v.size, v.created_at, v.created_by FROM file_versions v JOIN file_metadata f ON f.id = v.file_id WHERE v.file_id = ? AND f.user_id = ? ORDER BY v.version_number"
+        )
+        .bind(file_id)
         .bind(user_id)
         .fetch_all(&self.pool)
         .await
         .map_err(|e| Error::DatabaseError(e.to_string()))?;
 
-        Ok(files.into_iter().map(|(id, user_id, path, name, size, encrypted_hash, chunk_count, created_at, updated_at, is_deleted)| {
-            FileMetadata {
+        Ok(versions.into_iter().map(|(id, file_id, version_number, size, created_at, created_by)| FileVersion {
+            id,
+            file_id,
+            version_number: version_number as u32,
+            size: size as u64,
+            created_at: created_at.parse().unwrap_or_else(|_| Utc::now()),
+            created_by,
+        }).collect())
+    }
+
+    /// Lists the chunk ids of a file in order
+    ///
+    /// Files with a chunk recipe are listed by their chunk references, older
+    /// files by their stream chunks.
+    pub async fn list_chunk_ids(&self, file: &FileMetadata) -> Result<Vec<String>> {
+        let query = match file.chunk_recipe {
+            Some(_) => "SELECT id FROM file_chunk_refs WHERE file_id = ? ORDER BY chunk_index",
+            None => "SELECT id FROM file_chunks WHERE file_id = ? ORDER BY chunk_index",
+        };
+
+        let ids = sqlx::query_as::<_, (String,)>(query)
+        .bind(&file.id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(ids.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Retrieves a chunk of a file the user owns or has been shared
+    pub async fn get_chunk(&self, user_id: &str, chunk_id: &str) -> Result<Option<FileChunk>> {
+        let chunk = sqlx::query_as::<_, (String, String, i32, Vec<u8>, i32, String)>(
+            "SELECT c.id, c.file_id, c.chunk_index, c.encrypted_data, c.size, c.hash FROM file_chunks c JOIN file_metadata f ON f.id = c.file_id WHERE c.id = ? AND (f.user_id = ? OR f.id IN (SELECT file_id FROM shares WHERE recipient_id = ?))"
+        )
+        .bind(chunk_id)
+        .bind(user_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        // Otherwise the id may name a reference into the owner's chunk store
+        let chunk = match chunk {
+            Some(chunk) => Some(chunk),
+            None => sqlx::query_as::<_, (String, String, i32, Vec<u8>, i32, String)>(
+                "SELECT r.id, r.file_id, r.chunk_index, s.encrypted_data, s.size, s.hash FROM file_chunk_refs r JOIN file_metadata f ON f.id = r.file_id JOIN chunk_store s ON s.user_id = f.user_id AND s.fingerprint = r.fingerprint WHERE r.id = ? AND (f.user_id = ? OR f.id IN (SELECT file_id FROM shares WHERE recipient_id = ?))"
+            )
+            .bind(chunk_id)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?,
+        };
+
+        Ok(chunk.map(|(id, file_id, chunk_index, encrypted_data, size, hash)| FileChunk {
+            id,
+            file_id,
+            chunk_index: chunk_index as u32,
+            encrypted_data,
+            size: size as u32,
+            hash,
+        }))
+    }
+
+    /// Shares a file with another user, replacing any existing share
+    pub async fn create_share(&self, file_id: &str, owner_id: &str, recipient: &User, wrapped_key: &str) -> Result<FileShare> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO shares (id, file_id, owner_id, recipient_id, wrapped_key, created_at) VALUES (?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&id)
+        .bind(file_id)
+        .bind(owner_id)
+        .bind(&recipient.id)
+        .bind(wrapped_key)
+        .bind(now.to_rfc3339())
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(FileShare {
+            id,
+            file_id: file_id.to_string(),
+            owner_id: owner_id.to_string(),
+            recipient_id: recipient.id.clone(),
+            recipient_username: recipient.username.clone(),
+            wrapped_key: wrapped_key.to_string(),
+            created_at: now,
+        })
+    }
+
+    /// Lists shares created by a user
+    pub async fn list_outgoing_shares(&self, owner_id: &str) -> Result<Vec<FileShare>> {
+        self.query_shares("WHERE s.owner_id = ?", owner_id)
+            .await
+    }
+
+    /// Lists files shared with a user
+    pub async fn list_incoming_shares(&self, recipient_id: &str) -> Result<Vec<SharedFile>> {
+        let shares = self
+            .query_shares("WHERE s.recipient_id = ?", recipient_id)
+            .await?;
+
+        let mut shared = Vec::with_capacity(shares.len());
+        for share in shares {
+            if let Some(file) = self.get_file_metadata(&share.owner_id, &share.file_id).await? {
+                if !file.is_deleted {
+                    shared.push(SharedFile { share, file });
+                }
+            }
+        }
+        Ok(shared)
+    }
+
+    async fn query_shares(&self, filter: &str, user_id: &str) -> Result<Vec<FileShare>> {
+        let query = format!(
+            "SELECT s.id, s.file_id, s.owner_id, s.recipient_id, u.username, s.wrapped_key, s.created_at FROM shares s JOIN users u ON u.id = s.recipient_id {}",
+            filter
+        );
+        let shares = sqlx::query_as::<_, (String, String, String, String, String, String, String)>(&query)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(shares.into_iter().map(|(id, file_id, owner_id, recipient_id, recipient_username, wrapped_key, created_at)| {
+            FileShare {
                 id,
-                user_id,
-                path,
-                name,
-                size: size as u64,
-                encrypted_hash,
-                chunk_count: chunk_count as u32,
+                file_id,
+                owner_id,
+                recipient_id,
+                recipient_username,
+                wrapped_key,
                 created_at: created_at.parse().unwrap_or_else(|_| Utc::now()),
-                updated_at: updated_at.parse().unwrap_or_else(|_| Utc::now()),
-                is_deleted,
             }
         }).collect())
     }
+
+    /// Revokes a share created by `owner_id`
+    pub async fn delete_share(&self, owner_id: &str, share_id: &str) -> Result<()> {
+        let result = sqlx::query("DELETE FROM shares WHERE id = ? AND owner_id = ?")
+            .bind(share_id)
+            .bind(owner_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::InvalidInput(format!("Share not found: {}", share_id)));
+        }
+        Ok(())
+    }
+
+    /// Stores the latest signed manifest for a user, replacing the previous one
+    pub async fn store_manifest(&self, user_id: &str, manifest: &SignedManifest) -> Result<()> {
+        let mut conn = self.pool.acquire().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+        Self::write_manifest(&mut conn, user_id, manifest).await
+    }
+
+    /// Writes a manifest on `conn`, which may be inside a transaction
+    ///
+    /// Sequence numbers only move forward: the comparison and the write are
+    /// one statement, so of two devices signing the same sequence at once
+    /// exactly one wins and the other gets [`Error::ConflictError`].
+    async fn write_manifest(conn: &mut SqliteConnection, user_id: &str, manifest: &SignedManifest) -> Result<()> {
+        let encoded = serde_json::to_string(manifest).map_err(|e| Error::SerializationError(e.to_string()))?;
+
+        let result = sqlx::query(
+            "INSERT INTO manifests (user_id, signed_manifest, updated_at) VALUES (?, ?, ?) ON CONFLICT(user_id) DO UPDATE SET signed_manifest = excluded.signed_manifest, updated_at = excluded.updated_at WHERE COALESCE(json_extract(manifests.signed_manifest, '$.manifest.sequence'), 0) < ?"
+        )
+        .bind(user_id)
+        .bind(encoded)
+        .bind(Utc::now().to_rfc3339())
+        .bind(manifest.manifest.sequence as i64)
+        .execute(conn)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::ConflictError(format!(
+                "Manifest {} is not newer than the stored manifest; sync and try again",
+                manifest.manifest.sequence
+            )));
+        }
+        Ok(())
+    }
+
+    /// Retrieves the latest signed manifest for a user
+    pub async fn get_manifest(&self, user_id: &str) -> Result<Option<SignedManifest>> {
+        let row = sqlx::query_as::<_, (String,)>("SELECT signed_manifest FROM manifests WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        row.map(|(encoded,)| {
+            serde_json::from_str(&encoded).map_err(|e| Error::SerializationError(e.to_string()))
+        })
+        .transpose()
+    }
+}
+
+/// Fingerprints looked up per query, well below SQLite's variable limit
+const FINGERPRINT_QUERY_BATCH: usize = 500;
+
+/// Replaced versions kept per file; older ones are pruned on commit
+pub const MAX_FILE_VERSIONS: i64 = 32;
+
+/// How long an unreferenced chunk is kept before it is deleted
+pub const UNREFERENCED_CHUNK_GRACE: chrono::Duration = chrono::Duration::hours(24);
+
+const FILE_COLUMNS: &str = "id, user_id, path, name, path_token, size, encrypted_hash, chunk_count, version, wrapped_key, chunk_recipe, created_at, updated_at, is_deleted";
+
+type FileRow = (String, String, String, String, Option<String>, i64, String, i32, i64, Option<String>, Option<String>, String, String, bool);
+
+fn file_from_row(row: FileRow) -> FileMetadata {
+    let (id, user_id, path, name, path_token, size, encrypted_hash, chunk_count, version, wrapped_key, chunk_recipe, created_at, updated_at, is_deleted) = row;
+
+    FileMetadata {
+        id,
+        user_id,
+        path,
+        name,
+        path_token,
+        size: size as u64,
+        encrypted_hash,
+        chunk_count: chunk_count as u32,
+        version: version as u64,
+        wrapped_key,
+        chunk_recipe,
+        created_at: created_at.parse().unwrap_or_else(|_| Utc::now()),
+        updated_at: updated_at.parse().unwrap_or_else(|_| Utc::now()),
+        is_deleted,
+    }
+}
+
+fn encode_key_material(key_material: &UserKeyMaterial) -> Result<String> {
+    serde_json::to_string(key_material).map_err(|e| Error::SerializationError(e.to_string()))
+}
+
+fn decode_key_material(json: &str) -> Result<UserKeyMaterial> {
+    serde_json::from_str(json).map_err(|e| Error::SerializationError(e.to_string()))
 }
@@ -1,6 +1,7 @@
 //! API handlers for RustGuard server
 
-use crate::crypto;
+use crate::crypto::manifest::SignedManifest;
+use crate::crypto::{self, KdfDescriptor, KdfParams, SecretString};
 use crate::error::{Error, Result};
 use crate::models::*;
 use crate::server::auth;
@@ -36,13 +37,19 @@ pub async fn register(
 }
 
 async fn _register(state: &ServerState, req: &RegisterRequest) -> Result<serde_json::Value> {
-    // Hash password
-    let password_hash = crypto::hash_password(&req.password)?;
+    // Hash the authentication key; the passphrase never reaches the server
+    let password_hash = crypto::hash_password(&req.auth_key)?;
 
     // Create user in database
     let user = state
         .db
-        .create_user(&req.username, &req.email, &password_hash, &req.public_key)
+        .create_user(
+            &req.username,
+            &req.email,
+            &password_hash,
+            &req.public_key,
+            req.key_material.as_ref(),
+        )
         .await?;
 
     // Generate token
@@ -58,6 +65,42 @@ async fn _register(state: &ServerState, req: &RegisterRequest) -> Result<serde_j
     }))
 }
 
+/// Prelogin endpoint, returning the KDF descriptor a client needs to
+/// derive its authentication key
+///
+/// Unknown usernames get a stable made-up descriptor, so the response does
+/// not reveal which accounts exist.
+pub async fn prelogin(
+    State(state): State<ServerState>,
+    Json(req): Json<PreloginRequest>,
+) -> impl IntoResponse {
+    match _prelogin(&state, &req).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _prelogin(state: &ServerState, req: &PreloginRequest) -> Result<PreloginResponse> {
+    let key_material = state
+        .db
+        .get_user_by_username(&req.username)
+        .await?
+        .and_then(|user| user.key_material);
+
+    let kdf = match key_material {
+        Some(key_material) => key_material.kdf,
+        None => KdfDescriptor {
+            salt: auth::placeholder_salt(&req.username),
+            ..KdfDescriptor::new(KdfParams::default())
+        },
+    };
+
+    Ok(PreloginResponse { kdf })
+}
+
 /// User login endpoint
 pub async fn login(
     State(state): State<ServerState>,
@@ -80,8 +123,8 @@ async fn _login(state: &ServerState, req: &LoginRequest) -> Result<serde_json::V
         .await?
         .ok_or(Error::UserNotFound)?;
 
-    // Verify password
-    if !crypto::verify_password(&req.password, &user.password_hash)? {
+    // Verify the authentication key
+    if !crypto::verify_password(&req.auth_key, &user.password_hash)? {
         return Err(Error::InvalidCredentials);
     }
 
@@ -93,7 +136,218 @@ async fn _login(state: &ServerState, req: &LoginRequest) -> Result<serde_json::V
     Ok(json!({
         "token": token,
         "user_id": user.id,
-        "username": user.username
+        "username": user.username,
+        "key_material": user.key_material
+    }))
+}
+
+/// Key material update endpoint
+pub async fn update_key_material(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(req): Json<UpdateKeyMaterialRequest>,
+) -> impl IntoResponse {
+    match _update_key_material(&state, &headers, &req).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _update_key_material(
+    state: &ServerState,
+    headers: &HeaderMap,
+    req: &UpdateKeyMaterialRequest,
+) -> Result<serde_json::Value> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    // A session token alone must not be enough to change the passphrase
+    let password_hash = state.db.get_password_hash(&claims.sub).await?.ok_or(Error::UserNotFound)?;
+    if !crypto::verify_password(&req.auth_key, &password_hash)? {
+        return Err(Error::InvalidCredentials);
+    }
+
+    let new_password_hash = crypto::hash_password(&req.new_auth_key)?;
+    state
+        .db
+        .reset_credentials(&claims.sub, &new_password_hash, &req.key_material)
+        .await?;
+
+    info!("Key material updated for user {}", claims.sub);
+
+    Ok(json!({"updated": true}))
+}
+
+/// Master key rotation endpoint
+pub async fn rotate_keys(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(req): Json<RotateKeysRequest>,
+) -> impl IntoResponse {
+    match _rotate_keys(&state, &headers, &req).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(Error::ConflictError(msg)) => {
+            let msg = json!({"error": msg});
+            (StatusCode::CONFLICT, Json(msg)).into_response()
+        }
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _rotate_keys(state: &ServerState, headers: &HeaderMap, req: &RotateKeysRequest) -> Result<serde_json::Value> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    state
+        .db
+        .rotate_keys(&claims.sub, &req.key_material, &req.file_keys)
+        .await?;
+
+    info!("Master key rotated for user {}", claims.sub);
+
+    Ok(json!({"updated": req.file_keys.len()}))
+}
+
+/// Lists every stored data key, for re-wrapping under a new master key
+pub async fn list_file_keys(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let keys = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.list_file_keys(&claims.sub).await,
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            return (StatusCode::UNAUTHORIZED, Json(msg)).into_response();
+        }
+    };
+
+    match keys {
+        Ok(keys) => (StatusCode::OK, Json(json!({"keys": keys}))).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(msg)).into_response()
+        }
+    }
+}
+
+/// Recovery key setup endpoint
+pub async fn enable_recovery(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(req): Json<EnableRecoveryRequest>,
+) -> impl IntoResponse {
+    match _enable_recovery(&state, &headers, &req).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _enable_recovery(
+    state: &ServerState,
+    headers: &HeaderMap,
+    req: &EnableRecoveryRequest,
+) -> Result<serde_json::Value> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    if req.key_material.recovery_wrapped_master_key.is_none() {
+        return Err(Error::InvalidInput("Key material has no recovery key".to_string()));
+    }
+
+    let verifier_hash = crypto::hash_password(&req.recovery_verifier)?;
+    state
+        .db
+        .enable_recovery(&claims.sub, &verifier_hash, &req.key_material)
+        .await?;
+
+    info!("Recovery key enabled for user {}", claims.sub);
+
+    Ok(json!({"enabled": true}))
+}
+
+/// Checks a recovery verifier, returning the user it belongs to
+async fn verify_recovery(state: &ServerState, username: &str, verifier: &SecretString) -> Result<User> {
+    let user = state
+        .db
+        .get_user_by_username(username)
+        .await?
+        .ok_or(Error::InvalidCredentials)?;
+
+    let verifier_hash = state
+        .db
+        .get_recovery_verifier_hash(&user.id)
+        .await?
+        .ok_or(Error::InvalidCredentials)?;
+
+    if !crypto::verify_password(verifier, &verifier_hash)? {
+        return Err(Error::InvalidCredentials);
+    }
+
+    Ok(user)
+}
+
+/// Returns key material to a client holding the account's recovery key
+pub async fn recovery_key_material(
+    State(state): State<ServerState>,
+    Json(req): Json<RecoveryKeyMaterialRequest>,
+) -> impl IntoResponse {
+    match _recovery_key_material(&state, &req).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::UNAUTHORIZED, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _recovery_key_material(state: &ServerState, req: &RecoveryKeyMaterialRequest) -> Result<KeyMaterialResponse> {
+    let user = verify_recovery(state, &req.username, &req.recovery_verifier).await?;
+
+    Ok(KeyMaterialResponse {
+        key_material: user
+            .key_material
+            .ok_or_else(|| Error::InvalidInput("Account has no key material".to_string()))?,
+    })
+}
+
+/// Account recovery endpoint: sets a new password and key material
+pub async fn recover_account(
+    State(state): State<ServerState>,
+    Json(req): Json<RecoverAccountRequest>,
+) -> impl IntoResponse {
+    match _recover_account(&state, &req).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::UNAUTHORIZED, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _recover_account(state: &ServerState, req: &RecoverAccountRequest) -> Result<serde_json::Value> {
+    let user = verify_recovery(state, &req.username, &req.recovery_verifier).await?;
+
+    let password_hash = crypto::hash_password(&req.new_auth_key)?;
+    state
+        .db
+        .reset_credentials(&user.id, &password_hash, &req.key_material)
+        .await?;
+
+    let token = auth::generate_token(&user.id)?;
+
+    info!("Account recovered: {}", user.username);
+
+    Ok(json!({
+        "token": token,
+        "user_id": user.id,
+        "username": user.username,
+        "key_material": req.key_material
     }))
 }
 
@@ -157,16 +411,31 @@ async fn _upload_file(
     let claims = crate::server::auth::verify_token(token)?;
     let user_id = claims.sub;
 
-    // Compute hash for file
-    let hash = crypto::compute_hash(req.path.as_bytes());
+    // Clients send a keyed fingerprint of the content; older clients get a
+    // placeholder derived from the (encrypted) path
+    let hash = req
+        .content_fingerprint
+        .clone()
+        .unwrap_or_else(|| crypto::compute_hash(req.path.as_bytes()));
+
+    // Uploads to a known path go to the existing file; its content only
+    // changes once the new chunks are committed
+    if let Some(path_token) = &req.path_token {
+        if let Some(existing) = state.db.find_upload_target(&user_id, path_token).await? {
+            return Ok(json!({
+                "file_id": existing.id,
+                "path": existing.path,
+                "size": req.size
+            }));
+        }
+    }
 
-    // Create file metadata
     let file = state
         .db
-        .create_file_metadata(&user_id, &req.path, "", req.size, &hash, 0)
+        .create_pending_file(&user_id, &req.path, req.path_token.as_deref(), req.size, &hash)
         .await?;
 
-    info!("File uploaded: {} by user {}", req.path, user_id);
+    info!("File {} created by user {}", file.id, user_id);
 
     Ok(json!({
         "file_id": file.id,
@@ -175,12 +444,71 @@ async fn _upload_file(
     }))
 }
 
+/// File lookup by path token endpoint
+pub async fn lookup_file(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(path_token): Path<String>,
+) -> impl IntoResponse {
+    let file = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.find_file_by_token(&claims.sub, &path_token).await,
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            return (StatusCode::UNAUTHORIZED, Json(msg)).into_response();
+        }
+    };
+
+    match file {
+        Ok(Some(file)) => (StatusCode::OK, Json(file)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({"error": "File not found"}))).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(msg)).into_response()
+        }
+    }
+}
+
 /// File download endpoint
+///
+/// Returns the file metadata and its chunk ids in order; the chunks
+/// themselves are fetched one at a time so large files can be streamed.
 pub async fn download_file(
-    State(_state): State<ServerState>,
-    Path(_file_id): Path<String>,
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(file_id): Path<String>,
 ) -> impl IntoResponse {
-    (StatusCode::OK, "File download not yet implemented").into_response()
+    match _download_file(&state, &headers, &file_id).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(Error::FileNotFound(id)) => {
+            let msg = json!({"error": format!("File not found: {}", id)});
+            (StatusCode::NOT_FOUND, Json(msg)).into_response()
+        }
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::UNAUTHORIZED, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _download_file(
+    state: &ServerState,
+    headers: &HeaderMap,
+    file_id: &str,
+) -> Result<serde_json::Value> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    let file = state
+        .db
+        .get_accessible_file(&claims.sub, file_id)
+        .await?
+        .ok_or_else(|| Error::FileNotFound(file_id.to_string()))?;
+
+    let chunks = state.db.list_chunk_ids(&file).await?;
+
+    Ok(json!({
+        "file": file,
+        "chunks": chunks
+    }))
 }
 
 /// List files endpoint
@@ -208,28 +536,430 @@ async fn _list_files(state: &ServerState, headers: &HeaderMap) -> Result<Vec<Fil
     state.db.list_user_files(&claims.sub).await
 }
 
+/// Signed manifest upload endpoint
+///
+/// The server cannot verify the signature; it only keeps the latest manifest
+/// so the account's other devices can check their listings against it.
+pub async fn store_manifest(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(manifest): Json<SignedManifest>,
+) -> impl IntoResponse {
+    match _store_manifest(&state, &headers, &manifest).await {
+        Ok(()) => (StatusCode::OK, Json(json!({"stored": true}))).into_response(),
+        Err(Error::ConflictError(msg)) => {
+            let msg = json!({"error": msg});
+            (StatusCode::CONFLICT, Json(msg)).into_response()
+        }
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _store_manifest(state: &ServerState, headers: &HeaderMap, manifest: &SignedManifest) -> Result<()> {
+    let claims = auth::claims_from_headers(headers)?;
+    state.db.store_manifest(&claims.sub, manifest).await
+}
+
+/// Signed manifest download endpoint
+pub async fn get_manifest(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let manifest = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.get_manifest(&claims.sub).await,
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            return (StatusCode::UNAUTHORIZED, Json(msg)).into_response();
+        }
+    };
+
+    match manifest {
+        Ok(Some(manifest)) => (StatusCode::OK, Json(manifest)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({"error": "No manifest stored"}))).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(msg)).into_response()
+        }
+    }
+}
+
 /// Delete file endpoint
 pub async fn delete_file(
-    State(_state): State<ServerState>,
-    Path(_file_id): Path<String>,
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(file_id): Path<String>,
+    req: Option<Json<DeleteFileRequest>>,
+) -> impl IntoResponse {
+    let manifest = req.and_then(|Json(req)| req.manifest);
+    let result = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.delete_file(&claims.sub, &file_id, manifest.as_ref()).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(()) => (StatusCode::OK, Json(json!({"deleted": true}))).into_response(),
+        Err(Error::FileNotFound(_)) => {
+            let msg = json!({"error": "File not found"});
+            (StatusCode::NOT_FOUND, Json(msg)).into_response()
+        }
+        Err(Error::ConflictError(msg)) => {
+            let msg = json!({"error": msg});
+            (StatusCode::CONFLICT, Json(msg)).into_response()
+        }
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+/// Move file endpoint; only the path changes, so the file keeps its id,
+/// content and version history
+pub async fn move_file(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(file_id): Path<String>,
+    Json(req): Json<MoveFileRequest>,
 ) -> impl IntoResponse {
-    (StatusCode::OK, Json(json!({"deleted": true}))).into_response()
+    match _move_file(&state, &headers, &file_id, &req).await {
+        Ok(file) => (StatusCode::OK, Json(file)).into_response(),
+        Err(Error::FileNotFound(id)) => {
+            let msg = json!({"error": format!("File not found: {}", id)});
+            (StatusCode::NOT_FOUND, Json(msg)).into_response()
+        }
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _move_file(
+    state: &ServerState,
+    headers: &HeaderMap,
+    file_id: &str,
+    req: &MoveFileRequest,
+) -> Result<FileMetadata> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    let file = state
+        .db
+        .move_file(&claims.sub, file_id, &req.path, &req.path_token)
+        .await?;
+
+    info!("File {} moved by user {}", file_id, claims.sub);
+    Ok(file)
 }
 
 /// Upload chunk endpoint
 pub async fn upload_chunk(
-    State(_state): State<ServerState>,
-    Json(_req): Json<UploadChunkRequest>,
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(req): Json<UploadChunkRequest>,
 ) -> impl IntoResponse {
-    (StatusCode::CREATED, Json(json!({"chunk_id": "chunk_123"}))).into_response()
+    match _upload_chunk(&state, &headers, &req).await {
+        Ok(response) => (StatusCode::CREATED, Json(response)).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _upload_chunk(
+    state: &ServerState,
+    headers: &HeaderMap,
+    req: &UploadChunkRequest,
+) -> Result<serde_json::Value> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    // Only well-formed envelopes are accepted
+    crypto::Envelope::from_bytes(&req.encrypted_data)?;
+
+    state
+        .db
+        .get_file_metadata(&claims.sub, &req.file_id)
+        .await?
+        .ok_or_else(|| Error::FileNotFound(req.file_id.clone()))?;
+
+    let hash = crypto::compute_hash(&req.encrypted_data);
+
+    // Content-defined chunks go to the user's chunk store until a file
+    // commits them
+    if let Some(fingerprint) = &req.fingerprint {
+        validate_fingerprint(fingerprint)?;
+        let stored = state
+            .db
+            .store_content_chunk(&claims.sub, fingerprint, &req.encrypted_data, &hash)
+            .await?;
+        return Ok(json!({"stored": stored}));
+    }
+
+    let chunk_index = req
+        .chunk_index
+        .ok_or_else(|| Error::InvalidInput("A chunk needs a fingerprint or a chunk index".to_string()))?;
+    let chunk = state
+        .db
+        .store_chunk(&req.file_id, chunk_index, &req.encrypted_data, &hash)
+        .await?;
+
+    Ok(json!({"chunk_id": chunk.id}))
+}
+
+/// Largest batch of fingerprints accepted by [`missing_chunks`]
+pub const MAX_MISSING_CHUNKS_QUERY: usize = 4096;
+
+/// Missing chunk query endpoint
+///
+/// Returns the fingerprints among those sent that the caller's chunk store
+/// lacks; only these need to be sent before committing a file.
+pub async fn missing_chunks(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(req): Json<MissingChunksRequest>,
+) -> impl IntoResponse {
+    match _missing_chunks(&state, &headers, &req).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _missing_chunks(
+    state: &ServerState,
+    headers: &HeaderMap,
+    req: &MissingChunksRequest,
+) -> Result<MissingChunksResponse> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    if req.fingerprints.len() > MAX_MISSING_CHUNKS_QUERY {
+        return Err(Error::InvalidInput(format!(
+            "At most {} fingerprints can be queried at once",
+            MAX_MISSING_CHUNKS_QUERY
+        )));
+    }
+    for fingerprint in &req.fingerprints {
+        validate_fingerprint(fingerprint)?;
+    }
+
+    let missing = state.db.missing_chunks(&claims.sub, &req.fingerprints).await?;
+    Ok(MissingChunksResponse { missing })
+}
+
+/// Chunk commit endpoint, building a file from references to stored chunks
+pub async fn commit_chunks(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(file_id): Path<String>,
+    Json(req): Json<CommitChunksRequest>,
+) -> impl IntoResponse {
+    match _commit_chunks(&state, &headers, &file_id, &req).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(Error::FileNotFound(id)) => {
+            let msg = json!({"error": format!("File not found: {}", id)});
+            (StatusCode::NOT_FOUND, Json(msg)).into_response()
+        }
+        Err(Error::ConflictError(msg)) => {
+            let msg = json!({"error": msg});
+            (StatusCode::CONFLICT, Json(msg)).into_response()
+        }
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _commit_chunks(
+    state: &ServerState,
+    headers: &HeaderMap,
+    file_id: &str,
+    req: &CommitChunksRequest,
+) -> Result<serde_json::Value> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    let file = state.db.commit_chunks(&claims.sub, file_id, req).await?;
+
+    info!("File {} version {} committed by user {}", file.id, file.version, claims.sub);
+
+    Ok(json!({
+        "chunk_count": file.chunk_count,
+        "version": file.version
+    }))
+}
+
+/// Fingerprints are hex encoded HMAC-SHA256 values
+fn validate_fingerprint(fingerprint: &str) -> Result<()> {
+    if fingerprint.len() != 64 || !fingerprint.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::InvalidInput(format!("Invalid chunk fingerprint: {}", fingerprint)));
+    }
+    Ok(())
 }
 
 /// Download chunk endpoint
 pub async fn download_chunk(
-    State(_state): State<ServerState>,
-    Path(_chunk_id): Path<String>,
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(chunk_id): Path<String>,
+) -> impl IntoResponse {
+    let chunk = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.get_chunk(&claims.sub, &chunk_id).await,
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            return (StatusCode::UNAUTHORIZED, Json(msg)).into_response();
+        }
+    };
+
+    match chunk {
+        Ok(Some(chunk)) => (StatusCode::OK, Json(chunk)).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, Json(json!({"error": "Chunk not found"}))).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(msg)).into_response()
+        }
+    }
+}
+
+/// Public key lookup endpoint, used to wrap keys for share recipients
+pub async fn get_public_key(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+) -> impl IntoResponse {
+    match _get_public_key(&state, &headers, &username).await {
+        Ok(response) => (StatusCode::OK, Json(response)).into_response(),
+        Err(Error::UserNotFound) => {
+            let msg = json!({"error": Error::UserNotFound.to_string()});
+            (StatusCode::NOT_FOUND, Json(msg)).into_response()
+        }
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::UNAUTHORIZED, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _get_public_key(state: &ServerState, headers: &HeaderMap, username: &str) -> Result<PublicKeyResponse> {
+    auth::claims_from_headers(headers)?;
+
+    let user = state
+        .db
+        .get_user_by_username(username)
+        .await?
+        .ok_or(Error::UserNotFound)?;
+
+    Ok(PublicKeyResponse {
+        user_id: user.id,
+        username: user.username,
+        public_key: user.public_key,
+    })
+}
+
+/// Share creation endpoint
+pub async fn create_share(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateShareRequest>,
+) -> impl IntoResponse {
+    match _create_share(&state, &headers, &req).await {
+        Ok(share) => (StatusCode::CREATED, Json(share)).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
+}
+
+async fn _create_share(state: &ServerState, headers: &HeaderMap, req: &CreateShareRequest) -> Result<FileShare> {
+    let claims = auth::claims_from_headers(headers)?;
+
+    state
+        .db
+        .get_file_metadata(&claims.sub, &req.file_id)
+        .await?
+        .ok_or_else(|| Error::FileNotFound(req.file_id.clone()))?;
+
+    let recipient = state
+        .db
+        .get_user_by_username(&req.recipient_username)
+        .await?
+        .ok_or(Error::UserNotFound)?;
+
+    if recipient.id == claims.sub {
+        return Err(Error::InvalidInput("Cannot share a file with yourself".to_string()));
+    }
+
+    let share = state
+        .db
+        .create_share(&req.file_id, &claims.sub, &recipient, &req.wrapped_key)
+        .await?;
+
+    info!("File {} shared by {} with {}", req.file_id, claims.sub, recipient.id);
+
+    Ok(share)
+}
+
+/// Lists files shared with the caller
+pub async fn list_incoming_shares(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    (StatusCode::OK, "Chunk download not yet implemented").into_response()
+    let shares = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.list_incoming_shares(&claims.sub).await,
+        Err(e) => Err(e),
+    };
+
+    match shares {
+        Ok(shares) => (StatusCode::OK, Json(json!({"shares": shares}))).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::UNAUTHORIZED, Json(msg)).into_response()
+        }
+    }
+}
+
+/// Lists shares created by the caller
+pub async fn list_outgoing_shares(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let shares = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.list_outgoing_shares(&claims.sub).await,
+        Err(e) => Err(e),
+    };
+
+    match shares {
+        Ok(shares) => (StatusCode::OK, Json(json!({"shares": shares}))).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::UNAUTHORIZED, Json(msg)).into_response()
+        }
+    }
+}
+
+/// Share revocation endpoint
+pub async fn revoke_share(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(share_id): Path<String>,
+) -> impl IntoResponse {
+    let result = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.delete_share(&claims.sub, &share_id).await,
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(()) => (StatusCode::OK, Json(json!({"revoked": true}))).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::BAD_REQUEST, Json(msg)).into_response()
+        }
+    }
 }
 
 /// Sync status endpoint
@@ -259,13 +989,27 @@ pub async fn add_sync_dir(
         .into_response()
 }
 
-/// List versions endpoint
+/// List versions endpoint, returning the replaced versions of a file
 pub async fn list_versions(
-    State(_state): State<ServerState>,
-    Path(_file_id): Path<String>,
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Path(file_id): Path<String>,
 ) -> impl IntoResponse {
-    (StatusCode::OK, Json(json!({"versions": []})))
-        .into_response()
+    let versions = match auth::claims_from_headers(&headers) {
+        Ok(claims) => state.db.list_file_versions(&claims.sub, &file_id).await,
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            return (StatusCode::UNAUTHORIZED, Json(msg)).into_response();
+        }
+    };
+
+    match versions {
+        Ok(versions) => (StatusCode::OK, Json(json!({"versions": versions}))).into_response(),
+        Err(e) => {
+            let msg = json!({"error": e.to_string()});
+            (StatusCode::INTERNAL_SERVER_ERROR, Json(msg)).into_response()
+        }
+    }
 }
 
 /// Restore version endpoint
@@ -29,16 +29,36 @@ pub async fn create_app(db: Arc<db::Database>) -> Result<Router> {
         .route("/health", get(handlers::health))
         // Auth endpoints
         .route("/api/v1/auth/register", post(handlers::register))
+        .route("/api/v1/auth/prelogin", post(handlers::prelogin))
         .route("/api/v1/auth/login", post(handlers::login))
         .route("/api/v1/auth/verify", get(handlers::verify_token))
+        .route("/api/v1/auth/key-material", post(handlers::update_key_material))
+        .route("/api/v1/auth/rotate", post(handlers::rotate_keys))
+        .route("/api/v1/auth/recovery", post(handlers::enable_recovery))
+        .route("/api/v1/auth/recovery/key-material", post(handlers::recovery_key_material))
+        .route("/api/v1/auth/recover", post(handlers::recover_account))
         // File endpoints
         .route("/api/v1/files/upload", post(handlers::upload_file))
         .route("/api/v1/files/download/:file_id", get(handlers::download_file))
         .route("/api/v1/files/list", get(handlers::list_files))
+        .route("/api/v1/files/lookup/:path_token", get(handlers::lookup_file))
         .route("/api/v1/files/delete/:file_id", post(handlers::delete_file))
+        .route("/api/v1/files/:file_id/move", post(handlers::move_file))
+        .route("/api/v1/files/:file_id/chunks", post(handlers::commit_chunks))
+        .route("/api/v1/files/keys", get(handlers::list_file_keys))
+        // Manifest endpoints
+        .route("/api/v1/manifest", get(handlers::get_manifest))
+        .route("/api/v1/manifest", post(handlers::store_manifest))
         // Chunk endpoints
         .route("/api/v1/chunks/upload", post(handlers::upload_chunk))
+        .route("/api/v1/chunks/missing", post(handlers::missing_chunks))
         .route("/api/v1/chunks/download/:chunk_id", get(handlers::download_chunk))
+        // Sharing endpoints
+        .route("/api/v1/users/:username/public-key", get(handlers::get_public_key))
+        .route("/api/v1/shares", post(handlers::create_share))
+        .route("/api/v1/shares/incoming", get(handlers::list_incoming_shares))
+        .route("/api/v1/shares/outgoing", get(handlers::list_outgoing_shares))
+        .route("/api/v1/shares/:share_id/revoke", post(handlers::revoke_share))
         // Sync endpoints
         .route("/api/v1/sync/status", get(handlers::sync_status))
         .route("/api/v1/sync/directories", get(handlers::list_sync_dirs))
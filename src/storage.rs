@@ -37,6 +37,15 @@ impl LocalStorage {
 
         Ok(file_path)
     }
+
+    /// Reads a locally stored file
+    pub async fn retrieve_file(&self, relative_path: &str) -> Result<Vec<u8>> {
+        let file_path = self.base_path.join(relative_path);
+
+        async_fs::read(&file_path)
+            .await
+            .map_err(|e| Error::StorageError(e.to_string()))
+    }
 }
 
 /// S3-compatible object storage handler (stub)
@@ -0,0 +1,372 @@
+//! Conflict detection and resolution
+//!
+//! Whether a file conflicts is decided by a three-way comparison against the
+//! base recorded in the sync index: the content both sides agreed on at the
+//! last sync. A side whose fingerprint still equals the base has not
+//! changed, so if only one side differs its change is simply applied. Only
+//! when both sides moved away from the base, to different content, is there
+//! a conflict. No clocks are involved.
+
+use crate::error::Result;
+use std::fs;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// How a conflict came about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// Both sides edited the file since the last sync
+    BothModified,
+    /// The file appeared on both sides with different content
+    BothCreated,
+    /// Deleted locally, edited remotely
+    LocalDeleted,
+    /// Edited locally, deleted remotely
+    RemoteDeleted,
+}
+
+/// What to do with a file after comparing both sides with the base
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncDecision {
+    /// Nothing changed on either side
+    Unchanged,
+    /// Both sides changed identically; only the base needs updating
+    Converged,
+    Upload,
+    Download,
+    DeleteLocal,
+    DeleteRemote,
+    Conflict(ConflictKind),
+}
+
+/// Decides how to sync one file from its base, local and remote fingerprints
+///
+/// `None` means the file does not exist on that side (or, for the base, was
+/// never synced).
+pub fn reconcile(base: Option<&str>, local: Option<&str>, remote: Option<&str>) -> SyncDecision {
+    let local_changed = local != base;
+    let remote_changed = remote != base;
+
+    match (local_changed, remote_changed) {
+        (false, false) => SyncDecision::Unchanged,
+        (true, false) if local.is_some() => SyncDecision::Upload,
+        (true, false) => SyncDecision::DeleteRemote,
+        (false, true) if remote.is_some() => SyncDecision::Download,
+        (false, true) => SyncDecision::DeleteLocal,
+        (true, true) if local == remote => SyncDecision::Converged,
+        (true, true) => SyncDecision::Conflict(match (local, remote) {
+            (None, _) => ConflictKind::LocalDeleted,
+            (_, None) => ConflictKind::RemoteDeleted,
+            _ if base.is_none() => ConflictKind::BothCreated,
+            _ => ConflictKind::BothModified,
+        }),
+    }
+}
+
+/// Conflict resolution strategy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictResolution {
+    /// Keep the latest modified file
+    LastWriteWins,
+    /// Ask user to decide
+    Manual,
+    /// Keep local version
+    KeepLocal,
+    /// Keep remote version
+    KeepRemote,
+    /// Keep the remote version in place and the local one as a conflicted copy
+    KeepBoth,
+}
+
+impl ConflictResolution {
+    /// Name used when the resolution is stored
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::LastWriteWins => "last_write_wins",
+            Self::Manual => "manual",
+            Self::KeepLocal => "keep_local",
+            Self::KeepRemote => "keep_remote",
+            Self::KeepBoth => "keep_both",
+        }
+    }
+
+    /// Parses a name produced by [`as_str`](Self::as_str)
+    pub fn parse(name: &str) -> Option<Self> {
+        [Self::LastWriteWins, Self::Manual, Self::KeepLocal, Self::KeepRemote, Self::KeepBoth]
+            .into_iter()
+            .find(|resolution| resolution.as_str() == name)
+    }
+}
+
+impl ConflictKind {
+    /// Name used when the conflict is stored
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::BothModified => "both_modified",
+            Self::BothCreated => "both_created",
+            Self::LocalDeleted => "local_deleted",
+            Self::RemoteDeleted => "remote_deleted",
+        }
+    }
+
+    /// Parses a name produced by [`as_str`](Self::as_str)
+    pub fn parse(name: &str) -> Option<Self> {
+        [Self::BothModified, Self::BothCreated, Self::LocalDeleted, Self::RemoteDeleted]
+            .into_iter()
+            .find(|kind| kind.as_str() == name)
+    }
+}
+
+/// One side of a conflict, as it was when the conflict was detected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConflictSide {
+    pub size: u64,
+    pub modified: DateTime<Utc>,
+    /// Remote content version; `None` for the local side
+    pub version: Option<u64>,
+}
+
+/// What is known about a conflict when it is detected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictDetails {
+    /// Path relative to the sync root
+    pub path: String,
+    pub kind: ConflictKind,
+    /// `None` if the file was deleted locally
+    pub local: Option<ConflictSide>,
+    /// `None` if the file was deleted remotely
+    pub remote: Option<ConflictSide>,
+    pub remote_id: Option<String>,
+    /// Device that detected the conflict, which holds the local side
+    pub device: String,
+}
+
+/// A conflict as recorded in the sync index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConflictRecord {
+    pub id: i64,
+    /// Sync root the path is relative to
+    pub root: PathBuf,
+    pub details: ConflictDetails,
+    /// How it was resolved; `None` while it is still waiting for the user
+    pub resolution: Option<ConflictResolution>,
+    /// Where the losing version was preserved, relative to the sync root
+    pub conflicted_copy: Option<String>,
+    pub detected_at: DateTime<Utc>,
+    pub resolved_at: Option<DateTime<Utc>>,
+}
+
+/// Outcome of resolving a conflict
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedConflict {
+    /// Path holding the winning content, which becomes the synced version
+    pub kept: PathBuf,
+    /// Where the losing local version was preserved, if anywhere
+    pub conflicted_copy: Option<PathBuf>,
+}
+
+impl ResolvedConflict {
+    fn kept(path: &Path) -> Self {
+        Self {
+            kept: path.to_path_buf(),
+            conflicted_copy: None,
+        }
+    }
+}
+
+pub(crate) fn keep_both(local_path: &Path, remote_path: &Path, device: &str, date: NaiveDate) -> Result<ResolvedConflict> {
+    // With one side deleted there is only one version to keep
+    if !remote_path.exists() {
+        return Ok(ResolvedConflict::kept(local_path));
+    }
+    if !local_path.exists() {
+        return Ok(ResolvedConflict::kept(remote_path));
+    }
+
+    let copy = conflicted_copy_path(local_path, device, date);
+    fs::rename(local_path, &copy)?;
+    info!("Kept local version of {} as {}", local_path.display(), copy.display());
+
+    Ok(ResolvedConflict {
+        kept: remote_path.to_path_buf(),
+        conflicted_copy: Some(copy),
+    })
+}
+
+/// Returns a free path next to `path` for a conflicted copy, of the form
+/// `name (conflicted copy from <device> <date>).ext`
+pub fn conflicted_copy_path(path: &Path, device: &str, date: NaiveDate) -> PathBuf {
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let (stem, extension) = match file_name.rfind('.') {
+        Some(dot) if dot > 0 => (&file_name[..dot], &file_name[dot..]),
+        _ => (&file_name[..], ""),
+    };
+    let device: String = device
+        .chars()
+        .map(|c| if matches!(c, '/' | '\\' | ':' | '(' | ')') { '-' } else { c })
+        .collect();
+    let label = format!("conflicted copy from {} {}", device, date.format("%Y-%m-%d"));
+
+    let mut candidate = path.with_file_name(format!("{} ({}){}", stem, label, extension));
+    let mut counter = 2;
+    while candidate.exists() {
+        candidate = path.with_file_name(format!("{} ({} {}){}", stem, label, counter, extension));
+        counter += 1;
+    }
+    candidate
+}
+
+/// Files larger than this many lines are not diffed
+pub const MAX_DIFF_LINES: usize = 5000;
+
+/// A line in a diff between the local and remote versions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    Both(&'a str),
+    Local(&'a str),
+    Remote(&'a str),
+}
+
+/// Computes a line diff between two texts
+///
+/// Returns `None` if either text is too long to diff.
+pub fn line_diff<'a>(local: &'a str, remote: &'a str) -> Option<Vec<DiffLine<'a>>> {
+    let local: Vec<&str> = local.lines().collect();
+    let remote: Vec<&str> = remote.lines().collect();
+    if local.len() > MAX_DIFF_LINES || remote.len() > MAX_DIFF_LINES {
+        return None;
+    }
+
+    // Longest common subsequence lengths of every pair of suffixes
+    let width = remote.len() + 1;
+    let mut lcs = vec![0u32; (local.len() + 1) * width];
+    for i in (0..local.len()).rev() {
+        for j in (0..remote.len()).rev() {
+            lcs[i * width + j] = if local[i] == remote[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut diff = Vec::with_capacity(local.len().max(remote.len()));
+    while i < local.len() && j < remote.len() {
+        if local[i] == remote[j] {
+            diff.push(DiffLine::Both(local[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+            diff.push(DiffLine::Local(local[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Remote(remote[j]));
+            j += 1;
+        }
+    }
+    diff.extend(local[i..].iter().map(|line| DiffLine::Local(line)));
+    diff.extend(remote[j..].iter().map(|line| DiffLine::Remote(line)));
+    Some(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date() -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 3, 5).unwrap()
+    }
+
+    #[test]
+    fn test_one_sided_changes_are_not_conflicts() {
+        assert_eq!(reconcile(Some("a"), Some("a"), Some("a")), SyncDecision::Unchanged);
+        assert_eq!(reconcile(Some("a"), Some("b"), Some("a")), SyncDecision::Upload);
+        assert_eq!(reconcile(Some("a"), Some("a"), Some("b")), SyncDecision::Download);
+        assert_eq!(reconcile(Some("a"), None, Some("a")), SyncDecision::DeleteRemote);
+        assert_eq!(reconcile(Some("a"), Some("a"), None), SyncDecision::DeleteLocal);
+        assert_eq!(reconcile(None, Some("a"), None), SyncDecision::Upload);
+        assert_eq!(reconcile(None, None, Some("a")), SyncDecision::Download);
+    }
+
+    #[test]
+    fn test_changes_on_both_sides() {
+        assert_eq!(reconcile(Some("a"), Some("b"), Some("b")), SyncDecision::Converged);
+        assert_eq!(reconcile(Some("a"), None, None), SyncDecision::Converged);
+        assert_eq!(
+            reconcile(Some("a"), Some("b"), Some("c")),
+            SyncDecision::Conflict(ConflictKind::BothModified)
+        );
+        assert_eq!(
+            reconcile(None, Some("b"), Some("c")),
+            SyncDecision::Conflict(ConflictKind::BothCreated)
+        );
+        assert_eq!(
+            reconcile(Some("a"), None, Some("c")),
+            SyncDecision::Conflict(ConflictKind::LocalDeleted)
+        );
+        assert_eq!(
+            reconcile(Some("a"), Some("b"), None),
+            SyncDecision::Conflict(ConflictKind::RemoteDeleted)
+        );
+    }
+
+    #[test]
+    fn test_conflicted_copy_names() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("report.final.docx");
+
+        let copy = conflicted_copy_path(&path, "work/laptop", date());
+        assert_eq!(
+            copy.file_name().unwrap(),
+            "report.final (conflicted copy from work-laptop 2024-03-05).docx"
+        );
+
+        fs::write(&copy, b"taken").unwrap();
+        let second = conflicted_copy_path(&path, "work/laptop", date());
+        assert_eq!(
+            second.file_name().unwrap(),
+            "report.final (conflicted copy from work-laptop 2024-03-05 2).docx"
+        );
+
+        let dotfile = conflicted_copy_path(&temp_dir.path().join(".bashrc"), "desk", date());
+        assert_eq!(dotfile.file_name().unwrap(), ".bashrc (conflicted copy from desk 2024-03-05)");
+    }
+
+    #[test]
+    fn test_keep_both_preserves_local_version() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let local = temp_dir.path().join("notes.txt");
+        let remote = temp_dir.path().join("notes.txt.remote");
+        fs::write(&local, b"local edit").unwrap();
+        fs::write(&remote, b"remote edit").unwrap();
+
+        let resolved = keep_both(&local, &remote, "desk", date()).unwrap();
+        let copy = resolved.conflicted_copy.unwrap();
+        assert_eq!(resolved.kept, remote);
+        assert_eq!(fs::read(&copy).unwrap(), b"local edit");
+        assert!(!local.exists());
+
+        // Nothing to preserve when the local side was deleted
+        let resolved = keep_both(&local, &remote, "desk", date()).unwrap();
+        assert_eq!(resolved, ResolvedConflict::kept(&remote));
+    }
+
+    #[test]
+    fn test_line_diff() {
+        let diff = line_diff("a\nb\nc\n", "a\nx\nc\nd\n").unwrap();
+        assert_eq!(
+            diff,
+            vec![
+                DiffLine::Both("a"),
+                DiffLine::Local("b"),
+                DiffLine::Remote("x"),
+                DiffLine::Both("c"),
+                DiffLine::Remote("d"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,477 @@
+//! Exclusion rules with gitignore semantics
+//!
+//! Each directory in a sync root may contain a `.rustguardignore` file using
+//! gitignore syntax: `#` comments, `!` negation, a trailing `/` for
+//! directories only, a leading or inner `/` to anchor a pattern to the
+//! file's directory, and `*`, `?`, `[...]` and `**` wildcards. Global
+//! patterns from the client config apply to every root with the lowest
+//! precedence; rules in deeper directories override those above them, and
+//! within one file the last matching line wins. As in git, nothing inside
+//! an excluded directory can be re-included.
+//!
+//! Ignore files are read lazily and cached; call
+//! [`IgnoreRules::invalidate`] when one changes.
+
+use crate::error::Result;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::warn;
+
+/// Name of the per-directory ignore file
+pub const IGNORE_FILE: &str = ".rustguardignore";
+
+/// Patterns that always apply, covering the client's own temporary files
+const BUILTIN_PATTERNS: &[&str] = &[".rustguard-*"];
+
+/// Where a rule was defined
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuleSource {
+    /// Built into the client
+    Builtin,
+    /// Global exclude patterns from the client config
+    Global,
+    /// An ignore file, given relative to the sync root, and the line number
+    File { path: String, line: usize },
+}
+
+impl fmt::Display for RuleSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Builtin => f.write_str("built-in rules"),
+            Self::Global => f.write_str("global excludes in config"),
+            Self::File { path, line } => write!(f, "{}:{}", path, line),
+        }
+    }
+}
+
+/// A single parsed pattern
+#[derive(Debug, Clone)]
+pub struct Rule {
+    /// The pattern as written
+    pub pattern: String,
+    pub source: RuleSource,
+    /// `!` pattern that re-includes matching paths
+    pub negated: bool,
+    dir_only: bool,
+    anchored: bool,
+    /// Directory of the ignore file relative to the root, `""` for the root
+    base: String,
+    tokens: Vec<Token>,
+}
+
+impl Rule {
+    /// Parses one line of an ignore file; blank lines and comments yield `None`
+    pub fn parse(line: &str, base: &str, source: RuleSource) -> Option<Self> {
+        let line = trim_trailing_spaces(line);
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+
+        let (negated, body) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line.strip_prefix('\\').filter(|rest| rest.starts_with(['#', '!'])).unwrap_or(line)),
+        };
+        let (dir_only, body) = match body.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, body),
+        };
+        if body.is_empty() {
+            return None;
+        }
+
+        // A slash anywhere but the end ties the pattern to the file's directory
+        let anchored = body.contains('/');
+        let body = body.strip_prefix('/').unwrap_or(body);
+
+        Some(Self {
+            pattern: line.to_string(),
+            source,
+            negated,
+            dir_only,
+            anchored,
+            base: base.to_string(),
+            tokens: compile(body),
+        })
+    }
+
+    /// Returns true if the rule matches `path`, which is relative to the sync root
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+
+        let relative = if self.base.is_empty() {
+            path
+        } else {
+            match path.strip_prefix(&self.base).and_then(|rest| rest.strip_prefix('/')) {
+                Some(rest) => rest,
+                None => return false,
+            }
+        };
+
+        if self.anchored {
+            glob_match(&self.tokens, &relative.chars().collect::<Vec<_>>())
+        } else {
+            let name = relative.rsplit('/').next().unwrap_or(relative);
+            glob_match(&self.tokens, &name.chars().collect::<Vec<_>>())
+        }
+    }
+}
+
+/// Why a path is or is not ignored
+#[derive(Debug, Clone)]
+pub struct IgnoreMatch {
+    /// The path, or the ancestor directory, that the rule matched
+    pub matched_path: String,
+    pub rule: Rule,
+}
+
+impl IgnoreMatch {
+    /// Returns true if the path is excluded from sync
+    pub fn is_ignored(&self) -> bool {
+        !self.rule.negated
+    }
+}
+
+/// Exclusion rules for one sync root
+#[derive(Clone)]
+pub struct IgnoreRules {
+    root: PathBuf,
+    global: Arc<Vec<Rule>>,
+    /// Parsed ignore files by directory relative to the root
+    files: Arc<Mutex<HashMap<String, Arc<Vec<Rule>>>>>,
+}
+
+impl IgnoreRules {
+    /// Creates the rules for `root` with the given global exclude patterns
+    pub fn new(root: &Path, global_patterns: &[String]) -> Self {
+        let builtin = BUILTIN_PATTERNS
+            .iter()
+            .filter_map(|pattern| Rule::parse(pattern, "", RuleSource::Builtin));
+        let global = global_patterns
+            .iter()
+            .filter_map(|pattern| Rule::parse(pattern, "", RuleSource::Global));
+
+        Self {
+            root: root.to_path_buf(),
+            global: Arc::new(builtin.chain(global).collect()),
+            files: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// The sync root these rules belong to
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Drops cached ignore files so they are read again on next use
+    pub fn invalidate(&self) {
+        self.files.lock().clear();
+    }
+
+    /// Returns true if `path` (relative to the root, `/`-separated) is excluded
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        self.explain(path, is_dir).is_some_and(|found| found.is_ignored())
+    }
+
+    /// Returns the rule that decides whether `path` is excluded, if any
+    ///
+    /// An excluded ancestor directory decides for everything below it.
+    pub fn explain(&self, path: &str, is_dir: bool) -> Option<IgnoreMatch> {
+        let parts: Vec<&str> = path.split('/').filter(|part| !part.is_empty()).collect();
+
+        for depth in 1..parts.len() {
+            let ancestor = parts[..depth].join("/");
+            if let Some(found) = self.decide(&ancestor, true) {
+                if found.is_ignored() {
+                    return Some(found);
+                }
+            }
+        }
+
+        self.decide(&parts.join("/"), is_dir)
+    }
+
+    /// Finds the last matching rule for `path`, ignoring its ancestors
+    fn decide(&self, path: &str, is_dir: bool) -> Option<IgnoreMatch> {
+        let mut decided = None;
+        let found = |rule: &Rule| IgnoreMatch {
+            matched_path: path.to_string(),
+            rule: rule.clone(),
+        };
+
+        if let Some(rule) = self.global.iter().rev().find(|rule| rule.matches(path, is_dir)) {
+            decided = Some(found(rule));
+        }
+
+        // Ignore files from the root down; deeper files override shallower ones
+        let mut dir = String::new();
+        let parents: Vec<&str> = path.split('/').collect();
+        for (i, part) in parents.iter().enumerate() {
+            if let Some(rule) = self.rules_in(&dir).iter().rev().find(|rule| rule.matches(path, is_dir)) {
+                decided = Some(found(rule));
+            }
+            if i + 1 == parents.len() {
+                break;
+            }
+            dir = if dir.is_empty() { part.to_string() } else { format!("{}/{}", dir, part) };
+        }
+
+        decided
+    }
+
+    fn rules_in(&self, dir: &str) -> Arc<Vec<Rule>> {
+        if let Some(rules) = self.files.lock().get(dir) {
+            return rules.clone();
+        }
+
+        let relative_file = if dir.is_empty() { IGNORE_FILE.to_string() } else { format!("{}/{}", dir, IGNORE_FILE) };
+        let rules = match read_rules(&self.root.join(&relative_file), dir, &relative_file) {
+            Ok(rules) => rules,
+            Err(e) => {
+                warn!("Cannot read {}: {}", relative_file, e);
+                Vec::new()
+            }
+        };
+
+        let rules = Arc::new(rules);
+        self.files.lock().insert(dir.to_string(), rules.clone());
+        rules
+    }
+}
+
+fn read_rules(path: &Path, base: &str, relative_file: &str) -> Result<Vec<Rule>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(content
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let source = RuleSource::File {
+                path: relative_file.to_string(),
+                line: i + 1,
+            };
+            Rule::parse(line, base, source)
+        })
+        .collect())
+}
+
+/// Trims trailing spaces unless they are escaped with a backslash
+fn trim_trailing_spaces(line: &str) -> &str {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let trimmed = line.trim_end_matches(' ');
+    if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+        &line[..trimmed.len() + 1]
+    } else {
+        trimmed
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(char),
+    /// `?`: any single character except `/`
+    Any,
+    /// `*`: any run of characters except `/`
+    Star,
+    /// `**`: any run of characters including `/`
+    DoubleStar,
+    /// `[...]`: a set of characters or ranges, possibly negated
+    Class { negated: bool, ranges: Vec<(char, char)> },
+}
+
+fn compile(pattern: &str) -> Vec<Token> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = Vec::with_capacity(chars.len());
+    let mut i = 0;
+
+    while i < chars.len() {
+        match chars[i] {
+            '\\' if i + 1 < chars.len() => {
+                tokens.push(Token::Literal(chars[i + 1]));
+                i += 2;
+                continue;
+            }
+            '?' => tokens.push(Token::Any),
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                tokens.push(Token::DoubleStar);
+                while chars.get(i + 1) == Some(&'*') {
+                    i += 1;
+                }
+            }
+            '*' => tokens.push(Token::Star),
+            '[' => match compile_class(&chars[i + 1..]) {
+                Some((token, used)) => {
+                    tokens.push(token);
+                    i += used + 1;
+                    continue;
+                }
+                None => tokens.push(Token::Literal('[')),
+            },
+            c => tokens.push(Token::Literal(c)),
+        }
+        i += 1;
+    }
+
+    tokens
+}
+
+/// Parses a character class after its `[`; returns the token and the
+/// number of characters consumed including the closing `]`
+fn compile_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 0;
+    let negated = matches!(chars.first(), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    let mut first = true;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == ']' && !first {
+            return Some((Token::Class { negated, ranges }, i + 1));
+        }
+        first = false;
+
+        let start = if c == '\\' && i + 1 < chars.len() {
+            i += 1;
+            chars[i]
+        } else {
+            c
+        };
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&end| end != ']') {
+            ranges.push((start, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((start, start));
+            i += 1;
+        }
+    }
+
+    None
+}
+
+fn glob_match(tokens: &[Token], text: &[char]) -> bool {
+    let Some((token, rest)) = tokens.split_first() else {
+        return text.is_empty();
+    };
+
+    match token {
+        Token::Literal(c) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+        Token::Any => text.first().is_some_and(|&c| c != '/') && glob_match(rest, &text[1..]),
+        Token::Class { negated, ranges } => {
+            text.first().is_some_and(|&c| {
+                c != '/' && ranges.iter().any(|&(start, end)| (start..=end).contains(&c)) != *negated
+            }) && glob_match(rest, &text[1..])
+        }
+        Token::Star => {
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Token::DoubleStar => {
+            // `**/` also matches no directories at all
+            if rest.first() == Some(&Token::Literal('/')) && glob_match(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules_with(files: &[(&str, &str)], global: &[&str]) -> (tempfile::TempDir, IgnoreRules) {
+        let temp_dir = tempfile::tempdir().unwrap();
+        for (path, content) in files {
+            let path = temp_dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+
+        let global: Vec<String> = global.iter().map(|pattern| pattern.to_string()).collect();
+        let rules = IgnoreRules::new(temp_dir.path(), &global);
+        (temp_dir, rules)
+    }
+
+    #[test]
+    fn test_glob_patterns() {
+        let matches = |pattern: &str, path: &str| {
+            Rule::parse(pattern, "", RuleSource::Global).unwrap().matches(path, false)
+        };
+
+        assert!(matches("*.swp", "src/.main.rs.swp"));
+        assert!(!matches("*.swp", "src/main.rs"));
+        assert!(matches("/build.log", "build.log"));
+        assert!(!matches("/build.log", "sub/build.log"));
+        assert!(matches("docs/*.md", "docs/readme.md"));
+        assert!(!matches("docs/*.md", "docs/api/readme.md"));
+        assert!(matches("docs/**/*.md", "docs/readme.md"));
+        assert!(matches("docs/**/*.md", "docs/api/v1/readme.md"));
+        assert!(matches("**/cache", "a/b/cache"));
+        assert!(matches("**/cache", "cache"));
+        assert!(matches("logs/**", "logs/2024/jan.log"));
+        assert!(matches("file[0-9].txt", "file7.txt"));
+        assert!(!matches("file[!0-9].txt", "file7.txt"));
+        assert!(matches("photo?.jpg", "photo1.jpg"));
+        assert!(matches("\\#notes", "#notes"));
+        assert!(Rule::parse("# comment", "", RuleSource::Global).is_none());
+    }
+
+    #[test]
+    fn test_directories_and_negation() {
+        let (_dir, rules) = rules_with(
+            &[
+                (IGNORE_FILE, "target/\n*.log\n!keep.log\nnode_modules\n"),
+                ("web/.rustguardignore", "!debug.log\n"),
+            ],
+            &["*.tmp"],
+        );
+
+        assert!(rules.is_ignored("target", true));
+        assert!(!rules.is_ignored("target", false));
+        assert!(rules.is_ignored("target/debug/app", false));
+        assert!(rules.is_ignored("web/node_modules/react/index.js", false));
+        assert!(rules.is_ignored("server.log", false));
+        assert!(!rules.is_ignored("keep.log", false));
+        assert!(!rules.is_ignored("web/debug.log", false));
+        assert!(rules.is_ignored("web/other.log", false));
+        assert!(rules.is_ignored("notes.tmp", false));
+        assert!(rules.is_ignored(".rustguard-notes.txt.remote", false));
+        assert!(!rules.is_ignored("src/main.rs", false));
+    }
+
+    #[test]
+    fn test_excluded_directory_cannot_be_reincluded() {
+        let (_dir, rules) = rules_with(&[(IGNORE_FILE, "build/\n!build/keep.txt\n")], &[]);
+
+        let found = rules.explain("build/keep.txt", false).unwrap();
+        assert!(found.is_ignored());
+        assert_eq!(found.matched_path, "build");
+        assert_eq!(found.rule.pattern, "build/");
+        assert_eq!(
+            found.rule.source,
+            RuleSource::File {
+                path: IGNORE_FILE.to_string(),
+                line: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,951 @@
+//! Local sync index
+//!
+//! Records what the client last knew about each file in a sync directory:
+//! its stat data (size, mtime, inode), its content fingerprint and the
+//! remote file it maps to. A scan trusts the stored fingerprint while the
+//! stat data is unchanged, so only files that were actually touched are
+//! read again.
+//!
+//! Paths are stored relative to their sync root with `/` separators.
+
+use super::conflict::{ConflictDetails, ConflictKind, ConflictRecord, ConflictResolution, ConflictSide};
+use super::ignore::IgnoreRules;
+use crate::crypto::FingerprintKey;
+use crate::error::{Error, Result};
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+/// A file modified this close to when it was indexed may have changed again
+/// within the same mtime tick, so its stat data is not trusted
+const RACY_WINDOW_NS: i64 = 2_000_000_000;
+
+/// Stat data used to decide whether a file needs hashing again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: u64,
+    pub mtime_ns: i64,
+    pub inode: u64,
+}
+
+impl FileStat {
+    /// Reads the stat data of `path`
+    pub fn read(path: &Path) -> Result<Self> {
+        Ok(Self::from_metadata(&fs::metadata(path)?))
+    }
+
+    fn from_metadata(metadata: &fs::Metadata) -> Self {
+        Self {
+            size: metadata.len(),
+            mtime_ns: metadata.modified().map(system_time_ns).unwrap_or(0),
+            inode: inode(metadata),
+        }
+    }
+}
+
+/// What the index knows about one file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Path relative to the sync root
+    pub path: String,
+    pub stat: FileStat,
+    /// Keyed content fingerprint
+    pub fingerprint: String,
+    pub remote_id: Option<String>,
+    pub remote_version: Option<u64>,
+    /// Fingerprint both sides agreed on at the last sync; the common base
+    /// for three-way conflict detection
+    pub base_fingerprint: Option<String>,
+    /// When the stat data and fingerprint were recorded
+    pub indexed_at_ns: i64,
+}
+
+impl IndexEntry {
+    /// Entry for a file that was just synced, so both sides hold `fingerprint`
+    pub fn synced(path: &str, stat: FileStat, fingerprint: &str, remote_id: &str, remote_version: u64) -> Self {
+        Self {
+            path: path.to_string(),
+            stat,
+            fingerprint: fingerprint.to_string(),
+            remote_id: Some(remote_id.to_string()),
+            remote_version: Some(remote_version),
+            base_fingerprint: Some(fingerprint.to_string()),
+            indexed_at_ns: system_time_ns(SystemTime::now()),
+        }
+    }
+
+    /// Returns true if a file with `stat` can be assumed to still have this
+    /// entry's content
+    pub fn matches(&self, stat: &FileStat) -> bool {
+        self.stat == *stat && self.indexed_at_ns - stat.mtime_ns > RACY_WINDOW_NS
+    }
+}
+
+/// Result of scanning a sync directory against the index
+#[derive(Debug, Default)]
+pub struct ScanResult {
+    pub created: Vec<String>,
+    pub modified: Vec<String>,
+    pub deleted: Vec<String>,
+    pub unchanged: usize,
+    /// Number of files whose content was read
+    pub hashed: usize,
+}
+
+impl ScanResult {
+    /// Returns true if nothing changed since the last scan
+    pub fn is_clean(&self) -> bool {
+        self.created.is_empty() && self.modified.is_empty() && self.deleted.is_empty()
+    }
+}
+
+/// Persistent index of synced files, stored in SQLite
+pub struct SyncIndex {
+    pool: SqlitePool,
+}
+
+impl SyncIndex {
+    /// Opens the index at `path`, creating it if needed
+    pub async fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        let index = Self { pool };
+        index.init_schema().await?;
+        Ok(index)
+    }
+
+    async fn init_schema(&self) -> Result<()> {
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS index_entries (
+                root TEXT NOT NULL,
+                path TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                mtime_ns INTEGER NOT NULL,
+                inode INTEGER NOT NULL,
+                fingerprint TEXT NOT NULL,
+                remote_id TEXT,
+                remote_version INTEGER,
+                base_fingerprint TEXT,
+                indexed_at_ns INTEGER NOT NULL,
+                PRIMARY KEY (root, path)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        self.add_column_if_missing("index_entries", "base_fingerprint", "TEXT").await?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS conflicts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                root TEXT NOT NULL,
+                path TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                resolution TEXT,
+                conflicted_copy TEXT,
+                device TEXT NOT NULL,
+                detected_at TEXT NOT NULL,
+                resolved_at TEXT,
+                remote_id TEXT,
+                local_size INTEGER,
+                local_modified TEXT,
+                remote_size INTEGER,
+                remote_modified TEXT,
+                remote_version INTEGER
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        for (column, definition) in [
+            ("resolved_at", "TEXT"),
+            ("remote_id", "TEXT"),
+            ("local_size", "INTEGER"),
+            ("local_modified", "TEXT"),
+            ("remote_size", "INTEGER"),
+            ("remote_modified", "TEXT"),
+            ("remote_version", "INTEGER"),
+        ] {
+            self.add_column_if_missing("conflicts", column, definition).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds a column to an existing table if an older schema lacks it
+    async fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) -> Result<()> {
+        let exists = sqlx::query_as::<_, (String,)>("SELECT name FROM pragma_table_info(?) WHERE name = ?")
+            .bind(table)
+            .bind(column)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if exists.is_none() {
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition))
+                .execute(&self.pool)
+                .await
+                .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the entry for `path` under `root`
+    pub async fn get(&self, root: &Path, path: &str) -> Result<Option<IndexEntry>> {
+        let row = sqlx::query_as::<_, EntryRow>(&format!(
+            "SELECT {} FROM index_entries WHERE root = ? AND path = ?",
+            ENTRY_COLUMNS
+        ))
+        .bind(root_key(root))
+        .bind(path)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(row.map(entry_from_row))
+    }
+
+    /// Returns all entries under `root`
+    pub async fn entries(&self, root: &Path) -> Result<Vec<IndexEntry>> {
+        let rows = sqlx::query_as::<_, EntryRow>(&format!(
+            "SELECT {} FROM index_entries WHERE root = ? ORDER BY path",
+            ENTRY_COLUMNS
+        ))
+        .bind(root_key(root))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(rows.into_iter().map(entry_from_row).collect())
+    }
+
+    /// Inserts or replaces an entry
+    pub async fn upsert(&self, root: &Path, entry: &IndexEntry) -> Result<()> {
+        upsert_entry(&self.pool, &root_key(root), entry).await
+    }
+
+    /// Records that a file was synced: both sides now hold `fingerprint`,
+    /// which becomes the base for future conflict detection
+    pub async fn mark_synced(
+        &self,
+        root: &Path,
+        path: &str,
+        remote_id: &str,
+        remote_version: u64,
+        fingerprint: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            "UPDATE index_entries SET remote_id = ?, remote_version = ?, base_fingerprint = ? WHERE root = ? AND path = ?",
+        )
+        .bind(remote_id)
+        .bind(remote_version as i64)
+        .bind(fingerprint)
+        .bind(root_key(root))
+        .bind(path)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Removes the entry for `path` under `root`
+    pub async fn remove(&self, root: &Path, path: &str) -> Result<()> {
+        sqlx::query("DELETE FROM index_entries WHERE root = ? AND path = ?")
+            .bind(root_key(root))
+            .bind(path)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Moves the entry for `from` to `to`, replacing any entry already there
+    pub async fn rename(&self, root: &Path, from: &str, to: &str) -> Result<()> {
+        let root_key = root_key(root);
+        let mut tx = self.pool.begin().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query("DELETE FROM index_entries WHERE root = ? AND path = ?")
+            .bind(&root_key)
+            .bind(to)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        sqlx::query("UPDATE index_entries SET path = ? WHERE root = ? AND path = ?")
+            .bind(to)
+            .bind(&root_key)
+            .bind(from)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        tx.commit().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+        Ok(())
+    }
+
+    /// Records a conflict that was resolved automatically, returning its id
+    pub async fn record_conflict(
+        &self,
+        root: &Path,
+        details: &ConflictDetails,
+        resolution: ConflictResolution,
+        conflicted_copy: Option<&str>,
+    ) -> Result<i64> {
+        let id = self.queue_conflict(root, details).await?;
+        self.resolve_conflict(id, resolution, conflicted_copy).await?;
+        Ok(id)
+    }
+
+    /// Queues a conflict for the user to resolve, returning its id
+    ///
+    /// An unresolved conflict already queued for the same path is replaced,
+    /// so repeated scans do not pile up duplicates.
+    pub async fn queue_conflict(&self, root: &Path, details: &ConflictDetails) -> Result<i64> {
+        let root_key = root_key(root);
+        let mut tx = self.pool.begin().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query("DELETE FROM conflicts WHERE root = ? AND path = ? AND resolution IS NULL")
+            .bind(&root_key)
+            .bind(&details.path)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        let result = sqlx::query(
+            "INSERT INTO conflicts (root, path, kind, device, detected_at, remote_id, local_size, local_modified, remote_size, remote_modified, remote_version) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&root_key)
+        .bind(&details.path)
+        .bind(details.kind.as_str())
+        .bind(&details.device)
+        .bind(Utc::now().to_rfc3339())
+        .bind(&details.remote_id)
+        .bind(details.local.as_ref().map(|side| side.size as i64))
+        .bind(details.local.as_ref().map(|side| side.modified.to_rfc3339()))
+        .bind(details.remote.as_ref().map(|side| side.size as i64))
+        .bind(details.remote.as_ref().map(|side| side.modified.to_rfc3339()))
+        .bind(details.remote.as_ref().and_then(|side| side.version).map(|version| version as i64))
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        tx.commit().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+        Ok(result.last_insert_rowid())
+    }
+
+    /// Marks a queued conflict as resolved
+    pub async fn resolve_conflict(
+        &self,
+        id: i64,
+        resolution: ConflictResolution,
+        conflicted_copy: Option<&str>,
+    ) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE conflicts SET resolution = ?, conflicted_copy = ?, resolved_at = ? WHERE id = ? AND resolution IS NULL",
+        )
+        .bind(resolution.as_str())
+        .bind(conflicted_copy)
+        .bind(Utc::now().to_rfc3339())
+        .bind(id)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::InvalidInput(format!("No unresolved conflict with id {}", id)));
+        }
+        Ok(())
+    }
+
+    /// Returns all conflicts still waiting for the user, oldest first
+    pub async fn pending_conflicts(&self) -> Result<Vec<ConflictRecord>> {
+        let rows = sqlx::query_as::<_, ConflictRow>(&format!(
+            "SELECT {} FROM conflicts WHERE resolution IS NULL ORDER BY id",
+            CONFLICT_COLUMNS
+        ))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        rows.into_iter().map(conflict_from_row).collect()
+    }
+
+    /// Returns the conflicts recorded under `root`, newest first
+    pub async fn conflict_history(&self, root: &Path) -> Result<Vec<ConflictRecord>> {
+        let rows = sqlx::query_as::<_, ConflictRow>(&format!(
+            "SELECT {} FROM conflicts WHERE root = ? ORDER BY id DESC",
+            CONFLICT_COLUMNS
+        ))
+        .bind(root_key(root))
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        rows.into_iter().map(conflict_from_row).collect()
+    }
+
+    /// Scans `root`, re-hashing only files whose stat data changed
+    ///
+    /// New and changed files are written to the index. Deleted files are
+    /// reported but keep their entries, since their remote ids are still
+    /// needed to propagate the deletion; call [`remove`](Self::remove) once
+    /// that is done.
+    pub async fn scan(&self, root: &Path, ignore: &IgnoreRules, key: &FingerprintKey) -> Result<ScanResult> {
+        self.scan_scope(root, None, ignore, key).await
+    }
+
+    /// Scans only `paths` under `root`, including everything below those
+    /// that are directories
+    ///
+    /// Lets reported changes be synced without walking the whole sync
+    /// directory; entries outside `paths` are neither read nor reported.
+    pub async fn scan_paths(
+        &self,
+        root: &Path,
+        paths: &[String],
+        ignore: &IgnoreRules,
+        key: &FingerprintKey,
+    ) -> Result<ScanResult> {
+        self.scan_scope(root, Some(paths), ignore, key).await
+    }
+
+    async fn scan_scope(
+        &self,
+        root: &Path,
+        scope: Option<&[String]>,
+        ignore: &IgnoreRules,
+        key: &FingerprintKey,
+    ) -> Result<ScanResult> {
+        let in_scope = |path: &str| scope.is_none_or(|scope| scope.iter().any(|dir| within(path, dir)));
+        let known: HashMap<String, IndexEntry> = self
+            .entries(root)
+            .await?
+            .into_iter()
+            .filter(|entry| in_scope(&entry.path))
+            .map(|entry| (entry.path.clone(), entry))
+            .collect();
+
+        // Nested scope paths are covered by their parents
+        let starts: Vec<PathBuf> = match scope {
+            None => vec![root.to_path_buf()],
+            Some(scope) => scope
+                .iter()
+                .filter(|path| !scope.iter().any(|other| other != *path && within(path, other)))
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .map(|path| local_path(root, path))
+                .collect(),
+        };
+
+        let walk_root = root.to_path_buf();
+        let walk_ignore = ignore.clone();
+        let key = key.duplicate();
+        let (outcomes, known) = tokio::task::spawn_blocking(move || {
+            let outcomes = starts
+                .iter()
+                .flat_map(|start| walk(&walk_root, start, &walk_ignore, &known, &key))
+                .collect::<Vec<_>>();
+            (outcomes, known)
+        })
+        .await
+        .map_err(|e| Error::SyncError(format!("Scan task failed: {}", e)))?;
+
+        let mut result = ScanResult::default();
+        let mut seen = HashSet::with_capacity(outcomes.len());
+        let root_key = root_key(root);
+        let mut tx = self.pool.begin().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        for outcome in outcomes {
+            match outcome {
+                ScanOutcome::Unchanged(path) => {
+                    result.unchanged += 1;
+                    seen.insert(path);
+                }
+                ScanOutcome::Hashed(entry) => {
+                    result.hashed += 1;
+                    match known.get(&entry.path) {
+                        None => result.created.push(entry.path.clone()),
+                        Some(previous) if previous.fingerprint != entry.fingerprint => {
+                            result.modified.push(entry.path.clone())
+                        }
+                        Some(_) => result.unchanged += 1,
+                    }
+                    upsert_entry(&mut *tx, &root_key, &entry).await?;
+                    seen.insert(entry.path);
+                }
+            }
+        }
+
+        tx.commit().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        // Files that became ignored are no longer synced, but not deleted either
+        result.deleted = known
+            .into_keys()
+            .filter(|path| !seen.contains(path) && !ignore.is_ignored(path, false))
+            .collect();
+        result.created.sort();
+        result.modified.sort();
+        result.deleted.sort();
+
+        debug!(
+            "Scanned {}: {} hashed, {} unchanged, {} created, {} modified, {} deleted",
+            root.display(),
+            result.hashed,
+            result.unchanged,
+            result.created.len(),
+            result.modified.len(),
+            result.deleted.len()
+        );
+        Ok(result)
+    }
+}
+
+enum ScanOutcome {
+    Unchanged(String),
+    Hashed(IndexEntry),
+}
+
+fn walk(
+    root: &Path,
+    start: &Path,
+    ignore: &IgnoreRules,
+    known: &HashMap<String, IndexEntry>,
+    key: &FingerprintKey,
+) -> Vec<ScanOutcome> {
+    let mut outcomes = Vec::new();
+    if !start.starts_with(root) || fs::symlink_metadata(start).is_err() {
+        return outcomes;
+    }
+
+    // Ignored directories are pruned, not descended into
+    let items = WalkDir::new(start).follow_links(false).into_iter().filter_entry(|item| {
+        item.path() == root
+            || relative_path(root, item.path())
+                .is_none_or(|path| !ignore.is_ignored(&path, item.file_type().is_dir()))
+    });
+
+    for item in items {
+        let item = match item {
+            Ok(item) => item,
+            Err(e) => {
+                warn!("Skipping unreadable entry during scan: {}", e);
+                continue;
+            }
+        };
+        if !item.file_type().is_file() {
+            continue;
+        }
+
+        let Some(path) = relative_path(root, item.path()) else {
+            warn!("Skipping file with a non UTF-8 path: {}", item.path().display());
+            continue;
+        };
+        let stat = match item.metadata() {
+            Ok(metadata) => FileStat::from_metadata(&metadata),
+            Err(e) => {
+                warn!("Skipping {}: {}", item.path().display(), e);
+                continue;
+            }
+        };
+
+        if let Some(entry) = known.get(&path) {
+            if entry.matches(&stat) {
+                outcomes.push(ScanOutcome::Unchanged(path));
+                continue;
+            }
+        }
+
+        let indexed_at_ns = system_time_ns(SystemTime::now());
+        match key.fingerprint_file(item.path()) {
+            Ok(fingerprint) => {
+                let previous = known.get(&path);
+                outcomes.push(ScanOutcome::Hashed(IndexEntry {
+                    remote_id: previous.and_then(|entry| entry.remote_id.clone()),
+                    remote_version: previous.and_then(|entry| entry.remote_version),
+                    base_fingerprint: previous.and_then(|entry| entry.base_fingerprint.clone()),
+                    path,
+                    stat,
+                    fingerprint,
+                    indexed_at_ns,
+                }));
+            }
+            // Most likely removed or locked while scanning; the next scan retries
+            Err(e) => warn!("Skipping {}: {}", item.path().display(), e),
+        }
+    }
+
+    outcomes
+}
+
+/// Converts `path` under `root` to the index's `/`-separated relative form
+pub fn relative_path(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative.components().map(|c| c.as_os_str().to_str()).collect();
+    Some(parts?.join("/"))
+}
+
+/// Returns true if `path` is `dir` or lies below it, both as index paths
+pub fn within(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// Converts an index path back to a local path under `root`
+pub fn local_path(root: &Path, path: &str) -> PathBuf {
+    path.split('/').fold(root.to_path_buf(), |local, part| local.join(part))
+}
+
+fn root_key(root: &Path) -> String {
+    root.to_string_lossy().into_owned()
+}
+
+fn system_time_ns(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_nanos() as i64,
+        Err(before) => -(before.duration().as_nanos() as i64),
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> u64 {
+    0
+}
+
+async fn upsert_entry<'e, E>(executor: E, root_key: &str, entry: &IndexEntry) -> Result<()>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    sqlx::query(&format!(
+        "INSERT OR REPLACE INTO index_entries (root, {}) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        ENTRY_COLUMNS
+    ))
+    .bind(root_key)
+    .bind(&entry.path)
+    .bind(entry.stat.size as i64)
+    .bind(entry.stat.mtime_ns)
+    .bind(entry.stat.inode as i64)
+    .bind(&entry.fingerprint)
+    .bind(&entry.remote_id)
+    .bind(entry.remote_version.map(|version| version as i64))
+    .bind(&entry.base_fingerprint)
+    .bind(entry.indexed_at_ns)
+    .execute(executor)
+    .await
+    .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+    Ok(())
+}
+
+const ENTRY_COLUMNS: &str =
+    "path, size, mtime_ns, inode, fingerprint, remote_id, remote_version, base_fingerprint, indexed_at_ns";
+
+type EntryRow = (String, i64, i64, i64, String, Option<String>, Option<i64>, Option<String>, i64);
+
+fn entry_from_row(row: EntryRow) -> IndexEntry {
+    let (path, size, mtime_ns, inode, fingerprint, remote_id, remote_version, base_fingerprint, indexed_at_ns) = row;
+
+    IndexEntry {
+        path,
+        stat: FileStat {
+            size: size as u64,
+            mtime_ns,
+            inode: inode as u64,
+        },
+        fingerprint,
+        remote_id,
+        remote_version: remote_version.map(|version| version as u64),
+        base_fingerprint,
+        indexed_at_ns,
+    }
+}
+
+const CONFLICT_COLUMNS: &str = "id, root, path, kind, resolution, conflicted_copy, device, detected_at, resolved_at, \
+     remote_id, local_size, local_modified, remote_size, remote_modified, remote_version";
+
+type ConflictRow = (
+    i64,
+    String,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    String,
+    String,
+    Option<String>,
+    Option<String>,
+    Option<i64>,
+    Option<String>,
+    Option<i64>,
+    Option<String>,
+    Option<i64>,
+);
+
+fn conflict_from_row(row: ConflictRow) -> Result<ConflictRecord> {
+    let (
+        id,
+        root,
+        path,
+        kind,
+        resolution,
+        conflicted_copy,
+        device,
+        detected_at,
+        resolved_at,
+        remote_id,
+        local_size,
+        local_modified,
+        remote_size,
+        remote_modified,
+        remote_version,
+    ) = row;
+
+    let local = match (local_size, local_modified) {
+        (Some(size), Some(modified)) => Some(ConflictSide {
+            size: size as u64,
+            modified: parse_time(&modified)?,
+            version: None,
+        }),
+        _ => None,
+    };
+    let remote = match (remote_size, remote_modified) {
+        (Some(size), Some(modified)) => Some(ConflictSide {
+            size: size as u64,
+            modified: parse_time(&modified)?,
+            version: remote_version.map(|version| version as u64),
+        }),
+        _ => None,
+    };
+
+    Ok(ConflictRecord {
+        id,
+        root: PathBuf::from(root),
+        details: ConflictDetails {
+            path,
+            kind: ConflictKind::parse(&kind)
+                .ok_or_else(|| Error::DatabaseError(format!("Unknown conflict kind: {}", kind)))?,
+            local,
+            remote,
+            remote_id,
+            device,
+        },
+        resolution: resolution.as_deref().and_then(ConflictResolution::parse),
+        conflicted_copy,
+        detected_at: parse_time(&detected_at)?,
+        resolved_at: resolved_at.as_deref().map(parse_time).transpose()?,
+    })
+}
+
+fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| Error::DatabaseError(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+
+    fn key() -> FingerprintKey {
+        FingerprintKey::derive(&SecretKey::from_bytes([3u8; 32]))
+    }
+
+    /// Backdates an entry so its stat data is outside the racy window
+    async fn age_entries(index: &SyncIndex, root: &Path) {
+        for mut entry in index.entries(root).await.unwrap() {
+            entry.indexed_at_ns += 10 * RACY_WINDOW_NS;
+            index.upsert(root, &entry).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_detects_changes() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("sync");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("a.txt"), b"alpha").unwrap();
+        fs::write(root.join("docs/b.txt"), b"beta").unwrap();
+
+        let index = SyncIndex::open(&temp_dir.path().join("index.db")).await.unwrap();
+        let first = index.scan(&root, &IgnoreRules::new(&root, &[]), &key()).await.unwrap();
+        assert_eq!(first.created, vec!["a.txt", "docs/b.txt"]);
+        assert_eq!(first.hashed, 2);
+
+        age_entries(&index, &root).await;
+        let synced = index.get(&root, "a.txt").await.unwrap().unwrap().fingerprint;
+        index.mark_synced(&root, "a.txt", "file-1", 3, &synced).await.unwrap();
+
+        let clean = index.scan(&root, &IgnoreRules::new(&root, &[]), &key()).await.unwrap();
+        assert!(clean.is_clean());
+        assert_eq!((clean.hashed, clean.unchanged), (0, 2));
+
+        fs::write(root.join("a.txt"), b"alpha, edited").unwrap();
+        fs::remove_file(root.join("docs/b.txt")).unwrap();
+        let changed = index.scan(&root, &IgnoreRules::new(&root, &[]), &key()).await.unwrap();
+        assert_eq!(changed.modified, vec!["a.txt"]);
+        assert_eq!(changed.deleted, vec!["docs/b.txt"]);
+        assert_eq!(changed.hashed, 1);
+
+        let entry = index.get(&root, "a.txt").await.unwrap().unwrap();
+        assert_eq!(entry.remote_id.as_deref(), Some("file-1"));
+        assert_eq!(entry.remote_version, Some(3));
+        assert_eq!(entry.base_fingerprint, Some(synced));
+        assert_ne!(entry.fingerprint, entry.base_fingerprint.clone().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_scan_respects_ignore_rules() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("sync");
+        fs::create_dir_all(root.join("target/debug")).unwrap();
+        fs::write(root.join(".rustguardignore"), "target/\n*.log\n").unwrap();
+        fs::write(root.join("target/debug/app"), b"binary").unwrap();
+        fs::write(root.join("main.rs"), b"fn main() {}").unwrap();
+        fs::write(root.join("build.log"), b"ok").unwrap();
+
+        let index = SyncIndex::open(&temp_dir.path().join("index.db")).await.unwrap();
+        let result = index.scan(&root, &IgnoreRules::new(&root, &[]), &key()).await.unwrap();
+        assert_eq!(result.created, vec![".rustguardignore", "main.rs"]);
+
+        // Files that become ignored are not reported as deleted
+        let everything_ignored = IgnoreRules::new(&root, &["*.rs".to_string()]);
+        let result = index.scan(&root, &everything_ignored, &key()).await.unwrap();
+        assert!(result.deleted.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scan_paths_stays_in_scope() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("sync");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("a.txt"), b"alpha").unwrap();
+        fs::write(root.join("docs/b.txt"), b"beta").unwrap();
+
+        let index = SyncIndex::open(&temp_dir.path().join("index.db")).await.unwrap();
+        let ignore = IgnoreRules::new(&root, &[]);
+        index.scan(&root, &ignore, &key()).await.unwrap();
+
+        fs::write(root.join("a.txt"), b"alpha, edited").unwrap();
+        fs::remove_file(root.join("docs/b.txt")).unwrap();
+        fs::write(root.join("docs/c.txt"), b"gamma").unwrap();
+
+        let scope = vec!["docs".to_string(), "docs/c.txt".to_string(), "missing.txt".to_string()];
+        let result = index.scan_paths(&root, &scope, &ignore, &key()).await.unwrap();
+        assert_eq!(result.created, vec!["docs/c.txt"]);
+        assert_eq!(result.deleted, vec!["docs/b.txt"]);
+        assert!(result.modified.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_index_survives_reopen() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("sync");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("photo.jpg"), b"pixels").unwrap();
+
+        let db_path = temp_dir.path().join("index.db");
+        {
+            let index = SyncIndex::open(&db_path).await.unwrap();
+            index.scan(&root, &IgnoreRules::new(&root, &[]), &key()).await.unwrap();
+            age_entries(&index, &root).await;
+        }
+
+        let index = SyncIndex::open(&db_path).await.unwrap();
+        let result = index.scan(&root, &IgnoreRules::new(&root, &[]), &key()).await.unwrap();
+        assert!(result.is_clean());
+        assert_eq!(result.hashed, 0);
+    }
+
+    fn conflict(path: &str) -> ConflictDetails {
+        ConflictDetails {
+            path: path.to_string(),
+            kind: ConflictKind::BothModified,
+            local: Some(ConflictSide {
+                size: 10,
+                modified: Utc::now(),
+                version: None,
+            }),
+            remote: Some(ConflictSide {
+                size: 12,
+                modified: Utc::now(),
+                version: Some(4),
+            }),
+            remote_id: Some("file-1".to_string()),
+            device: "desk".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conflict_queue() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("sync");
+        let index = SyncIndex::open(&temp_dir.path().join("index.db")).await.unwrap();
+
+        let copy = "notes (conflicted copy from desk 2024-03-05).txt";
+        index
+            .record_conflict(&root, &conflict("notes.txt"), ConflictResolution::KeepBoth, Some(copy))
+            .await
+            .unwrap();
+        let details = conflict("todo.txt");
+        index.queue_conflict(&root, &details).await.unwrap();
+        let queued = index.queue_conflict(&root, &details).await.unwrap();
+
+        let pending = index.pending_conflicts().await.unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, queued);
+        assert_eq!(pending[0].root, root);
+        assert_eq!(pending[0].details, details);
+
+        index.resolve_conflict(queued, ConflictResolution::KeepLocal, None).await.unwrap();
+        assert!(index.pending_conflicts().await.unwrap().is_empty());
+        assert!(index.resolve_conflict(queued, ConflictResolution::KeepRemote, None).await.is_err());
+
+        let history = index.conflict_history(&root).await.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].resolution, Some(ConflictResolution::KeepLocal));
+        assert_eq!(history[1].resolution, Some(ConflictResolution::KeepBoth));
+        assert_eq!(history[1].conflicted_copy.as_deref(), Some(copy));
+        assert!(history[1].resolved_at.is_some());
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let root = Path::new("/home/user/Documents");
+        let path = root.join("taxes").join("2024.pdf");
+
+        assert_eq!(relative_path(root, &path).as_deref(), Some("taxes/2024.pdf"));
+        assert_eq!(local_path(root, "taxes/2024.pdf"), path);
+        assert_eq!(relative_path(root, Path::new("/elsewhere/file")), None);
+    }
+}
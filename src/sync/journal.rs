@@ -0,0 +1,464 @@
+//! Persistent change journal
+//!
+//! Changes the watcher reports are written to the journal before anything
+//! is done with them, so they survive going offline and crashes alike. A
+//! drain syncs the journaled paths and only then removes the changes it
+//! covered; a failed drain leaves them in place to be retried after an
+//! exponential, jittered backoff. Redundant changes are merged before each
+//! drain, so a file created and deleted while offline costs nothing.
+
+use super::index::relative_path;
+use super::watcher::FileChangeEvent;
+use crate::error::{Error, Result};
+use chrono::Utc;
+use rand::Rng;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions};
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+/// A journaled change, with paths relative to its sync root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Created(String),
+    Modified(String),
+    Deleted(String),
+    Renamed(String, String),
+    /// Events were lost; the whole sync directory needs scanning
+    Rescan,
+}
+
+impl Change {
+    /// Converts a watcher event under `root`
+    ///
+    /// A rename across the edge of the sync directory becomes a create or a
+    /// delete. Returns `None` for events outside `root`.
+    pub fn from_event(root: &Path, event: &FileChangeEvent) -> Option<Self> {
+        let relative = |path: &Path| relative_path(root, path).filter(|path| !path.is_empty());
+        match event {
+            FileChangeEvent::Created(path) => relative(path).map(Self::Created),
+            FileChangeEvent::Modified(path) => relative(path).map(Self::Modified),
+            FileChangeEvent::Deleted(path) => relative(path).map(Self::Deleted),
+            FileChangeEvent::Renamed(from, to) => match (relative(from), relative(to)) {
+                (Some(from), Some(to)) => Some(Self::Renamed(from, to)),
+                (Some(from), None) => Some(Self::Deleted(from)),
+                (None, Some(to)) => Some(Self::Created(to)),
+                (None, None) => None,
+            },
+            FileChangeEvent::Rescan => Some(Self::Rescan),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Created(_) => "created",
+            Self::Modified(_) => "modified",
+            Self::Deleted(_) => "deleted",
+            Self::Renamed(_, _) => "renamed",
+            Self::Rescan => "rescan",
+        }
+    }
+
+    fn parse(kind: &str, path: String, to_path: Option<String>) -> Result<Self> {
+        match (kind, to_path) {
+            ("created", _) => Ok(Self::Created(path)),
+            ("modified", _) => Ok(Self::Modified(path)),
+            ("deleted", _) => Ok(Self::Deleted(path)),
+            ("renamed", Some(to)) => Ok(Self::Renamed(path, to)),
+            ("rescan", _) => Ok(Self::Rescan),
+            (kind, _) => Err(Error::SyncError(format!("Unknown journal change: {}", kind))),
+        }
+    }
+}
+
+/// Merges changes into the fewest that have the same effect, keeping order
+///
+/// A create followed by a delete cancels out, repeated edits collapse into
+/// one, and chained renames become a single rename from the first path to
+/// the last.
+pub fn coalesce(changes: impl IntoIterator<Item = Change>) -> Vec<Change> {
+    let mut merged = Vec::new();
+    for change in changes {
+        push(&mut merged, change);
+    }
+    merged
+}
+
+fn push(merged: &mut Vec<Change>, change: Change) {
+    match change {
+        Change::Created(path) => {
+            if take(merged, |c| *c == Change::Deleted(path.clone())).is_some() {
+                // Deleted and created again: the file was replaced
+                push(merged, Change::Modified(path));
+            } else if !has_edit(merged, &path) {
+                merged.push(Change::Created(path));
+            }
+        }
+        Change::Modified(path) => {
+            if !has_edit(merged, &path) {
+                merged.push(Change::Modified(path));
+            }
+        }
+        Change::Deleted(path) => {
+            take(merged, |c| *c == Change::Modified(path.clone()));
+            if take(merged, |c| *c == Change::Created(path.clone())).is_some() {
+                return;
+            }
+            if let Some(Change::Renamed(from, _)) = take(merged, |c| matches!(c, Change::Renamed(_, to) if *to == path)) {
+                push(merged, Change::Deleted(from));
+            } else if !merged.contains(&Change::Deleted(path.clone())) {
+                merged.push(Change::Deleted(path));
+            }
+        }
+        Change::Renamed(from, to) => {
+            if from == to {
+                return;
+            }
+            let modified = take(merged, |c| *c == Change::Modified(from.clone())).is_some();
+            if take(merged, |c| *c == Change::Created(from.clone())).is_some() {
+                push(merged, Change::Created(to));
+                return;
+            }
+
+            let origin = match take(merged, |c| matches!(c, Change::Renamed(_, renamed) if *renamed == from)) {
+                Some(Change::Renamed(origin, _)) => origin,
+                _ => from,
+            };
+            if origin != to {
+                merged.push(Change::Renamed(origin, to.clone()));
+            }
+            if modified {
+                push(merged, Change::Modified(to));
+            }
+        }
+        Change::Rescan => {
+            // Renames stay useful as move hints for the full scan
+            merged.retain(|c| matches!(c, Change::Renamed(_, _)));
+            merged.push(Change::Rescan);
+        }
+    }
+}
+
+fn has_edit(merged: &[Change], path: &str) -> bool {
+    merged
+        .iter()
+        .any(|c| matches!(c, Change::Created(p) | Change::Modified(p) if p == path))
+}
+
+fn take(merged: &mut Vec<Change>, matches: impl Fn(&Change) -> bool) -> Option<Change> {
+    let position = merged.iter().rposition(matches)?;
+    Some(merged.remove(position))
+}
+
+/// Coalesced changes waiting to be synced for one sync directory
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingChanges {
+    pub changes: Vec<Change>,
+    /// Highest journal sequence number covered; pass it to
+    /// [`ChangeJournal::complete`] once the changes are synced
+    pub up_to: i64,
+}
+
+impl PendingChanges {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Returns true if the whole sync directory has to be scanned
+    pub fn needs_rescan(&self) -> bool {
+        self.changes.contains(&Change::Rescan)
+    }
+
+    /// Paths to scan, including both ends of every rename
+    pub fn paths(&self) -> Vec<String> {
+        let paths: BTreeSet<&String> = self
+            .changes
+            .iter()
+            .flat_map(|change| match change {
+                Change::Created(path) | Change::Modified(path) | Change::Deleted(path) => vec![path],
+                Change::Renamed(from, to) => vec![from, to],
+                Change::Rescan => vec![],
+            })
+            .collect();
+        paths.into_iter().cloned().collect()
+    }
+
+    /// Renames, in the order they happened
+    pub fn moves(&self) -> Vec<(String, String)> {
+        self.changes
+            .iter()
+            .filter_map(|change| match change {
+                Change::Renamed(from, to) => Some((from.clone(), to.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Persistent journal of local changes not yet synced, stored in SQLite
+pub struct ChangeJournal {
+    pool: SqlitePool,
+}
+
+impl ChangeJournal {
+    /// Opens the journal at `path`, creating it if needed
+    pub async fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS changes (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                root TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                path TEXT NOT NULL,
+                to_path TEXT,
+                recorded_at TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(Self { pool })
+    }
+
+    /// Appends a change for the sync directory at `root`
+    pub async fn record(&self, root: &Path, change: &Change) -> Result<()> {
+        insert_change(&self.pool, &root_key(root), change).await
+    }
+
+    /// Returns the changes waiting for `root`, merged
+    ///
+    /// The merged form replaces the journaled changes, so the journal stays
+    /// small however long the client is offline.
+    pub async fn pending(&self, root: &Path) -> Result<PendingChanges> {
+        let root_key = root_key(root);
+        let mut tx = self.pool.begin().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        let rows = sqlx::query_as::<_, (i64, String, String, Option<String>)>(
+            "SELECT seq, kind, path, to_path FROM changes WHERE root = ? ORDER BY seq",
+        )
+        .bind(&root_key)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        let Some(&(last_seq, ..)) = rows.last() else {
+            return Ok(PendingChanges::default());
+        };
+
+        let count = rows.len();
+        let changes = coalesce(
+            rows.into_iter()
+                .map(|(_, kind, path, to_path)| Change::parse(&kind, path, to_path))
+                .collect::<Result<Vec<_>>>()?,
+        );
+        if changes.len() == count {
+            return Ok(PendingChanges { changes, up_to: last_seq });
+        }
+
+        sqlx::query("DELETE FROM changes WHERE root = ? AND seq <= ?")
+            .bind(&root_key)
+            .bind(last_seq)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+        for change in &changes {
+            insert_change(&mut *tx, &root_key, change).await?;
+        }
+        let (up_to,): (i64,) = sqlx::query_as("SELECT COALESCE(MAX(seq), 0) FROM changes WHERE root = ?")
+            .bind(&root_key)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        tx.commit().await.map_err(|e| Error::DatabaseError(e.to_string()))?;
+        Ok(PendingChanges { changes, up_to })
+    }
+
+    /// Removes the changes for `root` up to `up_to`, once they are synced
+    ///
+    /// Changes recorded while the drain ran have higher sequence numbers and
+    /// stay queued.
+    pub async fn complete(&self, root: &Path, up_to: i64) -> Result<()> {
+        sqlx::query("DELETE FROM changes WHERE root = ? AND seq <= ?")
+            .bind(root_key(root))
+            .bind(up_to)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+async fn insert_change<'e, E>(executor: E, root_key: &str, change: &Change) -> Result<()>
+where
+    E: sqlx::Executor<'e, Database = sqlx::Sqlite>,
+{
+    let (path, to_path) = match change {
+        Change::Created(path) | Change::Modified(path) | Change::Deleted(path) => (path.as_str(), None),
+        Change::Renamed(from, to) => (from.as_str(), Some(to.as_str())),
+        Change::Rescan => ("", None),
+    };
+
+    sqlx::query("INSERT INTO changes (root, kind, path, to_path, recorded_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(root_key)
+        .bind(change.kind())
+        .bind(path)
+        .bind(to_path)
+        .bind(Utc::now().to_rfc3339())
+        .execute(executor)
+        .await
+        .map_err(|e| Error::DatabaseError(e.to_string()))?;
+
+    Ok(())
+}
+
+fn root_key(root: &Path) -> String {
+    root.to_string_lossy().into_owned()
+}
+
+/// Exponential backoff with jitter for retrying failed drains
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max, failures: 0 }
+    }
+
+    /// Records a failure and returns how long to wait before retrying
+    ///
+    /// The wait doubles with each consecutive failure up to `max`; a random
+    /// half of it is jitter, so clients that went offline together do not
+    /// retry in lockstep.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.base.saturating_mul(1 << self.failures.min(16)).min(self.max);
+        self.failures = self.failures.saturating_add(1);
+
+        let half = ceiling / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    /// Records a success, so the next failure waits `base` again
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Number of consecutive failures
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn created(path: &str) -> Change {
+        Change::Created(path.to_string())
+    }
+
+    fn modified(path: &str) -> Change {
+        Change::Modified(path.to_string())
+    }
+
+    fn deleted(path: &str) -> Change {
+        Change::Deleted(path.to_string())
+    }
+
+    fn renamed(from: &str, to: &str) -> Change {
+        Change::Renamed(from.to_string(), to.to_string())
+    }
+
+    #[test]
+    fn test_coalesce_cancels_and_merges() {
+        assert_eq!(coalesce([created("a"), modified("a"), deleted("a")]), vec![]);
+        assert_eq!(coalesce([modified("a"), modified("a"), deleted("a")]), vec![deleted("a")]);
+        assert_eq!(coalesce([deleted("a"), created("a")]), vec![modified("a")]);
+        assert_eq!(coalesce([created("a"), renamed("a", "b")]), vec![created("b")]);
+        assert_eq!(coalesce([renamed("a", "b"), renamed("b", "c")]), vec![renamed("a", "c")]);
+        assert_eq!(coalesce([renamed("a", "b"), renamed("b", "a")]), vec![]);
+        assert_eq!(coalesce([renamed("a", "b"), deleted("b")]), vec![deleted("a")]);
+        assert_eq!(
+            coalesce([modified("a"), renamed("a", "b")]),
+            vec![renamed("a", "b"), modified("b")]
+        );
+        assert_eq!(
+            coalesce([modified("x"), Change::Rescan, renamed("a", "b"), modified("y")]),
+            vec![Change::Rescan, renamed("a", "b"), modified("y")]
+        );
+    }
+
+    #[test]
+    fn test_change_from_event() {
+        let root = PathBuf::from("/sync");
+        let event = FileChangeEvent::Renamed(root.join("a"), PathBuf::from("/elsewhere/a"));
+        assert_eq!(Change::from_event(&root, &event), Some(deleted("a")));
+
+        let event = FileChangeEvent::Renamed(root.join("a"), root.join("docs/a"));
+        assert_eq!(Change::from_event(&root, &event), Some(renamed("a", "docs/a")));
+
+        let event = FileChangeEvent::Modified(PathBuf::from("/elsewhere/a"));
+        assert_eq!(Change::from_event(&root, &event), None);
+    }
+
+    #[tokio::test]
+    async fn test_journal_survives_reopen_and_compacts() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("journal.db");
+        let root = temp_dir.path().join("sync");
+
+        {
+            let journal = ChangeJournal::open(&path).await.unwrap();
+            for change in [created("tmp"), modified("a"), deleted("tmp"), renamed("b", "c")] {
+                journal.record(&root, &change).await.unwrap();
+            }
+        }
+
+        let journal = ChangeJournal::open(&path).await.unwrap();
+        let pending = journal.pending(&root).await.unwrap();
+        assert_eq!(pending.changes, vec![modified("a"), renamed("b", "c")]);
+        assert_eq!(pending.paths(), vec!["a", "b", "c"]);
+        assert_eq!(pending.moves(), vec![("b".to_string(), "c".to_string())]);
+
+        // Recorded during the drain, so not covered by it
+        journal.record(&root, &modified("d")).await.unwrap();
+        journal.complete(&root, pending.up_to).await.unwrap();
+        assert_eq!(journal.pending(&root).await.unwrap().changes, vec![modified("d")]);
+    }
+
+    #[test]
+    fn test_backoff_grows_with_jitter() {
+        let mut backoff = Backoff::new(Duration::from_secs(2), Duration::from_secs(60));
+        for ceiling in [2, 4, 8, 16, 32, 60, 60] {
+            let delay = backoff.next_delay();
+            let ceiling = Duration::from_secs(ceiling);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?} not within {:?}", delay, ceiling);
+        }
+        assert_eq!(backoff.failures(), 7);
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_secs(2));
+    }
+}
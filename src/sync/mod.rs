@@ -0,0 +1,58 @@
+//! File synchronization engine
+
+pub mod conflict;
+pub mod ignore;
+pub mod index;
+pub mod journal;
+pub mod planner;
+pub mod watcher;
+
+pub use conflict::{
+    reconcile, ConflictKind, ConflictRecord, ConflictResolution, ResolvedConflict, SyncDecision,
+};
+pub use ignore::IgnoreRules;
+pub use index::{IndexEntry, ScanResult, SyncIndex};
+pub use journal::{Backoff, Change, ChangeJournal};
+pub use planner::{RemoteEntry, SyncPlan};
+pub use watcher::{FileChangeEvent, FileWatcher};
+
+use crate::crypto::FingerprintKey;
+use crate::error::Result;
+use std::path::Path;
+
+/// Computes the keyed content fingerprint of a file for change detection
+pub fn compute_file_hash(path: &Path, key: &FingerprintKey) -> Result<String> {
+    key.fingerprint_file(path)
+}
+
+/// Detects changes between a local file and the fingerprint recorded remotely
+///
+/// Always reads the whole file; use [`SyncIndex::scan`] for sync directories.
+pub fn detect_changes(local_path: &Path, remote_hash: &str, key: &FingerprintKey) -> Result<bool> {
+    let local_hash = compute_file_hash(local_path, key)?;
+    Ok(local_hash != remote_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use std::fs;
+    use std::io::Write;
+
+    #[test]
+    fn test_compute_file_hash() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let file_path = temp_dir.path().join("test.txt");
+        let mut file = fs::File::create(&file_path).unwrap();
+        file.write_all(b"test content").unwrap();
+
+        let key = FingerprintKey::derive(&SecretKey::from_bytes([1u8; 32]));
+        let hash = compute_file_hash(&file_path, &key).unwrap();
+        assert!(!hash.is_empty());
+        assert!(!detect_changes(&file_path, &hash, &key).unwrap());
+
+        let other_key = FingerprintKey::derive(&SecretKey::from_bytes([2u8; 32]));
+        assert!(detect_changes(&file_path, &hash, &other_key).unwrap());
+    }
+}
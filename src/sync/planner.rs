@@ -0,0 +1,576 @@
+//! Sync planning
+//!
+//! A sync pass first builds a [`SyncPlan`] from three inputs: the local sync
+//! index after a scan, the remote listing, and the base each file had when
+//! it was last synced. Every path is decided with [`reconcile`]; renames are
+//! then recognised, remotely by a file id turning up at a new path and
+//! locally by a rename the watcher reported or a deleted file's content
+//! reappearing under a new name. Renames are carried out as moves, so the
+//! content is not transferred again. The plan only describes what to do, so
+//! it can be shown before anything is changed.
+
+use super::conflict::{reconcile, ConflictDetails, ConflictKind, ConflictSide, SyncDecision};
+use super::ignore::IgnoreRules;
+use super::index::IndexEntry;
+use crate::crypto::paths::normalize_path;
+use crate::models::FileMetadata;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// A file in the remote listing, with its path relative to the sync root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteEntry {
+    pub path: String,
+    pub id: String,
+    /// Keyed content fingerprint
+    pub fingerprint: String,
+    pub size: u64,
+    pub version: u64,
+    pub modified: DateTime<Utc>,
+}
+
+/// Returns the files of a decrypted listing that lie under `remote_root`
+///
+/// Remote paths are stored normalized, so `remote_root` is normalized the
+/// same way before matching.
+pub fn remote_entries(files: &[FileMetadata], remote_root: &str) -> Vec<RemoteEntry> {
+    let root = normalize_path(remote_root);
+    files
+        .iter()
+        .filter(|file| !file.is_deleted)
+        .filter_map(|file| {
+            let path = match root.as_str() {
+                "" => file.path.as_str(),
+                root => file.path.strip_prefix(root)?.strip_prefix('/')?,
+            };
+            let path = Some(path).filter(|path| !path.is_empty())?;
+            Some(RemoteEntry {
+                path: path.to_string(),
+                id: file.id.clone(),
+                fingerprint: file.encrypted_hash.clone(),
+                size: file.size,
+                version: file.version,
+                modified: file.updated_at,
+            })
+        })
+        .collect()
+}
+
+/// Which side of the sync a rename is carried out on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    Local,
+    Remote,
+}
+
+/// A file whose content is copied from one side to the other
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedTransfer {
+    /// Path relative to the sync root
+    pub path: String,
+    pub size: u64,
+    /// Fingerprint of the content being transferred
+    pub fingerprint: String,
+    /// Remote file that is replaced or read; `None` for new uploads
+    pub remote_id: Option<String>,
+    pub remote_version: Option<u64>,
+}
+
+/// A file removed on one side because it was deleted on the other
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedDelete {
+    pub path: String,
+    pub remote_id: Option<String>,
+}
+
+/// A file that moved on one side and is moved to match on the other
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedRename {
+    pub from: String,
+    pub to: String,
+    /// Side the rename is applied to, opposite to where it happened
+    pub side: Side,
+    pub remote_id: String,
+}
+
+/// A file both sides changed, left for the user to resolve
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedConflict {
+    pub path: String,
+    pub kind: ConflictKind,
+    /// `None` if the file was deleted locally
+    pub local: Option<ConflictSide>,
+    /// `None` if the file was deleted remotely
+    pub remote: Option<ConflictSide>,
+    pub remote_id: Option<String>,
+}
+
+impl PlannedConflict {
+    /// Details to queue the conflict with; `device` names this machine
+    pub fn details(&self, device: &str) -> ConflictDetails {
+        ConflictDetails {
+            path: self.path.clone(),
+            kind: self.kind,
+            local: self.local.clone(),
+            remote: self.remote.clone(),
+            remote_id: self.remote_id.clone(),
+            device: device.to_string(),
+        }
+    }
+}
+
+/// Everything one sync pass would do for a sync directory
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SyncPlan {
+    pub uploads: Vec<PlannedTransfer>,
+    pub downloads: Vec<PlannedTransfer>,
+    /// Local files to delete because they were deleted remotely
+    pub local_deletes: Vec<PlannedDelete>,
+    /// Remote files to delete because they were deleted locally
+    pub remote_deletes: Vec<PlannedDelete>,
+    pub renames: Vec<PlannedRename>,
+    pub conflicts: Vec<PlannedConflict>,
+    /// Files both sides changed identically; only their base is recorded
+    pub converged: Vec<PlannedTransfer>,
+    /// Files deleted on both sides; only their index entries remain
+    pub forgotten: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl SyncPlan {
+    /// Plans a sync pass from the index `entries` of a freshly scanned sync
+    /// directory and the `remote` files under it
+    ///
+    /// `deleted` lists the entries whose files the scan no longer found, and
+    /// `moves` the renames the watcher reported since the last pass, in
+    /// order; renaming a directory moves everything under it. Paths excluded
+    /// by `ignore` are left alone on both sides.
+    pub fn build(
+        entries: &[IndexEntry],
+        deleted: &[String],
+        moves: &[(String, String)],
+        remote: &[RemoteEntry],
+        ignore: &IgnoreRules,
+    ) -> Self {
+        let deleted: HashSet<&str> = deleted.iter().map(String::as_str).collect();
+        let local: HashMap<&str, &IndexEntry> = entries
+            .iter()
+            .filter(|entry| !ignore.is_ignored(&entry.path, false))
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect();
+        let remote: Vec<&RemoteEntry> = remote.iter().filter(|file| !ignore.is_ignored(&file.path, false)).collect();
+        let remote_by_path: HashMap<&str, &RemoteEntry> = remote.iter().map(|file| (file.path.as_str(), *file)).collect();
+        let remote_by_id: HashMap<&str, &RemoteEntry> = remote.iter().map(|file| (file.id.as_str(), *file)).collect();
+
+        let mut plan = Self::default();
+        let mut slots: Vec<Slot> = Vec::new();
+        let mut claimed: HashSet<&str> = HashSet::new();
+
+        // A synced file whose remote id now lives at a free path was moved remotely
+        for entry in local.values() {
+            if deleted.contains(entry.path.as_str()) || entry.base_fingerprint.is_none() {
+                continue;
+            }
+            let Some(moved) = entry.remote_id.as_deref().and_then(|id| remote_by_id.get(id)) else {
+                continue;
+            };
+            if moved.path == entry.path
+                || remote_by_path.contains_key(entry.path.as_str())
+                || local.contains_key(moved.path.as_str())
+            {
+                continue;
+            }
+
+            plan.renames.push(PlannedRename {
+                from: entry.path.clone(),
+                to: moved.path.clone(),
+                side: Side::Local,
+                remote_id: moved.id.clone(),
+            });
+            claimed.insert(entry.path.as_str());
+            claimed.insert(moved.path.as_str());
+            slots.push(Slot {
+                path: &moved.path,
+                entry: Some(entry),
+                present: true,
+                remote: Some(moved),
+            });
+        }
+
+        let paths: BTreeSet<&str> = local.keys().chain(remote_by_path.keys()).copied().collect();
+        for path in paths {
+            if claimed.contains(path) {
+                continue;
+            }
+            slots.push(Slot {
+                path,
+                entry: local.get(path).copied(),
+                present: local.contains_key(path) && !deleted.contains(path),
+                remote: remote_by_path.get(path).copied(),
+            });
+        }
+        slots.sort_by(|a, b| a.path.cmp(b.path));
+
+        for slot in &slots {
+            plan.decide(slot);
+        }
+        plan.pair_local_renames(&local, moves);
+        plan.renames.sort_by(|a, b| a.from.cmp(&b.from));
+        plan
+    }
+
+    /// Returns true if the pass would change files on either side
+    pub fn has_changes(&self) -> bool {
+        !(self.uploads.is_empty()
+            && self.downloads.is_empty()
+            && self.local_deletes.is_empty()
+            && self.remote_deletes.is_empty()
+            && self.renames.is_empty()
+            && self.conflicts.is_empty())
+    }
+
+    fn decide(&mut self, slot: &Slot) {
+        let base = slot.entry.and_then(|entry| entry.base_fingerprint.as_deref());
+        let local = slot.local().map(|entry| entry.fingerprint.as_str());
+        let remote = slot.remote.map(|file| file.fingerprint.as_str());
+        let path = slot.path.to_string();
+        let remote_id = slot.remote.map(|file| file.id.clone());
+
+        match reconcile(base, local, remote) {
+            SyncDecision::Unchanged => self.unchanged += 1,
+            SyncDecision::Converged => match slot.remote {
+                Some(file) => self.converged.push(PlannedTransfer::from_remote(path, file)),
+                None => self.forgotten.push(path),
+            },
+            SyncDecision::Upload => {
+                let entry = slot.local().expect("uploads have local content");
+                self.uploads.push(PlannedTransfer {
+                    path,
+                    size: entry.stat.size,
+                    fingerprint: entry.fingerprint.clone(),
+                    remote_id,
+                    remote_version: slot.remote.map(|file| file.version),
+                });
+            }
+            SyncDecision::Download => {
+                let file = slot.remote.expect("downloads have remote content");
+                self.downloads.push(PlannedTransfer::from_remote(path, file));
+            }
+            SyncDecision::DeleteLocal => self.local_deletes.push(PlannedDelete { path, remote_id: None }),
+            SyncDecision::DeleteRemote => self.remote_deletes.push(PlannedDelete { path, remote_id }),
+            SyncDecision::Conflict(kind) => self.conflicts.push(PlannedConflict {
+                path,
+                kind,
+                local: slot.local().map(|entry| ConflictSide {
+                    size: entry.stat.size,
+                    modified: DateTime::from_timestamp_nanos(entry.stat.mtime_ns),
+                    version: None,
+                }),
+                remote: slot.remote.map(|file| ConflictSide {
+                    size: file.size,
+                    modified: file.modified,
+                    version: Some(file.version),
+                }),
+                remote_id,
+            }),
+        }
+    }
+
+    /// Turns a remote delete and a new upload into a remote move
+    ///
+    /// A new file is paired with a deleted one if the watcher saw the rename,
+    /// or else if it has the deleted file's content, preferring one with the
+    /// same file name. If a moved file was also edited, its upload stays in
+    /// the plan and replaces the content after the move.
+    fn pair_local_renames(&mut self, local: &HashMap<&str, &IndexEntry>, moves: &[(String, String)]) {
+        let is_new = |upload: &PlannedTransfer| {
+            upload.remote_id.is_none()
+                && local
+                    .get(upload.path.as_str())
+                    .is_none_or(|entry| entry.base_fingerprint.is_none())
+        };
+
+        let mut deletes = Vec::new();
+        for delete in std::mem::take(&mut self.remote_deletes) {
+            let base = local
+                .get(delete.path.as_str())
+                .and_then(|entry| entry.base_fingerprint.as_deref());
+            let (Some(base), Some(remote_id)) = (base, delete.remote_id.clone()) else {
+                deletes.push(delete);
+                continue;
+            };
+
+            let reported = moves
+                .iter()
+                .fold(delete.path.clone(), |path, (from, to)| moved_path(&path, from, to).unwrap_or(path));
+            let chosen = self
+                .uploads
+                .iter()
+                .position(|upload| upload.path == reported && is_new(upload))
+                .or_else(|| {
+                    let candidates: Vec<usize> = self
+                        .uploads
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, upload)| upload.fingerprint == base && is_new(upload))
+                        .map(|(i, _)| i)
+                        .collect();
+                    candidates
+                        .iter()
+                        .copied()
+                        .find(|&i| file_name(&self.uploads[i].path) == file_name(&delete.path))
+                        .or_else(|| candidates.first().copied())
+                });
+
+            let Some(i) = chosen else {
+                deletes.push(delete);
+                continue;
+            };
+            let to = self.uploads[i].path.clone();
+            if self.uploads[i].fingerprint == base {
+                self.uploads.remove(i);
+            } else {
+                self.uploads[i].remote_id = Some(remote_id.clone());
+            }
+            self.renames.push(PlannedRename {
+                from: delete.path,
+                to,
+                side: Side::Remote,
+                remote_id,
+            });
+        }
+        self.remote_deletes = deletes;
+    }
+}
+
+impl PlannedTransfer {
+    fn from_remote(path: String, file: &RemoteEntry) -> Self {
+        Self {
+            path,
+            size: file.size,
+            fingerprint: file.fingerprint.clone(),
+            remote_id: Some(file.id.clone()),
+            remote_version: Some(file.version),
+        }
+    }
+}
+
+/// One path as seen from both sides
+struct Slot<'a> {
+    path: &'a str,
+    /// Index entry holding the base, which for a remote rename is the
+    /// entry of the path the file moved from
+    entry: Option<&'a IndexEntry>,
+    /// Whether the entry's file still exists locally
+    present: bool,
+    remote: Option<&'a RemoteEntry>,
+}
+
+impl<'a> Slot<'a> {
+    fn local(&self) -> Option<&'a IndexEntry> {
+        self.entry.filter(|_| self.present)
+    }
+}
+
+/// Where `path` ended up if `from` was renamed to `to`
+fn moved_path(path: &str, from: &str, to: &str) -> Option<String> {
+    if path == from {
+        return Some(to.to_string());
+    }
+    let rest = path.strip_prefix(from)?.strip_prefix('/')?;
+    Some(format!("{}/{}", to, rest))
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::index::FileStat;
+
+    fn stat() -> FileStat {
+        FileStat {
+            size: 4,
+            mtime_ns: 1_700_000_000_000_000_000,
+            inode: 1,
+        }
+    }
+
+    fn local(path: &str, fingerprint: &str, base: Option<(&str, &str)>) -> IndexEntry {
+        IndexEntry {
+            path: path.to_string(),
+            stat: stat(),
+            fingerprint: fingerprint.to_string(),
+            remote_id: base.map(|(id, _)| id.to_string()),
+            remote_version: base.map(|_| 1),
+            base_fingerprint: base.map(|(_, base)| base.to_string()),
+            indexed_at_ns: 0,
+        }
+    }
+
+    fn remote(path: &str, id: &str, fingerprint: &str) -> RemoteEntry {
+        RemoteEntry {
+            path: path.to_string(),
+            id: id.to_string(),
+            fingerprint: fingerprint.to_string(),
+            size: 4,
+            version: 1,
+            modified: Utc::now(),
+        }
+    }
+
+    fn rules() -> (tempfile::TempDir, IgnoreRules) {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = IgnoreRules::new(dir.path(), &["*.tmp".to_string()]);
+        (dir, rules)
+    }
+
+    fn paths<T>(items: &[T], path: impl Fn(&T) -> &str) -> Vec<&str> {
+        items.iter().map(path).collect()
+    }
+
+    #[test]
+    fn test_plan_decides_each_path() {
+        let (_dir, ignore) = rules();
+        let entries = vec![
+            local("same.txt", "a", Some(("r1", "a"))),
+            local("edited.txt", "b2", Some(("r2", "b"))),
+            local("new.txt", "c", None),
+            local("gone-local.txt", "d", Some(("r4", "d"))),
+            local("gone-remote.txt", "e", Some(("r5", "e"))),
+            local("both.txt", "f2", Some(("r6", "f"))),
+        ];
+        let deleted = vec!["gone-local.txt".to_string()];
+        let remote_files = vec![
+            remote("same.txt", "r1", "a"),
+            remote("edited.txt", "r2", "b"),
+            remote("gone-local.txt", "r4", "d"),
+            remote("both.txt", "r6", "f3"),
+            remote("fresh.txt", "r7", "g"),
+        ];
+
+        let plan = SyncPlan::build(&entries, &deleted, &[], &remote_files, &ignore);
+
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(paths(&plan.uploads, |t| &t.path), ["edited.txt", "new.txt"]);
+        assert_eq!(paths(&plan.downloads, |t| &t.path), ["fresh.txt"]);
+        assert_eq!(paths(&plan.local_deletes, |d| &d.path), ["gone-remote.txt"]);
+        assert_eq!(paths(&plan.remote_deletes, |d| &d.path), ["gone-local.txt"]);
+        assert_eq!(plan.remote_deletes[0].remote_id.as_deref(), Some("r4"));
+        assert_eq!(plan.conflicts.len(), 1);
+        assert_eq!(plan.conflicts[0].kind, ConflictKind::BothModified);
+        assert!(plan.renames.is_empty());
+        assert!(plan.has_changes());
+    }
+
+    #[test]
+    fn test_plan_detects_remote_rename() {
+        let (_dir, ignore) = rules();
+        let entries = vec![local("old/a.txt", "a", Some(("r1", "a")))];
+        let remote_files = vec![remote("new/a.txt", "r1", "a")];
+
+        let plan = SyncPlan::build(&entries, &[], &[], &remote_files, &ignore);
+
+        assert_eq!(
+            plan.renames,
+            vec![PlannedRename {
+                from: "old/a.txt".to_string(),
+                to: "new/a.txt".to_string(),
+                side: Side::Local,
+                remote_id: "r1".to_string(),
+            }]
+        );
+        assert!(plan.downloads.is_empty() && plan.local_deletes.is_empty());
+        assert_eq!(plan.unchanged, 1);
+    }
+
+    #[test]
+    fn test_plan_detects_local_rename_by_content() {
+        let (_dir, ignore) = rules();
+        let entries = vec![
+            local("old/a.txt", "a", Some(("r1", "a"))),
+            local("copy.txt", "a", None),
+            local("new/a.txt", "a", None),
+        ];
+        let deleted = vec!["old/a.txt".to_string()];
+        let remote_files = vec![remote("old/a.txt", "r1", "a")];
+
+        let plan = SyncPlan::build(&entries, &deleted, &[], &remote_files, &ignore);
+
+        assert_eq!(plan.renames.len(), 1);
+        assert_eq!(plan.renames[0].to, "new/a.txt");
+        assert_eq!(plan.renames[0].side, Side::Remote);
+        assert!(plan.remote_deletes.is_empty());
+        assert_eq!(paths(&plan.uploads, |t| &t.path), ["copy.txt"]);
+    }
+
+    #[test]
+    fn test_plan_moves_edited_files_reported_by_watcher() {
+        let (_dir, ignore) = rules();
+        let entries = vec![
+            local("docs/a.txt", "a", Some(("r1", "a"))),
+            local("archive/docs/a.txt", "a2", None),
+        ];
+        let deleted = vec!["docs/a.txt".to_string()];
+        let moves = vec![("docs".to_string(), "archive/docs".to_string())];
+        let remote_files = vec![remote("docs/a.txt", "r1", "a")];
+
+        let plan = SyncPlan::build(&entries, &deleted, &moves, &remote_files, &ignore);
+
+        assert_eq!(plan.renames.len(), 1);
+        assert_eq!(plan.renames[0].to, "archive/docs/a.txt");
+        assert_eq!(plan.renames[0].side, Side::Remote);
+        assert!(plan.remote_deletes.is_empty());
+        // The edit is uploaded as a new version of the moved file
+        assert_eq!(paths(&plan.uploads, |t| &t.path), ["archive/docs/a.txt"]);
+        assert_eq!(plan.uploads[0].remote_id.as_deref(), Some("r1"));
+    }
+
+    #[test]
+    fn test_moved_path() {
+        assert_eq!(moved_path("a/b.txt", "a", "c").as_deref(), Some("c/b.txt"));
+        assert_eq!(moved_path("a", "a", "c").as_deref(), Some("c"));
+        assert_eq!(moved_path("ab/b.txt", "a", "c"), None);
+    }
+
+    #[test]
+    fn test_plan_skips_ignored_paths() {
+        let (_dir, ignore) = rules();
+        let entries = vec![local("scratch.tmp", "a", None)];
+        let remote_files = vec![remote("cache.tmp", "r1", "b")];
+
+        let plan = SyncPlan::build(&entries, &[], &[], &remote_files, &ignore);
+
+        assert!(!plan.has_changes());
+        assert_eq!(plan, SyncPlan::default());
+    }
+
+    #[test]
+    fn test_remote_entries_strips_root() {
+        let file = |path: &str| FileMetadata {
+            id: path.to_string(),
+            user_id: "u".to_string(),
+            path: path.to_string(),
+            name: String::new(),
+            path_token: None,
+            size: 1,
+            encrypted_hash: "h".to_string(),
+            chunk_count: 1,
+            version: 1,
+            wrapped_key: None,
+            chunk_recipe: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+            is_deleted: false,
+        };
+        let files = vec![file("docs/a.txt"), file("docs/sub/b.txt"), file("docsx/c.txt"), file("photos/d.jpg")];
+
+        let entries = remote_entries(&files, "/docs/");
+        assert_eq!(paths(&entries, |e| &e.path), ["a.txt", "sub/b.txt"]);
+        assert_eq!(remote_entries(&files, "/").len(), 4);
+    }
+}
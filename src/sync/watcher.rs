@@ -0,0 +1,582 @@
+//! Filesystem watching
+//!
+//! [`FileWatcher`] wraps a `notify` watcher. Raw events are fed through a
+//! [`Debouncer`] on a background thread, which merges bursts of events per
+//! path and pairs the two halves of a rename, and the result is delivered on
+//! an async channel. If events are lost, either because the OS queue
+//! overflowed or because the consumer fell behind, pending events are
+//! dropped and a single [`FileChangeEvent::Rescan`] is sent instead.
+//!
+//! Paths excluded by a root's [`IgnoreRules`] are filtered out before
+//! delivery; editing a `.rustguardignore` file reloads the rules.
+
+use super::ignore::{IgnoreRules, IGNORE_FILE};
+use super::index::relative_path;
+use crate::error::{Error, Result};
+use notify::event::{ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use parking_lot::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// How long a path must be quiet before its events are delivered
+pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Number of delivered events buffered before the watcher falls back to a rescan
+pub const EVENT_QUEUE_SIZE: usize = 4096;
+
+/// A path that keeps changing is still reported after this many debounce periods
+const MAX_DELAY_FACTOR: u32 = 10;
+
+/// File change event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileChangeEvent {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Deleted(PathBuf),
+    Renamed(PathBuf, PathBuf),
+    /// Events were lost; every watched path must be scanned again
+    Rescan,
+}
+
+/// File watcher for detecting changes
+pub struct FileWatcher {
+    watched_paths: Vec<PathBuf>,
+    debounce: Duration,
+    global_excludes: Vec<String>,
+    /// Exclusion rules of each watched root, shared with the debounce thread
+    ignore: Arc<RwLock<Vec<IgnoreRules>>>,
+    watcher: Option<RecommendedWatcher>,
+}
+
+impl FileWatcher {
+    /// Creates a new file watcher
+    pub fn new() -> Self {
+        Self {
+            watched_paths: Vec::new(),
+            debounce: DEFAULT_DEBOUNCE,
+            global_excludes: Vec::new(),
+            ignore: Arc::new(RwLock::new(Vec::new())),
+            watcher: None,
+        }
+    }
+
+    /// Sets exclude patterns that apply to every watched path
+    ///
+    /// Only affects paths added afterwards.
+    pub fn with_global_excludes(mut self, patterns: Vec<String>) -> Self {
+        self.global_excludes = patterns;
+        self
+    }
+
+    /// Sets how long a path must be quiet before its events are delivered
+    pub fn with_debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Adds a path to watch recursively
+    ///
+    /// Paths added after [`start_watching`](Self::start_watching) are
+    /// watched immediately.
+    pub fn add_path(&mut self, path: PathBuf) -> Result<()> {
+        if self.watched_paths.contains(&path) {
+            return Ok(());
+        }
+
+        if let Some(watcher) = &mut self.watcher {
+            watch(watcher, &path)?;
+        }
+        self.ignore.write().push(IgnoreRules::new(&path, &self.global_excludes));
+        self.watched_paths.push(path);
+        Ok(())
+    }
+
+    /// Starts watching all added paths and returns a channel for events
+    ///
+    /// Watching stops when the `FileWatcher` is dropped, which also closes
+    /// the channel.
+    pub fn start_watching(&mut self) -> Result<mpsc::Receiver<FileChangeEvent>> {
+        let (raw_tx, raw_rx) = std_mpsc::channel();
+        let (tx, rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+
+        let mut watcher = notify::recommended_watcher(raw_tx)
+            .map_err(|e| Error::SyncError(format!("Failed to start file watcher: {}", e)))?;
+        for path in &self.watched_paths {
+            watch(&mut watcher, path)?;
+        }
+
+        let debounce = self.debounce;
+        let ignore = self.ignore.clone();
+        std::thread::Builder::new()
+            .name("rustguard-watcher".to_string())
+            .spawn(move || run_debouncer(raw_rx, tx, debounce, ignore))?;
+
+        // Replacing a running watcher drops it, which ends its thread
+        self.watcher = Some(watcher);
+        Ok(rx)
+    }
+}
+
+impl Default for FileWatcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn watch(watcher: &mut RecommendedWatcher, path: &Path) -> Result<()> {
+    watcher
+        .watch(path, RecursiveMode::Recursive)
+        .map_err(|e| Error::SyncError(format!("Cannot watch {}: {}", path.display(), e)))
+}
+
+/// Debounces raw events until the watcher is dropped or the receiver closes
+fn run_debouncer(
+    raw: std_mpsc::Receiver<notify::Result<Event>>,
+    tx: mpsc::Sender<FileChangeEvent>,
+    debounce: Duration,
+    ignore: Arc<RwLock<Vec<IgnoreRules>>>,
+) {
+    let tick = (debounce / 2).max(Duration::from_millis(10));
+    let mut debouncer = Debouncer::new(debounce);
+    let mut needs_rescan = false;
+
+    loop {
+        match raw.recv_timeout(tick) {
+            Ok(Ok(event)) if event.need_rescan() => {
+                warn!("File watcher queue overflowed, scheduling a rescan");
+                needs_rescan = true;
+            }
+            Ok(Ok(event)) => debouncer.push(event, Instant::now()),
+            Ok(Err(e)) => {
+                warn!("File watcher error, scheduling a rescan: {}", e);
+                needs_rescan = true;
+            }
+            Err(std_mpsc::RecvTimeoutError::Timeout) => {}
+            Err(std_mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+
+        if needs_rescan {
+            // Everything pending is covered by the rescan
+            debouncer.clear();
+            match tx.try_send(FileChangeEvent::Rescan) {
+                Ok(()) => needs_rescan = false,
+                Err(mpsc::error::TrySendError::Full(_)) => continue,
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+            }
+        }
+
+        let events = debouncer.flush(Instant::now());
+        let events = filter_ignored(events, &ignore.read());
+        for event in events {
+            match tx.try_send(event) {
+                Ok(()) => {}
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    warn!("File change consumer is falling behind, scheduling a rescan");
+                    needs_rescan = true;
+                    break;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => return,
+            }
+        }
+    }
+}
+
+/// Drops events for excluded paths, reloading rules whose ignore file changed
+fn filter_ignored(events: Vec<FileChangeEvent>, rules: &[IgnoreRules]) -> Vec<FileChangeEvent> {
+    for event in &events {
+        let touched = match event {
+            FileChangeEvent::Renamed(from, to) => vec![from, to],
+            FileChangeEvent::Created(path) | FileChangeEvent::Modified(path) | FileChangeEvent::Deleted(path) => {
+                vec![path]
+            }
+            FileChangeEvent::Rescan => Vec::new(),
+        };
+        for path in touched {
+            if path.file_name().is_some_and(|name| name == IGNORE_FILE) {
+                if let Some(root_rules) = rules_for(rules, path) {
+                    root_rules.invalidate();
+                }
+            }
+        }
+    }
+
+    let ignored = |path: &Path| {
+        rules_for(rules, path).is_some_and(|root_rules| {
+            relative_path(root_rules.root(), path).is_some_and(|relative| root_rules.is_ignored(&relative, path.is_dir()))
+        })
+    };
+
+    events
+        .into_iter()
+        .filter_map(|event| match event {
+            FileChangeEvent::Renamed(from, to) => match (ignored(&from), ignored(&to)) {
+                (true, true) => None,
+                (true, false) => Some(FileChangeEvent::Created(to)),
+                (false, true) => Some(FileChangeEvent::Deleted(from)),
+                (false, false) => Some(FileChangeEvent::Renamed(from, to)),
+            },
+            FileChangeEvent::Created(ref path) | FileChangeEvent::Modified(ref path) | FileChangeEvent::Deleted(ref path)
+                if ignored(path) =>
+            {
+                None
+            }
+            event => Some(event),
+        })
+        .collect()
+}
+
+/// Rules of the innermost watched root containing `path`
+fn rules_for<'a>(rules: &'a [IgnoreRules], path: &Path) -> Option<&'a IgnoreRules> {
+    rules
+        .iter()
+        .filter(|root_rules| path.starts_with(root_rules.root()))
+        .max_by_key(|root_rules| root_rules.root().components().count())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change {
+    Created,
+    Modified,
+    Deleted,
+    Renamed { from: PathBuf, modified: bool },
+}
+
+#[derive(Debug)]
+struct Pending {
+    change: Change,
+    first_seen: Instant,
+    last_seen: Instant,
+}
+
+/// Merges raw `notify` events into one change per path
+///
+/// A path's change is released once no event has touched it for the
+/// debounce period, or once it has been pending for ten periods.
+#[derive(Debug)]
+struct Debouncer {
+    debounce: Duration,
+    pending: HashMap<PathBuf, Pending>,
+    /// First halves of renames, keyed by the platform's rename cookie
+    rename_from: HashMap<usize, (PathBuf, Instant)>,
+    /// Cookies of renames already paired, so a trailing combined event is ignored
+    paired: HashSet<usize>,
+}
+
+impl Debouncer {
+    fn new(debounce: Duration) -> Self {
+        Self {
+            debounce,
+            pending: HashMap::new(),
+            rename_from: HashMap::new(),
+            paired: HashSet::new(),
+        }
+    }
+
+    /// Records a raw event seen at `now`
+    fn push(&mut self, event: Event, now: Instant) {
+        let tracker = event.tracker();
+        let mut paths = event.paths.into_iter();
+
+        match event.kind {
+            EventKind::Create(_) => paths.for_each(|path| self.record(path, Change::Created, now)),
+            EventKind::Remove(_) => paths.for_each(|path| self.record(path, Change::Deleted, now)),
+            EventKind::Modify(ModifyKind::Name(mode)) => match (mode, tracker) {
+                (RenameMode::From, Some(tracker)) => {
+                    if let Some(path) = paths.next() {
+                        self.rename_from.insert(tracker, (path, now));
+                    }
+                }
+                (RenameMode::To, Some(tracker)) => {
+                    let Some(to) = paths.next() else { return };
+                    match self.rename_from.remove(&tracker) {
+                        Some((from, _)) => {
+                            self.paired.insert(tracker);
+                            self.record_rename(from, to, now);
+                        }
+                        None => self.record(to, Change::Created, now),
+                    }
+                }
+                (RenameMode::Both, tracker) => {
+                    if tracker.is_some_and(|tracker| self.paired.remove(&tracker)) {
+                        return;
+                    }
+                    if let (Some(from), Some(to)) = (paths.next(), paths.next()) {
+                        self.record_rename(from, to, now);
+                    }
+                }
+                // Without a cookie the other half cannot be found, so judge
+                // each path by whether it still exists
+                _ => paths.for_each(|path| {
+                    let change = if path.exists() { Change::Created } else { Change::Deleted };
+                    self.record(path, change, now);
+                }),
+            },
+            EventKind::Modify(ModifyKind::Metadata(_)) | EventKind::Access(_) => {}
+            EventKind::Modify(_) | EventKind::Any => {
+                paths.for_each(|path| self.record(path, Change::Modified, now))
+            }
+            EventKind::Other => {}
+        }
+    }
+
+    /// Returns the changes that have settled by `now`, oldest first
+    fn flush(&mut self, now: Instant) -> Vec<FileChangeEvent> {
+        // A rename whose second half never arrived moved out of the watched tree
+        let expired: Vec<usize> = self
+            .rename_from
+            .iter()
+            .filter(|(_, (_, seen))| now.duration_since(*seen) >= self.debounce)
+            .map(|(tracker, _)| *tracker)
+            .collect();
+        for tracker in expired {
+            if let Some((from, seen)) = self.rename_from.remove(&tracker) {
+                self.record(from, Change::Deleted, seen);
+            }
+        }
+
+        let max_delay = self.debounce * MAX_DELAY_FACTOR;
+        let settled: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| {
+                now.duration_since(pending.last_seen) >= self.debounce
+                    || now.duration_since(pending.first_seen) >= max_delay
+            })
+            .map(|(path, _)| path.clone())
+            .collect();
+        let mut ready: Vec<(PathBuf, Pending)> = settled
+            .into_iter()
+            .filter_map(|path| self.pending.remove_entry(&path))
+            .collect();
+        ready.sort_by(|(a_path, a), (b_path, b)| a.first_seen.cmp(&b.first_seen).then_with(|| a_path.cmp(b_path)));
+
+        if self.pending.is_empty() && self.rename_from.is_empty() {
+            self.paired.clear();
+        }
+
+        let mut events = Vec::with_capacity(ready.len());
+        for (path, pending) in ready {
+            match pending.change {
+                Change::Created => events.push(FileChangeEvent::Created(path)),
+                Change::Modified => events.push(FileChangeEvent::Modified(path)),
+                Change::Deleted => events.push(FileChangeEvent::Deleted(path)),
+                Change::Renamed { from, modified } => {
+                    events.push(FileChangeEvent::Renamed(from, path.clone()));
+                    if modified {
+                        events.push(FileChangeEvent::Modified(path));
+                    }
+                }
+            }
+        }
+        debug!("Delivering {} debounced file events", events.len());
+        events
+    }
+
+    /// Drops everything pending, e.g. because a rescan supersedes it
+    fn clear(&mut self) {
+        self.pending.clear();
+        self.rename_from.clear();
+        self.paired.clear();
+    }
+
+    fn record(&mut self, path: PathBuf, change: Change, now: Instant) {
+        let Some(mut pending) = self.pending.remove(&path) else {
+            self.insert(path, change, now, now);
+            return;
+        };
+
+        let merged = match (pending.change, change) {
+            (Change::Created, Change::Deleted) => None,
+            (Change::Created, _) => Some(Change::Created),
+            (Change::Renamed { from, .. }, Change::Deleted) => {
+                // The renamed file is gone, so what disappeared is the original
+                self.record(from, Change::Deleted, now);
+                None
+            }
+            (Change::Renamed { from, .. }, _) => Some(Change::Renamed { from, modified: true }),
+            (Change::Modified, Change::Deleted) | (Change::Deleted, Change::Deleted) => Some(Change::Deleted),
+            (Change::Modified | Change::Deleted, _) => Some(Change::Modified),
+        };
+
+        if let Some(change) = merged {
+            pending.change = change;
+            pending.last_seen = now;
+            self.pending.insert(path, pending);
+        }
+    }
+
+    fn record_rename(&mut self, from: PathBuf, to: PathBuf, now: Instant) {
+        let (change, first_seen) = match self.pending.remove(&from) {
+            // Created and moved within one burst: only the final path matters
+            Some(Pending { change: Change::Created, first_seen, .. }) => (Change::Created, first_seen),
+            Some(Pending { change: Change::Modified, first_seen, .. }) => {
+                (Change::Renamed { from, modified: true }, first_seen)
+            }
+            Some(Pending { change: Change::Renamed { from: original, modified }, first_seen, .. }) => {
+                if original == to {
+                    // Moved back where it started
+                    let change = if modified { Change::Modified } else { return };
+                    (change, first_seen)
+                } else {
+                    (Change::Renamed { from: original, modified }, first_seen)
+                }
+            }
+            Some(Pending { change: Change::Deleted, .. }) | None => {
+                (Change::Renamed { from, modified: false }, now)
+            }
+        };
+
+        if change == Change::Created {
+            // Saved through a temporary file; merge with what happened at `to`
+            match self.pending.remove(&to) {
+                Some(existing) => {
+                    let first_seen = existing.first_seen.min(first_seen);
+                    self.insert(to.clone(), existing.change, first_seen, now);
+                    self.record(to, Change::Modified, now);
+                }
+                None => self.insert(to, Change::Created, first_seen, now),
+            }
+        } else {
+            // Whatever was at `to` before has been replaced
+            self.insert(to, change, first_seen, now);
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, change: Change, first_seen: Instant, last_seen: Instant) {
+        self.pending.insert(
+            path,
+            Pending {
+                change,
+                first_seen,
+                last_seen,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange, RemoveKind};
+
+    const DEBOUNCE: Duration = Duration::from_millis(100);
+
+    fn event(kind: EventKind, paths: &[&str]) -> Event {
+        paths
+            .iter()
+            .fold(Event::new(kind), |event, path| event.add_path(PathBuf::from(path)))
+    }
+
+    fn modified(path: &str) -> Event {
+        event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &[path])
+    }
+
+    #[test]
+    fn test_file_watcher_creation() {
+        let watcher = FileWatcher::new();
+        assert!(watcher.watched_paths.is_empty());
+        assert!(watcher.watcher.is_none());
+    }
+
+    #[test]
+    fn test_add_path() {
+        let mut watcher = FileWatcher::new();
+        let path = PathBuf::from("./test");
+        assert!(watcher.add_path(path.clone()).is_ok());
+        assert!(watcher.add_path(path).is_ok());
+        assert_eq!(watcher.watched_paths.len(), 1);
+    }
+
+    #[test]
+    fn test_ignored_paths_are_filtered() {
+        let rules = vec![IgnoreRules::new(Path::new("/sync"), &["*.swp".to_string()])];
+        let events = vec![
+            FileChangeEvent::Modified(PathBuf::from("/sync/notes.txt")),
+            FileChangeEvent::Modified(PathBuf::from("/sync/.notes.txt.swp")),
+            FileChangeEvent::Renamed(PathBuf::from("/sync/.draft.swp"), PathBuf::from("/sync/draft.txt")),
+            FileChangeEvent::Rescan,
+        ];
+
+        assert_eq!(
+            filter_ignored(events, &rules),
+            vec![
+                FileChangeEvent::Modified(PathBuf::from("/sync/notes.txt")),
+                FileChangeEvent::Created(PathBuf::from("/sync/draft.txt")),
+                FileChangeEvent::Rescan,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bursts_are_merged() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DEBOUNCE);
+
+        debouncer.push(event(EventKind::Create(CreateKind::File), &["/a"]), start);
+        debouncer.push(modified("/a"), start);
+        debouncer.push(modified("/b"), start);
+        debouncer.push(modified("/b"), start + DEBOUNCE / 2);
+        debouncer.push(event(EventKind::Create(CreateKind::File), &["/tmp"]), start);
+        debouncer.push(event(EventKind::Remove(RemoveKind::File), &["/tmp"]), start);
+
+        assert!(debouncer.flush(start + DEBOUNCE / 2).is_empty());
+        assert_eq!(
+            debouncer.flush(start + DEBOUNCE),
+            vec![FileChangeEvent::Created(PathBuf::from("/a"))]
+        );
+        assert_eq!(
+            debouncer.flush(start + DEBOUNCE * 2),
+            vec![FileChangeEvent::Modified(PathBuf::from("/b"))]
+        );
+    }
+
+    #[test]
+    fn test_rename_halves_are_paired() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DEBOUNCE);
+
+        let from = event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &["/old"]).set_tracker(7);
+        let to = event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &["/new"]).set_tracker(7);
+        let both = event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &["/old", "/new"]).set_tracker(7);
+        debouncer.push(from, start);
+        debouncer.push(to, start);
+        debouncer.push(both, start);
+        debouncer.push(modified("/new"), start);
+
+        // A move out of the watched tree looks like a delete
+        let away = event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &["/gone"]).set_tracker(8);
+        debouncer.push(away, start);
+
+        assert_eq!(
+            debouncer.flush(start + DEBOUNCE),
+            vec![
+                FileChangeEvent::Deleted(PathBuf::from("/gone")),
+                FileChangeEvent::Renamed(PathBuf::from("/old"), PathBuf::from("/new")),
+                FileChangeEvent::Modified(PathBuf::from("/new")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_save_through_temporary_file() {
+        let start = Instant::now();
+        let mut debouncer = Debouncer::new(DEBOUNCE);
+
+        debouncer.push(event(EventKind::Create(CreateKind::File), &["/doc.swp"]), start);
+        debouncer.push(modified("/doc.swp"), start);
+        debouncer.push(event(EventKind::Remove(RemoveKind::File), &["/doc"]), start);
+        let rename = event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &["/doc.swp", "/doc"]);
+        debouncer.push(rename, start);
+
+        assert_eq!(
+            debouncer.flush(start + DEBOUNCE),
+            vec![FileChangeEvent::Modified(PathBuf::from("/doc"))]
+        );
+    }
+}